@@ -69,6 +69,8 @@ async fn main() -> eyre::Result<()> {
         })),
         check_pubkey: None,
         verify_signature: None,
+        keyboard_interactive: None,
+        auth_failure_delay: None,
         auth_banner: Some(
             "\
             !! this system ONLY allows catgirls to enter !!\r\n\
@@ -230,13 +232,17 @@ async fn handle_session_channel(
                             channel.send(ChannelOperationKind::Eof).await?;
                             channel.send(ChannelOperationKind::Close).await?;
                         }
-                        ChannelRequest::Subsystem { want_reply, .. } => {
+                        ChannelRequest::Subsystem { want_reply, .. }
+                        | ChannelRequest::AuthAgentReq { want_reply }
+                        | ChannelRequest::Break { want_reply, .. } => {
                             if want_reply {
                                 channel.send(ChannelOperationKind::Failure).await?;
                             }
                         }
                         ChannelRequest::ExitStatus { .. } => {}
                         ChannelRequest::Env { .. } => {}
+                        ChannelRequest::WindowChange { .. } => {}
+                        ChannelRequest::Signal { .. } => {}
                     };
                 }
                 ChannelUpdateKind::OpenFailed { .. } => todo!(),
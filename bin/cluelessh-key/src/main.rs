@@ -110,14 +110,16 @@ fn main() -> eyre::Result<()> {
                 .parse::<PublicKeyWithComment>()
                 .wrap_err("failed to parse public key")?;
 
+            let key_type = match public_key.key {
+                PublicKey::Ed25519 { .. } => cluelessh_keys::KeyType::Ed25519,
+                PublicKey::EcdsaSha2NistP256 { .. } => cluelessh_keys::KeyType::Ecdsa,
+                PublicKey::Certificate { .. } => {
+                    bail!("cannot create a private key for a certificate")
+                }
+            };
             let mut fake_private_key = PlaintextPrivateKey::generate(
                 "".into(),
-                cluelessh_keys::KeyGenerationParams {
-                    key_type: match public_key.key {
-                        PublicKey::Ed25519 { .. } => cluelessh_keys::KeyType::Ed25519,
-                        PublicKey::EcdsaSha2NistP256 { .. } => cluelessh_keys::KeyType::Ecdsa,
-                    },
-                },
+                cluelessh_keys::KeyGenerationParams { key_type },
             );
 
             match public_key.key {
@@ -141,6 +143,7 @@ fn main() -> eyre::Result<()> {
                     };
                     *fake_public_key = public_key;
                 }
+                PublicKey::Certificate { .. } => unreachable!(),
             }
 
             let fake_private_key = fake_private_key.encrypt(KeyEncryptionParams::plaintext())?;
@@ -181,7 +184,9 @@ fn info(id_file: &Path, decrypt: bool, show_private: bool) -> eyre::Result<()> {
 
         let keys = keys.decrypt(passphrase.as_deref())?;
         for key in keys {
-            println!("{} {}", key.private_key.public_key(), key.comment);
+            let public_key = key.private_key.public_key();
+            println!("{public_key} {}", key.comment);
+            println!("  fingerprint: {}", public_key.fingerprint_sha256());
             if show_private {
                 match key.private_key {
                     PrivateKey::Ed25519 { private_key, .. } => {
@@ -202,6 +207,7 @@ fn info(id_file: &Path, decrypt: bool, show_private: bool) -> eyre::Result<()> {
     } else {
         for key in keys.public_keys {
             println!("{key}");
+            println!("  fingerprint: {}", key.fingerprint_sha256());
         }
     }
     Ok(())
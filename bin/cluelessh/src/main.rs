@@ -1,27 +1,118 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 
+use cluelessh_keys::known_hosts::{HostKeyStore, KnownHosts, KnownHostsFile};
+use cluelessh_keys::private::{EncryptedPrivateKeys, PlaintextPrivateKey};
 use cluelessh_keys::public::PublicKey;
-use cluelessh_tokio::client::SignatureResult;
-use cluelessh_tokio::PendingChannel;
+use cluelessh_tokio::client::{disconnect_reason, AuthOption, ClientConnection, GlobalRequest};
+use cluelessh_tokio::identity::{Agent, Identities, PrivateKeys};
+use cluelessh_tokio::ssh_config;
+use cluelessh_tokio::stream::{self, CommandStream};
+use cluelessh_tokio::term_modes::TermModes;
+use cluelessh_tokio::{PendingChannel, SessionPty, WindowSize};
 use eyre::{bail, Context, ContextCompat, OptionExt, Result};
-use tokio::net::TcpStream;
-use tracing::{debug, error};
+use rustix::termios::{OptionalActions, Termios};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::signal::unix::SignalKind;
+use tracing::{debug, info, warn};
 
 use cluelessh_protocol::connection::{ChannelKind, ChannelOperationKind, ChannelRequest};
+use cluelessh_protocol::ChannelUpdateKind;
 use tracing_subscriber::EnvFilter;
 
 #[derive(clap::Parser, Debug)]
 struct Args {
-    #[arg(short = 'p', long, default_value_t = 22)]
-    port: u16,
+    /// The port to connect to, defaults to the `Port` from `~/.ssh/config` or 22.
+    #[arg(short = 'p', long)]
+    port: Option<u16>,
     #[arg(short, long)]
     user: Option<String>,
+    /// Never prompt for passwords, fail if non-interactive authentication does not succeed.
+    #[arg(long)]
+    batch_mode: bool,
+    /// The authentication methods to try in order, like `publickey,password`.
+    #[arg(long, value_delimiter = ',', value_parser = parse_auth_method)]
+    preferred_authentications: Option<Vec<AuthOption>>,
+    /// A private key file to authenticate with, before the keys of the SSH agent.
+    /// Can be passed multiple times.
+    #[arg(short = 'i', long)]
+    identity_file: Vec<PathBuf>,
+    /// Compress the connection, which helps with bulk transfers over slow links.
+    #[arg(short = 'C', long)]
+    compression: bool,
+    /// Give up if connecting or the handshake take longer than this many seconds.
+    /// With a `ProxyCommand`, this limits the handshake through the command,
+    /// which includes the time the command takes to connect.
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+    /// The local address to connect from, on hosts with several interfaces or addresses.
+    /// Defaults to the `BindAddress` from `~/.ssh/config`.
+    #[arg(short = 'b', long)]
+    bind_address: Option<IpAddr>,
+    /// Forward the SSH agent at `$SSH_AUTH_SOCK` to the server.
+    /// Only use this for trusted servers, as they can use the keys of the agent while connected.
+    #[arg(short = 'A', long)]
+    forward_agent: bool,
+    /// Record the host keys the server announces in `~/.ssh/known_hosts`, after it has proven
+    /// that it has the new ones, like OpenSSH's `UpdateHostKeys yes`.
+    /// Only hosts that are already known with the key of the connection are updated.
+    #[arg(long)]
+    update_host_keys: bool,
     destination: String,
     command: Vec<String>,
 }
 
+/// The connection to the server, over TCP or a proxy command.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Loads the private keys of identity files, prompting for passphrases of encrypted keys.
+async fn load_identity_files(
+    paths: &[PathBuf],
+    batch_mode: bool,
+) -> Result<Vec<PlaintextPrivateKey>> {
+    let mut keys = Vec::new();
+    for path in paths {
+        let file = tokio::fs::read(path)
+            .await
+            .wrap_err_with(|| format!("reading identity file {}", path.display()))?;
+        let encrypted = EncryptedPrivateKeys::parse(&file)
+            .wrap_err_with(|| format!("invalid identity file {}", path.display()))?;
+
+        let passphrase = if encrypted.requires_passphrase() {
+            if batch_mode {
+                debug!(path = %path.display(), "Skipping encrypted identity file in batch mode");
+                continue;
+            }
+            let prompt = format!("Enter passphrase for key '{}': ", path.display());
+            let passphrase =
+                tokio::task::spawn_blocking(move || rpassword::prompt_password(prompt))
+                    .await?
+                    .wrap_err("failed to prompt passphrase")?;
+            Some(passphrase)
+        } else {
+            None
+        };
+
+        let decrypted = encrypted
+            .decrypt(passphrase.as_deref())
+            .wrap_err_with(|| format!("failed to decrypt identity file {}", path.display()))?;
+        keys.extend(decrypted);
+    }
+    Ok(keys)
+}
+
+fn parse_auth_method(name: &str) -> Result<AuthOption, String> {
+    AuthOption::from_name(name).ok_or_else(|| format!("unsupported authentication method: {name}"))
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     let args = Args::parse();
@@ -29,7 +120,17 @@ async fn main() -> eyre::Result<()> {
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     tracing_subscriber::fmt().with_env_filter(env_filter).init();
 
-    let username = match args.user {
+    let mut host_config = ssh_config::Config::load()?.lookup(&args.destination);
+    let destination = host_config
+        .host_name
+        .clone()
+        .unwrap_or_else(|| args.destination.clone());
+    let port = args.port.or(host_config.port).unwrap_or(22);
+    if host_config.proxy_jump.is_some() {
+        warn!("Ignoring ProxyJump from ssh_config, jump hosts are not supported");
+    }
+
+    let username = match args.user.or(host_config.user.clone()) {
         None => {
             tokio::task::spawn_blocking(|| {
                 users::get_current_username()
@@ -46,18 +147,96 @@ async fn main() -> eyre::Result<()> {
         Some(user) => user,
     };
 
-    let conn = TcpStream::connect(&format!("{}:{}", args.destination, args.port))
-        .await
-        .wrap_err("connecting")?;
+    // The tokens of the config values are expanded with the resolved options.
+    host_config.port = Some(port);
+    host_config.user = Some(username.clone());
+
+    let connect_timeout = args.connect_timeout.map(Duration::from_secs);
+    let conn: Box<dyn Stream> = match &host_config.proxy_command {
+        Some(command) => {
+            let command = host_config.expand(command, &args.destination)?;
+            debug!(%command, "Connecting with proxy command");
+            Box::new(
+                CommandStream::spawn(&command)
+                    .wrap_err_with(|| format!("failed to run proxy command {command}"))?,
+            )
+        }
+        None => {
+            let bind_address = args.bind_address.or(host_config.bind_address);
+            let connect = stream::connect_tcp(&destination, port, bind_address);
+            let conn = match connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect)
+                    .await
+                    .wrap_err("timed out connecting")?,
+                None => connect.await,
+            }
+            .wrap_err("connecting")?;
+            Box::new(conn)
+        }
+    };
+
+    // Explicit identity files are offered before the ones from the config and the keys of the agent.
+    let mut identity_paths = args.identity_file.clone();
+    for path in &host_config.identity_files {
+        let path = PathBuf::from(host_config.expand(path, &args.destination)?);
+        if path.exists() {
+            identity_paths.push(path);
+        } else {
+            debug!(path = %path.display(), "Skipping missing identity file from ssh_config");
+        }
+    }
+    let identity_files = load_identity_files(&identity_paths, args.batch_mode).await?;
+    let identities =
+        Identities::collect(vec![Arc::new(PrivateKeys(identity_files)), Arc::new(Agent)]).await;
+
+    let forward_agent = if args.forward_agent {
+        let socket = std::env::var_os("SSH_AUTH_SOCK");
+        if socket.is_none() {
+            warn!("Not forwarding the SSH agent, $SSH_AUTH_SOCK is not set");
+        }
+        socket.map(PathBuf::from)
+    } else {
+        None
+    };
 
+    let host_name = KnownHosts::host_name(&destination, port);
+    let host_key_store = match known_hosts_path() {
+        Some(path) => Some((
+            host_name.clone(),
+            Box::new(KnownHostsFile::new(path)) as Box<dyn HostKeyStore>,
+        )),
+        None => {
+            warn!("Not verifying the host key, $HOME is not set");
+            None
+        }
+    };
     let username1 = username.clone();
     let mut tokio_conn = cluelessh_tokio::client::ClientConnection::connect(
         conn,
+        cluelessh_tokio::client::ClientConfig {
+            compression: args.compression,
+            preferred_host_key_algorithms: known_host_key_algorithms(&destination, port),
+            handshake_timeout: connect_timeout,
+            window_adjust_threshold: None,
+            initial_window_size: None,
+            read_buffer_size: None,
+            forward_agent: forward_agent.clone(),
+            max_consecutive_open_failures: None,
+            channel_operations_buffer: None,
+            channel_updates_buffer: None,
+            max_pipelined_queries: None,
+            max_auth_tries: None,
+            host_key_store,
+            rekey_limits: Default::default(),
+            packet_capture: None,
+        },
         cluelessh_tokio::client::ClientAuth {
             username: username.clone(),
+            batch_mode: args.batch_mode,
+            methods: args.preferred_authentications,
             prompt_password: Arc::new(move || {
                 let username = username1.clone();
-                let destination = args.destination.clone();
+                let destination = destination.clone();
                 Box::pin(async {
                     let result = tokio::task::spawn_blocking(move || {
                         rpassword::prompt_password(format!(
@@ -69,78 +248,401 @@ async fn main() -> eyre::Result<()> {
                     result.wrap_err("failed to prompt password")
                 })
             }),
-            sign_pubkey: Arc::new(move |session_id| {
-                let mut attempted_public_keys = HashSet::new();
-                let username = username.clone();
-                Box::pin(async move {
-                    // TODO: support agentless manual key opening
-                    // TODO: move
-                    let mut agent = cluelessh_agent_client::SocketAgentConnection::from_env()
-                        .await
-                        .wrap_err("failed to connect to SSH agent")?;
-                    let identities = agent.list_identities().await?;
-                    for identity in &identities {
-                        let pubkey = PublicKey::from_wire_encoding(&identity.key_blob)
-                            .wrap_err("received invalid public key from SSH agent")?;
-                        debug!(comment = ?identity.comment, %pubkey, "Found identity");
-                    }
-                    if identities.len() > 1 {
-                        todo!("try identities");
-                    }
-                    let identity = &identities[0];
-                    if !attempted_public_keys.insert(identity.key_blob.clone()) {
-                        bail!("authentication denied (publickey)");
-                    }
-                    let pubkey = PublicKey::from_wire_encoding(&identity.key_blob)?;
-
-                    let sign_data =
-                        cluelessh_keys::signature::signature_data(session_id.0, &username, &pubkey);
-                    let signature = agent
-                        .sign(&identity.key_blob, &sign_data, 0)
-                        .await
-                        .wrap_err("signing for authentication")?;
-
-                    Ok(SignatureResult {
-                        key_alg_name: pubkey.algorithm_name(),
-                        public_key: identity.key_blob.clone(),
-                        signature,
-                    })
-                })
-            }),
+            public_keys: identities.public_keys(),
+            sign_pubkey: identities.sign_pubkey(username),
         },
     )
     .await?;
 
+    debug!(info = ?tokio_conn.connection_info(), "Connection established");
+    let session_host_key = PublicKey::from_wire_encoding(&tokio_conn.connection_info().host_key)
+        .wrap_err("invalid host key")?;
+    let mut host_key_update = match known_hosts_path() {
+        Some(path) if args.update_host_keys => {
+            Some(HostKeyUpdate::new(path, host_name, session_host_key))
+        }
+        _ => None,
+    };
+
     let session = tokio_conn.open_channel(ChannelKind::Session);
+    let command = (!args.command.is_empty()).then(|| args.command.join(" ").into_bytes());
+
+    let mut session = tokio::spawn(main_channel(session, command, forward_agent.is_some()));
 
-    tokio::spawn(async {
-        let result = main_channel(session).await;
-        if let Err(err) = result {
-            error!(?err);
+    let exit_status = loop {
+        tokio::select! {
+            result = tokio_conn.progress() => match result {
+                Ok(()) => {
+                    if let Some(update) = &mut host_key_update {
+                        update_host_keys(&mut tokio_conn, update)?;
+                    }
+                    while let Some(channel) = tokio_conn.next_new_channel() {
+                        debug!(channel_type = %channel.kind().name(), "Closing unexpected channel from server");
+                        tokio::spawn(async move { channel.send(ChannelOperationKind::Close).await });
+                    }
+                }
+                Err(err) => {
+                    // Still write out the output that was received before the connection was closed.
+                    drop(tokio_conn);
+                    match session.await? {
+                        Ok(Some(exit_status)) => break exit_status,
+                        _ => return Err(err),
+                    }
+                }
+            },
+            exit_status = &mut session => {
+                let exit_status = exit_status??.unwrap_or(255);
+                if let Err(err) = tokio_conn
+                    .disconnect(disconnect_reason::BY_APPLICATION, "disconnected by user")
+                    .await
+                {
+                    debug!(?err, "Failed to disconnect");
+                }
+                break exit_status;
+            }
         }
-    });
+    };
+    std::process::exit(exit_status as i32);
+}
 
-    loop {
-        tokio_conn.progress().await?;
+fn known_hosts_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(Path::new(&home).join(".ssh/known_hosts"))
+}
+
+/// The algorithms of the keys in `~/.ssh/known_hosts` for the host.
+fn known_host_key_algorithms(destination: &str, port: u16) -> Vec<String> {
+    let Some(path) = known_hosts_path() else {
+        return Vec::new();
+    };
+    let Ok(known_hosts) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    let known_hosts = KnownHosts::parse(&known_hosts);
+    for err in known_hosts.errors() {
+        debug!(%err, "Skipping invalid line in known_hosts");
+    }
+    let host_name = KnownHosts::host_name(destination, port);
+    let mut algorithms = Vec::new();
+    for key in known_hosts.find(&host_name) {
+        let algorithm = key.algorithm_name().to_owned();
+        if !algorithms.contains(&algorithm) {
+            algorithms.push(algorithm);
+        }
+    }
+    algorithms
+}
+
+/// Records the host keys announced by the server in `known_hosts` for `--update-host-keys`,
+/// once it has proven that it has the new ones.
+fn update_host_keys(
+    conn: &mut ClientConnection<Box<dyn Stream>>,
+    update: &mut HostKeyUpdate,
+) -> Result<()> {
+    while let Some(request) = conn.next_global_request() {
+        match request {
+            GlobalRequest::HostKeys(keys) => {
+                if let Some(new_keys) = update.announce(keys) {
+                    debug!(count = %new_keys.len(), "Asking server to prove new host keys");
+                    conn.prove_host_keys(new_keys)?;
+                }
+            }
+        }
+    }
+
+    while let Some(proven) = conn.next_proven_host_keys() {
+        update.proven(&proven);
+    }
+    Ok(())
+}
+
+/// The `--update-host-keys` state of the connection to `host_name`.
+struct HostKeyUpdate {
+    known_hosts: PathBuf,
+    host_name: String,
+    session_host_key: PublicKey,
+    /// The keys announced by the server, while it proves the new ones.
+    announced: Option<Vec<PublicKey>>,
+}
+
+impl HostKeyUpdate {
+    fn new(known_hosts: PathBuf, host_name: String, session_host_key: PublicKey) -> Self {
+        Self {
+            known_hosts,
+            host_name,
+            session_host_key,
+            announced: None,
+        }
+    }
+
+    /// Handles the keys announced by the server, returning the new ones it has to prove
+    /// before `known_hosts` is updated.
+    fn announce(&mut self, keys: Vec<PublicKey>) -> Option<Vec<PublicKey>> {
+        match self.new_host_keys(&keys) {
+            Ok(None) => None,
+            Ok(Some(new_keys)) if new_keys.is_empty() => {
+                // Only keys have been removed, which the server doesn't have to prove.
+                if let Err(err) = self.record(&keys) {
+                    warn!(?err, "Failed to update known_hosts");
+                }
+                None
+            }
+            Ok(Some(new_keys)) => {
+                self.announced = Some(keys);
+                Some(new_keys)
+            }
+            Err(err) => {
+                warn!(?err, "Failed to read known_hosts");
+                None
+            }
+        }
+    }
+
+    /// Handles the reply to the proof of the new keys, which is empty if the server failed to prove them.
+    fn proven(&mut self, proven: &[PublicKey]) {
+        let Some(keys) = self.announced.take() else {
+            return;
+        };
+        if proven.is_empty() {
+            warn!("Server failed to prove its new host keys, not updating known_hosts");
+            return;
+        }
+        if let Err(err) = self.record(&keys) {
+            warn!(?err, "Failed to update known_hosts");
+        }
+    }
+
+    /// The announced keys that are not in `known_hosts` yet, or `None` if the host's keys must not be updated
+    /// because it is unknown or the key of the connection is not known or announced.
+    fn new_host_keys(&self, announced: &[PublicKey]) -> Result<Option<Vec<PublicKey>>> {
+        let known_hosts = match std::fs::read_to_string(&self.known_hosts) {
+            Ok(file) => KnownHosts::parse(&file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let known = known_hosts.find(&self.host_name).collect::<Vec<_>>();
+        if !known.contains(&&self.session_host_key) {
+            debug!(
+                "Not updating the host keys of a host that is not known with the key of the connection"
+            );
+            return Ok(None);
+        }
+        if !announced.contains(&self.session_host_key) {
+            debug!(
+                "Not updating host keys as the server did not announce the key of the connection"
+            );
+            return Ok(None);
+        }
+
+        let new_keys = announced
+            .iter()
+            .filter(|key| !known.contains(key))
+            .cloned()
+            .collect();
+        Ok(Some(new_keys))
+    }
+
+    /// Sets the keys of the host in `known_hosts` to `keys`.
+    fn record(&self, keys: &[PublicKey]) -> Result<()> {
+        let mut known_hosts = KnownHosts::parse(
+            &std::fs::read_to_string(&self.known_hosts).wrap_err("reading known_hosts")?,
+        );
+        if known_hosts.set_host_keys(&self.host_name, keys) {
+            known_hosts
+                .save(&self.known_hosts)
+                .wrap_err("writing known_hosts")?;
+            info!(host_name = %self.host_name, "Updated the host keys in known_hosts");
+        }
+        Ok(())
     }
 }
 
-async fn main_channel(channel: PendingChannel) -> Result<()> {
-    let Ok(channel) = channel.wait_ready().await else {
+/// Runs the shell or command, forwarding stdin and output until the channel is closed.
+/// Returns the exit status of the command.
+async fn main_channel(
+    channel: PendingChannel,
+    command: Option<Vec<u8>>,
+    forward_agent: bool,
+) -> Result<Option<u32>> {
+    let Ok(mut channel) = channel.wait_ready().await else {
         bail!("failed to create channel");
     };
 
-    channel
-        .send(ChannelOperationKind::Request(ChannelRequest::PtyReq {
-            want_reply: true,
-            term: "xterm-256color".to_owned(),
-            width_chars: 70,
-            height_rows: 10,
-            width_px: 0,
-            height_px: 0,
-            term_modes: vec![],
-        }))
+    if forward_agent && !channel.request_agent_forwarding().await? {
+        warn!("Server refused agent forwarding");
+    }
+
+    // Like OpenSSH, only interactive shells get a PTY.
+    let wants_pty = command.is_none() && rustix::termios::isatty(std::io::stdin());
+    let pty = wants_pty.then(|| SessionPty {
+        term: std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_owned()),
+        term_modes: TermModes::of_local_terminal().encode(),
+        window_size: &WindowSize::of_local_terminal,
+    });
+    let has_pty = channel
+        .start_session(pty.as_ref(), command.as_deref())
         .await?;
 
-    Ok(())
+    let _raw_mode = if has_pty {
+        Some(RawMode::enable()?)
+    } else {
+        None
+    };
+    let mut window_changes = tokio::signal::unix::signal(SignalKind::window_change())?;
+
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    let mut stdin_buf = [0; 4096];
+    let mut stdin_open = true;
+    let mut exit_status = None;
+
+    loop {
+        tokio::select! {
+            read = stdin.read(&mut stdin_buf), if stdin_open => {
+                let read = read.wrap_err("reading stdin")?;
+                if read == 0 {
+                    stdin_open = false;
+                    channel.send(ChannelOperationKind::Eof).await?;
+                } else {
+                    channel.send(ChannelOperationKind::Data(stdin_buf[..read].to_vec())).await?;
+                }
+            }
+            _ = window_changes.recv(), if has_pty => {
+                channel.window_change(WindowSize::of_local_terminal()).await?;
+            }
+            update = channel.next_update() => match update? {
+                ChannelUpdateKind::Failure => bail!("server refused to start the session"),
+                ChannelUpdateKind::Data { data } => {
+                    stdout.write_all(&data).await?;
+                    stdout.flush().await?;
+                }
+                ChannelUpdateKind::ExtendedData { data, .. } => {
+                    stderr.write_all(&data).await?;
+                    stderr.flush().await?;
+                }
+                ChannelUpdateKind::Request(ChannelRequest::ExitStatus { status }) => {
+                    exit_status = Some(status);
+                }
+                ChannelUpdateKind::Closed => return Ok(exit_status),
+                _ => {}
+            },
+        }
+    }
+}
+
+/// Puts the local terminal into raw mode so that all input goes to the remote PTY,
+/// restoring the previous mode when dropped.
+struct RawMode {
+    previous: Termios,
+}
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        let stdin = std::io::stdin();
+        let previous = rustix::termios::tcgetattr(&stdin).wrap_err("getting terminal mode")?;
+        let mut raw = previous.clone();
+        raw.make_raw();
+        rustix::termios::tcsetattr(&stdin, OptionalActions::Now, &raw)
+            .wrap_err("enabling raw mode")?;
+        Ok(Self { previous })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = rustix::termios::tcsetattr(std::io::stdin(), OptionalActions::Now, &self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use cluelessh_keys::known_hosts::KnownHosts;
+    use cluelessh_keys::private::PlaintextPrivateKey;
+    use cluelessh_keys::public::PublicKey;
+
+    use super::HostKeyUpdate;
+
+    fn key() -> PublicKey {
+        PlaintextPrivateKey::generate(
+            String::new(),
+            cluelessh_keys::KeyGenerationParams {
+                key_type: cluelessh_keys::KeyType::Ed25519,
+            },
+        )
+        .private_key
+        .public_key()
+    }
+
+    /// A `known_hosts` file with `keys` for `example.com`.
+    fn known_hosts(name: &str, keys: &[&PublicKey]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "cluelessh-update-host-keys-{}-{name}",
+            std::process::id()
+        ));
+        let mut known_hosts = KnownHosts::parse("");
+        for key in keys {
+            known_hosts.add("example.com", (*key).clone());
+        }
+        known_hosts.save(&path).unwrap();
+        path
+    }
+
+    fn keys_of(path: &Path) -> Vec<PublicKey> {
+        let known_hosts = KnownHosts::parse(&std::fs::read_to_string(path).unwrap());
+        known_hosts.find("example.com").cloned().collect()
+    }
+
+    #[test]
+    fn new_keys_recorded_after_proof() {
+        let (old, new) = (key(), key());
+        let path = known_hosts("proof", &[&old]);
+        let mut update = HostKeyUpdate::new(path.clone(), "example.com".to_owned(), old.clone());
+
+        let to_prove = update.announce(vec![old.clone(), new.clone()]).unwrap();
+        assert_eq!(to_prove, std::slice::from_ref(&new));
+        assert_eq!(keys_of(&path), std::slice::from_ref(&old));
+
+        update.proven(&to_prove);
+        assert_eq!(keys_of(&path), [old, new]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_proof_not_recorded() {
+        let (old, new) = (key(), key());
+        let path = known_hosts("failed", &[&old]);
+        let mut update = HostKeyUpdate::new(path.clone(), "example.com".to_owned(), old.clone());
+
+        assert!(update.announce(vec![old.clone(), new]).is_some());
+        update.proven(&[]);
+        assert_eq!(keys_of(&path), [old]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn removed_keys_recorded_without_proof() {
+        let (kept, removed) = (key(), key());
+        let path = known_hosts("removed", &[&kept, &removed]);
+        let mut update = HostKeyUpdate::new(path.clone(), "example.com".to_owned(), kept.clone());
+
+        assert_eq!(update.announce(vec![kept.clone()]), None);
+        assert_eq!(keys_of(&path), [kept]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_session_key_not_updated() {
+        let (known, session, new) = (key(), key(), key());
+        let path = known_hosts("unknown", &[&known]);
+        let mut update =
+            HostKeyUpdate::new(path.clone(), "example.com".to_owned(), session.clone());
+
+        assert_eq!(update.announce(vec![session, new]), None);
+        assert_eq!(keys_of(&path), [known]);
+        std::fs::remove_file(path).unwrap();
+    }
 }
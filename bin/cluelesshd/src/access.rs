@@ -0,0 +1,113 @@
+//! Restricting which addresses may connect and which users may log in from where.
+
+use std::net::IpAddr;
+
+use cluelessh_keys::authorized_keys::{matches_address_list, wildcard_matches};
+
+use crate::config::AccessConfig;
+
+/// Whether a client from `addr` may connect at all.
+pub fn address_allowed(config: &AccessConfig, addr: IpAddr) -> bool {
+    if config
+        .deny_addresses
+        .iter()
+        .any(|pattern| matches_address_list(std::slice::from_ref(pattern), addr))
+    {
+        return false;
+    }
+    match &config.allow_addresses {
+        Some(patterns) => matches_address_list(patterns, addr),
+        None => true,
+    }
+}
+
+/// Whether `user` may log in from `addr`, before even checking their credentials.
+pub fn user_allowed(config: &AccessConfig, user: &str, addr: IpAddr) -> bool {
+    if config
+        .deny_users
+        .iter()
+        .any(|pattern| user_matches(pattern, user, addr))
+    {
+        return false;
+    }
+    match &config.allow_users {
+        Some(patterns) => patterns
+            .iter()
+            .any(|pattern| user_matches(pattern, user, addr)),
+        None => true,
+    }
+}
+
+/// Matches patterns like `alice`, `admin-*` or `alice@192.0.2.0/24`.
+fn user_matches(pattern: &str, user: &str, addr: IpAddr) -> bool {
+    match pattern.split_once('@') {
+        Some((user_pattern, address_pattern)) => {
+            wildcard_matches(user_pattern, user)
+                && matches_address_list(&[address_pattern.to_owned()], addr)
+        }
+        None => wildcard_matches(pattern, user),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::config::AccessConfig;
+
+    fn addr(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn strings(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|&pattern| pattern.to_owned()).collect()
+    }
+
+    #[test]
+    fn everything_allowed_by_default() {
+        let config = AccessConfig::default();
+        assert!(super::address_allowed(&config, addr("198.51.100.1")));
+        assert!(super::user_allowed(&config, "root", addr("198.51.100.1")));
+    }
+
+    #[test]
+    fn allow_and_deny_addresses() {
+        let config = AccessConfig {
+            allow_addresses: Some(strings(&["10.0.0.0/8", "2001:db8::/32"])),
+            deny_addresses: strings(&["10.0.0.13", "10.1.*"]),
+            ..Default::default()
+        };
+        assert!(super::address_allowed(&config, addr("10.0.0.1")));
+        assert!(super::address_allowed(&config, addr("::ffff:10.0.0.1")));
+        assert!(super::address_allowed(&config, addr("2001:db8::1")));
+        assert!(!super::address_allowed(&config, addr("10.0.0.13")));
+        assert!(!super::address_allowed(&config, addr("10.1.2.3")));
+        assert!(!super::address_allowed(&config, addr("198.51.100.1")));
+    }
+
+    #[test]
+    fn allow_users() {
+        let config = AccessConfig {
+            allow_users: Some(strings(&["alice", "deploy-*", "root@192.0.2.0/24"])),
+            ..Default::default()
+        };
+        let outside = addr("198.51.100.1");
+        assert!(super::user_allowed(&config, "alice", outside));
+        assert!(super::user_allowed(&config, "deploy-web", outside));
+        assert!(!super::user_allowed(&config, "bob", outside));
+        assert!(!super::user_allowed(&config, "root", outside));
+        assert!(super::user_allowed(&config, "root", addr("192.0.2.7")));
+    }
+
+    #[test]
+    fn deny_users_takes_precedence() {
+        let config = AccessConfig {
+            allow_users: Some(strings(&["*"])),
+            deny_users: strings(&["root", "*@198.51.100.*"]),
+            ..Default::default()
+        };
+        assert!(!super::user_allowed(&config, "root", addr("192.0.2.7")));
+        assert!(!super::user_allowed(&config, "alice", addr("198.51.100.1")));
+        assert!(super::user_allowed(&config, "alice", addr("192.0.2.7")));
+    }
+}
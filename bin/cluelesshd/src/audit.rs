@@ -0,0 +1,147 @@
+//! Structured logging of authentication decisions for security monitoring.
+//!
+//! Every event is logged at info level with the `cluelesshd::audit` target,
+//! so it can be filtered with `RUST_LOG=cluelesshd::audit=info` and
+//! parsed by tools like fail2ban.
+
+use std::{fmt::Display, net::SocketAddr};
+
+use cluelessh_keys::public::PublicKey;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AuthMethod {
+    /// The client asked whether a public key would be accepted, without a signature.
+    PublicKeyQuery,
+    PublicKey,
+    Password,
+    KeyboardInteractive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Accepted,
+    /// The credentials are not valid for the user.
+    Rejected,
+    /// The user may not log in from the address, see [`crate::access`],
+    /// or already has as many sessions as allowed, see [`crate::sessions`].
+    Denied,
+    /// The credentials could not be checked, for example because of an invalid `authorized_keys`.
+    Error,
+}
+
+impl Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::PublicKeyQuery => "publickey-query",
+            Self::PublicKey => "publickey",
+            Self::Password => "password",
+            Self::KeyboardInteractive => "keyboard-interactive",
+        })
+    }
+}
+
+impl Display for AuthOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Accepted => "accepted",
+            Self::Rejected => "rejected",
+            Self::Denied => "denied",
+            Self::Error => "error",
+        })
+    }
+}
+
+impl AuthOutcome {
+    pub fn from_result(result: &Result<bool, String>) -> Self {
+        match result {
+            Ok(true) => Self::Accepted,
+            Ok(false) => Self::Rejected,
+            Err(_) => Self::Error,
+        }
+    }
+}
+
+pub fn auth_attempt(
+    addr: SocketAddr,
+    user: &str,
+    method: AuthMethod,
+    public_key: Option<&PublicKey>,
+    outcome: AuthOutcome,
+) {
+    let key_type = public_key.map(PublicKey::algorithm_name);
+    let fingerprint = public_key.map(PublicKey::fingerprint_sha256);
+    info!(
+        target: "cluelesshd::audit",
+        %addr,
+        // The user name is sent by the client before authentication, so it is escaped
+        // to keep it from forging log lines.
+        ?user,
+        %method,
+        key_type,
+        fingerprint,
+        %outcome,
+        "Authentication {outcome}"
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{AuthMethod, AuthOutcome};
+
+    /// Collects the formatted log lines of [`Buffer::subscriber`].
+    #[derive(Clone, Default)]
+    pub(crate) struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        pub(crate) fn subscriber(&self) -> impl tracing::Subscriber + Send + Sync {
+            let writer = self.clone();
+            tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_max_level(tracing::Level::DEBUG)
+                .with_writer(move || writer.clone())
+                .finish()
+        }
+
+        pub(crate) fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn user_with_newline() {
+        let buffer = Buffer::default();
+        tracing::subscriber::with_default(buffer.subscriber(), || {
+            super::auth_attempt(
+                "127.0.0.1:22".parse().unwrap(),
+                "nora\nFailed password for root from 192.0.2.1",
+                AuthMethod::Password,
+                None,
+                AuthOutcome::Rejected,
+            );
+        });
+
+        let output = buffer.output();
+        assert_eq!(output.lines().count(), 1, "{output}");
+        assert!(
+            output.contains(r#"user="nora\nFailed password for root from 192.0.2.1""#),
+            "{output}"
+        );
+    }
+}
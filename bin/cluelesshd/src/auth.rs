@@ -1,20 +1,45 @@
 //! User authentication.
 
-use std::io;
+use std::{
+    ffi::{c_char, CStr, CString},
+    io,
+    mem::MaybeUninit,
+    net::IpAddr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 
 use cluelessh_keys::{
-    authorized_keys::{self, AuthorizedKeys},
+    authorized_keys::{wildcard_matches, AuthorizedKeys, KeyOptions},
+    authorized_principals::{self, AuthorizedPrincipals},
+    certificate::{Certificate, CertificateError, CertificateType},
     public::{PublicKey, PublicKeyWithComment},
     signature::Signature,
 };
 use cluelessh_protocol::auth::VerifySignature;
-use eyre::eyre;
-use tracing::debug;
+use eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 use users::{os::unix::UserExt, User};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::AuthConfig,
+    pam::{Message, Pam},
+};
 
 /// A known-authorized public key for a user.
 pub struct UserPublicKey {
     key: PublicKeyWithComment,
+    options: KeyOptions,
     user: User,
 }
 
@@ -24,16 +49,196 @@ pub enum AuthError {
     UnknownUser,
     #[error("~/.ssh/authorized_keys not found")]
     NoAuthorizedKeys(#[source] io::Error),
-    #[error("invalid ~/.ssh/authorized_keys")]
-    InvalidAuthorizedKeys(#[from] authorized_keys::Error),
+    #[error("bad ownership or modes for {}", .0.display())]
+    BadModes(PathBuf),
+    #[error("authorized_keys_command failed")]
+    AuthorizedKeysCommand(#[source] io::Error),
     #[error("public key not authorized")]
     UnauthorizedPublicKey,
+    #[error("public key not authorized from this address")]
+    UnauthorizedSource,
+    #[error("public key algorithm not accepted")]
+    AlgorithmNotAccepted,
+    #[error("no trusted user certificate authorities configured")]
+    NoTrustedUserCaKeys,
+    #[error("failed to read trusted user certificate authorities")]
+    ReadTrustedUserCaKeys(#[source] io::Error),
+    #[error("authorized_principals not found")]
+    NoAuthorizedPrincipals(#[source] io::Error),
+    #[error("invalid authorized_principals")]
+    InvalidAuthorizedPrincipals(#[from] authorized_principals::Error),
+    #[error("no principal of the certificate is authorized")]
+    UnauthorizedPrincipal,
+    #[error("certificate not valid")]
+    InvalidCertificate(#[from] CertificateError),
+}
+
+/// How long the `authorized_keys_command` may run before the key is rejected.
+const AUTHORIZED_KEYS_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most bytes read from each of stdout and stderr of the `authorized_keys_command`.
+/// A command printing more is treated as failed instead of buffering its output without bound.
+const MAX_AUTHORIZED_KEYS_COMMAND_OUTPUT: u64 = 1024 * 1024;
+
+/// The most outputs of the `authorized_keys_command` kept in an [`AuthorizedKeysCommandCache`],
+/// enough for the keys a client queries at once.
+const MAX_CACHED_COMMAND_OUTPUTS: usize = 8;
+
+/// The outputs of the `authorized_keys_command` for the keys the client asked about,
+/// so that the command does not run again when the client signs with one of them.
+/// Cleared once a signature has been verified, which ends the attempt.
+#[derive(Default)]
+pub struct AuthorizedKeysCommandCache {
+    outputs: Vec<(String, PublicKey, String)>,
+}
+
+impl AuthorizedKeysCommandCache {
+    async fn output(
+        &mut self,
+        command: &[String],
+        run_as: &str,
+        user: &User,
+        key: &PublicKey,
+    ) -> Result<&str, AuthError> {
+        let name = user.name().to_str().ok_or(AuthError::UnknownUser)?;
+        let cached = self
+            .outputs
+            .iter()
+            .position(|(cached_name, cached_key, _)| cached_name == name && cached_key == key);
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let output = run_authorized_keys_command(command, run_as, user, key)
+                    .await
+                    .inspect_err(|err| warn!(%err, %name, "authorized_keys_command failed"))
+                    .map_err(AuthError::AuthorizedKeysCommand)?;
+                if self.outputs.len() >= MAX_CACHED_COMMAND_OUTPUTS {
+                    self.outputs.remove(0);
+                }
+                self.outputs.push((name.to_owned(), key.clone(), output));
+                self.outputs.len() - 1
+            }
+        };
+        Ok(&self.outputs[index].2)
+    }
+
+    pub fn clear(&mut self) {
+        self.outputs.clear();
+    }
+}
+
+/// Runs the `authorized_keys_command` for the user and key as `run_as`, returning its output.
+async fn run_authorized_keys_command(
+    command: &[String],
+    run_as: &str,
+    user: &User,
+    key: &PublicKey,
+) -> io::Result<String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+    };
+    let run_as = users::get_user_by_name(run_as)
+        .ok_or_else(|| io::Error::other(format!("unknown user {run_as}")))?;
+
+    let mut cmd = tokio::process::Command::new(program);
+    if args.is_empty() {
+        cmd.arg(user.name());
+    }
+    for arg in args {
+        cmd.arg(expand_command_arg(arg, user, key)?);
+    }
+    cmd.env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .uid(run_as.uid())
+        .gid(run_as.primary_group_id())
+        .kill_on_drop(true);
+
+    let mut child = cmd.spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let run = async {
+        let (stdout, stderr) = tokio::try_join!(read_capped(stdout), read_capped(stderr))?;
+        let status = child.wait().await?;
+        io::Result::Ok((status, stdout, stderr))
+    };
+    let (status, stdout, stderr) = tokio::time::timeout(AUTHORIZED_KEYS_COMMAND_TIMEOUT, run)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "command timed out"))??;
+    if !stderr.is_empty() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        debug!(%stderr, "authorized_keys_command printed errors");
+    }
+    if !status.success() {
+        return Err(io::Error::other(format!("command {status}")));
+    }
+    String::from_utf8(stdout)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "output is not valid UTF-8"))
+}
+
+/// Reads all of an output of the `authorized_keys_command`,
+/// failing once it exceeds [`MAX_AUTHORIZED_KEYS_COMMAND_OUTPUT`].
+async fn read_capped(output: impl tokio::io::AsyncRead + Unpin) -> io::Result<Vec<u8>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    output
+        .take(MAX_AUTHORIZED_KEYS_COMMAND_OUTPUT + 1)
+        .read_to_end(&mut buf)
+        .await?;
+    if buf.len() as u64 > MAX_AUTHORIZED_KEYS_COMMAND_OUTPUT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "command printed too much output",
+        ));
+    }
+    Ok(buf)
+}
+
+/// Expands the tokens in an argument of the `authorized_keys_command`, see [`AuthConfig`].
+fn expand_command_arg(arg: &str, user: &User, key: &PublicKey) -> io::Result<String> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let mut expanded = String::new();
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => expanded.push_str(
+                user.name()
+                    .to_str()
+                    .ok_or_else(|| invalid("user name is not valid UTF-8".to_owned()))?,
+            ),
+            Some('h') => expanded.push_str(
+                user.home_dir()
+                    .to_str()
+                    .ok_or_else(|| invalid("home directory is not valid UTF-8".to_owned()))?,
+            ),
+            Some('t') => expanded.push_str(key.algorithm_name()),
+            Some('k') => {
+                let line = key.to_string();
+                let (_, base64) = line.split_once(' ').unwrap_or_default();
+                expanded.push_str(base64);
+            }
+            Some('f') => expanded.push_str(&key.fingerprint_sha256()),
+            Some('%') => expanded.push('%'),
+            Some(token) => return Err(invalid(format!("unknown token %{token} in argument"))),
+            None => return Err(invalid("trailing % in argument".to_owned())),
+        }
+    }
+    Ok(expanded)
 }
 
 impl UserPublicKey {
     pub async fn for_user_and_key(
         user: String,
         provided_key: &PublicKey,
+        peer_addr: IpAddr,
+        config: &AuthConfig,
+        command_cache: &mut AuthorizedKeysCommandCache,
     ) -> Result<Self, AuthError> {
         let user = tokio::task::spawn_blocking(move || {
             users::get_user_by_name(&user).ok_or(AuthError::UnknownUser)
@@ -41,22 +246,49 @@ impl UserPublicKey {
         .await
         .unwrap()?;
 
-        let sshd_dir = user.home_dir().join(".ssh").join("authorized_keys");
-
-        let file = tokio::fs::read_to_string(sshd_dir)
-            .await
-            .map_err(AuthError::NoAuthorizedKeys)?;
-
-        let authorized_keys = AuthorizedKeys::parse(&file)?;
+        check_algorithm(provided_key, config.pubkey_accepted_algorithms.as_deref())?;
 
-        if let Some(key) = authorized_keys.contains(provided_key) {
-            Ok(Self {
-                key: key.clone(),
+        if let PublicKey::Certificate { certificate } = provided_key {
+            verify_user_certificate(&user, certificate, config).await?;
+            return Ok(Self {
+                key: PublicKeyWithComment {
+                    key: provided_key.clone(),
+                    comment: certificate.key_id.clone(),
+                },
+                options: KeyOptions::default(),
                 user,
-            })
-        } else {
-            Err(AuthError::UnauthorizedPublicKey)
+            });
         }
+
+        let from_file = read_authorized_keys(&user, config.strict_modes)
+            .await
+            .and_then(|file| {
+                find_authorized_key(
+                    &file,
+                    provided_key,
+                    peer_addr,
+                    config.pubkey_accepted_algorithms.as_deref(),
+                )
+            });
+        let (key, options) = match (from_file, &config.authorized_keys_command) {
+            (Ok(found), _) => found,
+            (Err(err), None) => return Err(err),
+            (Err(_), Some(command)) => {
+                // Checked when loading the config.
+                let run_as = config.authorized_keys_command_user.as_deref().unwrap();
+                let output = command_cache
+                    .output(command, run_as, &user, provided_key)
+                    .await?;
+                find_authorized_key(
+                    output,
+                    provided_key,
+                    peer_addr,
+                    config.pubkey_accepted_algorithms.as_deref(),
+                )?
+            }
+        };
+
+        Ok(Self { key, options, user })
     }
 
     pub fn verify_signature(&self, data: &[u8], signature: &Signature) -> bool {
@@ -64,10 +296,142 @@ impl UserPublicKey {
     }
 }
 
-pub async fn verify_signature(auth: VerifySignature) -> eyre::Result<Option<User>> {
-    let result = UserPublicKey::for_user_and_key(auth.user.clone(), &auth.public_key).await;
+/// Reads `~/.ssh/authorized_keys` of the user, if its modes are safe or `strict_modes` is disabled.
+async fn read_authorized_keys(user: &User, strict_modes: bool) -> Result<String, AuthError> {
+    let path = user.home_dir().join(".ssh").join("authorized_keys");
+    if strict_modes {
+        let result = check_modes(&path, user.home_dir(), user.uid()).await;
+        if let Err(AuthError::BadModes(path)) = &result {
+            warn!(path = %path.display(), "Ignoring authorized_keys with bad ownership or modes");
+        }
+        result?;
+    }
+    tokio::fs::read_to_string(&path)
+        .await
+        .map_err(AuthError::NoAuthorizedKeys)
+}
+
+/// Like OpenSSH's `StrictModes`: Checks that the file and the directories up to `home` are owned by
+/// the user or root and not writable by anyone else, who could add keys to it otherwise.
+async fn check_modes(path: &Path, home: &Path, uid: u32) -> Result<(), AuthError> {
+    for component in path.ancestors() {
+        let metadata = tokio::fs::metadata(component)
+            .await
+            .map_err(AuthError::NoAuthorizedKeys)?;
+        if (metadata.uid() != uid && metadata.uid() != 0) || metadata.mode() & 0o022 != 0 {
+            return Err(AuthError::BadModes(component.to_owned()));
+        }
+        if component == home {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the provided key in the contents of an `authorized_keys` file,
+/// if it may be used from the address.
+fn find_authorized_key(
+    authorized_keys: &str,
+    provided_key: &PublicKey,
+    peer_addr: IpAddr,
+    accepted_algorithms: Option<&[String]>,
+) -> Result<(PublicKeyWithComment, KeyOptions), AuthError> {
+    check_algorithm(provided_key, accepted_algorithms)?;
+
+    let authorized_keys = AuthorizedKeys::parse(authorized_keys);
+    for err in &authorized_keys.errors {
+        debug!(%err, "Skipping invalid line in authorized_keys");
+    }
+
+    let Some(key) = authorized_keys.contains(provided_key) else {
+        return Err(AuthError::UnauthorizedPublicKey);
+    };
+    if !key.options.allows_source(peer_addr) {
+        return Err(AuthError::UnauthorizedSource);
+    }
+
+    Ok((key.key.clone(), key.options.clone()))
+}
+
+/// Checks that the algorithm of the key is accepted by `pubkey_accepted_algorithms`.
+fn check_algorithm(key: &PublicKey, accepted: Option<&[String]>) -> Result<(), AuthError> {
+    let Some(accepted) = accepted else {
+        return Ok(());
+    };
+    let algorithm = key.algorithm_name();
+    if accepted
+        .iter()
+        .any(|pattern| wildcard_matches(pattern, algorithm))
+    {
+        Ok(())
+    } else {
+        Err(AuthError::AlgorithmNotAccepted)
+    }
+}
+
+/// Checks that a user certificate is signed by a trusted CA and valid for the user.
+async fn verify_user_certificate(
+    user: &User,
+    certificate: &Certificate,
+    config: &AuthConfig,
+) -> Result<(), AuthError> {
+    let Some(ca_keys) = &config.trusted_user_ca_keys else {
+        return Err(AuthError::NoTrustedUserCaKeys);
+    };
+    let ca_keys = tokio::fs::read_to_string(ca_keys)
+        .await
+        .map_err(AuthError::ReadTrustedUserCaKeys)?;
+    let ca_keys = AuthorizedKeys::parse(&ca_keys);
+    for err in &ca_keys.errors {
+        debug!(%err, "Skipping invalid line in trusted user certificate authorities");
+    }
+    let ca_keys = ca_keys
+        .keys
+        .into_iter()
+        .map(|key| key.key.key)
+        .collect::<Vec<_>>();
+
+    let user_name = user.name().to_str().ok_or(AuthError::UnknownUser)?;
+    let principal = match &config.authorized_principals_file {
+        Some(path) => {
+            let path = AuthorizedPrincipals::expand_path(path, user_name, user.home_dir())?;
+            let file = tokio::fs::read_to_string(path)
+                .await
+                .map_err(AuthError::NoAuthorizedPrincipals)?;
+            let principals = AuthorizedPrincipals::parse(&file)?;
+            principals
+                .find_authorized(&certificate.valid_principals)
+                .ok_or(AuthError::UnauthorizedPrincipal)?
+        }
+        None => user_name,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    certificate.verify(&ca_keys, CertificateType::User, principal, now)?;
+    Ok(())
+}
+
+/// Verifies the signature, returning the user and the options of their key if it is valid.
+pub async fn verify_signature(
+    auth: VerifySignature,
+    peer_addr: IpAddr,
+    config: &AuthConfig,
+    command_cache: &mut AuthorizedKeysCommandCache,
+) -> eyre::Result<Option<(User, KeyOptions)>> {
+    let result = UserPublicKey::for_user_and_key(
+        auth.user.clone(),
+        &auth.public_key,
+        peer_addr,
+        config,
+        command_cache,
+    )
+    .await;
+    command_cache.clear();
 
-    debug!(user = %auth.user, err = ?result.as_ref().err(), "Attempting publickey signature");
+    debug!(user = ?auth.user, err = ?result.as_ref().err(), "Attempting publickey signature");
 
     match result {
         Ok(user_key) => {
@@ -80,7 +444,7 @@ pub async fn verify_signature(auth: VerifySignature) -> eyre::Result<Option<User
             );
 
             if user_key.verify_signature(&sign_data, &auth.signature) {
-                Ok(Some(user_key.user))
+                Ok(Some((user_key.user, user_key.options)))
             } else {
                 Ok(None)
             }
@@ -88,24 +452,543 @@ pub async fn verify_signature(auth: VerifySignature) -> eyre::Result<Option<User
         Err(
             AuthError::UnknownUser
             | AuthError::UnauthorizedPublicKey
-            | AuthError::NoAuthorizedKeys(_),
+            | AuthError::UnauthorizedSource
+            | AuthError::AlgorithmNotAccepted
+            | AuthError::NoAuthorizedKeys(_)
+            | AuthError::BadModes(_)
+            | AuthError::AuthorizedKeysCommand(_)
+            | AuthError::NoTrustedUserCaKeys
+            | AuthError::NoAuthorizedPrincipals(_)
+            | AuthError::UnauthorizedPrincipal
+            | AuthError::InvalidCertificate(_),
         ) => Ok(None),
-        Err(AuthError::InvalidAuthorizedKeys(err)) => Err(eyre!(err)),
+        Err(
+            err @ (AuthError::ReadTrustedUserCaKeys(_) | AuthError::InvalidAuthorizedPrincipals(_)),
+        ) => Err(eyre!(err)),
     }
 }
 
-pub async fn check_pubkey(user: String, public_key: PublicKey) -> eyre::Result<bool> {
-    let result = UserPublicKey::for_user_and_key(user.clone(), &public_key).await;
+pub async fn check_pubkey(
+    user: String,
+    public_key: PublicKey,
+    peer_addr: IpAddr,
+    config: &AuthConfig,
+    command_cache: &mut AuthorizedKeysCommandCache,
+) -> eyre::Result<bool> {
+    let result = UserPublicKey::for_user_and_key(
+        user.clone(),
+        &public_key,
+        peer_addr,
+        config,
+        command_cache,
+    )
+    .await;
 
-    debug!(%user, err = ?result.as_ref().err(), "Attempting publickey check");
+    debug!(?user, err = ?result.as_ref().err(), "Attempting publickey check");
 
     match result {
         Ok(_) => Ok(true),
         Err(
             AuthError::UnknownUser
             | AuthError::UnauthorizedPublicKey
-            | AuthError::NoAuthorizedKeys(_),
+            | AuthError::UnauthorizedSource
+            | AuthError::AlgorithmNotAccepted
+            | AuthError::NoAuthorizedKeys(_)
+            | AuthError::BadModes(_)
+            | AuthError::AuthorizedKeysCommand(_)
+            | AuthError::NoTrustedUserCaKeys
+            | AuthError::NoAuthorizedPrincipals(_)
+            | AuthError::UnauthorizedPrincipal
+            | AuthError::InvalidCertificate(_),
         ) => Ok(false),
-        Err(AuthError::InvalidAuthorizedKeys(err)) => Err(eyre!(err)),
+        Err(
+            err @ (AuthError::ReadTrustedUserCaKeys(_) | AuthError::InvalidAuthorizedPrincipals(_)),
+        ) => Err(eyre!(err)),
+    }
+}
+
+pub async fn verify_password(
+    user: String,
+    password: Zeroizing<String>,
+) -> eyre::Result<Option<User>> {
+    tokio::task::spawn_blocking(move || {
+        let result = verify_password_blocking(&user, &password);
+        debug!(?user, ok = ?result.as_ref().map(Option::is_some), "Attempting password");
+        result
+    })
+    .await?
+}
+
+fn verify_password_blocking(user_name: &str, password: &str) -> eyre::Result<Option<User>> {
+    let Some(user) = users::get_user_by_name(user_name) else {
+        return Ok(None);
+    };
+    let Some(hash) = shadow_password_hash(user_name)? else {
+        return Ok(None);
+    };
+
+    // Empty passwords are not allowed, and `!` or `*` mark accounts that cannot log in with a password.
+    if hash.is_empty() || hash.starts_with('!') || hash.starts_with('*') {
+        return Ok(None);
+    }
+
+    Ok(password_matches(password, &hash)?.then_some(user))
+}
+
+/// Whether the password hashes to `hash`, compared in constant time to not leak how much of it matched.
+fn password_matches(password: &str, hash: &str) -> eyre::Result<bool> {
+    Ok(match hash_password(password, hash)? {
+        Some(computed) => computed.as_bytes().ct_eq(hash.as_bytes()).into(),
+        None => false,
+    })
+}
+
+/// Looks up the password hash of the user in the shadow password database.
+fn shadow_password_hash(user: &str) -> eyre::Result<Option<Zeroizing<String>>> {
+    let Ok(name) = CString::new(user) else {
+        return Ok(None);
+    };
+    let mut buf = Zeroizing::new(vec![0 as c_char; 1024]);
+
+    loop {
+        let mut entry = MaybeUninit::<libc::spwd>::uninit();
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getspnam_r(
+                name.as_ptr(),
+                entry.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+
+        match ret {
+            libc::ERANGE => {
+                let new_len = buf.len() * 2;
+                buf = Zeroizing::new(vec![0; new_len]);
+            }
+            libc::ENOENT => return Ok(None),
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                // SAFETY: getspnam_r succeeded, so result points to a valid entry with its strings in buf.
+                let hash = unsafe { CStr::from_ptr((*result).sp_pwdp) };
+                let hash = hash.to_str().wrap_err("password hash is invalid UTF-8")?;
+                return Ok(Some(Zeroizing::new(hash.to_owned())));
+            }
+            err => {
+                return Err(io::Error::from_raw_os_error(err))
+                    .wrap_err("failed to read shadow password entry");
+            }
+        }
+    }
+}
+
+#[link(name = "crypt")]
+extern "C" {
+    /// <https://man7.org/linux/man-pages/man3/crypt.3.html>
+    fn crypt(phrase: *const c_char, setting: *const c_char) -> *mut c_char;
+}
+
+/// `crypt` uses a static buffer for the result, so calls must not overlap.
+static CRYPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Hashes the password with the algorithm and salt of `setting`, returning `None` if hashing failed.
+fn hash_password(password: &str, setting: &str) -> eyre::Result<Option<Zeroizing<String>>> {
+    if password.contains('\0') {
+        return Ok(None);
+    }
+    let mut phrase = Zeroizing::new(Vec::with_capacity(password.len() + 1));
+    phrase.extend_from_slice(password.as_bytes());
+    phrase.push(0);
+    let setting = CString::new(setting).wrap_err("password hash contains NUL")?;
+
+    let _guard = CRYPT_LOCK.lock().unwrap();
+    // SAFETY: Both strings are valid C strings, and we hold the lock while using the static buffer.
+    let result = unsafe { crypt(phrase.as_ptr().cast(), setting.as_ptr()) };
+    if result.is_null() {
+        return Ok(None);
+    }
+    let result = unsafe { CStr::from_ptr(result) };
+
+    // On failure, some implementations return an invalid hash starting with `*` instead of NULL.
+    if result.to_bytes().starts_with(b"*") {
+        return Ok(None);
+    }
+
+    Ok(Some(Zeroizing::new(
+        result
+            .to_str()
+            .wrap_err("crypt returned invalid UTF-8")?
+            .to_owned(),
+    )))
+}
+
+/// Prompts for the user in keyboard-interactive authentication.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prompts {
+    /// The informational and error messages of PAM modules since the last prompts.
+    pub instruction: String,
+    /// The prompts, and whether their responses may be shown while they are typed.
+    pub prompts: Vec<(String, bool)>,
+}
+
+pub enum PamStep {
+    Prompts(Prompts),
+    /// PAM has finished, with the authenticated user and their PAM transaction,
+    /// in which account management has already been done.
+    Finished(Option<(User, Pam)>),
+}
+
+/// How long PAM waits for the responses of the user to its prompts before failing,
+/// like the default `LoginGraceTime` of OpenSSH.
+const KEYBOARD_INTERACTIVE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+/// The most keyboard-interactive authentications of all connections at once,
+/// as each of them needs a thread while PAM waits for the responses.
+const MAX_KEYBOARD_INTERACTIVE: usize = 64;
+
+static ACTIVE_KEYBOARD_INTERACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// One of the [`MAX_KEYBOARD_INTERACTIVE`] authentications, released when dropped.
+struct KeyboardInteractiveSlot;
+
+impl KeyboardInteractiveSlot {
+    fn acquire() -> Option<Self> {
+        ACTIVE_KEYBOARD_INTERACTIVE
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < MAX_KEYBOARD_INTERACTIVE).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for KeyboardInteractiveSlot {
+    fn drop(&mut self) {
+        ACTIVE_KEYBOARD_INTERACTIVE.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Keyboard-interactive authentication with the PAM stack, which runs on its own thread
+/// where the conversation waits for the responses of the user to the prompts.
+pub struct KeyboardInteractive {
+    user: String,
+    steps: mpsc::Receiver<PamStep>,
+    responses: std::sync::mpsc::Sender<Vec<String>>,
+}
+
+impl KeyboardInteractive {
+    /// Starts authenticating the user, dropping it abandons the authentication.
+    /// Fails if there are too many keyboard-interactive authentications already.
+    pub fn start(service: String, user: String) -> eyre::Result<Self> {
+        let slot = KeyboardInteractiveSlot::acquire()
+            .ok_or_else(|| eyre!("too many keyboard-interactive authentications in progress"))?;
+        let (steps_send, steps) = mpsc::channel(1);
+        let (responses, responses_recv) = std::sync::mpsc::channel();
+
+        let name = user.clone();
+        let prompts_send = steps_send.clone();
+        let mut instruction = Vec::new();
+        let converse = Box::new(move |messages| {
+            let prompts = collect_prompts(&mut instruction, messages);
+            if prompts.prompts.is_empty() {
+                return Some(Vec::new());
+            }
+            prompts_send.blocking_send(PamStep::Prompts(prompts)).ok()?;
+            // Fails the conversation if the user takes too long or the authentication is abandoned.
+            responses_recv
+                .recv_timeout(KEYBOARD_INTERACTIVE_RESPONSE_TIMEOUT)
+                .ok()
+        });
+
+        // Not on the blocking pool of the runtime, which all connections share for other work.
+        std::thread::Builder::new()
+            .name("pam".to_owned())
+            .spawn(move || {
+                let _slot = slot;
+                let result = match Pam::authenticate(&service, &name, converse) {
+                    Ok(pam) => users::get_user_by_name(&name).map(|user| (user, pam)),
+                    Err(err) => {
+                        info!(?err, user = ?name, "PAM authentication failed");
+                        None
+                    }
+                };
+                let _ = steps_send.blocking_send(PamStep::Finished(result));
+            })
+            .wrap_err("spawning PAM thread")?;
+
+        Ok(Self {
+            user,
+            steps,
+            responses,
+        })
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Passes the responses to the last prompts to PAM.
+    pub fn respond(&self, responses: Vec<String>) -> eyre::Result<()> {
+        self.responses
+            .send(responses)
+            .map_err(|_| eyre!("PAM is not waiting for responses"))
+    }
+
+    /// Waits until PAM prompts the user or has finished.
+    pub async fn next_step(&mut self) -> eyre::Result<PamStep> {
+        self.steps
+            .recv()
+            .await
+            .ok_or_else(|| eyre!("PAM authentication thread has exited"))
+    }
+}
+
+/// Collects the prompts of PAM messages. Other messages are shown with the next prompts,
+/// so they are kept in `instruction` until then.
+fn collect_prompts(instruction: &mut Vec<String>, messages: Vec<Message>) -> Prompts {
+    let mut prompts = Vec::new();
+    for message in messages {
+        match message {
+            Message::Prompt { text, echo } => prompts.push((text, echo)),
+            Message::Info(text) | Message::Error(text) => instruction.push(text),
+        }
+    }
+    if prompts.is_empty() {
+        return Prompts {
+            instruction: String::new(),
+            prompts,
+        };
+    }
+    Prompts {
+        instruction: std::mem::take(instruction).join("\n"),
+        prompts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use cluelessh_keys::private::PlaintextPrivateKey;
+    use cluelessh_keys::{KeyGenerationParams, KeyType};
+
+    use cluelessh_protocol::auth::VerifySignature;
+    use cluelessh_transport::SessionId;
+
+    use super::{AuthError, AuthorizedKeysCommandCache, KeyboardInteractiveSlot, Prompts};
+    use crate::config::AuthConfig;
+    use crate::pam::Message;
+
+    fn addr() -> IpAddr {
+        "198.51.100.1".parse().unwrap()
+    }
+
+    fn generate(key_type: KeyType) -> PlaintextPrivateKey {
+        PlaintextPrivateKey::generate(String::new(), KeyGenerationParams { key_type })
+    }
+
+    #[test]
+    fn accepted_algorithms() {
+        let ed25519 = generate(KeyType::Ed25519).private_key.public_key();
+        let ecdsa = generate(KeyType::Ecdsa).private_key.public_key();
+        let authorized_keys = format!("{ed25519}\n{ecdsa}\n");
+        let accepted = ["ssh-ed25519".to_owned()];
+
+        let (key, _) =
+            super::find_authorized_key(&authorized_keys, &ed25519, addr(), Some(&accepted))
+                .unwrap();
+        assert_eq!(key.key, ed25519);
+
+        let err = super::find_authorized_key(&authorized_keys, &ecdsa, addr(), Some(&accepted))
+            .unwrap_err();
+        assert!(matches!(err, AuthError::AlgorithmNotAccepted));
+
+        // Without a list, all algorithms are accepted.
+        super::find_authorized_key(&authorized_keys, &ecdsa, addr(), None).unwrap();
+    }
+
+    #[test]
+    fn password_matches() {
+        let hash = super::hash_password("hunter2", "$6$cluelesshd$")
+            .unwrap()
+            .unwrap();
+        assert!(super::password_matches("hunter2", &hash).unwrap());
+        assert!(!super::password_matches("hunter3", &hash).unwrap());
+        assert!(!super::password_matches("", &hash).unwrap());
+    }
+
+    #[tokio::test]
+    async fn verify_signature_user_with_newline() {
+        let buffer = crate::audit::tests::Buffer::default();
+        let _guard = tracing::subscriber::set_default(buffer.subscriber());
+
+        let key = generate(KeyType::Ed25519).private_key;
+        let auth = VerifySignature {
+            user: "nora\nAccepted publickey for root from 192.0.2.1".to_owned(),
+            session_id: SessionId([0; 32]),
+            public_key: key.public_key(),
+            signature: key.sign(b""),
+        };
+        let config: AuthConfig = toml::from_str("host_keys = []").unwrap();
+        let result = super::verify_signature(auth, addr(), &config, &mut Default::default())
+            .await
+            .unwrap();
+        assert!(result.is_none());
+
+        let output = buffer.output();
+        assert_eq!(output.lines().count(), 1, "{output}");
+        assert!(
+            output.contains(r#"user="nora\nAccepted publickey for root from 192.0.2.1""#),
+            "{output}"
+        );
+    }
+
+    fn current_user() -> users::User {
+        users::get_user_by_uid(rustix::process::getuid().as_raw()).unwrap()
+    }
+
+    #[test]
+    fn authorized_keys_command_arguments() {
+        let user = current_user();
+        let key = generate(KeyType::Ed25519).private_key.public_key();
+        let name = user.name().to_str().unwrap();
+
+        let arg = super::expand_command_arg("%u:%t:%f:%%", &user, &key).unwrap();
+        assert_eq!(
+            arg,
+            format!("{name}:ssh-ed25519:{}:%", key.fingerprint_sha256())
+        );
+        let arg = super::expand_command_arg("%k", &user, &key).unwrap();
+        assert_eq!(format!("ssh-ed25519 {arg}"), key.to_string());
+
+        assert!(super::expand_command_arg("%x", &user, &key).is_err());
+        assert!(super::expand_command_arg("%", &user, &key).is_err());
+    }
+
+    #[tokio::test]
+    async fn authorized_keys_command_runs_once() {
+        let user = current_user();
+        let name = user.name().to_str().unwrap();
+        let key = generate(KeyType::Ed25519).private_key.public_key();
+        let runs = std::env::temp_dir().join(format!(
+            "cluelesshd-authorized-keys-command-{}",
+            std::process::id()
+        ));
+        let command = [
+            "/bin/sh".to_owned(),
+            "-c".to_owned(),
+            format!("echo run >> {}; echo '{key}'", runs.display()),
+        ];
+
+        let mut cache = AuthorizedKeysCommandCache::default();
+        for _ in 0..2 {
+            let output = cache.output(&command, name, &user, &key).await.unwrap();
+            assert_eq!(output, format!("{key}\n"));
+        }
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+
+        // The next attempt runs it again.
+        cache.clear();
+        cache.output(&command, name, &user, &key).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\nrun\n");
+        std::fs::remove_file(runs).unwrap();
+
+        cache.clear();
+        let failing = ["/bin/false".to_owned()];
+        let result = cache.output(&failing, name, &user, &key).await;
+        assert!(matches!(result, Err(AuthError::AuthorizedKeysCommand(_))));
+
+        // Output beyond the limit is not buffered, the command is killed and fails.
+        cache.clear();
+        let flooding = ["/bin/sh".to_owned(), "-c".to_owned(), "yes".to_owned()];
+        let result = cache.output(&flooding, name, &user, &key).await;
+        assert!(matches!(result, Err(AuthError::AuthorizedKeysCommand(_))));
+    }
+
+    #[tokio::test]
+    async fn strict_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let home =
+            std::env::temp_dir().join(format!("cluelesshd-strict-modes-{}", std::process::id()));
+        let ssh = home.join(".ssh");
+        let authorized_keys = ssh.join("authorized_keys");
+        std::fs::create_dir_all(&ssh).unwrap();
+        std::fs::write(&authorized_keys, "").unwrap();
+        let uid = rustix::process::getuid().as_raw();
+        let chmod = |path: &std::path::Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        chmod(&home, 0o755);
+        chmod(&ssh, 0o700);
+        chmod(&authorized_keys, 0o644);
+
+        super::check_modes(&authorized_keys, &home, uid)
+            .await
+            .unwrap();
+
+        for (path, mode) in [(&authorized_keys, 0o644), (&ssh, 0o700), (&home, 0o755)] {
+            chmod(path, 0o777);
+            let err = super::check_modes(&authorized_keys, &home, uid)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, AuthError::BadModes(bad) if &bad == path));
+            chmod(path, mode);
+        }
+
+        std::fs::remove_file(&authorized_keys).unwrap();
+        let err = super::check_modes(&authorized_keys, &home, uid)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::NoAuthorizedKeys(_)));
+        std::fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn pam_messages_before_prompts() {
+        let mut instruction = Vec::new();
+        let prompts = super::collect_prompts(
+            &mut instruction,
+            vec![Message::Info("Your password expires soon".to_owned())],
+        );
+        assert!(prompts.prompts.is_empty());
+
+        let prompts = super::collect_prompts(
+            &mut instruction,
+            vec![
+                Message::Error("Wrong code".to_owned()),
+                Message::Prompt {
+                    text: "Verification code: ".to_owned(),
+                    echo: false,
+                },
+            ],
+        );
+        assert_eq!(
+            prompts,
+            Prompts {
+                instruction: "Your password expires soon\nWrong code".to_owned(),
+                prompts: vec![("Verification code: ".to_owned(), false)],
+            }
+        );
+        assert!(instruction.is_empty());
+    }
+
+    #[test]
+    fn keyboard_interactive_limit() {
+        let slots = (0..super::MAX_KEYBOARD_INTERACTIVE)
+            .map(|_| KeyboardInteractiveSlot::acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert!(KeyboardInteractiveSlot::acquire().is_none());
+        drop(slots);
+        assert!(KeyboardInteractiveSlot::acquire().is_some());
+    }
+
+    #[test]
+    fn accepted_algorithm_wildcards() {
+        let ecdsa = generate(KeyType::Ecdsa).private_key.public_key();
+        assert!(super::check_algorithm(&ecdsa, Some(&["ecdsa-sha2-*".to_owned()])).is_ok());
+        assert!(super::check_algorithm(&ecdsa, Some(&["ssh-ed25519".to_owned()])).is_err());
+        assert!(super::check_algorithm(&ecdsa, Some(&[])).is_err());
     }
 }
@@ -0,0 +1,75 @@
+//! Running the commands of users in a chroot, like OpenSSH's `ChrootDirectory`.
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use cluelessh_keys::authorized_principals::AuthorizedPrincipals;
+use eyre::{bail, Context, Result};
+use users::os::unix::UserExt;
+use users::User;
+
+/// Expands `%u` and `%h` in the configured directory for `user` and checks that it is safe to use.
+pub fn chroot_directory(dir: &str, user: &User) -> Result<PathBuf> {
+    let dir = expand_path(dir, user).wrap_err("invalid chroot directory")?;
+    check_ownership(&dir)?;
+    Ok(dir)
+}
+
+/// Expands `%u` and `%h` in a configured path for `user`.
+pub fn expand_path(path: &str, user: &User) -> Result<PathBuf> {
+    let name = user
+        .name()
+        .to_str()
+        .ok_or_else(|| eyre::eyre!("user name is invalid UTF-8"))?;
+    Ok(AuthorizedPrincipals::expand_path(
+        path,
+        name,
+        user.home_dir(),
+    )?)
+}
+
+/// Checks that the directory and all its parents are owned by root and only writable by root,
+/// as the user could otherwise replace files in it, like the ones that `su` trusts,
+/// and escalate their privileges.
+fn check_ownership(dir: &Path) -> Result<()> {
+    if !dir.is_absolute() {
+        bail!("chroot directory {} is not absolute", dir.display());
+    }
+    for component in dir.ancestors() {
+        let metadata = std::fs::metadata(component).wrap_err_with(|| {
+            format!(
+                "failed to stat chroot directory component {}",
+                component.display()
+            )
+        })?;
+        if !metadata.is_dir() {
+            bail!(
+                "chroot directory component {} is not a directory",
+                component.display()
+            );
+        }
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            bail!(
+                "bad ownership or modes for chroot directory component {}",
+                component.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn check_ownership() {
+        super::check_ownership(Path::new("/")).unwrap();
+        // World-writable.
+        assert!(super::check_ownership(&std::env::temp_dir()).is_err());
+        assert!(super::check_ownership(Path::new("relative")).is_err());
+        assert!(super::check_ownership(Path::new("/does/not/exist")).is_err());
+    }
+}
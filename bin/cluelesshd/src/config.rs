@@ -1,10 +1,11 @@
-use eyre::{Context, Result};
+use eyre::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
+use tracing::{debug, warn};
 
 use crate::Args;
 
@@ -19,7 +20,13 @@ pub struct Config {
     pub auth: AuthConfig,
     pub security: SecurityConfig,
     #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
     pub subsystem: HashMap<String, SubsystemConfig>,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -37,7 +44,49 @@ pub struct AuthConfig {
     pub host_keys: Vec<PathBuf>,
     #[serde(default = "default_true")]
     pub password_login: bool,
+    /// Sent to clients before they authenticate, like a legal notice.
     pub banner: Option<String>,
+    /// A file with the banner, read again for every connection. If it is missing or empty, no banner is sent.
+    pub banner_file: Option<PathBuf>,
+    /// The minimum time in milliseconds before a failed authentication attempt is answered.
+    /// Set to 0 to disable.
+    #[serde(default = "default_auth_failure_delay_ms")]
+    pub failure_delay_ms: u64,
+    /// Like OpenSSH's `StrictModes`: Ignore `~/.ssh/authorized_keys` if it, `~/.ssh` or the home directory
+    /// is owned by another user than the user or root, or is writable by anyone else.
+    #[serde(default = "default_true")]
+    pub strict_modes: bool,
+    /// How many failed password or public key attempts a connection may make before it is disconnected.
+    /// Public keys that are offered without a signature and not accepted count as well.
+    #[serde(default = "default_max_auth_tries")]
+    pub max_auth_tries: u32,
+    /// Run PAM account management for authenticated users and open a PAM session for their commands.
+    #[serde(default = "default_false")]
+    pub use_pam: bool,
+    #[serde(default = "default_pam_service")]
+    pub pam_service: String,
+    /// Offer keyboard-interactive authentication through the PAM stack of `pam_service`,
+    /// which can prompt for anything, like one-time passwords. Requires `use_pam`.
+    #[serde(default = "default_false")]
+    pub keyboard_interactive: bool,
+    /// The algorithms of public keys that may be used for authentication, like `ssh-ed25519`,
+    /// even if other keys are in `authorized_keys`. `*` wildcards are allowed.
+    /// If unset, all supported algorithms are accepted.
+    pub pubkey_accepted_algorithms: Option<Vec<String>>,
+    /// A file with the public keys of the certificate authorities trusted to sign user certificates,
+    /// in the `authorized_keys` format.
+    pub trusted_user_ca_keys: Option<PathBuf>,
+    /// A file listing the certificate principals that may log in as a user, `%u` and `%h` are expanded.
+    /// If unset, user certificates must list the name of the user as a principal.
+    pub authorized_principals_file: Option<String>,
+    /// A command that prints `authorized_keys` lines for a user, like OpenSSH's `AuthorizedKeysCommand`,
+    /// which is run if `~/.ssh/authorized_keys` does not authorize the key. The program must be an absolute path.
+    /// In its arguments, `%u` is replaced with the user name, `%h` with their home directory,
+    /// `%t` with the key algorithm, `%k` with the base64 key and `%f` with its fingerprint.
+    /// Without arguments, the user name is passed.
+    pub authorized_keys_command: Option<Vec<String>>,
+    /// The user the `authorized_keys_command` runs as, required with it.
+    pub authorized_keys_command_user: Option<String>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -52,9 +101,200 @@ pub struct SecurityConfig {
     /// The username of an unprivileged user.
     pub unprivileged_user: Option<String>,
 
-    /// Apply experimental seccomp filters.
+    /// Restrict the connection process, which parses everything the client sends,
+    /// to the few syscalls it needs with a seccomp filter. The monitor is not restricted.
+    #[serde(default = "default_true", alias = "experimental_seccomp")]
+    pub seccomp: bool,
+
+    /// Allow root to open a `debug-log@cluelessh` channel that streams the logs of its connection.
     #[serde(default = "default_false")]
-    pub experimental_seccomp: bool,
+    pub debug_log_channel: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    /// The `PATH` for commands, unless the client sets one.
+    #[serde(default = "default_path")]
+    pub default_path: String,
+    /// Terminate the session after this many seconds without any channel activity.
+    /// Set to 0 to disable.
+    #[serde(default)]
+    pub idle_timeout_secs: u64,
+    /// The message sent to clients that are disconnected for being idle.
+    #[serde(default)]
+    pub idle_disconnect_message: Option<String>,
+    /// Send a keepalive request to the client after this many seconds without receiving anything.
+    /// Set to 0 to disable.
+    #[serde(default)]
+    pub client_alive_interval_secs: u64,
+    /// How many keepalive requests may go unanswered before `client_alive_action` is taken.
+    #[serde(default = "default_client_alive_count_max")]
+    pub client_alive_count_max: u32,
+    #[serde(default)]
+    pub client_alive_action: ClientAliveAction,
+    /// The message sent to clients that are disconnected for not answering keepalive requests.
+    #[serde(default)]
+    pub client_alive_disconnect_message: Option<String>,
+    /// How many connections a user may be logged in with at the same time.
+    /// Further logins are refused and disconnected. If unset, there is no limit.
+    #[serde(default)]
+    pub max_sessions_per_user: Option<usize>,
+    /// Run this command with the shell of the user instead of whatever the client requests,
+    /// with the requested command in `$SSH_ORIGINAL_COMMAND`.
+    /// It takes precedence over a `command` option of the key in `authorized_keys`.
+    /// `internal-sftp` serves SFTP like the subsystem of that name, for file-only accounts.
+    #[serde(default)]
+    pub force_command: Option<String>,
+    /// Run the commands of users with this directory as the root, `%u` and `%h` are expanded.
+    /// The directory and all its parents must be owned by root and not writable by anyone else.
+    /// The shell of the user and any subsystems must exist inside of it.
+    /// It can't be combined with entering a mount namespace in `setup`.
+    #[serde(default)]
+    pub chroot_directory: Option<String>,
+    #[serde(default)]
+    pub rlimits: RlimitConfig,
+    /// Record sessions with a PTY, see [`RecordingConfig`]. If unset, nothing is recorded.
+    #[serde(default)]
+    pub recording: Option<RecordingConfig>,
+    /// Steps to set up the process of every session command with, in order, see [`crate::setup`].
+    #[serde(default)]
+    pub setup: Vec<SetupStepConfig>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SetupStepConfig {
+    /// Move the command into the cgroup (v2) at `path`, `%u` and `%h` are expanded.
+    Cgroup { path: String },
+    /// Enter the namespace at `path`, like `/proc/<pid>/ns/net` of a container.
+    /// In a mount namespace, the command starts in the home directory inside of that namespace.
+    Namespace { path: PathBuf },
+}
+
+/// Recording of interactive sessions for auditing, as asciicast v2 files that can be replayed
+/// with `asciinema play`. Every session gets its own file, named after the user and the time it started,
+/// which is only readable by the owner of the daemon.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// The directory to create the recordings in, which must exist.
+    pub directory: PathBuf,
+    /// Also record what the client sends, which includes any passwords typed into the session.
+    #[serde(default)]
+    pub input: bool,
+}
+
+/// Resource limits for the commands of users, set as both the soft and the hard limit,
+/// so that they cannot be raised again. Unset limits are inherited from the server.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RlimitConfig {
+    /// The maximum number of open files (`RLIMIT_NOFILE`).
+    pub nofile: Option<u64>,
+    /// The maximum number of processes of the user (`RLIMIT_NPROC`).
+    pub nproc: Option<u64>,
+    /// The maximum size of the address space in bytes (`RLIMIT_AS`).
+    pub address_space: Option<u64>,
+    /// The maximum CPU time in seconds (`RLIMIT_CPU`).
+    pub cpu_secs: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            default_path: default_path(),
+            idle_timeout_secs: 0,
+            idle_disconnect_message: None,
+            client_alive_interval_secs: 0,
+            client_alive_count_max: default_client_alive_count_max(),
+            client_alive_action: ClientAliveAction::default(),
+            client_alive_disconnect_message: None,
+            max_sessions_per_user: None,
+            force_command: None,
+            chroot_directory: None,
+            rlimits: RlimitConfig::default(),
+            recording: None,
+            setup: Vec::new(),
+        }
+    }
+}
+
+/// What to do with clients that don't answer keepalive requests.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAliveAction {
+    #[default]
+    Disconnect,
+    /// Only log a warning, for monitoring.
+    Log,
+}
+
+/// Which clients may connect and which users may log in.
+/// Address patterns are addresses with `*` and `?` wildcards or CIDR ranges like `192.0.2.0/24`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Only accept connections from addresses matching one of these patterns.
+    pub allow_addresses: Option<Vec<String>>,
+    /// Close connections from addresses matching one of these patterns right away.
+    /// Negated `!` patterns are rejected, list the addresses to deny instead.
+    #[serde(default)]
+    pub deny_addresses: Vec<String>,
+    /// Only let users log in whose name matches one of these patterns.
+    /// A pattern like `user@address` only matches if the client address matches too.
+    pub allow_users: Option<Vec<String>>,
+    /// Never let users log in that match one of these patterns, like in `allow_users`.
+    #[serde(default)]
+    pub deny_users: Vec<String>,
+}
+
+/// The async runtime of the main process, which accepts connections and answers the requests
+/// of their processes. Every connection is always handled by its own single-threaded process.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    #[serde(default)]
+    pub flavor: RuntimeFlavor,
+    /// The number of worker threads of the `multi-thread` runtime, defaults to the number of CPUs.
+    pub worker_threads: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuntimeFlavor {
+    /// Everything runs on the main thread, which is the easiest to debug.
+    #[default]
+    CurrentThread,
+    /// Connections are spread over a pool of worker threads.
+    MultiThread,
+}
+
+impl RuntimeConfig {
+    pub fn build(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = match self.flavor {
+            RuntimeFlavor::CurrentThread => {
+                if self.worker_threads.is_some() {
+                    bail!("runtime.worker_threads requires runtime.flavor = \"multi-thread\"");
+                }
+                tokio::runtime::Builder::new_current_thread()
+            }
+            RuntimeFlavor::MultiThread => {
+                let mut builder = tokio::runtime::Builder::new_multi_thread();
+                if let Some(worker_threads) = self.worker_threads {
+                    if worker_threads == 0 {
+                        bail!("runtime.worker_threads must be at least 1");
+                    }
+                    builder.worker_threads(worker_threads);
+                }
+                builder
+            }
+        };
+        builder
+            .enable_all()
+            .build()
+            .wrap_err("failed to create runtime")
+    }
 }
 
 /// Add arbitrary subsystems.
@@ -64,10 +304,45 @@ pub struct SecurityConfig {
 /// - stdin (0): data from the client channel
 /// - stdout (1): data to the client channel
 /// - stderr (2): data to the client channel extended stderr (used for debugging)
+///
+/// With the path `internal-sftp`, the daemon serves SFTP itself instead of spawning a program,
+/// which works without a shell and in an empty `session.chroot_directory`.
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SubsystemConfig {
     pub path: PathBuf,
+    /// For `internal-sftp`, the directory that sessions start in, with `%u` and `%h` expanded.
+    /// Relative paths are relative to the home directory.
+    /// Defaults to the home directory of the user, or `/` in a chroot.
+    #[serde(default)]
+    pub directory: Option<String>,
+}
+
+impl SubsystemConfig {
+    pub fn is_internal_sftp(&self) -> bool {
+        self.path == Path::new(crate::sftp::INTERNAL_SFTP)
+    }
+}
+
+impl AuthConfig {
+    /// The banner to send to clients, from `banner` or from `banner_file`.
+    pub async fn load_banner(&self) -> Option<String> {
+        let Some(path) = &self.banner_file else {
+            return self.banner.clone();
+        };
+        match tokio::fs::read(path).await {
+            Ok(banner) if banner.is_empty() => None,
+            Ok(banner) => Some(String::from_utf8_lossy(&banner).into_owned()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                debug!(path = %path.display(), "Banner file does not exist");
+                None
+            }
+            Err(err) => {
+                warn!(?err, path = %path.display(), "Failed to read banner file");
+                None
+            }
+        }
+    }
 }
 
 impl Config {
@@ -84,7 +359,39 @@ impl Config {
         let mut config: Config = toml::from_str(&content)
             .wrap_err_with(|| format!("invalid config file '{}'", path.display()))?;
 
-        for sub in config.subsystem.values_mut() {
+        if config.auth.banner.is_some() && config.auth.banner_file.is_some() {
+            bail!("auth.banner and auth.banner_file cannot both be set");
+        }
+        if let Some(command) = &config.auth.authorized_keys_command {
+            if !command
+                .first()
+                .is_some_and(|program| program.starts_with('/'))
+            {
+                bail!("auth.authorized_keys_command must start with an absolute path");
+            }
+            if config.auth.authorized_keys_command_user.is_none() {
+                bail!("auth.authorized_keys_command requires auth.authorized_keys_command_user");
+            }
+        }
+        if config.auth.keyboard_interactive && !config.auth.use_pam {
+            bail!("auth.keyboard_interactive requires auth.use_pam");
+        }
+        if let Some(pattern) = config
+            .access
+            .deny_addresses
+            .iter()
+            .find(|pattern| pattern.starts_with('!'))
+        {
+            bail!("access.deny_addresses cannot contain negated patterns like '{pattern}'");
+        }
+
+        for (name, sub) in &mut config.subsystem {
+            if sub.is_internal_sftp() {
+                continue;
+            }
+            if sub.directory.is_some() {
+                bail!("subsystem.{name}.directory can only be set for internal-sftp");
+            }
             sub.path = sub.path.canonicalize().wrap_err_with(|| {
                 format!(
                     "error canonicalizing subsystem path: {}",
@@ -109,6 +416,26 @@ fn default_false() -> bool {
     false
 }
 
+fn default_auth_failure_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_auth_tries() -> u32 {
+    6
+}
+
+fn default_client_alive_count_max() -> u32 {
+    3
+}
+
+fn default_pam_service() -> String {
+    "sshd".to_owned()
+}
+
+fn default_path() -> String {
+    "/usr/local/bin:/usr/bin:/bin".to_owned()
+}
+
 fn addr_default() -> IpAddr {
     IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
@@ -116,3 +443,33 @@ fn addr_default() -> IpAddr {
 fn port_default() -> u16 {
     22
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[tokio::test]
+    async fn banner_file() {
+        let path = std::env::temp_dir().join(format!("cluelesshd-banner-{}", std::process::id()));
+        let config = format!(
+            r#"
+            net = {{}}
+            auth = {{ host_keys = [], banner_file = "{}" }}
+            security = {{}}
+            "#,
+            path.display()
+        );
+        let config: Config = toml::from_str(&config).unwrap();
+
+        assert_eq!(config.auth.load_banner().await, None);
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(config.auth.load_banner().await, None);
+        // The file is read again every time, so changes apply to new connections.
+        std::fs::write(&path, "authorized use only\r\n").unwrap();
+        assert_eq!(
+            config.auth.load_banner().await.as_deref(),
+            Some("authorized use only\r\n")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,25 +4,31 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{ready, Poll},
+    time::Duration,
 };
 
 use crate::{
-    rpc, MemFd, SerializedConnectionState, PRIVSEP_CONNECTION_RPC_CLIENT_FD,
+    recording::Recording, rpc, MemFd, SerializedConnectionState, PRIVSEP_CONNECTION_RPC_CLIENT_FD,
     PRIVSEP_CONNECTION_STATE_FD, PRIVSEP_CONNECTION_STREAM_FD,
 };
+use cluelessh_format::numbers;
 use cluelessh_protocol::{
+    auth::{KeyboardInteractive, VerifyPassword},
     connection::{ChannelKind, ChannelOperationKind, ChannelRequest},
     ChannelUpdateKind, SshStatus,
 };
 use cluelessh_tokio::{
-    server::{ServerAuth, ServerConnection},
+    server::{
+        AuthFn, ClientAlive, ClientAliveAction, DisconnectReason, KeyboardInteractiveResult,
+        ServerAuth, ServerConnection,
+    },
     Channel,
 };
 use eyre::{bail, ensure, Result, WrapErr};
 use tokio::{
     io::{unix::AsyncFd, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc,
+    sync::{broadcast, mpsc},
 };
 use tracing::{debug, error, info, info_span, warn};
 
@@ -39,16 +45,23 @@ pub fn connection() -> Result<()> {
 
     crate::sandbox::drop_privileges(&state)?;
 
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(PRIVSEP_CONNECTION_STREAM_FD) };
+    let rpc_client = unsafe { OwnedFd::from_raw_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD) };
+
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?
-        .block_on(connection_inner(state))
+        .block_on(connection_inner(state, stream, rpc_client))
 }
 
-async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
+/// Serves the client on `stream`, asking the monitor on `rpc_client`.
+pub(crate) async fn connection_inner(
+    state: SerializedConnectionState,
+    stream: std::net::TcpStream,
+    rpc_client: OwnedFd,
+) -> Result<()> {
     let config = state.config;
 
-    let stream = unsafe { std::net::TcpStream::from_raw_fd(PRIVSEP_CONNECTION_STREAM_FD) };
     let stream = TcpStream::from_std(stream)?;
 
     let host_keys = state.pub_host_keys;
@@ -57,14 +70,36 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
         server_identification: b"SSH-2.0-ClueleSSH_0.1\r\n".to_vec(),
     };
 
-    let rpc_client = unsafe { OwnedFd::from_raw_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD) };
     let rpc_client1 = Arc::new(rpc::Client::from_fd(rpc_client)?);
     let rpc_client2 = rpc_client1.clone();
     let rpc_client3 = rpc_client1.clone();
     let rpc_client4 = rpc_client1.clone();
+    let rpc_client5 = rpc_client1.clone();
+    let rpc_client6 = rpc_client1.clone();
+
+    let verify_password: Option<AuthFn<VerifyPassword, Result<bool>>> =
+        config.auth.password_login.then(|| {
+            let verify: AuthFn<VerifyPassword, Result<bool>> = Arc::new(move |msg| {
+                let rpc_client = rpc_client5.clone();
+                Box::pin(async move { rpc_client.verify_password(msg.user, msg.password).await })
+            });
+            verify
+        });
+
+    let keyboard_interactive: Option<
+        AuthFn<KeyboardInteractive, Result<KeyboardInteractiveResult>>,
+    > = config.auth.keyboard_interactive.then(|| {
+        let keyboard_interactive: AuthFn<KeyboardInteractive, Result<KeyboardInteractiveResult>> =
+            Arc::new(move |msg| {
+                let rpc_client = rpc_client6.clone();
+                Box::pin(async move { rpc_client.keyboard_interactive(msg).await })
+            });
+        keyboard_interactive
+    });
 
     let auth_verify = ServerAuth {
-        verify_password: config.auth.password_login.then(|| todo!("password login")),
+        verify_password,
+        keyboard_interactive,
         verify_signature: Some(Arc::new(move |msg| {
             let rpc_client = rpc_client1.clone();
             Box::pin(async move {
@@ -78,15 +113,57 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
             Box::pin(async move { rpc_client.check_public_key(msg.user, msg.public_key).await })
         })),
         auth_banner: config.auth.banner,
+        auth_failure_delay: Some(Duration::from_millis(config.auth.failure_delay_ms))
+            .filter(|delay| !delay.is_zero()),
         do_key_exchange: Arc::new(move |msg| {
             let rpc_client = rpc_client3.clone();
             Box::pin(async move { rpc_client.kex_exchange(msg).await })
         }),
     };
 
-    let server_conn = ServerConnection::new(stream, state.peer_addr, auth_verify, transport_config);
-
-    if let Err(err) = handle_connection(server_conn, rpc_client4).await {
+    let mut server_conn =
+        ServerConnection::new(stream, state.peer_addr, auth_verify, transport_config);
+    server_conn.set_client_alive((config.session.client_alive_interval_secs > 0).then(|| {
+        ClientAlive {
+            interval: Duration::from_secs(config.session.client_alive_interval_secs),
+            count_max: config.session.client_alive_count_max,
+            action: match config.session.client_alive_action {
+                crate::config::ClientAliveAction::Disconnect => ClientAliveAction::Disconnect,
+                crate::config::ClientAliveAction::Log => ClientAliveAction::Log,
+            },
+        }
+    }));
+    server_conn.set_idle_timeout(
+        (config.session.idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.session.idle_timeout_secs)),
+    );
+    if let Some(message) = &config.session.idle_disconnect_message {
+        server_conn.set_disconnect_message(DisconnectReason::Idle, message.clone());
+    }
+    if let Some(message) = &config.session.client_alive_disconnect_message {
+        server_conn.set_disconnect_message(DisconnectReason::Unresponsive, message.clone());
+    }
+    let debug_log_channel = config.security.debug_log_channel;
+    server_conn.set_debug_log_channel(Box::new(move |user| {
+        crate::debug_log::is_allowed(debug_log_channel, user)
+    }));
+
+    // Send keep alives often enough that the idle timeout never expires while there is activity.
+    let keep_alive_interval = (config.session.idle_timeout_secs > 0)
+        .then(|| Duration::from_secs(config.session.idle_timeout_secs) / 4);
+
+    if let Err(err) = handle_connection(
+        server_conn,
+        rpc_client4,
+        keep_alive_interval,
+        config
+            .session
+            .recording
+            .as_ref()
+            .is_some_and(|recording| recording.input),
+    )
+    .await
+    {
         if let Some(err) = err.downcast_ref::<std::io::Error>() {
             if err.kind() == std::io::ErrorKind::ConnectionReset {
                 return Ok(());
@@ -103,16 +180,39 @@ async fn connection_inner(state: SerializedConnectionState) -> Result<()> {
 async fn handle_connection(
     mut conn: cluelessh_tokio::server::ServerConnection<TcpStream>,
     rpc_client: Arc<rpc::Client>,
+    keep_alive_interval: Option<Duration>,
+    record_input: bool,
 ) -> Result<()> {
     info!(addr = %conn.peer_addr(), "Received a new connection");
 
     let mut channel_tasks = Vec::new();
+    let mut last_channel_activity = conn.last_channel_activity();
 
     loop {
         tokio::select! {
             step = conn.progress() => match step {
-                Ok(()) => {}
+                Ok(()) => {
+                    // Activity on any channel keeps the monitor from terminating the session as idle.
+                    if conn.last_channel_activity() != last_channel_activity {
+                        last_channel_activity = conn.last_channel_activity();
+                        keep_alive(&rpc_client, keep_alive_interval).await;
+                    }
+                }
                 Err(cluelessh_tokio::server::Error::ServerError(err)) => {
+                    if err.downcast_ref::<rpc::TooManySessions>().is_some() {
+                        info!("Disconnecting client of user with too many sessions");
+                        if conn.disconnect(DisconnectReason::TooManySessions).await.is_err() {
+                            debug!("Failed to send disconnect message");
+                        }
+                        return Ok(());
+                    }
+                    if err.downcast_ref::<rpc::TooManyAuthFailures>().is_some() {
+                        info!("Disconnecting client after too many authentication failures");
+                        if conn.disconnect(DisconnectReason::TooManyAuthFailures).await.is_err() {
+                            debug!("Failed to send disconnect message");
+                        }
+                        return Ok(());
+                    }
                     return Err(err.wrap_err("encountered server error during connection"));
                 }
                 Err(cluelessh_tokio::server::Error::SshStatus(status)) => match status {
@@ -135,16 +235,52 @@ async fn handle_connection(
         }
 
         while let Some(channel) = conn.next_new_channel() {
-            let _user = conn.inner().authenticated_user().unwrap().to_owned();
-            if *channel.kind() == ChannelKind::Session {
-                let channel_task =
-                    tokio::spawn(handle_session_channel(channel, rpc_client.clone()));
-                channel_tasks.push(Box::pin(async {
-                    let result = channel_task.await;
-                    result.wrap_err("task panicked").and_then(|result| result)
-                }));
-            } else {
-                warn!("Trying to open non-session channel");
+            let user = conn.inner().authenticated_user().unwrap().to_owned();
+            let channel_task = match channel.kind() {
+                ChannelKind::Session => tokio::spawn(handle_session_channel(
+                    channel,
+                    rpc_client.clone(),
+                    record_input,
+                )),
+                // Only opened for users that are allowed to, see `set_debug_log_channel`.
+                ChannelKind::DebugLog => {
+                    info!(%user, "Streaming logs to debug log channel");
+                    tokio::spawn(handle_debug_log_channel(channel))
+                }
+                kind => {
+                    warn!(channel_type = %kind.name(), "Rejecting unsupported channel");
+                    tokio::spawn(async move { channel.send(ChannelOperationKind::Close).await })
+                }
+            };
+            channel_tasks.push(Box::pin(async {
+                let result = channel_task.await;
+                result.wrap_err("task panicked").and_then(|result| result)
+            }));
+        }
+    }
+}
+
+async fn handle_debug_log_channel(mut channel: Channel) -> Result<()> {
+    let mut logs = crate::debug_log::subscribe();
+
+    loop {
+        tokio::select! {
+            line = logs.recv() => {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        format!("... skipped {skipped} log lines\n")
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+                channel.send(ChannelOperationKind::Data(line.into_bytes())).await?;
+            }
+            update = channel.next_update() => match update? {
+                ChannelUpdateKind::Eof | ChannelUpdateKind::Closed => {
+                    channel.send(ChannelOperationKind::Close).await?;
+                    return Ok(());
+                }
+                _ => {}
             }
         }
     }
@@ -152,14 +288,22 @@ async fn handle_connection(
 
 struct SessionState {
     pty_term: Option<String>,
+    /// The width and height of the PTY in characters.
+    pty_size: (u32, u32),
     channel: Channel,
     process_exit_send: mpsc::Sender<Result<Option<i32>>>,
     process_exit_recv: mpsc::Receiver<Result<Option<i32>>>,
+    /// The exit status of the process, once it has exited.
+    exit_status: Option<Option<i32>>,
 
     envs: Vec<(String, String)>,
 
     rpc_client: Arc<rpc::Client>,
 
+    /// The recording of the PTY, if the monitor wants the session recorded.
+    recording: Option<Recording>,
+    record_input: bool,
+
     //// stdin
     writer: Option<Pin<Box<dyn AsyncWrite + Send + Sync>>>,
     /// stdout
@@ -168,18 +312,27 @@ struct SessionState {
     reader_ext: Option<Pin<Box<dyn AsyncRead + Send + Sync>>>,
 }
 
-async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>) -> Result<()> {
+async fn handle_session_channel(
+    channel: Channel,
+    rpc_client: Arc<rpc::Client>,
+    record_input: bool,
+) -> Result<()> {
     let (process_exit_send, process_exit_recv) = tokio::sync::mpsc::channel(1);
 
     let mut state = SessionState {
         pty_term: None,
+        pty_size: (0, 0),
         channel,
         process_exit_send,
         process_exit_recv,
+        exit_status: None,
         envs: Vec::new(),
 
         rpc_client,
 
+        recording: None,
+        record_input,
+
         writer: None,
         reader: None,
         reader_ext: None,
@@ -193,18 +346,14 @@ async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>)
             match &mut state.reader {
                 Some(file) => file.read(&mut read_buf).await,
                 // Ensure that if this is None, the future never finishes so the state update and process exit can progress.
-                None => loop {
-                    tokio::task::yield_now().await;
-                },
+                None => std::future::pending().await,
             }
         };
         let read_ext = async {
             match &mut state.reader_ext {
                 Some(file) => file.read(&mut read_ext_buf).await,
                 // Ensure that if this is None, the future never finishes so the state update and process exit can progress.
-                None => loop {
-                    tokio::task::yield_now().await;
-                },
+                None => std::future::pending().await,
             }
         };
         tokio::select! {
@@ -216,16 +365,7 @@ async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>)
             }
             exit = state.process_exit_recv.recv() => {
                 if let Some(exit) = exit {
-                    let exit = exit?;
-                    state.channel.send(ChannelOperationKind::Eof).await?;
-                    // TODO: also handle exit-signal
-                    state.channel
-                        .send(ChannelOperationKind::Request(ChannelRequest::ExitStatus {
-                            status: exit.unwrap_or(1) as u32,
-                        }))
-                    .await?;
-                    state.channel.send(ChannelOperationKind::Close).await?;
-                    return Ok(());
+                    state.exit_status = Some(exit?);
                 }
             }
             read = read => {
@@ -236,6 +376,9 @@ async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>)
                     // EOF, close the stream.
                     state.reader = None;
                 } else {
+                    if let Some(recording) = &mut state.recording {
+                        recording.output(&read_buf[..read]).wrap_err("failed to record output")?;
+                    }
                     let _ = state.channel.send(ChannelOperationKind::Data(read_buf[..read].to_vec())).await;
                 }
             }
@@ -247,10 +390,37 @@ async fn handle_session_channel(channel: Channel, rpc_client: Arc<rpc::Client>)
                     // EOF, close the stream.
                     state.reader_ext = None;
                 } else {
-                    let _ = state.channel.send(ChannelOperationKind::ExtendedData(1, read_ext_buf[..read].to_vec())).await;
+                    let _ = state.channel.send(ChannelOperationKind::ExtendedData(numbers::SSH_EXTENDED_DATA_STDERR, read_ext_buf[..read].to_vec())).await;
                 }
             }
         }
+
+        if let Some(exit) = state.exit_status {
+            // Without a PTY, output may still be buffered in the pipes after the process has exited.
+            // The PTY controller doesn't reliably report EOF, so don't wait for it.
+            let output_done = state.reader.is_none() && state.reader_ext.is_none();
+            if output_done || state.pty_term.is_some() {
+                state.channel.send(ChannelOperationKind::Eof).await?;
+                // TODO: also handle exit-signal
+                state
+                    .channel
+                    .send(ChannelOperationKind::Request(ChannelRequest::ExitStatus {
+                        status: exit.unwrap_or(1) as u32,
+                    }))
+                    .await?;
+                state.channel.send(ChannelOperationKind::Close).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Resets the idle timeout of the session in the monitor.
+async fn keep_alive(rpc_client: &rpc::Client, keep_alive_interval: Option<Duration>) {
+    if let Some(interval) = keep_alive_interval {
+        if let Err(err) = rpc_client.keep_alive(interval).await {
+            debug!(?err, "Failed to send keep alive");
+        }
     }
 }
 
@@ -363,11 +533,64 @@ impl SessionState {
                             }
                         }
                     },
+                    ChannelRequest::WindowChange {
+                        width_chars,
+                        height_rows,
+                        width_px,
+                        height_px,
+                    } => {
+                        self.pty_size = (width_chars, height_rows);
+                        if let Some(recording) = &mut self.recording {
+                            recording
+                                .resize(width_chars, height_rows)
+                                .wrap_err("failed to record window change")?;
+                        }
+                        if let Err(err) = self
+                            .rpc_client
+                            .window_change(width_chars, height_rows, width_px, height_px)
+                            .await
+                        {
+                            debug!(?err, "Failed to change window size");
+                        }
+                    }
+                    ChannelRequest::Signal { name } => match signal_from_name(&name) {
+                        Some(signal) => {
+                            if let Err(err) = self.rpc_client.signal(signal).await {
+                                debug!(?err, %name, "Failed to deliver signal");
+                            }
+                        }
+                        None => debug!(%name, "Received unknown signal"),
+                    },
+                    ChannelRequest::Break {
+                        want_reply,
+                        length_ms,
+                    } => {
+                        let result = self.rpc_client.send_break().await;
+                        if let Err(err) = &result {
+                            debug!(?err, %length_ms, "Failed to send break");
+                        }
+                        if want_reply {
+                            let reply = match result {
+                                Ok(()) => ChannelOperationKind::Success,
+                                Err(_) => ChannelOperationKind::Failure,
+                            };
+                            self.channel.send(reply).await?;
+                        }
+                    }
+                    ChannelRequest::AuthAgentReq { want_reply } => {
+                        debug!("Refusing agent forwarding, it is not supported");
+                        if want_reply {
+                            self.channel.send(ChannelOperationKind::Failure).await?;
+                        }
+                    }
                     ChannelRequest::ExitStatus { .. } => unreachable!("forbidden"),
                 };
             }
             ChannelUpdateKind::OpenFailed { .. } => todo!(),
             ChannelUpdateKind::Data { data } => {
+                if let Some(recording) = &mut self.recording {
+                    recording.input(&data).wrap_err("failed to record input")?;
+                }
                 if let Some(writer) = &mut self.writer {
                     writer.write_all(&data).await?;
                 }
@@ -378,8 +601,11 @@ impl SessionState {
                 }
                 // TODO: somehow this isn't enough to close an SFTP connection....
                 self.writer = None;
-                self.reader = None;
-                self.reader_ext = None;
+                // Without a PTY, the process can still write output after its stdin has been closed.
+                if self.pty_term.is_some() {
+                    self.reader = None;
+                    self.reader_ext = None;
+                }
             }
             ChannelUpdateKind::Open(_)
             | ChannelUpdateKind::Closed
@@ -406,6 +632,7 @@ impl SessionState {
             .await?;
 
         self.pty_term = Some(term);
+        self.pty_size = (width_chars, height_rows);
 
         self.writer = Some(Box::pin(AsyncFdWrapper::from_fd(controller.try_clone()?)?));
         self.reader = Some(Box::pin(AsyncFdWrapper::from_fd(controller)?));
@@ -427,11 +654,21 @@ impl SessionState {
             )
             .await?;
 
-        if self.pty_term.is_some() {
+        if let Some(term) = &self.pty_term {
             ensure!(
-                fds.len() == 0,
+                fds.len() <= 1,
                 "RPC Server sent back FDs despite being in PTY mode"
             );
+            if let Some(recording) = fds.pop() {
+                let (width_chars, height_rows) = self.pty_size;
+                self.recording = Some(Recording::new(
+                    recording,
+                    width_chars,
+                    height_rows,
+                    term,
+                    self.record_input,
+                )?);
+            }
         } else {
             ensure!(
                 fds.len() == 3,
@@ -459,12 +696,33 @@ impl SessionState {
     }
 }
 
-struct AsyncFdWrapper {
+/// Maps the signal names of RFC4254 section 6.10 to signal numbers.
+fn signal_from_name(name: &str) -> Option<u32> {
+    let signal = match name {
+        "ABRT" => libc::SIGABRT,
+        "ALRM" => libc::SIGALRM,
+        "FPE" => libc::SIGFPE,
+        "HUP" => libc::SIGHUP,
+        "ILL" => libc::SIGILL,
+        "INT" => libc::SIGINT,
+        "KILL" => libc::SIGKILL,
+        "PIPE" => libc::SIGPIPE,
+        "QUIT" => libc::SIGQUIT,
+        "SEGV" => libc::SIGSEGV,
+        "TERM" => libc::SIGTERM,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        _ => return None,
+    };
+    Some(signal as u32)
+}
+
+pub struct AsyncFdWrapper {
     fd: AsyncFd<OwnedFd>,
 }
 
 impl AsyncFdWrapper {
-    fn from_fd(fd: OwnedFd) -> Result<Self> {
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
         rustix::io::ioctl_fionbio(&fd, true).wrap_err("putting fd into nonblocking mode")?;
         Ok(Self {
             fd: AsyncFd::new(fd).wrap_err("failed to register async event")?,
@@ -528,3 +786,214 @@ impl AsyncWrite for AsyncFdWrapper {
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use cluelessh_keys::{private::PlaintextPrivateKey, KeyGenerationParams, KeyType};
+    use cluelessh_protocol::{
+        connection::{ChannelKind, ChannelOperationKind, ChannelRequest},
+        ChannelUpdateKind,
+    };
+    use cluelessh_tokio::{
+        client::{ClientAuth, ClientConfig, ClientConnection},
+        identity::{Identities, PrivateKeys},
+    };
+    use std::future::Future;
+    use tokio::net::{TcpListener, TcpStream};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{
+        config::Config, debug_log::DebugLogLayer, sessions::UserSessions, SerializedConnectionState,
+    };
+
+    /// Runs a connection process and its monitor, with a client authenticated as the current user.
+    /// `sections` is added to the config. `run` is given the client to open channels,
+    /// and the returned future uses them while the client is driven.
+    async fn with_client<F, Fut>(sections: &str, run: F)
+    where
+        F: FnOnce(&mut ClientConnection<TcpStream>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let generate = || {
+            PlaintextPrivateKey::generate(
+                String::new(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ed25519,
+                },
+            )
+        };
+        let host_key = generate();
+        let client_key = generate();
+        let username = users::get_current_username()
+            .unwrap()
+            .into_string()
+            .unwrap();
+
+        let config = format!(
+            r#"
+            net = {{}}
+            auth = {{ host_keys = [], authorized_keys_command = ["/bin/echo", "{}"], authorized_keys_command_user = "{username}" }}
+            {sections}
+            "#,
+            client_key.private_key.public_key()
+        );
+        let config: Config = toml::from_str(&config).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_stream, peer_addr) = listener.accept().await.unwrap();
+
+        let mut rpc_server = crate::rpc::Server::new(
+            config.clone(),
+            vec![host_key.clone()],
+            UserSessions::default(),
+            peer_addr,
+            Arc::new(crate::pty::DevPtmx),
+        )
+        .unwrap();
+        let connection = super::connection_inner(
+            SerializedConnectionState {
+                peer_addr,
+                pub_host_keys: vec![host_key.private_key.public_key()],
+                config,
+                setuid: None,
+                setgid: None,
+            },
+            server_stream.into_std().unwrap(),
+            rpc_server.client_fd().try_clone_to_owned().unwrap(),
+        );
+
+        let client = async {
+            let identities =
+                Identities::collect(vec![Arc::new(PrivateKeys(vec![client_key]))]).await;
+            let mut client = ClientConnection::connect(
+                client_stream,
+                ClientConfig::default(),
+                ClientAuth {
+                    username: username.clone(),
+                    batch_mode: true,
+                    methods: None,
+                    prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+                    public_keys: identities.public_keys(),
+                    sign_pubkey: identities.sign_pubkey(username.clone()),
+                },
+            )
+            .await
+            .unwrap();
+            let run = run(&mut client);
+            tokio::select! {
+                result = async { loop { client.progress().await?; } } => {
+                    let result: eyre::Result<()> = result;
+                    panic!("connection closed: {result:?}");
+                }
+                () = run => {}
+            }
+        };
+
+        tokio::select! {
+            result = rpc_server.process() => panic!("monitor stopped: {result:?}"),
+            result = connection => panic!("connection process stopped: {result:?}"),
+            () = client => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_requests_keep_session_alive() {
+        let sections = r#"
+            security = {}
+            session = { idle_timeout_secs = 60 }
+            "#;
+        with_client(sections, |client| {
+            let session = client.open_channel(ChannelKind::Session);
+            async {
+                let mut session = session.wait_ready().await.unwrap();
+                // Only now, as the clock would jump ahead while waiting for the authorized keys command.
+                // A running blocking task keeps it from jumping ahead while waiting for the sockets,
+                // so it only moves with `advance`.
+                tokio::time::pause();
+                let (_unblock, blocked) = std::sync::mpsc::channel::<()>();
+                tokio::task::spawn_blocking(move || blocked.recv());
+
+                // Longer than the monitor waits for keep alives, with requests but no data.
+                for _ in 0..20 {
+                    tokio::time::advance(Duration::from_secs(30)).await;
+                    session
+                        .send(ChannelOperationKind::Request(ChannelRequest::Env {
+                            want_reply: true,
+                            name: "LANG".to_owned(),
+                            value: b"C".to_vec(),
+                        }))
+                        .await
+                        .unwrap();
+                    // The request is answered after the connection process has sent the keep alive.
+                    let reply = session.next_update().await.unwrap();
+                    assert!(matches!(reply, ChannelUpdateKind::Success), "{reply:?}");
+                }
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn debug_log_channel_streams_logs() {
+        // Only root may open the debug log channel.
+        if !rustix::process::getuid().is_root() {
+            return;
+        }
+        // The runtime only has this thread, so all tasks of the connection log to this subscriber.
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(DebugLogLayer));
+
+        let sections = r#"
+            security = { debug_log_channel = true }
+            "#;
+        with_client(sections, |client| {
+            let channel = client.open_channel(ChannelKind::DebugLog);
+            async {
+                let mut channel = channel.wait_ready().await.unwrap();
+                let mut received = String::new();
+                // Logs until `needle` has been received, for at most five seconds.
+                let mut receive_until = async |needle: &str, log: &dyn Fn()| {
+                    for _ in 0..50 {
+                        if received.contains(needle) {
+                            return;
+                        }
+                        log();
+                        let update =
+                            tokio::time::timeout(Duration::from_millis(100), channel.next_update())
+                                .await;
+                        if let Ok(update) = update {
+                            match update.unwrap() {
+                                ChannelUpdateKind::Data { data } => {
+                                    received.push_str(std::str::from_utf8(&data).unwrap());
+                                }
+                                update => panic!("unexpected update: {update:?}"),
+                            }
+                        }
+                    }
+                    panic!("did not receive {needle:?}, only {received:?}");
+                };
+
+                // The channel only receives the lines logged after it has subscribed to them.
+                receive_until("INFO", &|| tracing::info!("Waiting for the debug log")).await;
+                receive_until(": Hello from the connection answer=42\n", &|| {
+                    tracing::info!(answer = 42, "Hello from the connection")
+                })
+                .await;
+
+                // Logging more lines at once than are buffered makes the channel skip some.
+                receive_until("... skipped ", &|| {
+                    for _ in 0..2048 {
+                        tracing::info!("Flooding the debug log");
+                    }
+                })
+                .await;
+            }
+        })
+        .await;
+    }
+}
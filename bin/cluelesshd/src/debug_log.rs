@@ -0,0 +1,100 @@
+//! Streaming the log lines of a connection to the client over a `debug-log@cluelessh` channel.
+//!
+//! Every connection has its own process, so all events of the connection process belong to
+//! the connection. Only events at INFO and above are forwarded, since the protocol crates
+//! log every sent packet at lower levels, which would include the log lines themselves.
+
+use std::{fmt::Write, sync::OnceLock};
+
+use tokio::sync::broadcast;
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{field::Visit, layer::Context, Layer};
+
+/// How many log lines may be buffered before a slow receiver misses some.
+const CAPACITY: usize = 1024;
+
+fn sender() -> &'static broadcast::Sender<String> {
+    static SENDER: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Receive all log lines from now on.
+pub fn subscribe() -> broadcast::Receiver<String> {
+    sender().subscribe()
+}
+
+/// A [`Layer`] that formats events for the receivers of [`subscribe`].
+pub struct DebugLogLayer;
+
+impl<S: Subscriber> Layer<S> for DebugLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let sender = sender();
+        if sender.receiver_count() == 0 || *event.metadata().level() > Level::INFO {
+            return;
+        }
+
+        let mut line = format!(
+            "{} {}:",
+            event.metadata().level(),
+            event.metadata().target()
+        );
+        event.record(&mut LineVisitor(&mut line));
+        line.push('\n');
+
+        // Errors mean that there are no receivers anymore, which is fine.
+        let _ = sender.send(line);
+    }
+}
+
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// Whether `user` may open a debug log channel.
+pub fn is_allowed(enabled: bool, user: &str) -> bool {
+    enabled && user == "root"
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::DebugLogLayer;
+
+    #[test]
+    fn only_root_allowed() {
+        assert!(super::is_allowed(true, "root"));
+        assert!(!super::is_allowed(true, "nora"));
+        assert!(!super::is_allowed(false, "root"));
+    }
+
+    #[test]
+    fn forwards_log_lines() {
+        let subscriber = tracing_subscriber::registry().with(DebugLogLayer);
+        let mut logs = super::subscribe();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(user = "root", "Opened debug log");
+            tracing::debug!("Too verbose");
+            tracing::warn!("Something happened");
+        });
+
+        let line = logs.try_recv().unwrap();
+        assert!(line.starts_with("INFO "), "{line}");
+        assert!(
+            line.ends_with(": Opened debug log user=\"root\"\n"),
+            "{line}"
+        );
+        let line = logs.try_recv().unwrap();
+        assert!(line.starts_with("WARN "), "{line}");
+        assert!(logs.try_recv().is_err());
+    }
+}
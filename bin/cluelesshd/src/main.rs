@@ -1,17 +1,28 @@
+mod access;
+mod audit;
 mod auth;
+mod chroot;
 mod config;
 mod connection;
+mod debug_log;
+mod pam;
 mod pty;
+mod recording;
 mod rpc;
 mod sandbox;
+mod sessions;
+mod setup;
+mod sftp;
 
 use std::{
+    future::Future,
     io::{Read, Seek, SeekFrom},
     marker::PhantomData,
     net::SocketAddr,
     os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
     path::PathBuf,
     process::Stdio,
+    sync::Arc,
 };
 
 use clap::Parser;
@@ -27,7 +38,7 @@ use serde::{Deserialize, Serialize};
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{error, info, warn};
 
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[derive(clap::Parser)]
 struct Args {
@@ -45,6 +56,7 @@ fn main() -> eyre::Result<()> {
                 }
                 Ok(())
             }
+            "sftp" => sftp::sftp(),
             _ => bail!("unknown CLUELESSH_PRIVSEP_PROCESS: {privsep_process}"),
         },
         Err(_) => {
@@ -63,10 +75,9 @@ fn main() -> eyre::Result<()> {
                 warn!("Daemon not started as root. This disables several security mitigations and permits logging in as any other user");
             }
 
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()?
-                .block_on(main_process(config))
+            info!(flavor = ?config.runtime.flavor, worker_threads = ?config.runtime.worker_threads, "Starting runtime");
+
+            config.runtime.build()?.block_on(main_process(config))
         }
     }
 }
@@ -121,6 +132,12 @@ struct SerializedConnectionState {
     setgid: Option<u32>,
 }
 
+/// Removes what only the monitor uses from the config passed to the connection process.
+/// The session setup steps are internally tagged, which postcard can't deserialize.
+fn strip_monitor_config(config: &mut Config) {
+    config.session.setup.clear();
+}
+
 async fn main_process(config: Config) -> Result<()> {
     let user = match &config.security.unprivileged_user {
         Some(user) => Some(
@@ -166,24 +183,35 @@ async fn main_process(config: Config) -> Result<()> {
         .await
         .wrap_err_with(|| format!("trying to listen on {addr}"))?;
 
+    let sessions = sessions::UserSessions::default();
+
+    accept_connections(listener, |next_stream, peer_addr| {
+        spawn_connection_child(
+            next_stream,
+            peer_addr,
+            pub_host_keys.clone(),
+            config.clone(),
+            host_keys.clone(),
+            sessions.clone(),
+            setuid,
+            setgid,
+        )
+    })
+    .await
+}
+
+/// Accepts connections forever, handling each of them in its own task on the current runtime.
+async fn accept_connections<F, Fut>(listener: TcpListener, mut handle: F) -> Result<()>
+where
+    F: FnMut(TcpStream, SocketAddr) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
     loop {
         let (next_stream, peer_addr) = listener.accept().await?;
 
-        let config = config.clone();
-        let pub_host_keys = pub_host_keys.clone();
-        let host_keys = host_keys.clone();
+        let connection = handle(next_stream, peer_addr);
         tokio::spawn(async move {
-            let err = spawn_connection_child(
-                next_stream,
-                peer_addr,
-                pub_host_keys,
-                config,
-                host_keys,
-                setuid,
-                setgid,
-            )
-            .await;
-            if let Err(err) = err {
+            if let Err(err) = connection.await {
                 error!(?err, "child failed");
             }
         });
@@ -194,18 +222,34 @@ async fn spawn_connection_child(
     stream: TcpStream,
     peer_addr: SocketAddr,
     pub_host_keys: Vec<PublicKey>,
-    config: Config,
+    mut config: Config,
     host_keys: Vec<PlaintextPrivateKey>,
+    sessions: sessions::UserSessions,
     setuid: Option<u32>,
     setgid: Option<u32>,
 ) -> Result<()> {
+    if !access::address_allowed(&config.access, peer_addr.ip()) {
+        info!(addr = %peer_addr, "Closing connection from denied address");
+        return Ok(());
+    }
+
     let stream_fd = stream.as_raw_fd();
 
-    let mut rpc_server =
-        rpc::Server::new(config.clone(), host_keys).wrap_err("creating RPC server")?;
+    let mut rpc_server = rpc::Server::new(
+        config.clone(),
+        host_keys,
+        sessions,
+        peer_addr,
+        Arc::new(pty::DevPtmx),
+    )
+    .wrap_err("creating RPC server")?;
 
     let rpc_client_fd = rpc_server.client_fd().as_raw_fd();
 
+    // The connection process may not be able to read the file anymore after dropping privileges.
+    config.auth.banner = config.auth.load_banner().await;
+    strip_monitor_config(&mut config);
+
     let state_fd = MemFd::new(&SerializedConnectionState {
         peer_addr,
         pub_host_keys,
@@ -221,8 +265,48 @@ async fn spawn_connection_child(
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit());
 
+    pass_connection_fds(&mut cmd, state_fd.fd.as_raw_fd(), stream_fd, rpc_client_fd);
+
+    let mut listen_child = cmd.spawn().wrap_err("failed to spawn listener process")?;
+
+    let mut exited = false;
+
+    tokio::select! {
+        server_result = rpc_server.process() => {
+            match server_result {
+                // The session timed out, tear down the connection.
+                Ok(()) => listen_child.start_kill()?,
+                Err(err) => error!(?err, "RPC server error"),
+            }
+        }
+        status = listen_child.wait() => {
+            let status = status?;
+            if !status.success() {
+                bail!("connection child process failed: {}", status);
+            }
+            exited = true;
+        }
+    }
+
+    if !exited {
+        let status = listen_child.wait().await?;
+        if !status.success() {
+            bail!("connection child process failed: {}", status);
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the FDs of a connection to where the connection process `cmd` expects them,
+/// closing all others except stdout and stderr.
+fn pass_connection_fds(
+    cmd: &mut tokio::process::Command,
+    state_fd: RawFd,
+    stream_fd: RawFd,
+    rpc_client_fd: RawFd,
+) {
     unsafe {
-        let state_fd = state_fd.fd.as_raw_fd();
         cmd.pre_exec(move || {
             let mut new_state_fd = OwnedFd::from_raw_fd(PRIVSEP_CONNECTION_STATE_FD);
             let mut new_stream_fd = OwnedFd::from_raw_fd(PRIVSEP_CONNECTION_STREAM_FD);
@@ -253,32 +337,6 @@ async fn spawn_connection_child(
             Ok(())
         });
     }
-
-    let mut listen_child = cmd.spawn().wrap_err("failed to spawn listener process")?;
-
-    let mut exited = false;
-
-    tokio::select! {
-        server_err = rpc_server.process() => {
-            error!(err = ?server_err, "RPC server error");
-        }
-        status = listen_child.wait() => {
-            let status = status?;
-            if !status.success() {
-                bail!("connection child process failed: {}", status);
-            }
-            exited = true;
-        }
-    }
-
-    if !exited {
-        let status = listen_child.wait().await?;
-        if !status.success() {
-            bail!("connection child process failed: {}", status);
-        }
-    }
-
-    Ok(())
 }
 
 async fn load_host_keys(keys: &[PathBuf]) -> Result<HostKeySet> {
@@ -308,9 +366,10 @@ async fn load_host_key(key_path: &PathBuf, host_keys: &mut HostKeySet) -> Result
     }
     let key = key.remove(0);
     let algorithm = key.private_key.algorithm_name();
+    let fingerprint = key.private_key.public_key().fingerprint_sha256();
     host_keys.insert(key)?;
 
-    info!(?key_path, ?algorithm, "Loaded host key");
+    info!(?key_path, ?algorithm, %fingerprint, "Loaded host key");
     Ok(())
 }
 
@@ -319,5 +378,104 @@ fn setup_tracing(config: &Config) {
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
 
-    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+    let debug_log = config
+        .security
+        .debug_log_channel
+        .then_some(debug_log::DebugLogLayer);
+
+    tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .finish()
+        .with(debug_log)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::{Config, RuntimeConfig, RuntimeFlavor};
+
+    /// Echoes one byte back on every connection.
+    async fn serve_echo(listener: TcpListener) -> eyre::Result<()> {
+        super::accept_connections(listener, |mut stream, _| async move {
+            let byte = stream.read_u8().await?;
+            stream.write_u8(byte).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn run_with(config: RuntimeConfig) {
+        config.build().unwrap().block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(serve_echo(listener));
+
+            // The first connection stays open while the second one is handled.
+            let mut first = TcpStream::connect(addr).await.unwrap();
+            let mut second = TcpStream::connect(addr).await.unwrap();
+            second.write_u8(2).await.unwrap();
+            assert_eq!(second.read_u8().await.unwrap(), 2);
+            first.write_u8(1).await.unwrap();
+            assert_eq!(first.read_u8().await.unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn current_thread_runtime_handles_connections_concurrently() {
+        run_with(RuntimeConfig {
+            flavor: RuntimeFlavor::CurrentThread,
+            worker_threads: None,
+        });
+    }
+
+    #[test]
+    fn multi_thread_runtime_handles_connections_concurrently() {
+        run_with(RuntimeConfig {
+            flavor: RuntimeFlavor::MultiThread,
+            worker_threads: Some(2),
+        });
+    }
+
+    #[test]
+    fn invalid_worker_threads() {
+        let config = RuntimeConfig {
+            flavor: RuntimeFlavor::CurrentThread,
+            worker_threads: Some(2),
+        };
+        assert!(config.build().is_err());
+        let config = RuntimeConfig {
+            flavor: RuntimeFlavor::MultiThread,
+            worker_threads: Some(0),
+        };
+        assert!(config.build().is_err());
+    }
+
+    #[test]
+    fn connection_state_with_setup() {
+        let config = r#"
+            net = {}
+            auth = { host_keys = [] }
+            security = {}
+            [[session.setup]]
+            type = "namespace"
+            path = "/proc/self/ns/net"
+            "#;
+        let mut config: Config = toml::from_str(config).unwrap();
+        super::strip_monitor_config(&mut config);
+
+        let state = super::SerializedConnectionState {
+            peer_addr: "127.0.0.1:22".parse().unwrap(),
+            pub_host_keys: Vec::new(),
+            config,
+            setuid: None,
+            setgid: None,
+        };
+        let mut memfd = super::MemFd::new(&state).unwrap();
+        memfd.read().unwrap();
+    }
 }
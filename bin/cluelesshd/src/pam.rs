@@ -0,0 +1,466 @@
+//! Minimal PAM bindings for account and session management.
+//!
+//! libpam is loaded at runtime, so PAM is only required when it's enabled in the config.
+//! Password and public key authentication is not done through PAM, only the account and session hooks
+//! that run after the user has been authenticated. Keyboard-interactive authentication uses the
+//! PAM stack, see [`Pam::authenticate`].
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    sync::OnceLock,
+};
+
+use eyre::{bail, eyre, Context, Result};
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(
+        num_msg: c_int,
+        msg: *mut *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut c_void,
+    ) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_TTY: c_int = 3;
+const PAM_CONV: c_int = 5;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+const PAM_ESTABLISH_CRED: c_int = 0x2;
+const PAM_DELETE_CRED: c_int = 0x4;
+const PAM_SILENT: c_int = 0x8000;
+
+type PamStartFn = unsafe extern "C" fn(
+    service_name: *const c_char,
+    user: *const c_char,
+    pam_conversation: *const PamConv,
+    pamh: *mut *mut PamHandle,
+) -> c_int;
+/// `pam_end`, `pam_authenticate`, `pam_acct_mgmt`, `pam_setcred`, `pam_open_session`, and `pam_close_session`.
+type PamIntFn = unsafe extern "C" fn(pamh: *mut PamHandle, flags_or_status: c_int) -> c_int;
+type PamSetItemFn =
+    unsafe extern "C" fn(pamh: *mut PamHandle, item_type: c_int, item: *const c_void) -> c_int;
+type PamGetEnvListFn = unsafe extern "C" fn(pamh: *mut PamHandle) -> *mut *mut c_char;
+type PamStrerrorFn = unsafe extern "C" fn(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+
+/// The functions of libpam that we use.
+struct PamLib {
+    pam_start: PamStartFn,
+    pam_end: PamIntFn,
+    pam_set_item: PamSetItemFn,
+    pam_authenticate: PamIntFn,
+    pam_acct_mgmt: PamIntFn,
+    pam_setcred: PamIntFn,
+    pam_open_session: PamIntFn,
+    pam_close_session: PamIntFn,
+    pam_getenvlist: PamGetEnvListFn,
+    pam_strerror: PamStrerrorFn,
+}
+
+impl PamLib {
+    fn get() -> Result<&'static PamLib> {
+        static LIB: OnceLock<Result<PamLib, String>> = OnceLock::new();
+        LIB.get_or_init(|| unsafe { Self::load() })
+            .as_ref()
+            .map_err(|err| eyre!("failed to load libpam: {err}"))
+    }
+
+    unsafe fn load() -> Result<PamLib, String> {
+        let lib = libc::dlopen(c"libpam.so.0".as_ptr(), libc::RTLD_NOW);
+        if lib.is_null() {
+            return Err(CStr::from_ptr(libc::dlerror())
+                .to_string_lossy()
+                .into_owned());
+        }
+
+        macro_rules! sym {
+            ($name:ident: $ty:ty) => {{
+                let sym = libc::dlsym(
+                    lib,
+                    concat!(stringify!($name), "\0").as_ptr().cast::<c_char>(),
+                );
+                if sym.is_null() {
+                    return Err(format!("missing symbol {}", stringify!($name)));
+                }
+                std::mem::transmute::<*mut c_void, $ty>(sym)
+            }};
+        }
+
+        Ok(PamLib {
+            pam_start: sym!(pam_start: PamStartFn),
+            pam_end: sym!(pam_end: PamIntFn),
+            pam_set_item: sym!(pam_set_item: PamSetItemFn),
+            pam_authenticate: sym!(pam_authenticate: PamIntFn),
+            pam_acct_mgmt: sym!(pam_acct_mgmt: PamIntFn),
+            pam_setcred: sym!(pam_setcred: PamIntFn),
+            pam_open_session: sym!(pam_open_session: PamIntFn),
+            pam_close_session: sym!(pam_close_session: PamIntFn),
+            pam_getenvlist: sym!(pam_getenvlist: PamGetEnvListFn),
+            pam_strerror: sym!(pam_strerror: PamStrerrorFn),
+        })
+    }
+}
+
+fn logging_conv() -> Box<PamConv> {
+    Box::new(PamConv {
+        conv: conversation,
+        appdata_ptr: std::ptr::null_mut(),
+    })
+}
+
+/// The conversation function, called by PAM modules to talk to the user.
+/// We do not support any prompts after authentication, but log informational messages.
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    _appdata_ptr: *mut c_void,
+) -> c_int {
+    let Ok(num_msg) = usize::try_from(num_msg) else {
+        return PAM_CONV_ERR;
+    };
+
+    for i in 0..num_msg {
+        // SAFETY: PAM passes an array of num_msg valid messages.
+        let msg = unsafe { &**msg.add(i) };
+        let text = unsafe { CStr::from_ptr(msg.msg) }.to_string_lossy();
+        match msg.msg_style {
+            PAM_TEXT_INFO => info!(%text, "PAM info message"),
+            PAM_ERROR_MSG => warn!(%text, "PAM error message"),
+            _ => {
+                debug!(%text, "PAM module tried to prompt, which is not supported");
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+
+    // PAM frees the responses, so they must be allocated with malloc.
+    let responses = unsafe { libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) };
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    unsafe { *resp = responses.cast() };
+
+    PAM_SUCCESS
+}
+
+/// A message of a PAM module to the user.
+#[derive(Debug)]
+pub enum Message {
+    /// Asks for a response, which may be shown while it is typed if `echo` is set.
+    Prompt {
+        text: String,
+        echo: bool,
+    },
+    Info(String),
+    Error(String),
+}
+
+/// Shows messages to the user and returns the responses to the prompts among them, in order,
+/// or `None` if there are no responses, for example because the user has gone away.
+/// It is called on the thread that runs PAM, so it may block while waiting for the user.
+pub type Converse = Box<dyn FnMut(Vec<Message>) -> Option<Vec<String>> + Send>;
+
+/// The conversation function during authentication, which lets the [`Converse`] in `appdata_ptr` talk to the user.
+extern "C" fn interactive_conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    let Ok(num_msg) = usize::try_from(num_msg) else {
+        return PAM_CONV_ERR;
+    };
+    // SAFETY: The Converse is owned by the Pam, which outlives the transaction.
+    let converse = unsafe { &mut *appdata_ptr.cast::<Converse>() };
+
+    let mut messages = Vec::with_capacity(num_msg);
+    for i in 0..num_msg {
+        // SAFETY: PAM passes an array of num_msg valid messages.
+        let msg = unsafe { &**msg.add(i) };
+        let text = unsafe { CStr::from_ptr(msg.msg) }
+            .to_string_lossy()
+            .into_owned();
+        messages.push(match msg.msg_style {
+            PAM_PROMPT_ECHO_OFF => Message::Prompt { text, echo: false },
+            PAM_PROMPT_ECHO_ON => Message::Prompt { text, echo: true },
+            PAM_ERROR_MSG => Message::Error(text),
+            PAM_TEXT_INFO => Message::Info(text),
+            _ => return PAM_CONV_ERR,
+        });
+    }
+    let is_prompt = messages
+        .iter()
+        .map(|message| matches!(message, Message::Prompt { .. }))
+        .collect::<Vec<_>>();
+
+    let Some(answers) = converse(messages) else {
+        return PAM_CONV_ERR;
+    };
+    let answers = answers
+        .into_iter()
+        .map(|answer| Zeroizing::new(answer.into_bytes()))
+        .collect::<Vec<_>>();
+    if answers.len() != is_prompt.iter().filter(|&&is_prompt| is_prompt).count()
+        || answers.iter().any(|answer| answer.contains(&0))
+    {
+        return PAM_CONV_ERR;
+    }
+
+    // PAM frees the responses, so they must be allocated with malloc.
+    let responses =
+        unsafe { libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) }.cast::<PamResponse>();
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    let mut answers = answers.iter();
+    for (i, _) in is_prompt
+        .iter()
+        .enumerate()
+        .filter(|(_, &is_prompt)| is_prompt)
+    {
+        let answer = answers.next().unwrap();
+        let copy = unsafe { libc::calloc(answer.len() + 1, 1) }.cast::<u8>();
+        if copy.is_null() {
+            free_responses(responses, num_msg);
+            return PAM_BUF_ERR;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(answer.as_ptr(), copy, answer.len());
+            (*responses.add(i)).resp = copy.cast();
+        }
+    }
+    unsafe { *resp = responses };
+
+    PAM_SUCCESS
+}
+
+fn free_responses(responses: *mut PamResponse, num_msg: usize) {
+    for i in 0..num_msg {
+        unsafe { libc::free((*responses.add(i)).resp.cast()) };
+    }
+    unsafe { libc::free(responses.cast()) };
+}
+
+/// A PAM transaction for a user, who has already been authenticated or is authenticated with it.
+pub struct Pam {
+    lib: &'static PamLib,
+    handle: *mut PamHandle,
+    /// Kept alive for the duration of the transaction, PAM may reference it.
+    conv: Box<PamConv>,
+    /// Referenced by `conv` during authentication.
+    _converse: Option<Box<Converse>>,
+    has_credentials: bool,
+    session_open: bool,
+}
+
+// SAFETY: A PAM handle can be used from any thread as long as it's not used concurrently,
+// which is ensured by requiring &mut for all operations.
+unsafe impl Send for Pam {}
+unsafe impl Sync for Pam {}
+
+impl Pam {
+    /// Starts a PAM transaction and runs account management for the user.
+    /// Returns an error if the account may not log in, for example because it is locked or expired.
+    pub fn account_management(service: &str, user: &str) -> Result<Self> {
+        let mut pam = Self::start(service, user, logging_conv(), None)?;
+        pam.acct_mgmt()?;
+        Ok(pam)
+    }
+
+    /// Starts a PAM transaction and authenticates the user with the PAM stack,
+    /// whose modules talk to the user through `converse`, and then runs account management.
+    pub fn authenticate(service: &str, user: &str, converse: Converse) -> Result<Self> {
+        let mut converse = Box::new(converse);
+        let conv = Box::new(PamConv {
+            conv: interactive_conversation,
+            appdata_ptr: (&mut *converse as *mut Converse).cast(),
+        });
+        let mut pam = Self::start(service, user, conv, Some(converse))?;
+
+        pam.check(
+            unsafe { (pam.lib.pam_authenticate)(pam.handle, 0) },
+            "pam_authenticate",
+        )?;
+        pam.acct_mgmt()?;
+
+        // The user is not asked anything after authentication, like for other methods.
+        let conv = logging_conv();
+        pam.check(
+            unsafe {
+                (pam.lib.pam_set_item)(pam.handle, PAM_CONV, (&*conv as *const PamConv).cast())
+            },
+            "pam_set_item(PAM_CONV)",
+        )?;
+        pam.conv = conv;
+
+        Ok(pam)
+    }
+
+    fn start(
+        service: &str,
+        user: &str,
+        conv: Box<PamConv>,
+        converse: Option<Box<Converse>>,
+    ) -> Result<Self> {
+        let lib = PamLib::get()?;
+
+        let service = CString::new(service).wrap_err("invalid PAM service name")?;
+        let user_c = CString::new(user).wrap_err("invalid user name")?;
+
+        let mut handle = std::ptr::null_mut();
+        let ret =
+            unsafe { (lib.pam_start)(service.as_ptr(), user_c.as_ptr(), &*conv, &mut handle) };
+        if ret != PAM_SUCCESS {
+            bail!("pam_start failed with status {ret}");
+        }
+
+        let mut pam = Self {
+            lib,
+            handle,
+            conv,
+            _converse: converse,
+            has_credentials: false,
+            session_open: false,
+        };
+
+        // Like OpenSSH, some modules need a TTY even before we know whether there is a PTY.
+        pam.check(
+            unsafe { (lib.pam_set_item)(handle, PAM_TTY, c"ssh".as_ptr().cast()) },
+            "pam_set_item(PAM_TTY)",
+        )?;
+
+        Ok(pam)
+    }
+
+    /// Returns an error if the account may not log in, for example because it is locked or expired.
+    fn acct_mgmt(&mut self) -> Result<()> {
+        self.check(
+            unsafe { (self.lib.pam_acct_mgmt)(self.handle, PAM_SILENT) },
+            "pam_acct_mgmt",
+        )
+    }
+
+    /// Establishes the credentials and opens the session before spawning the user's process.
+    pub fn open_session(&mut self, tty: Option<&str>) -> Result<()> {
+        if let Some(tty) = tty {
+            let tty = CString::new(tty).wrap_err("invalid tty name")?;
+            self.check(
+                unsafe { (self.lib.pam_set_item)(self.handle, PAM_TTY, tty.as_ptr().cast()) },
+                "pam_set_item(PAM_TTY)",
+            )?;
+        }
+
+        self.check(
+            unsafe { (self.lib.pam_setcred)(self.handle, PAM_ESTABLISH_CRED) },
+            "pam_setcred",
+        )?;
+        self.has_credentials = true;
+
+        self.check(
+            unsafe { (self.lib.pam_open_session)(self.handle, 0) },
+            "pam_open_session",
+        )?;
+        self.session_open = true;
+
+        Ok(())
+    }
+
+    pub fn is_session_open(&self) -> bool {
+        self.session_open
+    }
+
+    pub fn close_session(&mut self) -> Result<()> {
+        if self.session_open {
+            self.session_open = false;
+            self.check(
+                unsafe { (self.lib.pam_close_session)(self.handle, 0) },
+                "pam_close_session",
+            )?;
+        }
+        if self.has_credentials {
+            self.has_credentials = false;
+            self.check(
+                unsafe { (self.lib.pam_setcred)(self.handle, PAM_DELETE_CRED) },
+                "pam_setcred",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The environment variables set by PAM modules like `pam_env`.
+    pub fn env(&mut self) -> Vec<(String, String)> {
+        let list = unsafe { (self.lib.pam_getenvlist)(self.handle) };
+        if list.is_null() {
+            return Vec::new();
+        }
+
+        let mut env = Vec::new();
+        let mut i = 0;
+        loop {
+            let entry = unsafe { *list.add(i) };
+            if entry.is_null() {
+                break;
+            }
+            let entry_str = unsafe { CStr::from_ptr(entry) }.to_string_lossy();
+            if let Some((name, value)) = entry_str.split_once('=') {
+                env.push((name.to_owned(), value.to_owned()));
+            }
+            unsafe { libc::free(entry.cast()) };
+            i += 1;
+        }
+        unsafe { libc::free(list.cast()) };
+
+        env
+    }
+
+    fn check(&mut self, ret: c_int, function: &str) -> Result<()> {
+        if ret == PAM_SUCCESS {
+            return Ok(());
+        }
+        let err = unsafe { (self.lib.pam_strerror)(self.handle, ret) };
+        let err = if err.is_null() {
+            "unknown error".into()
+        } else {
+            unsafe { CStr::from_ptr(err) }.to_string_lossy()
+        };
+        bail!("{function} failed: {err}")
+    }
+}
+
+impl Drop for Pam {
+    fn drop(&mut self) {
+        if let Err(err) = self.close_session() {
+            warn!(?err, "Failed to close PAM session");
+        }
+        unsafe { (self.lib.pam_end)(self.handle, PAM_SUCCESS) };
+    }
+}
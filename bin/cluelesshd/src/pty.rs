@@ -1,7 +1,9 @@
 //! PTY-related operations for setting up the session.
 
 use std::os::fd::OwnedFd;
+use std::sync::Arc;
 
+use cluelessh_tokio::term_modes::TermModes;
 use eyre::{Context, Result};
 use rustix::{
     fs::{Mode, OFlags},
@@ -15,12 +17,19 @@ pub struct Pty {
     pub user_pty: OwnedFd,
 }
 
-impl Pty {
-    pub async fn new(winsize: Winsize, modes: Vec<u8>) -> Result<Self> {
-        tokio::task::spawn_blocking(move || Self::new_blocking(winsize, modes)).await?
-    }
+/// Creates the PTYs for sessions, so that they can come from somewhere else than `/dev/ptmx`,
+/// for example a fake PTY in tests.
+pub trait PtyAllocator: Send + Sync {
+    /// Allocates a PTY with the window size and terminal modes requested by the client.
+    /// This may block.
+    fn allocate(&self, winsize: Winsize, modes: &TermModes) -> Result<Pty>;
+}
 
-    pub fn new_blocking(winsize: Winsize, modes: Vec<u8>) -> Result<Self> {
+/// The default allocator, opening a new PTY through `/dev/ptmx`.
+pub struct DevPtmx;
+
+impl PtyAllocator for DevPtmx {
+    fn allocate(&self, winsize: Winsize, modes: &TermModes) -> Result<Pty> {
         // Create new PTY:
         let controller = rustix::pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY)
             .wrap_err("opening controller pty")?;
@@ -36,20 +45,28 @@ impl Pty {
 
         // Configure terminal:
         rustix::termios::tcsetwinsize(&user_pty, winsize)?;
-        let termios = rustix::termios::tcgetattr(&user_pty)?;
-        // TODO: set modes
-        // <https://datatracker.ietf.org/doc/html/rfc4254#section-8>
-        let _ = termios;
-        let _ = modes;
+        let mut termios = rustix::termios::tcgetattr(&user_pty)?;
+        modes.apply(&mut termios);
         rustix::termios::tcsetattr(&user_pty, rustix::termios::OptionalActions::Flush, &termios)?;
 
-        Ok(Self {
+        Ok(Pty {
             controller,
             user_pty,
         })
     }
 }
 
+impl Pty {
+    pub async fn new(
+        allocator: Arc<dyn PtyAllocator>,
+        winsize: Winsize,
+        modes: Vec<u8>,
+    ) -> Result<Self> {
+        let modes = TermModes::decode(&modes).wrap_err("invalid terminal modes")?;
+        tokio::task::spawn_blocking(move || allocator.allocate(winsize, &modes)).await?
+    }
+}
+
 pub fn start_session_for_command(user_pty: OwnedFd, term: String, cmd: &mut Command) -> Result<()> {
     let ttyname = rustix::termios::ttyname(&user_pty, Vec::new())?;
     let tty_name = std::str::from_utf8(ttyname.as_bytes())
@@ -76,3 +93,85 @@ pub fn start_session_for_command(user_pty: OwnedFd, term: String, cmd: &mut Comm
 
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use cluelessh_format::numbers;
+    use cluelessh_tokio::term_modes::TermModes;
+    use eyre::Result;
+    use rustix::termios::Winsize;
+
+    use super::{Pty, PtyAllocator};
+
+    /// Records requests and hands out a pipe instead of a real PTY.
+    #[derive(Default)]
+    pub(crate) struct MockAllocator {
+        pub(crate) requests: Mutex<Vec<(Winsize, TermModes)>>,
+    }
+
+    impl PtyAllocator for MockAllocator {
+        fn allocate(&self, winsize: Winsize, modes: &TermModes) -> Result<Pty> {
+            self.requests.lock().unwrap().push((winsize, modes.clone()));
+            let (controller, user_pty) = rustix::pipe::pipe()?;
+            Ok(Pty {
+                controller,
+                user_pty,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn allocator_receives_request() {
+        let allocator = Arc::new(MockAllocator::default());
+        let winsize = Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 640,
+            ws_ypixel: 480,
+        };
+        // VERASE 127, TTY_OP_END
+        let modes = vec![3, 0, 0, 0, 127, 0];
+
+        Pty::new(allocator.clone(), winsize, modes.clone())
+            .await
+            .unwrap();
+
+        let requests = allocator.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (got_winsize, got_modes) = &requests[0];
+        assert_eq!(
+            (
+                got_winsize.ws_row,
+                got_winsize.ws_col,
+                got_winsize.ws_xpixel,
+                got_winsize.ws_ypixel
+            ),
+            (24, 80, 640, 480)
+        );
+        assert_eq!(got_modes, &TermModes(vec![(numbers::VERASE, 127)]));
+    }
+
+    #[tokio::test]
+    async fn terminal_modes_decoded() {
+        let allocator = Arc::new(MockAllocator::default());
+        let winsize = Winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        // ECHO 0, an undefined opcode that stops parsing, then ICANON 1.
+        let modes = vec![53, 0, 0, 0, 0, 200, 51, 0, 0, 0, 1, 0];
+        Pty::new(allocator.clone(), winsize, modes).await.unwrap();
+        // Truncated.
+        let truncated = Pty::new(allocator.clone(), winsize, vec![53, 0, 0]).await;
+        assert!(truncated.is_err());
+
+        let requests = allocator.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1, TermModes(vec![(numbers::ECHO, 0)]));
+    }
+}
@@ -0,0 +1,189 @@
+//! Recording of sessions with a PTY in the [asciicast v2] format of asciinema.
+//!
+//! The file is created by the monitor, so that it is owned by root and the user can't tamper with it,
+//! and its FD is passed to the connection process, which sees the data of the PTY.
+//!
+//! [asciicast v2]: https://docs.asciinema.org/manual/asciicast/v2/
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::{fd::OwnedFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Context, Result};
+
+/// Creates the recording file of a new session of `user` in `dir`, only accessible by the owner.
+pub fn create(dir: &Path, user: &str) -> Result<OwnedFd> {
+    // All monitors run in the daemon, so its PID and a counter make the name unique.
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = dir.join(format!(
+        "{user}-{timestamp}-{}-{counter}.cast",
+        std::process::id()
+    ));
+    let file = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .wrap_err_with(|| format!("failed to create recording {}", path.display()))?;
+    Ok(file.into())
+}
+
+pub struct Recording {
+    file: File,
+    start: Instant,
+    /// Output that ends in an incomplete UTF-8 sequence, kept until the sequence is complete.
+    output: Vec<u8>,
+    /// Like `output`, `None` if the input is not recorded.
+    input: Option<Vec<u8>>,
+}
+
+impl Recording {
+    /// Starts the recording by writing the header for a terminal of the given size.
+    pub fn new(
+        fd: OwnedFd,
+        width_chars: u32,
+        height_rows: u32,
+        term: &str,
+        record_input: bool,
+    ) -> Result<Self> {
+        let mut file = File::from(fd);
+        let header = serde_json::json!({
+            "version": 2,
+            "width": width_chars,
+            "height": height_rows,
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            "env": { "TERM": term },
+        });
+        writeln!(file, "{header}").wrap_err("failed to write recording header")?;
+
+        Ok(Self {
+            file,
+            start: Instant::now(),
+            output: Vec::new(),
+            input: record_input.then(Vec::new),
+        })
+    }
+
+    /// Records data written to the terminal.
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = take_complete_utf8(&mut self.output, data);
+        self.event("o", &data)
+    }
+
+    /// Records data sent by the client, if enabled.
+    pub fn input(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(input) = &mut self.input else {
+            return Ok(());
+        };
+        let data = take_complete_utf8(input, data);
+        self.event("i", &data)
+    }
+
+    /// Records a change of the window size.
+    pub fn resize(&mut self, width_chars: u32, height_rows: u32) -> io::Result<()> {
+        self.event("r", &format!("{width_chars}x{height_rows}"))
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        let event = serde_json::to_string(&(time, code, data))?;
+        writeln!(self.file, "{event}")
+    }
+}
+
+/// Appends `data` to `pending` and takes everything but a trailing incomplete UTF-8 sequence,
+/// as a read can end in the middle of a character. Invalid UTF-8 is replaced.
+fn take_complete_utf8(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+
+    let mut end = pending.len();
+    // An incomplete sequence is at most 3 bytes long and starts with a leading byte.
+    for i in (pending.len().saturating_sub(3)..pending.len()).rev() {
+        let len = match pending[i] {
+            0x80..=0xBF => continue,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        if pending.len() - i < len {
+            end = i;
+        }
+        break;
+    }
+
+    let complete = String::from_utf8_lossy(&pending[..end]).into_owned();
+    pending.drain(..end);
+    complete
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{take_complete_utf8, Recording};
+
+    #[test]
+    fn unique_names() {
+        let dir =
+            std::env::temp_dir().join(format!("cluelesshd-recordings-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+        let first = super::create(&dir, "nora");
+        let second = super::create(&dir, "nora");
+        let count = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn incomplete_utf8() {
+        let mut pending = Vec::new();
+        let snowman = "☃".as_bytes();
+        assert_eq!(take_complete_utf8(&mut pending, b"a"), "a");
+        assert_eq!(take_complete_utf8(&mut pending, &snowman[..2]), "");
+        assert_eq!(take_complete_utf8(&mut pending, &snowman[2..]), "☃");
+        assert_eq!(take_complete_utf8(&mut pending, b"\xFFb"), "\u{FFFD}b");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn asciicast() {
+        let (read, write) = rustix::pipe::pipe().unwrap();
+        let mut recording = Recording::new(write, 80, 24, "xterm", false).unwrap();
+        recording.output(b"$ ").unwrap();
+        recording.input(b"secret\r").unwrap();
+        recording.resize(100, 30).unwrap();
+        drop(recording);
+
+        let mut content = String::new();
+        std::fs::File::from(read)
+            .read_to_string(&mut content)
+            .unwrap();
+        let lines = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["env"]["TERM"], "xterm");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "$ ");
+        assert_eq!(lines[2][1], "r");
+        assert_eq!(lines[2][2], "100x30");
+    }
+}
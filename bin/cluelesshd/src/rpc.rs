@@ -1,18 +1,36 @@
 //! [`postcard`]-based RPC between the different processes.
 
+use std::ffi::CString;
+use std::ffi::OsString;
 use std::fmt::Debug;
 use std::io;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
+use std::net::SocketAddr;
 use std::os::fd::AsFd;
 use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Stdio;
-
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use cluelessh_format::numbers;
+use cluelessh_format::NameList;
+use cluelessh_format::ParseError;
+use cluelessh_format::Reader;
+use cluelessh_keys::authorized_keys::KeyOptions;
 use cluelessh_keys::private::PlaintextPrivateKey;
 use cluelessh_keys::public::PublicKey;
 use cluelessh_keys::signature::Signature;
+use cluelessh_protocol::auth::InfoRequest;
+use cluelessh_protocol::auth::KeyboardInteractiveStep;
+use cluelessh_protocol::auth::Prompt;
 use cluelessh_protocol::auth::VerifySignature;
+use cluelessh_tokio::server::KeyboardInteractiveResult;
 use cluelessh_transport::crypto::AlgorithmName;
 use cluelessh_transport::SessionId;
 use eyre::bail;
@@ -26,6 +44,8 @@ use rustix::net::RecvFlags;
 use rustix::net::SendAncillaryBuffer;
 use rustix::net::SendAncillaryMessage;
 use rustix::net::SendFlags;
+use rustix::process::Resource;
+use rustix::process::Rlimit;
 use rustix::termios::Winsize;
 use secrecy::ExposeSecret;
 use secrecy::Secret;
@@ -35,13 +55,24 @@ use tokio::io::Interest;
 use tokio::net::UnixDatagram;
 use tokio::process::Child;
 use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex;
 use tracing::debug;
+use tracing::info;
 use tracing::trace;
+use tracing::warn;
 use users::os::unix::UserExt;
 use users::User;
+use zeroize::Zeroize;
 use zeroize::Zeroizing;
 
-use crate::config::Config;
+use crate::audit::{AuthMethod, AuthOutcome};
+use crate::auth::{AuthorizedKeysCommandCache, KeyboardInteractive, PamStep, Prompts};
+use crate::config::{Config, RlimitConfig};
+use crate::pam::Pam;
+use crate::pty::PtyAllocator;
+use crate::sessions::{SessionGuard, UserSessions};
+use crate::setup::SessionSetup;
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Request {
@@ -63,15 +94,50 @@ enum Request {
         public_key: PublicKey,
         signature: Signature,
     },
+    /// Verify that the password for the user is okay.
+    /// If it is okay, store the user so we can later spawn a process as them.
+    VerifyPassword {
+        user: String,
+        password: Secret<SerializablePassword>,
+    },
+    /// Start keyboard-interactive authentication of the user with PAM, abandoning the one in progress.
+    /// If PAM authenticates the user, store the user like for the other methods.
+    KeyboardInteractive {
+        user: String,
+    },
+    /// The responses of the user to the prompts of the keyboard-interactive authentication in progress.
+    InfoResponse {
+        responses: Vec<Secret<SerializablePassword>>,
+    },
     /// Request a PTY. We create a new PTY and give the client an FD to the controller.
     PtyReq(PtyRequest),
+    /// Changes the size of the PTY, which signals the command to redraw.
+    WindowChange(WindowSize),
     /// Executes a command on the host.
     /// IMPORTANT: This is the critical operation, and we must ensure that it is secure.
     /// To ensure that even a compromised auth process cannot escalate privileges via this RPC,
     /// the RPC server keeps track of the authenciated user
     Shell(ShellRequest),
     /// Wait for the currently running command to finish.
+    /// This does not block other requests, the result is sent as [`Response::ChildExited`] once the child exits.
     Wait,
+    /// Send a signal to the process group of the currently running command.
+    Signal {
+        signal: u32,
+    },
+    /// Send a BREAK on the PTY.
+    Break,
+    /// There was activity on a channel, which resets the idle timeout.
+    KeepAlive,
+}
+
+/// A message from the RPC server to the client.
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    /// The postcard-encoded [`ResponseResult`] to the current request, whose type depends on the request.
+    Reply(Vec<u8>),
+    /// The currently running command has exited, in response to an earlier [`Request::Wait`].
+    ChildExited(ResponseResult<WaitResponse>),
 }
 
 #[derive(Serialize, Deserialize)]
@@ -116,6 +182,23 @@ impl secrecy::CloneableSecret for SerializableSharedSecret {}
 impl secrecy::SerializableSecret for SerializableSharedSecret {}
 impl secrecy::DebugSecret for SerializableSharedSecret {}
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SerializablePassword(String);
+impl zeroize::Zeroize for SerializablePassword {
+    fn zeroize(&mut self) {
+        self.0.zeroize()
+    }
+}
+impl Debug for SerializablePassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SerializablePassword")
+            .finish_non_exhaustive()
+    }
+}
+impl secrecy::CloneableSecret for SerializablePassword {}
+impl secrecy::SerializableSecret for SerializablePassword {}
+impl secrecy::DebugSecret for SerializablePassword {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyExchangeResponse {
     pub hash: SessionId,
@@ -126,11 +209,27 @@ pub struct KeyExchangeResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct PtyRequest {
+    size: WindowSize,
+    term_modes: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WindowSize {
     height_rows: u32,
     width_chars: u32,
     width_px: u32,
     height_px: u32,
-    term_modes: Vec<u8>,
+}
+
+impl WindowSize {
+    fn winsize(&self) -> Winsize {
+        Winsize {
+            ws_row: self.height_rows as u16,
+            ws_col: self.width_chars as u16,
+            ws_xpixel: self.width_px as u16,
+            ws_ypixel: self.height_px as u16,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -142,47 +241,373 @@ struct ShellRequest {
     env: Vec<(String, String)>,
 }
 
+/// The answer to [`Request::CheckPublicKey`], [`Request::VerifySignature`] and [`Request::VerifyPassword`].
+#[derive(Debug, Serialize, Deserialize)]
+enum VerifyResponse {
+    Accepted,
+    Rejected,
+    /// The credentials are valid, but the user already has as many sessions as allowed.
+    TooManySessions,
+    /// The connection has failed to authenticate `auth.max_auth_tries` times,
+    /// so this attempt was refused or was the last one.
+    TooManyAuthFailures,
+}
+
+impl VerifyResponse {
+    fn outcome(result: &ResponseResult<Self>) -> AuthOutcome {
+        match result {
+            Ok(Self::Accepted) => AuthOutcome::Accepted,
+            Ok(Self::Rejected) => AuthOutcome::Rejected,
+            Ok(Self::TooManySessions | Self::TooManyAuthFailures) => AuthOutcome::Denied,
+            Err(_) => AuthOutcome::Error,
+        }
+    }
+
+    fn into_result(self) -> Result<bool> {
+        match self {
+            Self::Accepted => Ok(true),
+            Self::Rejected => Ok(false),
+            Self::TooManySessions => Err(TooManySessions.into()),
+            Self::TooManyAuthFailures => Err(TooManyAuthFailures.into()),
+        }
+    }
+}
+
+/// Returned by [`Client::verify_signature`] and [`Client::verify_password`] when the user is
+/// already logged in with as many connections as `session.max_sessions_per_user` allows.
+#[derive(Debug)]
+pub struct TooManySessions;
+
+impl std::fmt::Display for TooManySessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("user has too many sessions")
+    }
+}
+
+impl std::error::Error for TooManySessions {}
+
+/// Returned by [`Client::check_public_key`], [`Client::verify_signature`] and [`Client::verify_password`]
+/// once the connection has failed to authenticate `auth.max_auth_tries` times,
+/// after which it must be disconnected.
+#[derive(Debug)]
+pub struct TooManyAuthFailures;
+
+impl std::fmt::Display for TooManyAuthFailures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("too many authentication failures")
+    }
+}
+
+impl std::error::Error for TooManyAuthFailures {}
+
+/// The answer to [`Request::KeyboardInteractive`] and [`Request::InfoResponse`].
 #[derive(Debug, Serialize, Deserialize)]
-struct ShellRequestPty {
-    term: String,
+enum KeyboardInteractiveResponse {
+    /// PAM prompts the user, the responses are sent with [`Request::InfoResponse`].
+    Prompts(Prompts),
+    Finished(VerifyResponse),
 }
 
-type VerifySignatureResponse = bool;
-type CheckPublicKeyResponse = bool;
+type VerifySignatureResponse = VerifyResponse;
+type VerifyPasswordResponse = VerifyResponse;
+type CheckPublicKeyResponse = VerifyResponse;
 type ShellResponse = ();
 type PtyReqResponse = ();
+type WindowChangeResponse = ();
 type WaitResponse = Option<i32>;
+type SignalResponse = ();
+type BreakResponse = ();
+type KeepAliveResponse = ();
 
 type ResponseResult<T> = Result<T, String>;
 
+/// Checks that a key exchange request is consistent with the connection it was made for.
+///
+/// The signature covers the exchange hash, so a compromised connection process could otherwise
+/// get us to sign exchanges for other connections, by sending idents or a host key that don't match the
+/// previous exchanges, or algorithms that were never offered in the KEXINITs that are part of the hash.
+fn check_key_exchange(
+    previous: Option<&ConnectionKex>,
+    req: &KeyExchangeRequest,
+) -> std::result::Result<(), String> {
+    if let Some(previous) = previous {
+        if previous.client_ident != req.client_ident || previous.server_ident != req.server_ident {
+            return Err("identification strings changed during re-exchange".to_owned());
+        }
+        if previous.server_host_key != req.server_host_key {
+            return Err("host key changed during re-exchange".to_owned());
+        }
+    }
+
+    let host_key_algorithm = req.server_host_key.algorithm_name();
+    for (side, kexinit) in [
+        ("client", &req.client_kexinit),
+        ("server", &req.server_kexinit),
+    ] {
+        let (kex_algorithms, host_key_algorithms) = kexinit_algorithms(kexinit)
+            .map_err(|err| format!("invalid {side} KEXINIT: {}", err.0))?;
+        if !kex_algorithms.contains(&req.kex_algorithm) {
+            return Err(format!(
+                "{side} KEXINIT does not offer kex algorithm {}",
+                req.kex_algorithm
+            ));
+        }
+        if !host_key_algorithms.contains(host_key_algorithm) {
+            return Err(format!(
+                "{side} KEXINIT does not offer host key algorithm {host_key_algorithm}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the command or subsystem of the request with the forced command, if any,
+/// returning the command that the client requested.
+///
+/// The client must not be able to pass its own `SSH_ORIGINAL_COMMAND` to the forced command,
+/// which may trust it, so it is removed from the environment of the request.
+fn force_command(req: &mut ShellRequest, forced_command: Option<&str>) -> Option<String> {
+    let forced_command = forced_command?;
+    debug!(?req.command, ?req.subsystem, "Running forced command instead of requested command");
+    req.subsystem = None;
+    req.env.retain(|(name, _)| name != "SSH_ORIGINAL_COMMAND");
+    req.command.replace(forced_command.to_owned())
+}
+
+/// Makes the command run as `user` with all of their groups, in the chroot at `chroot_dir` if set,
+/// and in their home directory otherwise.
+/// The directory is changed after the steps of `session.setup`, as entering a mount namespace
+/// resets it, so the home directory is the one in that namespace.
+///
+/// The privileges are dropped right before the command is executed, in the only order that works:
+/// chroot while still being root, then the supplementary groups, the primary group and finally the user.
+/// If any step fails, the spawn fails. [`Command::uid`] can't be used, as it clears
+/// the supplementary groups and takes effect before `pre_exec`, after which chroot is impossible.
+/// The groups are looked up here, as `initgroups` is not safe to call after forking.
+fn become_user(cmd: &mut Command, user: &User, chroot_dir: Option<PathBuf>) -> Result<()> {
+    let uid = user.uid();
+    let gid = user.primary_group_id();
+    let groups = supplementary_groups(user)?;
+    let home = CString::new(user.home_dir().as_os_str().as_bytes())
+        .wrap_err("home directory contains a NUL byte")?;
+
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(dir) = &chroot_dir {
+                rustix::process::chroot(dir)?;
+                rustix::process::chdir("/")?;
+            } else {
+                rustix::process::chdir(home.as_c_str())?;
+            }
+            if let Some(groups) = &groups {
+                if libc::setgroups(groups.len(), groups.as_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// The groups of `user` to set with `setgroups`.
+/// Without root, only our own IDs can be set, and the groups are left alone, like Command::uid does.
+pub fn supplementary_groups(user: &User) -> Result<Option<Vec<u32>>> {
+    if !rustix::process::getuid().is_root() {
+        return Ok(None);
+    }
+    let groups = users::get_user_groups(user.name(), user.primary_group_id())
+        .ok_or_else(|| eyre!("failed to look up the groups of {:?}", user.name()))?;
+    Ok(Some(groups.iter().map(|group| group.gid()).collect()))
+}
+
+/// Connects stdio of the command to pipes, for sessions without a PTY.
+fn pipe_stdio(cmd: &mut Command) {
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    unsafe {
+        cmd.pre_exec(|| {
+            // Even without a PTY, the command gets its own session and process group,
+            // so that signals reach it and everything it spawned, but not us.
+            rustix::process::setsid()?;
+            Ok(())
+        });
+    }
+}
+
+/// Takes the pipes set up by [`pipe_stdio`], to be sent to the connection process.
+fn take_stdio(child: &mut Child) -> Result<Vec<OwnedFd>> {
+    let stdin = child.stdin.take().unwrap().into_owned_fd()?;
+    let stdout = child.stdout.take().unwrap().into_owned_fd()?;
+    let stderr = child.stderr.take().unwrap().into_owned_fd()?;
+    Ok(vec![stdin, stdout, stderr])
+}
+
+/// Sets the configured resource limits right before the command is executed,
+/// so that the spawn fails if one of them cannot be set.
+fn limit_resources(cmd: &mut Command, limits: RlimitConfig) {
+    let limits = [
+        (Resource::Nofile, limits.nofile),
+        (Resource::Nproc, limits.nproc),
+        (Resource::As, limits.address_space),
+        (Resource::Cpu, limits.cpu_secs),
+    ];
+    if limits.iter().all(|(_, limit)| limit.is_none()) {
+        return;
+    }
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, limit) in limits {
+                if let Some(limit) = limit {
+                    let limit = Rlimit {
+                        current: Some(limit),
+                        maximum: Some(limit),
+                    };
+                    rustix::process::setrlimit(resource, limit)?;
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Checks that an authentication request for `user` may be handled.
+///
+/// The connection is bound to the first user that authenticates: everything afterwards runs as them,
+/// so a compromised connection process must not be able to authenticate as, or probe the keys of, anyone else.
+fn check_user_binding(
+    authenticated_user: Option<&User>,
+    user: &str,
+) -> std::result::Result<(), String> {
+    let Some(authenticated_user) = authenticated_user else {
+        return Ok(());
+    };
+    if authenticated_user.name() != user {
+        return Err("already authenticated as a different user".to_owned());
+    }
+    Err("user already authenticated".to_owned())
+}
+
+/// Checks that a signature is verified for the session id of the connection it was made for.
+///
+/// Otherwise, a compromised connection process could replay a signature that a client made
+/// for another connection to authenticate as that client's user.
+fn check_session_id(
+    connection_kex: Option<&ConnectionKex>,
+    session_id: &SessionId,
+) -> std::result::Result<(), String> {
+    let Some(connection_kex) = connection_kex else {
+        return Err("no key exchange has happened yet".to_owned());
+    };
+    if connection_kex.session_id.0 != session_id.0 {
+        return Err("session id does not match the connection".to_owned());
+    }
+    Ok(())
+}
+
+/// Parses the kex and host key algorithm name-lists out of a SSH_MSG_KEXINIT payload.
+fn kexinit_algorithms(kexinit: &[u8]) -> cluelessh_format::Result<(NameList<'_>, NameList<'_>)> {
+    let mut p = Reader::new(kexinit);
+    let kind = p.u8()?;
+    if kind != numbers::SSH_MSG_KEXINIT {
+        return Err(ParseError(format!("unexpected packet type {kind}")));
+    }
+    let _cookie = p.array::<16>()?;
+    let kex_algorithms = p.name_list()?;
+    let host_key_algorithms = p.name_list()?;
+    Ok((kex_algorithms, host_key_algorithms))
+}
+
+/// How long the connection process gets to disconnect an idle client before the monitor terminates the session.
+const IDLE_DISCONNECT_GRACE: Duration = Duration::from_secs(10);
+
 pub struct Client {
-    socket: UnixDatagram,
+    socket: Arc<UnixDatagram>,
+    /// Replies to requests, in order.
+    /// Locking this ensures that only one request is in flight at a time, so that we receive the matching reply.
+    replies: Mutex<mpsc::Receiver<(Zeroizing<Vec<u8>>, Vec<OwnedFd>)>>,
+    child_exits: Mutex<mpsc::Receiver<ResponseResult<WaitResponse>>>,
+    /// When the last [`Request::KeepAlive`] was sent, to avoid sending one for every packet.
+    last_keep_alive: std::sync::Mutex<Option<Instant>>,
+}
+
+/// The parts of the first key exchange of a connection that must stay the same for all re-exchanges.
+struct ConnectionKex {
+    client_ident: Vec<u8>,
+    server_ident: Vec<u8>,
+    server_host_key: PublicKey,
+    /// The exchange hash of the first key exchange, which signatures during authentication must cover.
+    session_id: SessionId,
 }
 
 pub struct Server {
     server: UnixDatagram,
     client: UnixDatagram,
     host_keys: Vec<PlaintextPrivateKey>,
+    /// Set after the first successful key exchange of the connection this server belongs to.
+    connection_kex: Option<ConnectionKex>,
     authenticated_user: Option<users::User>,
+    /// The options of the key the `authenticated_user` logged in with, like a forced command.
+    key_options: KeyOptions,
+    /// Rejected public keys, passwords and signatures, limited by `auth.max_auth_tries`.
+    /// Counted here, as a compromised connection process could not be trusted to disconnect.
+    failed_auth_attempts: u32,
+    peer_addr: SocketAddr,
 
     config: Config,
 
     pty_user: Option<OwnedFd>,
     shell_process: Option<Child>,
+    /// Whether the client is waiting for the `shell_process` to exit.
+    waiting_for_child: bool,
+    /// The PAM transaction of the `authenticated_user`, if PAM is enabled.
+    pam: Option<Pam>,
+    keyboard_interactive: Option<KeyboardInteractive>,
+    authorized_keys_cache: AuthorizedKeysCommandCache,
+    /// The logins of all connections, to limit how many each user may have.
+    sessions: UserSessions,
+    /// The session of the `authenticated_user`, counted in `sessions` until the connection ends.
+    session: Option<SessionGuard>,
+    pty_allocator: Arc<dyn PtyAllocator>,
+    session_setup: Vec<Arc<dyn SessionSetup>>,
 }
 
 impl Server {
-    pub fn new(config: Config, host_keys: Vec<PlaintextPrivateKey>) -> Result<Self> {
+    pub fn new(
+        config: Config,
+        host_keys: Vec<PlaintextPrivateKey>,
+        sessions: UserSessions,
+        peer_addr: SocketAddr,
+        pty_allocator: Arc<dyn PtyAllocator>,
+    ) -> Result<Self> {
         let (server, client) = UnixDatagram::pair().wrap_err("creating socketpair")?;
 
         Ok(Self {
             server,
             client,
-            config,
             host_keys,
+            connection_kex: None,
             authenticated_user: None,
+            key_options: KeyOptions::default(),
+            failed_auth_attempts: 0,
+            peer_addr,
             pty_user: None,
             shell_process: None,
+            waiting_for_child: false,
+            pam: None,
+            keyboard_interactive: None,
+            authorized_keys_cache: AuthorizedKeysCommandCache::default(),
+            sessions,
+            session: None,
+            pty_allocator,
+            session_setup: crate::setup::steps(&config.session.setup),
+            config,
         })
     }
 
@@ -190,21 +615,79 @@ impl Server {
         self.client.as_fd()
     }
 
+    /// Processes requests until the connection process goes away (which is an error),
+    /// or until the idle timeout expires, after which the command is terminated
+    /// and the connection process should be killed.
     pub async fn process(&mut self) -> Result<()> {
+        // The connection process disconnects idle clients itself, telling them why.
+        // This is the backstop in case it doesn't, so it waits a bit longer, as keep alives
+        // are only sent every quarter of the idle timeout.
+        let idle_timeout = Some(Duration::from_secs(self.config.session.idle_timeout_secs))
+            .filter(|timeout| !timeout.is_zero())
+            .map(|timeout| timeout + timeout / 4 + IDLE_DISCONNECT_GRACE);
+
         loop {
-            let (recv, fds) = receive_with_fds::<Request>(&self.server)
-                .await
-                .wrap_err("parsing request from client")?;
-            ensure!(fds.is_empty(), "Client sent FDs in request");
-            self.receive_message(recv).await?;
+            let child_exit = async {
+                match &mut self.shell_process {
+                    Some(child) if self.waiting_for_child => child.wait().await,
+                    _ => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                recv = receive_request(&self.server, idle_timeout) => {
+                    let Some(recv) = recv else {
+                        info!(timeout = ?idle_timeout, "Session idle timeout expired, terminating");
+                        if self.shell_process.is_some() {
+                            if let Err(err) = self.signal(libc::SIGHUP as u32) {
+                                debug!(?err, "Failed to send SIGHUP to child");
+                            }
+                        }
+                        return Ok(());
+                    };
+                    self.receive_message(recv?).await?;
+                }
+                result = child_exit => {
+                    let result = result
+                        .map(|status| status.code())
+                        .map_err(|err| err.to_string());
+                    debug!(?result, "Child process exited");
+
+                    self.send_response(&Response::ChildExited(result), &[]).await?;
+
+                    // implicitly drop stdio
+                    self.shell_process = None;
+                    self.waiting_for_child = false;
+
+                    if let Err(err) = self.with_pam(|pam| pam.close_session()).await {
+                        warn!(?err, "Failed to close PAM session");
+                    }
+                }
+            }
         }
     }
 
     async fn receive_message(&mut self, req: Request) -> Result<()> {
         trace!(?req, "Received RPC message");
 
+        // The client has moved on to another authentication method, which ends the PAM conversation.
+        if matches!(
+            req,
+            Request::CheckPublicKey { .. }
+                | Request::VerifySignature { .. }
+                | Request::VerifyPassword { .. }
+        ) {
+            self.keyboard_interactive = None;
+        }
+
         match req {
             Request::KeyExchange(req) => {
+                if let Err(err) = check_key_exchange(self.connection_kex.as_ref(), &req) {
+                    warn!(%err, "Rejecting key exchange that does not belong to this connection");
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+
                 let Some(private) = self
                     .host_keys
                     .iter()
@@ -221,6 +704,10 @@ impl Server {
                     return Ok(());
                 };
 
+                let client_ident = req.client_ident.clone();
+                let server_ident = req.server_ident.clone();
+                let server_host_key = req.server_host_key.clone();
+
                 let req = cluelessh_transport::server::KeyExchangeParameters {
                     client_ident: req.client_ident,
                     server_ident: req.server_ident,
@@ -243,6 +730,13 @@ impl Server {
                     return Ok(());
                 };
 
+                self.connection_kex.get_or_insert(ConnectionKex {
+                    client_ident,
+                    server_ident,
+                    server_host_key,
+                    session_id: resp.hash,
+                });
+
                 let resp = KeyExchangeResponse {
                     hash: resp.hash,
                     server_ephemeral_public_key: resp.server_ephemeral_public_key,
@@ -258,11 +752,51 @@ impl Server {
                 user,
                 pubkey: public_key,
             } => {
-                let is_ok = crate::auth::check_pubkey(user, public_key)
-                    .await
-                    .map_err(|err| err.to_string());
-
-                self.respond::<CheckPublicKeyResponse>(is_ok).await?;
+                let method = AuthMethod::PublicKeyQuery;
+                if let Err(err) = check_user_binding(self.authenticated_user.as_ref(), &user) {
+                    warn!(%err, ?user, "Rejecting public key check after authentication");
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+                // Like OpenSSH, keys that are not accepted count as failed attempts,
+                // so that a client can't try any number of keys.
+                if self.auth_tries_exhausted() {
+                    warn!(
+                        ?user,
+                        "Refusing public key check after too many authentication failures"
+                    );
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Denied);
+                    self.respond::<CheckPublicKeyResponse>(Ok(VerifyResponse::TooManyAuthFailures))
+                        .await?;
+                    return Ok(());
+                }
+                if !self.user_allowed(&user) {
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Denied);
+                    let response = self.count_auth_failure(VerifyResponse::Rejected);
+                    self.respond::<CheckPublicKeyResponse>(Ok(response)).await?;
+                    return Ok(());
+                }
+                let is_ok = crate::auth::check_pubkey(
+                    user.clone(),
+                    public_key.clone(),
+                    self.peer_addr.ip(),
+                    &self.config.auth,
+                    &mut self.authorized_keys_cache,
+                )
+                .await
+                .map_err(|err| err.to_string());
+
+                let outcome = AuthOutcome::from_result(&is_ok);
+                self.audit(&user, method, Some(&public_key), outcome);
+                let result = is_ok.map(|is_ok| {
+                    let response = if is_ok {
+                        VerifyResponse::Accepted
+                    } else {
+                        VerifyResponse::Rejected
+                    };
+                    self.count_auth_failure(response)
+                });
+                self.respond::<CheckPublicKeyResponse>(result).await?;
             }
             Request::VerifySignature {
                 user,
@@ -270,42 +804,196 @@ impl Server {
                 public_key,
                 signature,
             } => {
-                if self.authenticated_user.is_some() {
-                    self.respond_err("user already authenticated".to_owned())
+                let method = AuthMethod::PublicKey;
+                if let Err(err) = check_user_binding(self.authenticated_user.as_ref(), &user) {
+                    warn!(%err, ?user, "Rejecting signature after authentication");
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Error);
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+                if let Err(err) = check_session_id(self.connection_kex.as_ref(), &session_id) {
+                    warn!(%err, "Rejecting signature that does not belong to this connection");
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Error);
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+                if self.auth_tries_exhausted() {
+                    warn!(
+                        ?user,
+                        "Refusing signature after too many authentication failures"
+                    );
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Denied);
+                    self.respond::<VerifySignatureResponse>(Ok(
+                        VerifyResponse::TooManyAuthFailures,
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                if !self.user_allowed(&user) {
+                    self.audit(&user, method, Some(&public_key), AuthOutcome::Denied);
+                    let response = self.count_auth_failure(VerifyResponse::Rejected);
+                    self.respond::<VerifySignatureResponse>(Ok(response))
                         .await?;
+                    return Ok(());
+                }
+                let audit_user = user.clone();
+                let audit_key = public_key.clone();
+                let user = crate::auth::verify_signature(
+                    VerifySignature {
+                        user,
+                        session_id,
+                        public_key,
+                        signature,
+                    },
+                    self.peer_addr.ip(),
+                    &self.config.auth,
+                    &mut self.authorized_keys_cache,
+                )
+                .await;
+                let result = self
+                    .finish_authentication(user, None)
+                    .await
+                    .map_err(|err| err.to_string());
+
+                let outcome = VerifyResponse::outcome(&result);
+                self.audit(&audit_user, method, Some(&audit_key), outcome);
+                let result = result.map(|response| self.count_auth_failure(response));
+                self.respond::<VerifySignatureResponse>(result).await?;
+            }
+            Request::VerifyPassword { user, password } => {
+                let method = AuthMethod::Password;
+                if let Err(err) = check_user_binding(self.authenticated_user.as_ref(), &user) {
+                    warn!(%err, ?user, "Rejecting password after authentication");
+                    self.audit(&user, method, None, AuthOutcome::Error);
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+                if self.auth_tries_exhausted() {
+                    warn!(
+                        ?user,
+                        "Refusing password after too many authentication failures"
+                    );
+                    self.audit(&user, method, None, AuthOutcome::Denied);
+                    self.respond::<VerifyPasswordResponse>(Ok(VerifyResponse::TooManyAuthFailures))
+                        .await?;
+                    return Ok(());
+                }
+                if !self.user_allowed(&user) {
+                    self.audit(&user, method, None, AuthOutcome::Denied);
+                    let response = self.count_auth_failure(VerifyResponse::Rejected);
+                    self.respond::<VerifyPasswordResponse>(Ok(response)).await?;
+                    return Ok(());
+                }
+                let audit_user = user.clone();
+                let password = Zeroizing::new(password.expose_secret().0.clone());
+                let user = crate::auth::verify_password(user, password)
+                    .await
+                    .map(|user| user.map(|user| (user, KeyOptions::default())));
+                let result = self
+                    .finish_authentication(user, None)
+                    .await
+                    .map_err(|err| err.to_string());
+
+                let outcome = VerifyResponse::outcome(&result);
+                self.audit(&audit_user, method, None, outcome);
+                let result = result.map(|response| self.count_auth_failure(response));
+                self.respond::<VerifyPasswordResponse>(result).await?;
+            }
+            Request::KeyboardInteractive { user } => {
+                let method = AuthMethod::KeyboardInteractive;
+                self.keyboard_interactive = None;
+                if !self.config.auth.keyboard_interactive {
+                    self.respond_err("keyboard-interactive authentication is disabled".to_owned())
+                        .await?;
+                    return Ok(());
+                }
+                if let Err(err) = check_user_binding(self.authenticated_user.as_ref(), &user) {
+                    warn!(%err, ?user, "Rejecting keyboard-interactive after authentication");
+                    self.audit(&user, method, None, AuthOutcome::Error);
+                    self.respond_err(err).await?;
+                    return Ok(());
+                }
+                if self.auth_tries_exhausted() {
+                    warn!(
+                        ?user,
+                        "Refusing keyboard-interactive after too many authentication failures"
+                    );
+                    self.audit(&user, method, None, AuthOutcome::Denied);
+                    self.respond::<KeyboardInteractiveResponse>(Ok(
+                        KeyboardInteractiveResponse::Finished(VerifyResponse::TooManyAuthFailures),
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                if !self.user_allowed(&user) {
+                    self.audit(&user, method, None, AuthOutcome::Denied);
+                    let response = self.count_auth_failure(VerifyResponse::Rejected);
+                    self.respond::<KeyboardInteractiveResponse>(Ok(
+                        KeyboardInteractiveResponse::Finished(response),
+                    ))
+                    .await?;
+                    return Ok(());
                 }
-                let is_ok = crate::auth::verify_signature(VerifySignature {
-                    user,
-                    session_id,
-                    public_key,
-                    signature,
-                })
-                .await
-                .map_err(|err| err.to_string())
-                .map(|user| match user {
-                    Some(user) => {
-                        self.authenticated_user = Some(user);
-                        true
-                    }
-                    None => false,
-                });
 
-                self.respond::<VerifySignatureResponse>(is_ok).await?;
+                match KeyboardInteractive::start(self.config.auth.pam_service.clone(), user.clone())
+                {
+                    Ok(keyboard_interactive) => {
+                        self.keyboard_interactive = Some(keyboard_interactive)
+                    }
+                    Err(err) => {
+                        warn!(?err, ?user, "Refusing keyboard-interactive authentication");
+                        self.audit(&user, method, None, AuthOutcome::Error);
+                        self.respond::<KeyboardInteractiveResponse>(Ok(
+                            KeyboardInteractiveResponse::Finished(VerifyResponse::Rejected),
+                        ))
+                        .await?;
+                        return Ok(());
+                    }
+                }
+                let result = self.keyboard_interactive_step().await;
+                self.respond::<KeyboardInteractiveResponse>(result).await?;
+            }
+            Request::InfoResponse { responses } => {
+                let Some(keyboard_interactive) = &self.keyboard_interactive else {
+                    self.respond_err(
+                        "no keyboard-interactive authentication in progress".to_owned(),
+                    )
+                    .await?;
+                    return Ok(());
+                };
+                let responses = responses
+                    .iter()
+                    .map(|response| response.expose_secret().0.clone())
+                    .collect();
+                if let Err(err) = keyboard_interactive.respond(responses) {
+                    self.keyboard_interactive = None;
+                    self.respond_err(err.to_string()).await?;
+                    return Ok(());
+                }
+                let result = self.keyboard_interactive_step().await;
+                self.respond::<KeyboardInteractiveResponse>(result).await?;
             }
             Request::PtyReq(req) => {
+                if self.authenticated_user.is_none() {
+                    self.respond_err("unauthenticated".to_owned()).await?;
+
+                    return Ok(());
+                }
                 if self.pty_user.is_some() {
                     self.respond_err("already requests pty".to_owned()).await?;
 
                     return Ok(());
                 }
+                if self.key_options.no_pty {
+                    self.respond_err("PTY allocation disabled by key options".to_owned())
+                        .await?;
+
+                    return Ok(());
+                }
 
                 let result = crate::pty::Pty::new(
-                    Winsize {
-                        ws_row: req.width_chars as u16,
-                        ws_col: req.height_rows as u16,
-                        ws_xpixel: req.width_px as u16,
-                        ws_ypixel: req.height_px as u16,
-                    },
+                    self.pty_allocator.clone(),
+                    req.size.winsize(),
                     req.term_modes,
                 )
                 .await;
@@ -323,6 +1011,15 @@ impl Server {
 
                 self.pty_user = user.ok();
             }
+            Request::WindowChange(size) => {
+                let result = match &self.pty_user {
+                    Some(pty) => rustix::termios::tcsetwinsize(pty, size.winsize())
+                        .map_err(|err| format!("failed to change window size: {err}")),
+                    None => Err("no pty requested".to_owned()),
+                };
+
+                self.respond::<WindowChangeResponse>(result).await?;
+            }
             Request::Shell(req) => {
                 if self.shell_process.is_some() {
                     self.respond_err("process already running".to_owned())
@@ -349,102 +1046,374 @@ impl Server {
                 )
                 .await?;
             }
-            Request::Wait => match &mut self.shell_process {
-                None => {
-                    self.respond_err("no child running".to_owned()).await?;
+            Request::Wait => {
+                if self.shell_process.is_none() {
+                    self.send_response(
+                        &Response::ChildExited(Err("no child running".to_owned())),
+                        &[],
+                    )
+                    .await?;
+                } else {
+                    // The response is sent in Server::process once the child exits.
+                    self.waiting_for_child = true;
                 }
-                Some(child) => {
-                    let result = child.wait().await;
-
-                    let result = result
-                        .map(|status| status.code())
-                        .map_err(|err| err.to_string());
-                    debug!(?result, "Child process exited");
+            }
+            Request::Signal { signal } => {
+                let result = self.signal(signal).map_err(|err| err.to_string());
 
-                    self.respond::<WaitResponse>(result).await?;
+                self.respond::<SignalResponse>(result).await?;
+            }
+            Request::Break => {
+                let result = match &self.pty_user {
+                    Some(pty) => rustix::termios::tcsendbreak(pty)
+                        .map_err(|err| format!("failed to send break: {err}")),
+                    None => Err("no pty requested".to_owned()),
+                };
 
-                    // implicitly drop stdio
-                    self.shell_process = None;
-                }
-            },
+                self.respond::<BreakResponse>(result).await?;
+            }
+            Request::KeepAlive => {
+                // Receiving any request already resets the idle timeout.
+                self.respond::<KeepAliveResponse>(Ok(())).await?;
+            }
         }
         Ok(())
     }
 
-    async fn shell(&mut self, user: &User, req: ShellRequest) -> Result<Vec<OwnedFd>> {
-        let subsystem = match req.subsystem.as_deref() {
-            Some(subsystem) => match self.config.subsystem.get(subsystem) {
-                Some(system) => Some(system.path.clone()),
-                None => bail!("unsupported subsystem: {subsystem}"),
-            },
-            None => None,
-        };
+    fn audit(
+        &self,
+        user: &str,
+        method: AuthMethod,
+        public_key: Option<&PublicKey>,
+        outcome: AuthOutcome,
+    ) {
+        crate::audit::auth_attempt(self.peer_addr, user, method, public_key, outcome);
+    }
 
-        let shell = user.shell();
+    /// Whether the access config lets the user log in from the address of the connection.
+    fn user_allowed(&self, user: &str) -> bool {
+        crate::access::user_allowed(&self.config.access, user, self.peer_addr.ip())
+    }
 
-        let cmd_arg0 = subsystem.as_deref().unwrap_or(shell);
+    /// The command that runs instead of what the client requests, like OpenSSH's `ForceCommand`.
+    /// The one from the config takes precedence over the one of the key, like in OpenSSH.
+    fn forced_command(&self) -> Option<&str> {
+        self.config
+            .session
+            .force_command
+            .as_deref()
+            .or(self.key_options.command.as_deref())
+    }
 
-        // TODO: the SSH RFC mentions subsystems going through shell... should we?
-        let mut cmd = Command::new(cmd_arg0);
+    fn auth_tries_exhausted(&self) -> bool {
+        self.failed_auth_attempts >= self.config.auth.max_auth_tries
+    }
 
-        if subsystem.is_none() {
-            if let Some(shell_command) = req.command {
-                cmd.arg("-c");
-                cmd.arg(shell_command);
+    /// Counts a rejected verification, turning it into [`VerifyResponse::TooManyAuthFailures`]
+    /// once `auth.max_auth_tries` has been reached, so that the connection process disconnects.
+    fn count_auth_failure(&mut self, response: VerifyResponse) -> VerifyResponse {
+        if !matches!(response, VerifyResponse::Rejected) {
+            return response;
+        }
+        self.failed_auth_attempts += 1;
+        if self.auth_tries_exhausted() {
+            info!(
+                attempts = self.failed_auth_attempts,
+                "Too many authentication failures"
+            );
+            return VerifyResponse::TooManyAuthFailures;
+        }
+        response
+    }
+
+    /// Waits for the next prompts of the keyboard-interactive authentication in progress,
+    /// or finishes the authentication once PAM is done.
+    async fn keyboard_interactive_step(&mut self) -> ResponseResult<KeyboardInteractiveResponse> {
+        let Some(keyboard_interactive) = &mut self.keyboard_interactive else {
+            return Err("no keyboard-interactive authentication in progress".to_owned());
+        };
+        let (authenticated, pam) = match keyboard_interactive.next_step().await {
+            Ok(PamStep::Prompts(prompts)) => {
+                return Ok(KeyboardInteractiveResponse::Prompts(prompts));
             }
+            Ok(PamStep::Finished(Some((user, pam)))) => {
+                (Ok(Some((user, KeyOptions::default()))), Some(pam))
+            }
+            Ok(PamStep::Finished(None)) => (Ok(None), None),
+            Err(err) => (Err(err), None),
         };
+        let user = keyboard_interactive.user().to_owned();
+        self.keyboard_interactive = None;
 
-        cmd.env_clear();
+        let result = self
+            .finish_authentication(authenticated, pam)
+            .await
+            .map_err(|err| err.to_string());
 
-        let has_pty = req.pty_term.is_some();
+        let outcome = VerifyResponse::outcome(&result);
+        self.audit(&user, AuthMethod::KeyboardInteractive, None, outcome);
+        result.map(|response| {
+            KeyboardInteractiveResponse::Finished(self.count_auth_failure(response))
+        })
+    }
 
-        ensure!(
-            has_pty == self.pty_user.is_some(),
-            "Mismatch between client and server PTY requests"
-        );
+    /// Stores the authenticated user and the options of their key,
+    /// if they don't have too many sessions and PAM account management has accepted the account.
+    /// `pam` is the transaction the user was authenticated with, which has already done account management.
+    async fn finish_authentication(
+        &mut self,
+        user: Result<Option<(User, KeyOptions)>>,
+        pam: Option<Pam>,
+    ) -> Result<VerifyResponse> {
+        let Some((user, key_options)) = user? else {
+            return Ok(VerifyResponse::Rejected);
+        };
 
-        if let Some(term) = req.pty_term {
-            let Some(pty_fd) = &self.pty_user else {
-                bail!("no pty requested before");
-            };
-            let pty_fd = pty_fd.try_clone()?;
+        let limit = self.config.session.max_sessions_per_user;
+        let Some(session) = self.sessions.try_start(user.uid(), limit) else {
+            info!(user = ?user.name(), ?limit, "Refusing login of user with too many sessions");
+            return Ok(VerifyResponse::TooManySessions);
+        };
+
+        if let Some(pam) = pam {
+            self.pam = Some(pam);
+        } else if self.config.auth.use_pam {
+            let service = self.config.auth.pam_service.clone();
+            let name = user
+                .name()
+                .to_str()
+                .ok_or_else(|| eyre!("user name is invalid UTF-8"))?
+                .to_owned();
+
+            let pam = tokio::task::spawn_blocking(move || Pam::account_management(&service, &name))
+                .await?;
+            match pam {
+                Ok(pam) => self.pam = Some(pam),
+                Err(err) => {
+                    info!(?err, user = ?user.name(), "PAM account management rejected user");
+                    return Ok(VerifyResponse::Rejected);
+                }
+            }
+        }
+
+        self.authenticated_user = Some(user);
+        self.key_options = key_options;
+        self.session = Some(session);
+        Ok(VerifyResponse::Accepted)
+    }
+
+    /// Runs a blocking PAM operation, if PAM is enabled.
+    async fn with_pam<R: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut Pam) -> Result<R> + Send + 'static,
+    ) -> Result<Option<R>> {
+        let Some(mut pam) = self.pam.take() else {
+            return Ok(None);
+        };
+        let (pam, result) = tokio::task::spawn_blocking(move || {
+            let result = f(&mut pam);
+            (pam, result)
+        })
+        .await?;
+        self.pam = Some(pam);
+        result.map(Some)
+    }
+
+    fn signal(&self, signal: u32) -> Result<()> {
+        let Some(child) = &self.shell_process else {
+            bail!("no child running");
+        };
+        let Some(pid) = child.id() else {
+            bail!("child has already exited");
+        };
+
+        let signal = i32::try_from(signal)
+            .ok()
+            .and_then(rustix::process::Signal::from_raw)
+            .ok_or_else(|| eyre!("invalid signal: {signal}"))?;
+        let pid = i32::try_from(pid)
+            .ok()
+            .and_then(rustix::process::Pid::from_raw)
+            .ok_or_else(|| eyre!("invalid child pid: {pid}"))?;
+
+        // The child is always a session leader (see Server::shell), so its PID is its process group ID.
+        debug!(?signal, pgid = ?pid, "Sending signal to child process group");
+        rustix::process::kill_process_group(pid, signal)
+            .wrap_err("failed to send signal to child")?;
+
+        Ok(())
+    }
+
+    async fn shell(&mut self, user: &User, mut req: ShellRequest) -> Result<Vec<OwnedFd>> {
+        let original_command = force_command(&mut req, self.forced_command());
+        // Checked before anything else is done, so that a bad directory fails closed.
+        let chroot_dir = match &self.config.session.chroot_directory {
+            Some(dir) => Some(crate::chroot::chroot_directory(dir, user)?),
+            None => None,
+        };
+        crate::setup::check_chroot(&self.session_setup, chroot_dir.as_deref())?;
+
+        let subsystem = match req.subsystem.as_deref() {
+            Some(subsystem) => match self.config.subsystem.get(subsystem) {
+                Some(system) => Some(system.clone()),
+                None => bail!("unsupported subsystem: {subsystem}"),
+            },
+            None => None,
+        };
+        if let Some(system) = subsystem
+            .as_ref()
+            .filter(|system| system.is_internal_sftp())
+        {
+            return self
+                .internal_sftp(user, chroot_dir, system.directory.as_deref())
+                .await;
+        }
+        if self.forced_command() == Some(crate::sftp::INTERNAL_SFTP) {
+            return self.internal_sftp(user, chroot_dir, None).await;
+        }
+        let subsystem = subsystem.map(|system| system.path);
+
+        let shell = user.shell();
+
+        let cmd_arg0 = subsystem.as_deref().unwrap_or(shell);
+
+        // TODO: the SSH RFC mentions subsystems going through shell... should we?
+        let mut cmd = Command::new(cmd_arg0);
+
+        if subsystem.is_none() {
+            if let Some(shell_command) = req.command {
+                cmd.arg("-c");
+                cmd.arg(shell_command);
+            } else if req.pty_term.is_some() {
+                // Like OpenSSH, interactive sessions get a login shell, signalled by a leading dash.
+                let mut login_arg0 = OsString::from("-");
+                login_arg0.push(shell.file_name().unwrap_or(shell.as_os_str()));
+                cmd.arg0(login_arg0);
+            }
+        };
+
+        cmd.env_clear();
+
+        let has_pty = req.pty_term.is_some();
+
+        ensure!(
+            has_pty == self.pty_user.is_some(),
+            "Mismatch between client and server PTY requests"
+        );
+
+        if let Some(term) = req.pty_term {
+            let Some(pty_fd) = &self.pty_user else {
+                bail!("no pty requested before");
+            };
+            let pty_fd = pty_fd.try_clone()?;
 
             crate::pty::start_session_for_command(pty_fd, term, &mut cmd)?;
         } else {
-            cmd.stdin(Stdio::piped());
-            cmd.stdout(Stdio::piped());
-            cmd.stderr(Stdio::piped());
+            pipe_stdio(&mut cmd);
         }
 
-        cmd.current_dir(user.home_dir());
+        crate::setup::apply(&self.session_setup, &mut cmd, user)?;
+        become_user(&mut cmd, user, chroot_dir)?;
+        limit_resources(&mut cmd, self.config.session.rlimits);
+        // The usual login environment, the environment sent by the client takes precedence.
         cmd.env("USER", user.name());
-        cmd.uid(user.uid());
-        cmd.gid(user.primary_group_id());
+        cmd.env("LOGNAME", user.name());
+        cmd.env("HOME", user.home_dir());
+        cmd.env("SHELL", user.shell());
+        cmd.env("PATH", &self.config.session.default_path);
+        cmd.env("MAIL", Path::new("/var/mail").join(user.name()));
+
+        let pam_env = self.open_pam_session().await?;
+        for (k, v) in pam_env.into_iter().flatten() {
+            cmd.env(k, v);
+        }
 
         for (k, v) in req.env {
             cmd.env(k, v);
         }
+        if let Some(original_command) = original_command {
+            cmd.env("SSH_ORIGINAL_COMMAND", original_command);
+        }
+
+        // Created before the command runs, so that a session that should be recorded never runs unrecorded.
+        let recording = match &self.config.session.recording {
+            Some(recording) if has_pty => Some(crate::recording::create(
+                &recording.directory,
+                &user.name().to_string_lossy(),
+            )?),
+            _ => None,
+        };
 
         debug!(cmd = %cmd_arg0.display(), uid = %user.uid(), gid = %user.primary_group_id(), "Executing process");
 
         let mut shell = cmd.spawn()?;
 
         // See Server::shell_process
-        let mut fds1 = Vec::new();
+        let fds = if has_pty {
+            recording.into_iter().collect()
+        } else {
+            take_stdio(&mut shell)?
+        };
+
+        self.shell_process = Some(shell);
 
-        if !has_pty {
-            let stdin = shell.stdin.take().unwrap().into_owned_fd()?;
-            let stdout = shell.stdout.take().unwrap().into_owned_fd()?;
-            let stderr = shell.stderr.take().unwrap().into_owned_fd()?;
+        Ok(fds)
+    }
 
-            fds1.push(stdin);
-            fds1.push(stdout);
-            fds1.push(stderr);
-        }
+    /// Serves SFTP from the daemon instead of running a program of the user, see [`crate::sftp`].
+    async fn internal_sftp(
+        &mut self,
+        user: &User,
+        chroot_dir: Option<PathBuf>,
+        directory: Option<&str>,
+    ) -> Result<Vec<OwnedFd>> {
+        ensure!(
+            self.pty_user.is_none(),
+            "internal-sftp cannot be used with a PTY"
+        );
 
-        self.shell_process = Some(shell);
+        // The daemon is executed after the setup, so it has to exist in any mount namespace entered.
+        let mut cmd = crate::sftp::command(
+            user,
+            chroot_dir,
+            directory,
+            &self.session_setup,
+            self.config.security.seccomp,
+        )?;
+        pipe_stdio(&mut cmd);
+        limit_resources(&mut cmd, self.config.session.rlimits);
+
+        // There is no environment to put the PAM variables into, but the session is still accounted.
+        self.open_pam_session().await?;
 
-        Ok(fds1)
+        debug!(uid = %user.uid(), gid = %user.primary_group_id(), "Serving internal SFTP");
+
+        let mut sftp = cmd.spawn()?;
+        let fds = take_stdio(&mut sftp)?;
+        self.shell_process = Some(sftp);
+
+        Ok(fds)
+    }
+
+    /// Opens the PAM session for the session command if it is not open yet,
+    /// returning the environment that PAM set up.
+    async fn open_pam_session(&mut self) -> Result<Option<Vec<(String, String)>>> {
+        let tty = match &self.pty_user {
+            Some(pty) => Some(
+                rustix::termios::ttyname(pty, Vec::new())?
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            None => None,
+        };
+        self.with_pam(move |pam| {
+            if !pam.is_session_open() {
+                pam.open_session(tty.as_deref())?;
+            }
+            Ok(pam.env())
+        })
+        .await
+        .wrap_err("opening PAM session")
     }
 
     async fn respond_err(&self, resp: String) -> Result<()> {
@@ -460,7 +1429,19 @@ impl Server {
         resp: ResponseResult<T>,
         fds: &[BorrowedFd<'_>],
     ) -> Result<()> {
-        let data = Zeroizing::new(postcard::to_allocvec(&resp)?);
+        let mut response = Response::Reply(postcard::to_allocvec(&resp)?);
+        let result = self.send_response(&response, fds).await;
+
+        // The reply may contain secrets like the shared secret of the key exchange.
+        if let Response::Reply(reply) = &mut response {
+            reply.zeroize();
+        }
+
+        result
+    }
+
+    async fn send_response(&self, resp: &Response, fds: &[BorrowedFd<'_>]) -> Result<()> {
+        let data = Zeroizing::new(postcard::to_allocvec(resp)?);
         send_with_fds(&self.server, &data, fds).await?;
 
         Ok(())
@@ -469,8 +1450,51 @@ impl Server {
 
 impl Client {
     pub fn from_fd(fd: OwnedFd) -> Result<Self> {
-        let socket = UnixDatagram::from_std(std::os::unix::net::UnixDatagram::from(fd))?;
-        Ok(Self { socket })
+        let socket = Arc::new(UnixDatagram::from_std(
+            std::os::unix::net::UnixDatagram::from(fd),
+        )?);
+
+        let (replies_send, replies_recv) = mpsc::channel(1);
+        let (child_exits_send, child_exits_recv) = mpsc::channel(1);
+
+        let reader_socket = socket.clone();
+        tokio::spawn(async move {
+            let result = async {
+                loop {
+                    let (resp, fds) =
+                        receive_with_fds::<Response>(&reader_socket, MAX_RESPONSE_FDS)
+                            .await
+                            .wrap_err("parsing response from server")?;
+                    match resp {
+                        Response::Reply(reply) => {
+                            if replies_send
+                                .send((Zeroizing::new(reply), fds))
+                                .await
+                                .is_err()
+                            {
+                                return Ok(());
+                            }
+                        }
+                        Response::ChildExited(result) => {
+                            if child_exits_send.send(result).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            };
+            let result: Result<()> = result.await;
+            if let Err(err) = result {
+                debug!(?err, "Failed to receive RPC response");
+            }
+        });
+
+        Ok(Self {
+            socket,
+            replies: Mutex::new(replies_recv),
+            child_exits: Mutex::new(child_exits_recv),
+            last_keep_alive: std::sync::Mutex::new(None),
+        })
     }
 
     pub async fn kex_exchange(
@@ -503,7 +1527,8 @@ impl Client {
 
     pub async fn check_public_key(&self, user: String, pubkey: PublicKey) -> Result<bool> {
         self.request_response::<CheckPublicKeyResponse>(&Request::CheckPublicKey { user, pubkey })
-            .await
+            .await?
+            .into_result()
     }
 
     pub async fn verify_signature(
@@ -519,7 +1544,53 @@ impl Client {
             public_key,
             signature,
         })
-        .await
+        .await?
+        .into_result()
+    }
+
+    pub async fn verify_password(&self, user: String, password: String) -> Result<bool> {
+        self.request_response::<VerifyPasswordResponse>(&Request::VerifyPassword {
+            user,
+            password: Secret::new(SerializablePassword(password)),
+        })
+        .await?
+        .into_result()
+    }
+
+    pub async fn keyboard_interactive(
+        &self,
+        step: cluelessh_protocol::auth::KeyboardInteractive,
+    ) -> Result<KeyboardInteractiveResult> {
+        let request = match step.step {
+            KeyboardInteractiveStep::Start { .. } => {
+                Request::KeyboardInteractive { user: step.user }
+            }
+            KeyboardInteractiveStep::Responses(responses) => Request::InfoResponse {
+                responses: responses
+                    .into_iter()
+                    .map(|response| Secret::new(SerializablePassword(response)))
+                    .collect(),
+            },
+        };
+        match self
+            .request_response::<KeyboardInteractiveResponse>(&request)
+            .await?
+        {
+            KeyboardInteractiveResponse::Prompts(prompts) => {
+                Ok(KeyboardInteractiveResult::InfoRequest(InfoRequest {
+                    name: String::new(),
+                    instruction: prompts.instruction,
+                    prompts: prompts
+                        .prompts
+                        .into_iter()
+                        .map(|(prompt, echo)| Prompt { prompt, echo })
+                        .collect(),
+                }))
+            }
+            KeyboardInteractiveResponse::Finished(response) => response
+                .into_result()
+                .map(KeyboardInteractiveResult::Verified),
+        }
     }
 
     pub async fn pty_req(
@@ -530,16 +1601,17 @@ impl Client {
         height_px: u32,
         term_modes: Vec<u8>,
     ) -> Result<OwnedFd> {
-        self.send_request(&Request::PtyReq(PtyRequest {
-            height_rows,
-            width_chars,
-            width_px,
-            height_px,
-            term_modes,
-        }))
-        .await?;
-
-        let (_, mut fds) = self.recv_response_ancillary::<PtyReqResponse>().await?;
+        let (_, mut fds) = self
+            .request_response_ancillary::<PtyReqResponse>(&Request::PtyReq(PtyRequest {
+                size: WindowSize {
+                    height_rows,
+                    width_chars,
+                    width_px,
+                    height_px,
+                },
+                term_modes,
+            }))
+            .await?;
         ensure!(
             fds.len() == 1,
             "Incorrect amount of FDs received: {}",
@@ -551,6 +1623,8 @@ impl Client {
         Ok(controller)
     }
 
+    /// Spawns the session command, returning its stdin, stdout and stderr without a PTY.
+    /// With a PTY, the FD of the recording is returned if the session is recorded.
     pub async fn shell(
         &self,
         command: Option<String>,
@@ -558,45 +1632,98 @@ impl Client {
         pty_term: Option<String>,
         env: Vec<(String, String)>,
     ) -> Result<Vec<OwnedFd>> {
-        self.send_request(&Request::Shell(ShellRequest {
-            pty_term,
-            command,
-            subsystem,
-            env,
-        }))
-        .await?;
-
-        let (_, fds) = self.recv_response_ancillary::<ShellResponse>().await?;
+        let (_, fds) = self
+            .request_response_ancillary::<ShellResponse>(&Request::Shell(ShellRequest {
+                pty_term,
+                command,
+                subsystem,
+                env,
+            }))
+            .await?;
 
         Ok(fds)
     }
 
+    /// Wait for the currently running command to exit.
+    /// Other requests can be made while waiting.
     pub async fn wait(&self) -> Result<Option<i32>> {
-        self.request_response::<WaitResponse>(&Request::Wait).await
+        let mut child_exits = self.child_exits.lock().await;
+        self.send_request(&Request::Wait).await?;
+
+        let resp = child_exits
+            .recv()
+            .await
+            .ok_or_else(|| eyre!("RPC server connection closed"))?;
+
+        trace!(?resp, "Received RPC child exit");
+
+        resp.map_err(|err| eyre!(err))
     }
 
-    async fn request_response<R: DeserializeOwned + Debug + Send + 'static>(
+    /// Resize the PTY of the session and notify its process group.
+    pub async fn window_change(
         &self,
-        req: &Request,
-    ) -> Result<R> {
-        self.send_request(req).await?;
-        Ok(self.recv_response_ancillary::<R>().await?.0)
+        width_chars: u32,
+        height_rows: u32,
+        width_px: u32,
+        height_px: u32,
+    ) -> Result<()> {
+        self.request_response::<WindowChangeResponse>(&Request::WindowChange(WindowSize {
+            height_rows,
+            width_chars,
+            width_px,
+            height_px,
+        }))
+        .await
     }
 
-    async fn send_request(&self, req: &Request) -> Result<()> {
-        trace!(?req, "Sending RPC request");
+    /// Send a signal to the process group of the currently running command.
+    pub async fn signal(&self, signal: u32) -> Result<()> {
+        self.request_response::<SignalResponse>(&Request::Signal { signal })
+            .await
+    }
 
-        let data = postcard::to_allocvec(&req)?;
+    /// Send a BREAK on the PTY. Its length can't be chosen, it is up to the system.
+    pub async fn send_break(&self) -> Result<()> {
+        self.request_response::<BreakResponse>(&Request::Break)
+            .await
+    }
 
-        send_with_fds(&self.socket, &data, &[]).await?;
-        Ok(())
+    /// Tell the server that the session is still in use.
+    /// Does nothing if the last keep alive was sent less than `min_interval` ago.
+    pub async fn keep_alive(&self, min_interval: Duration) -> Result<()> {
+        {
+            let mut last = self.last_keep_alive.lock().unwrap();
+            let now = Instant::now();
+            if last.is_some_and(|last| now.duration_since(last) < min_interval) {
+                return Ok(());
+            }
+            *last = Some(now);
+        }
+        self.request_response::<KeepAliveResponse>(&Request::KeepAlive)
+            .await
+    }
+
+    async fn request_response<R: DeserializeOwned + Debug + Send + 'static>(
+        &self,
+        req: &Request,
+    ) -> Result<R> {
+        Ok(self.request_response_ancillary::<R>(req).await?.0)
     }
 
-    async fn recv_response_ancillary<R: DeserializeOwned + Debug + Send + 'static>(
+    async fn request_response_ancillary<R: DeserializeOwned + Debug + Send + 'static>(
         &self,
+        req: &Request,
     ) -> Result<(R, Vec<OwnedFd>)> {
-        let (resp, fds) = receive_with_fds::<ResponseResult<R>>(&self.socket)
+        // Hold the lock across the request and response to make sure that we get the reply for our request.
+        let mut replies = self.replies.lock().await;
+        self.send_request(req).await?;
+
+        let (reply, fds) = replies
+            .recv()
             .await
+            .ok_or_else(|| eyre!("RPC server connection closed"))?;
+        let resp = postcard::from_bytes::<ResponseResult<R>>(&reply)
             .wrap_err("parsing response from server")?;
 
         trace!(?resp, ?fds, "Received RPC response");
@@ -605,9 +1732,20 @@ impl Client {
 
         Ok((resp, fds))
     }
+
+    async fn send_request(&self, req: &Request) -> Result<()> {
+        trace!(?req, "Sending RPC request");
+
+        let data = postcard::to_allocvec(&req)?;
+
+        send_with_fds(&self.socket, &data, &[]).await?;
+        Ok(())
+    }
 }
 
 const MAX_DATA_SIZE: usize = 4048;
+/// The most FDs the server sends with a single response, like the stdio of a command.
+const MAX_RESPONSE_FDS: usize = 3;
 
 async fn send_with_fds(socket: &UnixDatagram, data: &[u8], fds: &[BorrowedFd<'_>]) -> Result<()> {
     ensure!(
@@ -615,29 +1753,92 @@ async fn send_with_fds(socket: &UnixDatagram, data: &[u8], fds: &[BorrowedFd<'_>
         "Trying to send too much data: {} > {MAX_DATA_SIZE}",
         data.len()
     );
-
+    let mut sent = 0;
     socket
         .async_io(Interest::WRITABLE, || {
-            let mut space = [0; rustix::cmsg_space!(ScmRights(3))]; //we send up to 3 fds at once
-            let mut ancillary = SendAncillaryBuffer::new(&mut space);
-
-            ancillary.push(SendAncillaryMessage::ScmRights(fds));
-            rustix::net::sendmsg(
-                socket,
-                &[IoSlice::new(data)],
-                &mut ancillary,
-                SendFlags::empty(),
-            )
-            .map_err(|errno| io::Error::from(errno))?;
-            Ok(())
+            send_remaining(&mut sent, data, fds, |data, ancillary| {
+                rustix::net::sendmsg(socket, &[IoSlice::new(data)], ancillary, SendFlags::empty())
+            })
         })
         .await
         .wrap_err("failed to write to socket")
 }
 
-async fn receive_with_fds<R: DeserializeOwned>(socket: &UnixDatagram) -> Result<(R, Vec<OwnedFd>)> {
+/// Sends `data[*sent..]` with `sendmsg`, retrying when interrupted by a signal.
+///
+/// Datagrams are always sent at once, but stream sockets may only take part of the data,
+/// so this continues where the previous write stopped, also when called again after
+/// [`io::ErrorKind::WouldBlock`]. The FDs are attached to the first byte of the message,
+/// with the ancillary buffer sized for exactly as many FDs as are sent.
+fn send_remaining(
+    sent: &mut usize,
+    data: &[u8],
+    fds: &[BorrowedFd<'_>],
+    mut sendmsg: impl FnMut(&[u8], &mut SendAncillaryBuffer<'_, '_, '_>) -> rustix::io::Result<usize>,
+) -> io::Result<()> {
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(fds.len()))];
+    while *sent < data.len() {
+        let mut ancillary = SendAncillaryBuffer::new(&mut space);
+        if *sent == 0 && !fds.is_empty() && !ancillary.push(SendAncillaryMessage::ScmRights(fds)) {
+            return Err(io::Error::other(format!(
+                "failed to attach {} FDs to message",
+                fds.len()
+            )));
+        }
+        match sendmsg(&data[*sent..], &mut ancillary) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => *sent += len,
+            Err(rustix::io::Errno::INTR) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Receives the next request of the client, or `None` if there was none within `idle_timeout`.
+/// FDs are only ever sent to the client, so requests with FDs are rejected.
+async fn receive_request(
+    socket: &UnixDatagram,
+    idle_timeout: Option<Duration>,
+) -> Option<Result<Request>> {
+    // Any FDs are closed by the kernel, which makes receiving fail.
+    let recv = receive_with_idle_timeout::<Request>(socket, 0, idle_timeout).await?;
+    Some(
+        recv.wrap_err("parsing request from client")
+            .and_then(|(req, fds)| {
+                if !fds.is_empty() {
+                    let count = fds.len();
+                    // Close them right away instead of keeping them until the error is handled.
+                    drop(fds);
+                    bail!("Client sent {count} FDs in request");
+                }
+                Ok(req)
+            }),
+    )
+}
+
+/// Receives the next message, or `None` if there was none within `idle_timeout`.
+async fn receive_with_idle_timeout<R: DeserializeOwned>(
+    socket: &UnixDatagram,
+    max_fds: usize,
+    idle_timeout: Option<Duration>,
+) -> Option<Result<(R, Vec<OwnedFd>)>> {
+    match idle_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, receive_with_fds(socket, max_fds))
+            .await
+            .ok(),
+        None => Some(receive_with_fds(socket, max_fds).await),
+    }
+}
+
+/// Receives the next message along with up to `max_fds` FDs.
+/// Messages with more FDs are rejected.
+async fn receive_with_fds<R: DeserializeOwned>(
+    socket: &UnixDatagram,
+    max_fds: usize,
+) -> Result<(R, Vec<OwnedFd>)> {
     let mut data = Zeroizing::new([0; MAX_DATA_SIZE]);
-    let mut space = [0; rustix::cmsg_space!(ScmRights(3))]; // maximum size
+    let mut space = vec![0; rustix::cmsg_space!(ScmRights(max_fds))];
     let mut cmesg_buf = RecvAncillaryBuffer::new(&mut space);
 
     let read = socket
@@ -646,16 +1847,15 @@ async fn receive_with_fds<R: DeserializeOwned>(socket: &UnixDatagram) -> Result<
                 socket,
                 &mut [IoSliceMut::new(&mut *data)],
                 &mut cmesg_buf,
-                RecvFlags::empty(),
+                // Received FDs must never be inherited by the commands we spawn.
+                RecvFlags::CMSG_CLOEXEC,
             )
-            .map_err(|errno| io::Error::from(errno))
+            .map_err(io::Error::from)
         })
         .await?;
 
+    // Take ownership of the FDs first, so that they are closed on errors.
     let mut fds = Vec::new();
-
-    let data_parsed = postcard::from_bytes::<R>(&data[..read.bytes]).wrap_err("invalid request")?;
-
     for msg in cmesg_buf.drain() {
         match msg {
             RecvAncillaryMessage::ScmRights(fd) => fds.extend(fd),
@@ -663,5 +1863,551 @@ async fn receive_with_fds<R: DeserializeOwned>(socket: &UnixDatagram) -> Result<
         }
     }
 
+    // The kernel closes the FDs that don't fit into the buffer.
+    ensure!(
+        read.flags.bits() & (libc::MSG_CTRUNC as u32) == 0,
+        "Received more than {max_fds} FDs"
+    );
+    ensure!(
+        !read.flags.contains(RecvFlags::TRUNC),
+        "Received message larger than {MAX_DATA_SIZE} bytes"
+    );
+
+    let data_parsed = postcard::from_bytes::<R>(&data[..read.bytes]).wrap_err("invalid request")?;
+
     Ok((data_parsed, fds))
 }
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_format::{numbers, NameList, Writer};
+    use cluelessh_keys::authorized_keys::KeyOptions;
+    use cluelessh_keys::private::PlaintextPrivateKey;
+    use cluelessh_keys::public::PublicKey;
+    use cluelessh_keys::{KeyGenerationParams, KeyType};
+
+    use cluelessh_tokio::term_modes::TermModes;
+    use cluelessh_transport::SessionId;
+    use users::os::unix::UserExt;
+    use users::User;
+
+    use crate::config::RlimitConfig;
+    use crate::pty::tests::MockAllocator;
+    use crate::sessions::UserSessions;
+
+    use std::io::{IoSlice, IoSliceMut};
+    use std::os::fd::{AsFd, OwnedFd};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rustix::net::{RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendFlags};
+    use tokio::net::UnixDatagram;
+
+    use super::{
+        check_key_exchange, check_session_id, check_user_binding, force_command, receive_request,
+        receive_with_fds, send_remaining, send_with_fds, Client, ConnectionKex, KeyExchangeRequest,
+        PtyRequest, Request, Server, ShellRequest, TooManyAuthFailures, TooManySessions,
+        VerifyResponse, WindowSize, MAX_RESPONSE_FDS,
+    };
+
+    fn kexinit(kex_algorithms: &str, host_key_algorithms: &str) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_MSG_KEXINIT);
+        w.array([0; 16]);
+        w.name_list(NameList::multi(kex_algorithms));
+        w.name_list(NameList::multi(host_key_algorithms));
+        for _ in 0..8 {
+            w.name_list(NameList::none());
+        }
+        w.bool(false);
+        w.u32(0);
+        w.finish()
+    }
+
+    fn host_key() -> PublicKey {
+        PlaintextPrivateKey::generate(
+            String::new(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        )
+        .private_key
+        .public_key()
+    }
+
+    fn request(host_key: &PublicKey) -> KeyExchangeRequest {
+        KeyExchangeRequest {
+            client_ident: b"SSH-2.0-client".to_vec(),
+            server_ident: b"SSH-2.0-server".to_vec(),
+            client_kexinit: kexinit("curve25519-sha256", "ssh-ed25519"),
+            server_kexinit: kexinit("curve25519-sha256,ecdh-sha2-nistp256", "ssh-ed25519"),
+            eph_client_public_key: vec![0; 32],
+            server_host_key: host_key.clone(),
+            kex_algorithm: "curve25519-sha256".to_owned(),
+        }
+    }
+
+    fn previous(req: &KeyExchangeRequest) -> ConnectionKex {
+        ConnectionKex {
+            client_ident: req.client_ident.clone(),
+            server_ident: req.server_ident.clone(),
+            server_host_key: req.server_host_key.clone(),
+            session_id: SessionId([1; 32]),
+        }
+    }
+
+    #[test]
+    fn initial_and_rekey_accepted() {
+        let req = request(&host_key());
+        check_key_exchange(None, &req).unwrap();
+        check_key_exchange(Some(&previous(&req)), &req).unwrap();
+    }
+
+    #[test]
+    fn algorithm_not_offered() {
+        let mut req = request(&host_key());
+        req.kex_algorithm = "ecdh-sha2-nistp256".to_owned();
+        check_key_exchange(None, &req).unwrap_err();
+
+        let mut req = request(&host_key());
+        req.server_kexinit = kexinit("curve25519-sha256", "ecdsa-sha2-nistp256");
+        check_key_exchange(None, &req).unwrap_err();
+    }
+
+    #[test]
+    fn invalid_kexinit() {
+        let mut req = request(&host_key());
+        req.client_kexinit = b"not a kexinit".to_vec();
+        check_key_exchange(None, &req).unwrap_err();
+    }
+
+    #[test]
+    fn rekey_for_other_connection() {
+        let req = request(&host_key());
+        let previous = previous(&req);
+
+        let mut other = request(&req.server_host_key);
+        other.client_ident = b"SSH-2.0-other".to_vec();
+        check_key_exchange(Some(&previous), &other).unwrap_err();
+
+        let other = request(&host_key());
+        check_key_exchange(Some(&previous), &other).unwrap_err();
+    }
+
+    #[test]
+    fn session_id_mismatch() {
+        let connection_kex = previous(&request(&host_key()));
+        check_session_id(Some(&connection_kex), &SessionId([1; 32])).unwrap();
+        check_session_id(Some(&connection_kex), &SessionId([2; 32])).unwrap_err();
+        check_session_id(None, &SessionId([1; 32])).unwrap_err();
+    }
+
+    #[test]
+    fn authentication_bound_to_user() {
+        check_user_binding(None, "alice").unwrap();
+
+        let alice = users::User::new(1000, "alice", 1000);
+        let err = check_user_binding(Some(&alice), "bob").unwrap_err();
+        assert_eq!(err, "already authenticated as a different user");
+        // Authenticating again as the same user is not allowed either.
+        check_user_binding(Some(&alice), "alice").unwrap_err();
+    }
+
+    #[test]
+    fn pty_request_winsize() {
+        let req = PtyRequest {
+            size: WindowSize {
+                height_rows: 24,
+                width_chars: 80,
+                width_px: 640,
+                height_px: 480,
+            },
+            term_modes: vec![],
+        };
+        let winsize = req.size.winsize();
+        assert_eq!(
+            (
+                winsize.ws_row,
+                winsize.ws_col,
+                winsize.ws_xpixel,
+                winsize.ws_ypixel
+            ),
+            (24, 80, 640, 480)
+        );
+    }
+
+    fn shell_request(command: Option<&str>, subsystem: Option<&str>) -> ShellRequest {
+        ShellRequest {
+            pty_term: None,
+            command: command.map(ToOwned::to_owned),
+            subsystem: subsystem.map(ToOwned::to_owned),
+            env: vec![],
+        }
+    }
+
+    #[test]
+    fn forced_command_replaces_command() {
+        let mut req = shell_request(Some("rm -rf /"), None);
+        let original = force_command(&mut req, Some("echo hi"));
+        assert_eq!(original.as_deref(), Some("rm -rf /"));
+        assert_eq!(req.command.as_deref(), Some("echo hi"));
+    }
+
+    #[test]
+    fn forced_command_replaces_shell_and_subsystem() {
+        let mut req = shell_request(None, None);
+        req.env
+            .push(("SSH_ORIGINAL_COMMAND".to_owned(), "rm -rf /".to_owned()));
+        req.env.push(("LANG".to_owned(), "C".to_owned()));
+        assert_eq!(force_command(&mut req, Some("echo hi")), None);
+        assert_eq!(req.command.as_deref(), Some("echo hi"));
+        assert_eq!(req.env, [("LANG".to_owned(), "C".to_owned())]);
+
+        let mut req = shell_request(None, Some("sftp"));
+        assert_eq!(force_command(&mut req, Some("echo hi")), None);
+        assert_eq!(req.command.as_deref(), Some("echo hi"));
+        assert_eq!(req.subsystem, None);
+    }
+
+    #[test]
+    fn no_forced_command() {
+        let mut req = shell_request(Some("ls"), Some("sftp"));
+        assert_eq!(force_command(&mut req, None), None);
+        assert_eq!(req.command.as_deref(), Some("ls"));
+        assert_eq!(req.subsystem.as_deref(), Some("sftp"));
+    }
+
+    #[tokio::test]
+    async fn forced_command_precedence() {
+        let sessions = UserSessions::default();
+        let config = r#"
+            net = {}
+            auth = { host_keys = [] }
+            security = {}
+        "#;
+        let mut from_key = server(config, &sessions);
+        assert_eq!(from_key.forced_command(), None);
+        from_key.key_options.command = Some("git-shell".to_owned());
+        assert_eq!(from_key.forced_command(), Some("git-shell"));
+
+        let config = r#"
+            net = {}
+            auth = { host_keys = [] }
+            security = {}
+            session = { force_command = "rsync-wrapper" }
+        "#;
+        let mut from_config = server(config, &sessions);
+        assert_eq!(from_config.forced_command(), Some("rsync-wrapper"));
+        from_config.key_options.command = Some("git-shell".to_owned());
+        assert_eq!(from_config.forced_command(), Some("rsync-wrapper"));
+    }
+
+    #[tokio::test]
+    async fn become_user() {
+        let uid = rustix::process::getuid().as_raw();
+        let user = users::get_user_by_uid(uid).unwrap();
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg("id -u; id -g; id -G; pwd");
+        super::become_user(&mut cmd, &user, None).unwrap();
+        let output = String::from_utf8(cmd.output().await.unwrap().stdout).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], uid.to_string());
+        assert_eq!(lines[1], user.primary_group_id().to_string());
+        if uid == 0 {
+            let mut groups = lines[2].split(' ').collect::<Vec<_>>();
+            let mut expected = users::get_user_groups(user.name(), user.primary_group_id())
+                .unwrap()
+                .iter()
+                .map(|group| group.gid().to_string())
+                .collect::<Vec<_>>();
+            groups.sort();
+            expected.sort();
+            assert_eq!(groups, expected);
+        }
+        assert_eq!(Path::new(lines[3]), user.home_dir());
+    }
+
+    #[tokio::test]
+    async fn limit_resources() {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg("ulimit -n; ulimit -t");
+        let limits = RlimitConfig {
+            nofile: Some(64),
+            cpu_secs: Some(100),
+            ..RlimitConfig::default()
+        };
+        super::limit_resources(&mut cmd, limits);
+        let output = cmd.output().await.unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "64\n100\n");
+
+        // Limits that cannot be set make the spawn fail.
+        let mut cmd = tokio::process::Command::new("true");
+        let limits = RlimitConfig {
+            nofile: Some(u64::MAX - 1),
+            ..RlimitConfig::default()
+        };
+        super::limit_resources(&mut cmd, limits);
+        assert!(cmd.output().await.is_err());
+    }
+
+    /// A pipe with a read end that doesn't block, to check whether all write ends have been closed.
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        rustix::pipe::pipe_with(rustix::pipe::PipeFlags::NONBLOCK).unwrap()
+    }
+
+    fn write_ends_closed(read: &OwnedFd) -> bool {
+        rustix::io::read(read, &mut [0; 1]) == Ok(0)
+    }
+
+    fn window_change_request() -> Vec<u8> {
+        postcard::to_allocvec(&Request::WindowChange(WindowSize {
+            height_rows: 24,
+            width_chars: 80,
+            width_px: 0,
+            height_px: 0,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_with_fds_rejected() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        let (read, write) = pipe();
+
+        send_with_fds(&client, &window_change_request(), &[write.as_fd()])
+            .await
+            .unwrap();
+        drop(write);
+        assert!(receive_request(&server, None).await.unwrap().is_err());
+        assert!(write_ends_closed(&read));
+
+        send_with_fds(&client, &window_change_request(), &[])
+            .await
+            .unwrap();
+        let request = receive_request(&server, None).await.unwrap().unwrap();
+        assert!(matches!(request, Request::WindowChange(_)));
+    }
+
+    #[test]
+    fn send_retries_interrupted_and_partial_writes() {
+        let (sender, receiver) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (_read, write) = pipe();
+        let data = window_change_request();
+
+        let mut sent = 0;
+        let mut calls = 0;
+        send_remaining(&mut sent, &data, &[write.as_fd()], |data, ancillary| {
+            calls += 1;
+            if calls == 1 {
+                return Err(rustix::io::Errno::INTR);
+            }
+            // Like a stream socket with little buffer space left.
+            let len = data.len().min(2);
+            rustix::net::sendmsg(
+                &sender,
+                &[IoSlice::new(&data[..len])],
+                ancillary,
+                SendFlags::empty(),
+            )
+        })
+        .unwrap();
+        assert_eq!(sent, data.len());
+        assert_eq!(calls, 1 + data.len().div_ceil(2));
+
+        let mut received = vec![0; data.len()];
+        let mut received_len = 0;
+        let mut fds_per_read = Vec::new();
+        while received_len < data.len() {
+            let mut space = [0; rustix::cmsg_space!(ScmRights(1))];
+            let mut ancillary = RecvAncillaryBuffer::new(&mut space);
+            let read = rustix::net::recvmsg(
+                &receiver,
+                &mut [IoSliceMut::new(&mut received[received_len..])],
+                &mut ancillary,
+                RecvFlags::empty(),
+            )
+            .unwrap();
+            received_len += read.bytes;
+            let fds = ancillary
+                .drain()
+                .map(|msg| match msg {
+                    RecvAncillaryMessage::ScmRights(fds) => fds.count(),
+                    _ => panic!("unexpected ancillary message"),
+                })
+                .sum::<usize>();
+            fds_per_read.push(fds);
+        }
+        assert_eq!(received, data);
+        // The FD is only sent once, with the first byte.
+        assert_eq!(fds_per_read.iter().sum::<usize>(), 1);
+        assert_eq!(fds_per_read[0], 1);
+    }
+
+    fn server(config: &str, sessions: &UserSessions) -> Server {
+        let config = toml::from_str(config).unwrap();
+        Server::new(
+            config,
+            vec![],
+            sessions.clone(),
+            "127.0.0.1:22".parse().unwrap(),
+            Arc::new(MockAllocator::default()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn max_sessions_per_user() {
+        let config = r#"
+            net = {}
+            auth = { host_keys = [] }
+            security = {}
+            session = { max_sessions_per_user = 1 }
+        "#;
+        let sessions = UserSessions::default();
+        let alice = || {
+            Ok(Some((
+                User::new(1000, "alice", 1000),
+                KeyOptions::default(),
+            )))
+        };
+
+        let mut first = server(config, &sessions);
+        let response = first.finish_authentication(alice(), None).await.unwrap();
+        assert!(matches!(response, VerifyResponse::Accepted));
+
+        // Another connection of the same user is refused, even with valid credentials.
+        let mut second = server(config, &sessions);
+        let response = second.finish_authentication(alice(), None).await.unwrap();
+        assert!(matches!(response, VerifyResponse::TooManySessions));
+        assert!(second.authenticated_user.is_none());
+        assert!(response.into_result().unwrap_err().is::<TooManySessions>());
+
+        // Once the first connection is gone, the user may log in again.
+        drop(first);
+        let response = second.finish_authentication(alice(), None).await.unwrap();
+        assert!(matches!(response, VerifyResponse::Accepted));
+    }
+
+    #[tokio::test]
+    async fn max_auth_tries() {
+        let key = host_key();
+        let user = users::get_current_username()
+            .unwrap()
+            .into_string()
+            .unwrap();
+        let config = format!(
+            r#"
+            net = {{}}
+            auth = {{ host_keys = [], max_auth_tries = 2, authorized_keys_command = ["/bin/echo", "{key}"], authorized_keys_command_user = "{user}" }}
+            security = {{}}
+            "#
+        );
+        let sessions = UserSessions::default();
+        let mut server = server(&config, &sessions);
+        let client = Client::from_fd(server.client_fd().try_clone_to_owned().unwrap()).unwrap();
+
+        let requests = async {
+            // Accepted keys are not counted.
+            let check = client.check_public_key(user.clone(), key.clone()).await;
+            assert!(check.unwrap());
+            let check = client.check_public_key(user.clone(), host_key()).await;
+            assert!(!check.unwrap());
+
+            // The last failure tells the connection process to disconnect.
+            let check = client.check_public_key(user.clone(), host_key()).await;
+            assert!(check.unwrap_err().is::<TooManyAuthFailures>());
+
+            // Afterwards, nothing is tried anymore, not even an accepted key.
+            let check = client.check_public_key(user.clone(), key.clone()).await;
+            assert!(check.unwrap_err().is::<TooManyAuthFailures>());
+            let verify = client
+                .verify_password(user.clone(), "hunter2".to_owned())
+                .await;
+            assert!(verify.unwrap_err().is::<TooManyAuthFailures>());
+        };
+        tokio::select! {
+            result = server.process() => panic!("server stopped: {result:?}"),
+            () = requests => {}
+        }
+        assert_eq!(server.failed_auth_attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn pty_req_uses_allocator() {
+        let config = toml::from_str("net = {}\nauth = { host_keys = [] }\nsecurity = {}").unwrap();
+        let allocator = Arc::new(MockAllocator::default());
+        let mut server = Server::new(
+            config,
+            vec![],
+            UserSessions::default(),
+            "127.0.0.1:22".parse().unwrap(),
+            allocator.clone(),
+        )
+        .unwrap();
+        server.authenticated_user = users::get_user_by_uid(users::get_current_uid());
+        let client = Client::from_fd(server.client_fd().try_clone_to_owned().unwrap()).unwrap();
+
+        let requests = async {
+            // VERASE 127, TTY_OP_END
+            let controller = client.pty_req(80, 24, 640, 480, vec![3, 0, 0, 0, 127, 0]);
+            let controller = controller.await.unwrap();
+            let second = client.pty_req(80, 24, 0, 0, vec![0]).await;
+            assert!(second.is_err());
+            controller
+        };
+        let controller = tokio::select! {
+            result = server.process() => panic!("server stopped: {result:?}"),
+            controller = requests => controller,
+        };
+
+        let requests = allocator.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (winsize, modes) = &requests[0];
+        assert_eq!((winsize.ws_col, winsize.ws_row), (80, 24));
+        assert_eq!((winsize.ws_xpixel, winsize.ws_ypixel), (640, 480));
+        assert_eq!(modes, &TermModes(vec![(numbers::VERASE, 127)]));
+
+        // The monitor keeps the user side, the connection gets the controller.
+        let user_pty = server.pty_user.as_ref().unwrap();
+        rustix::io::write(user_pty, b"hi").unwrap();
+        let mut buf = [0; 2];
+        rustix::io::read(&controller, &mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    #[tokio::test]
+    async fn too_many_fds_closed() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        let (read, write) = pipe();
+
+        // More than the receiver has space for.
+        let fds = [write.as_fd(); 16];
+        send_with_fds(&client, &window_change_request(), &fds)
+            .await
+            .unwrap();
+        drop(write);
+
+        assert!(receive_with_fds::<Request>(&server, MAX_RESPONSE_FDS)
+            .await
+            .is_err());
+        assert!(write_ends_closed(&read));
+    }
+
+    #[tokio::test]
+    async fn fds_beyond_response_limit() {
+        let (client, server) = UnixDatagram::pair().unwrap();
+        let (_read, write) = pipe();
+
+        let fds = [write.as_fd(); MAX_RESPONSE_FDS + 2];
+        send_with_fds(&client, &window_change_request(), &fds)
+            .await
+            .unwrap();
+
+        let (request, received) = receive_with_fds::<Request>(&server, fds.len())
+            .await
+            .unwrap();
+        assert!(matches!(request, Request::WindowChange(_)));
+        assert_eq!(received.len(), fds.len());
+    }
+}
@@ -44,7 +44,7 @@ pub fn drop_privileges(state: &SerializedConnectionState) -> Result<()> {
 
     rustix::thread::set_no_new_privs(true)?;
 
-    if state.config.security.experimental_seccomp {
+    if state.config.security.seccomp {
         seccomp().wrap_err("setting up seccomp")?;
     }
 
@@ -187,7 +187,7 @@ fn unshare_namespaces() -> Result<()> {
     let old_root = &new_root.join("old-root");
 
     std::fs::create_dir_all(new_root)?;
-    std::fs::create_dir_all(&old_root)?;
+    std::fs::create_dir_all(old_root)?;
 
     rustix::fs::bind_mount(new_root, new_root).wrap_err("bind mount the empty dir")?;
 
@@ -199,97 +199,429 @@ fn unshare_namespaces() -> Result<()> {
     Ok(())
 }
 
+/// Restricts the connection process to the syscalls in [`allowed_syscalls`],
+/// any other syscall kills it.
 #[tracing::instrument]
 fn seccomp() -> Result<()> {
-    use seccompiler::{SeccompCmpArgLen as ArgLen, SeccompCmpOp as Op, SeccompCondition as Cond};
+    install_filter(connection_filter)
+}
 
-    let arch = match std::env::consts::ARCH {
-        "x86_64" => TargetArch::x86_64,
-        "aarch64" => TargetArch::aarch64,
-        arch => {
-            warn!("Seccomp not supported for architecture ({arch})m skipping");
-            return Ok(());
-        }
-    };
+/// Restricts the SFTP process to the syscalls in [`sftp_syscalls`], any other syscall kills it.
+#[tracing::instrument]
+pub fn sftp_seccomp() -> Result<()> {
+    install_filter(sftp_filter)
+}
 
-    let limit_fd = |fd: RawFd| {
-        SeccompRule::new(vec![Cond::new(
-            0, // fd
-            ArgLen::Dword,
-            Op::Eq,
-            fd as u64,
-        )
-        .unwrap()])
-        .unwrap()
+fn install_filter(filter: fn(TargetArch) -> Result<BpfProgram>) -> Result<()> {
+    let Some(arch) = target_arch() else {
+        warn!(
+            "Seccomp not supported for architecture ({}), skipping",
+            std::env::consts::ARCH
+        );
+        return Ok(());
     };
 
+    let program = filter(arch)?;
+
+    debug!("Installing seccomp filter");
+    seccompiler::apply_filter(&program).wrap_err("installing seccomp filter")?;
+
+    Ok(())
+}
+
+fn target_arch() -> Option<TargetArch> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some(TargetArch::x86_64),
+        "aarch64" => Some(TargetArch::aarch64),
+        _ => None,
+    }
+}
+
+fn connection_filter(arch: TargetArch) -> Result<BpfProgram> {
+    compile_filter(allowed_syscalls()?, arch)
+}
+
+fn sftp_filter(arch: TargetArch) -> Result<BpfProgram> {
+    compile_filter(sftp_syscalls()?, arch)
+}
+
+fn compile_filter(syscalls: Vec<(i64, Vec<SeccompRule>)>, arch: TargetArch) -> Result<BpfProgram> {
     let filter = SeccompFilter::new(
-        vec![
-            (libc::SYS_write, vec![]),
-            (libc::SYS_epoll_create1, vec![]),
-            (libc::SYS_eventfd2, vec![]),
-            (libc::SYS_epoll_wait, vec![]),
-            (libc::SYS_epoll_ctl, vec![]),
-            (libc::SYS_fcntl, vec![]), // todo: restrict this
-            (libc::SYS_socketpair, vec![]),
-            (
-                libc::SYS_sendmsg,
-                vec![limit_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD)],
-            ),
-            (
-                libc::SYS_recvmsg,
-                vec![limit_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD)],
-            ),
-            (
-                libc::SYS_sendto,
-                vec![limit_fd(PRIVSEP_CONNECTION_STREAM_FD)],
-            ),
-            (
-                libc::SYS_recvfrom,
-                vec![limit_fd(PRIVSEP_CONNECTION_STREAM_FD)],
-            ),
-            (libc::SYS_getrandom, vec![]),
-            (libc::SYS_rt_sigaction, vec![]),
-            (libc::SYS_rt_sigprocmask, vec![]),
-            (libc::SYS_mmap, vec![]),
-            (libc::SYS_munmap, vec![]),
-            (libc::SYS_sched_getaffinity, vec![]),
-            (libc::SYS_sigaltstack, vec![]),
-            (libc::SYS_futex, vec![]),
-            (libc::SYS_read, vec![]),
-            (libc::SYS_mprotect, vec![]),
-            (libc::SYS_rseq, vec![]),
-            (libc::SYS_set_robust_list, vec![]),
-            (libc::SYS_prctl, vec![]),
-            (libc::SYS_close, vec![]),
-            (libc::SYS_madvise, vec![]),
-            (libc::SYS_exit, vec![]),
-            (libc::SYS_exit_group, vec![]),
-            (libc::SYS_sched_yield, vec![]),
-            (
-                libc::SYS_ioctl,
-                vec![SeccompRule::new(vec![Cond::new(
-                    1, // op
-                    // dword for musl, qword for glibc :D.
-                    // but since FIONBIO is <u32::MAX, we can use dword.
-                    ArgLen::Dword,
-                    Op::Eq,
-                    libc::FIONBIO, // non-blocking
-                )?])?],
-            ),
-        ]
-        .into_iter()
-        .collect(),
+        syscalls.into_iter().collect(),
         SeccompAction::KillProcess,
         SeccompAction::Allow,
         arch,
     )
     .wrap_err("creating seccomp filter")?;
 
-    let program: BpfProgram = filter.try_into().wrap_err("compiling seccomp filter")?;
+    filter.try_into().wrap_err("compiling seccomp filter")
+}
+
+fn limit_arg(arg: u8, value: u64) -> Result<SeccompRule> {
+    use seccompiler::{SeccompCmpArgLen as ArgLen, SeccompCmpOp as Op, SeccompCondition as Cond};
 
-    debug!("Installing seccomp filter");
-    seccompiler::apply_filter(&program).wrap_err("installing seccomp filter")?;
+    Ok(SeccompRule::new(vec![Cond::new(
+        arg,
+        ArgLen::Dword,
+        Op::Eq,
+        value,
+    )?])?)
+}
 
-    Ok(())
+/// The syscalls that the connection process needs after dropping its privileges,
+/// with conditions on their arguments where possible (an empty list allows all arguments).
+///
+/// The connection process talks to the client over the stream and to the monitor over the RPC socket,
+/// everything else, like files, processes and authentication, is done by the monitor.
+/// Every syscall must be justified here, the fewer there are, the less a compromised process can do.
+fn allowed_syscalls() -> Result<Vec<(i64, Vec<SeccompRule>)>> {
+    let limit_fd = |fd: RawFd| -> Result<Vec<SeccompRule>> {
+        Ok(vec![limit_arg(0, fd as u64)?]) // fd
+    };
+
+    let mut syscalls = vec![
+        // Client connection: sending and receiving packets.
+        (libc::SYS_sendto, limit_fd(PRIVSEP_CONNECTION_STREAM_FD)?),
+        (libc::SYS_recvfrom, limit_fd(PRIVSEP_CONNECTION_STREAM_FD)?),
+        // RPC with the monitor, which also passes FDs like the pipes of the session command.
+        (
+            libc::SYS_sendmsg,
+            limit_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD)?,
+        ),
+        (
+            libc::SYS_recvmsg,
+            limit_fd(PRIVSEP_CONNECTION_RPC_CLIENT_FD)?,
+        ),
+        // Session data from and to the FDs passed by the monitor, session recordings and logging to stdout.
+        (libc::SYS_read, vec![]),
+        (libc::SYS_write, vec![]),
+        (libc::SYS_close, vec![]),
+        // Ephemeral keys of the key exchange.
+        (libc::SYS_getrandom, vec![]),
+    ];
+    syscalls.extend(runtime_syscalls()?);
+    Ok(syscalls)
+}
+
+/// The syscalls that the SFTP process needs after becoming the user, like [`allowed_syscalls`].
+///
+/// The SFTP process serves the client on its stdio and only works with the files of the user,
+/// which are protected by the permissions of the user and the chroot, not by this filter.
+/// It can't execute programs, create sockets or change any other process.
+fn sftp_syscalls() -> Result<Vec<(i64, Vec<SeccompRule>)>> {
+    let mut syscalls = vec![
+        // Requests on stdin, responses on stdout, and the data of the files that are transferred.
+        (libc::SYS_read, vec![]),
+        (libc::SYS_write, vec![]),
+        (libc::SYS_pread64, vec![]),
+        (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_close, vec![]),
+        // Opening, listing and inspecting files and directories.
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_open, vec![]),
+        (libc::SYS_openat, vec![]),
+        (libc::SYS_getdents64, vec![]),
+        (libc::SYS_newfstatat, vec![]),
+        (libc::SYS_fstat, vec![]),
+        // Resolving paths with realpath.
+        (libc::SYS_getcwd, vec![]),
+        (libc::SYS_readlinkat, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_readlink, vec![]),
+        // The keys of std's hash maps.
+        (libc::SYS_getrandom, vec![]),
+    ];
+    syscalls.extend(runtime_syscalls()?);
+    Ok(syscalls)
+}
+
+/// The syscalls of the single threaded tokio runtime, libc and std that every process needs.
+fn runtime_syscalls() -> Result<Vec<(i64, Vec<SeccompRule>)>> {
+    use seccompiler::{SeccompCmpArgLen as ArgLen, SeccompCmpOp as Op, SeccompCondition as Cond};
+
+    Ok(vec![
+        // Putting FDs into non-blocking mode for the runtime.
+        (
+            libc::SYS_ioctl,
+            vec![SeccompRule::new(vec![Cond::new(
+                1, // op
+                // dword for musl, qword for glibc :D.
+                // but since FIONBIO is <u32::MAX, we can use dword.
+                ArgLen::Dword,
+                Op::Eq,
+                libc::FIONBIO,
+            )?])?],
+        ),
+        // Duplicating FDs to read from and write to them separately, like the PTY controller.
+        // Debug builds of std also check with F_GETFD that dropped FDs are still open.
+        (
+            libc::SYS_fcntl,
+            vec![
+                limit_arg(1, libc::F_DUPFD_CLOEXEC as u64)?, // cmd
+                limit_arg(1, libc::F_GETFD as u64)?,
+            ],
+        ),
+        // The tokio runtime: the IO driver, waking it up, and the signal driver.
+        (libc::SYS_epoll_create1, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_eventfd2, vec![]),
+        (libc::SYS_socketpair, vec![]),
+        (libc::SYS_rt_sigaction, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_sched_yield, vec![]),
+        // Timers, like the keep alives, in case the vDSO can't be used.
+        (libc::SYS_clock_gettime, vec![]),
+        // Memory allocation: the main arena of malloc grows with brk, large allocations
+        // are mapped and grown with mremap.
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_mprotect, vec![]),
+        (libc::SYS_madvise, vec![]),
+        // Locks and thread setup by libc and std.
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_rseq, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_prctl, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_exit_group, vec![]),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        process::Stdio,
+        sync::Arc,
+    };
+
+    use cluelessh_keys::{private::PlaintextPrivateKey, KeyGenerationParams, KeyType};
+    use cluelessh_protocol::connection::ChannelKind;
+    use cluelessh_tokio::{
+        client::{ClientAuth, ClientConfig, ClientConnection},
+        identity::{Identities, PrivateKeys},
+        stream::ChannelStream,
+    };
+    use rustix::process::WaitOptions;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::Fork;
+    use crate::{
+        config::Config, sessions::UserSessions, MemFd, SerializedConnectionState,
+        PRIVSEP_CONNECTION_STATE_FD,
+    };
+
+    /// Set for the test binary started by [`connection_with_filter`] to run the connection process.
+    const FILTERED_CONNECTION_ENV: &str = "CLUELESSHD_TEST_FILTERED_CONNECTION";
+
+    /// Runs `f` in a child process with the connection filter, returning how the child ended.
+    fn run_filtered(f: fn()) -> rustix::process::WaitStatus {
+        let Some(arch) = super::target_arch() else {
+            panic!("unsupported architecture");
+        };
+        // Compiled before forking, as the child may not allocate.
+        let program = super::connection_filter(arch).unwrap();
+        match unsafe { super::fork() }.unwrap() {
+            Fork::Child => {
+                if seccompiler::apply_filter(&program).is_err() {
+                    unsafe { libc::_exit(2) };
+                }
+                f();
+                unsafe { libc::_exit(0) };
+            }
+            Fork::Parent(child) => rustix::process::waitpid(Some(child), WaitOptions::empty())
+                .unwrap()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp_filter() {
+        let allowed = run_filtered(|| {
+            let mut buf = [0_u8; 16];
+            unsafe { libc::getrandom(buf.as_mut_ptr().cast(), buf.len(), 0) };
+        });
+        assert_eq!(allowed.exit_status(), Some(0));
+
+        let denied = run_filtered(|| {
+            unsafe { libc::openat(libc::AT_FDCWD, c"/etc/passwd".as_ptr(), libc::O_RDONLY) };
+        });
+        assert_eq!(denied.terminating_signal(), Some(libc::SIGSYS as u32));
+
+        // Sending on anything but the stream.
+        let denied = run_filtered(|| {
+            unsafe { libc::sendto(1, [0_u8].as_ptr().cast(), 1, 0, std::ptr::null(), 0) };
+        });
+        assert_eq!(denied.terminating_signal(), Some(libc::SIGSYS as u32));
+    }
+
+    /// The connection process of [`connection_with_filter`], which does nothing in a normal test run.
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn filtered_connection_process() {
+        if std::env::var_os(FILTERED_CONNECTION_ENV).is_none() {
+            return;
+        }
+
+        let mut memfd =
+            unsafe { MemFd::<SerializedConnectionState>::from_raw_fd(PRIVSEP_CONNECTION_STATE_FD) }
+                .unwrap();
+        let state = memfd.read().unwrap();
+
+        // Like `drop_privileges`, without the namespaces that need root.
+        rustix::thread::set_no_new_privs(true).unwrap();
+        super::seccomp().unwrap();
+
+        // Growing a large allocation remaps it, which this short connection doesn't necessarily do.
+        let mut buffer = vec![0_u8; 1024 * 1024];
+        buffer.resize(16 * 1024 * 1024, 0);
+        drop(buffer);
+
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(crate::connection::connection_inner(
+                state,
+                unsafe { std::net::TcpStream::from_raw_fd(crate::PRIVSEP_CONNECTION_STREAM_FD) },
+                unsafe { OwnedFd::from_raw_fd(crate::PRIVSEP_CONNECTION_RPC_CLIENT_FD) },
+            ));
+        std::process::exit(if result.is_ok() { 0 } else { 1 });
+    }
+
+    #[tokio::test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    async fn connection_with_filter() {
+        // Enough for the connection process to grow its heap.
+        const LEN: usize = 4 * 1024 * 1024;
+
+        let generate = || {
+            PlaintextPrivateKey::generate(
+                String::new(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ed25519,
+                },
+            )
+        };
+        let host_key = generate();
+        let client_key = generate();
+        let user = users::get_user_by_uid(rustix::process::getuid().as_raw()).unwrap();
+        let username = user.name().to_str().unwrap().to_owned();
+
+        let config = format!(
+            r#"
+            net = {{}}
+            auth = {{ host_keys = [], authorized_keys_command = ["/bin/echo", "{}"], authorized_keys_command_user = "{username}" }}
+            security = {{ seccomp = true }}
+            "#,
+            client_key.private_key.public_key()
+        );
+        let config: Config = toml::from_str(&config).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_stream, peer_addr) = listener.accept().await.unwrap();
+
+        let mut rpc_server = crate::rpc::Server::new(
+            config.clone(),
+            vec![host_key.clone()],
+            UserSessions::default(),
+            peer_addr,
+            Arc::new(crate::pty::DevPtmx),
+        )
+        .unwrap();
+        let state_fd = MemFd::new(&SerializedConnectionState {
+            peer_addr,
+            pub_host_keys: vec![host_key.private_key.public_key()],
+            config,
+            setuid: None,
+            setgid: None,
+        })
+        .unwrap();
+
+        // A new process instead of a fork, so that the connection runs on the main thread
+        // of the process, which can't be taken over from the test harness. With a single arena,
+        // malloc grows the heap with brk like in the single threaded connection process.
+        let mut cmd = tokio::process::Command::new(std::env::current_exe().unwrap());
+        cmd.args(["--exact", "sandbox::tests::filtered_connection_process"])
+            .env(FILTERED_CONNECTION_ENV, "1")
+            .env("MALLOC_ARENA_MAX", "1")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        crate::pass_connection_fds(
+            &mut cmd,
+            state_fd.fd.as_raw_fd(),
+            server_stream.as_raw_fd(),
+            rpc_server.client_fd().as_raw_fd(),
+        );
+        let mut child = cmd.spawn().unwrap();
+        drop(server_stream);
+
+        let client = async {
+            let identities =
+                Identities::collect(vec![Arc::new(PrivateKeys(vec![client_key]))]).await;
+            let mut client = ClientConnection::connect(
+                client_stream,
+                ClientConfig::default(),
+                ClientAuth {
+                    username: username.clone(),
+                    batch_mode: true,
+                    methods: None,
+                    prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+                    public_keys: identities.public_keys(),
+                    sign_pubkey: identities.sign_pubkey(username.clone()),
+                },
+            )
+            .await
+            .unwrap();
+            let session = client.open_channel(ChannelKind::Session);
+            let progress = tokio::spawn(async move {
+                loop {
+                    client.progress().await?;
+                }
+                #[allow(unreachable_code)]
+                eyre::Ok(())
+            });
+
+            let mut session = session.wait_ready().await.unwrap();
+            session
+                .start_session(
+                    None,
+                    Some(format!("wc -c; head -c {LEN} /dev/zero").as_bytes()),
+                )
+                .await
+                .unwrap();
+            let mut stream = ChannelStream::new(session);
+            stream.write_all(&vec![1; LEN]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output).await.unwrap();
+
+            let expected_count = format!("{LEN}\n");
+            assert_eq!(&output[..expected_count.len()], expected_count.as_bytes());
+            assert_eq!(output.len(), expected_count.len() + LEN);
+            // Disconnect, which ends the connection process.
+            progress.abort();
+        };
+
+        let status = tokio::select! {
+            result = rpc_server.process() => panic!("monitor stopped: {result:?}"),
+            status = async {
+                client.await;
+                child.wait().await.unwrap()
+            } => status,
+        };
+        assert!(status.success(), "connection process failed: {status}");
+    }
 }
@@ -0,0 +1,87 @@
+//! Limiting how many connections a user may be logged in with at the same time.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// The users logged in over the connections of this server, shared by the RPC servers of all connections.
+/// Users are counted by uid, so that aliases with the same uid share the limit.
+#[derive(Clone, Default)]
+pub struct UserSessions {
+    counts: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+/// A session counted in [`UserSessions`], which ends when this is dropped.
+pub struct SessionGuard {
+    sessions: UserSessions,
+    uid: u32,
+}
+
+impl UserSessions {
+    /// Starts a session for the user, unless they already have `limit` sessions.
+    pub fn try_start(&self, uid: u32, limit: Option<usize>) -> Option<SessionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(uid).or_default();
+        if limit.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(SessionGuard {
+            sessions: self.clone(),
+            uid,
+        })
+    }
+
+    #[cfg(test)]
+    fn count(&self, uid: u32) -> usize {
+        self.counts.lock().unwrap().get(&uid).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.sessions.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.uid) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.uid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserSessions;
+
+    #[test]
+    fn limit_per_user() {
+        let sessions = UserSessions::default();
+
+        let first = sessions.try_start(1000, Some(2)).unwrap();
+        let _second = sessions.try_start(1000, Some(2)).unwrap();
+        assert!(sessions.try_start(1000, Some(2)).is_none());
+        assert_eq!(sessions.count(1000), 2);
+
+        // Other users have their own limit.
+        let _other = sessions.try_start(1001, Some(2)).unwrap();
+
+        drop(first);
+        assert_eq!(sessions.count(1000), 1);
+        let _third = sessions.try_start(1000, Some(2)).unwrap();
+    }
+
+    #[test]
+    fn unlimited() {
+        let sessions = UserSessions::default();
+        let guards = (0..100)
+            .map(|_| sessions.try_start(1000, None).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sessions.count(1000), 100);
+
+        drop(guards);
+        assert_eq!(sessions.count(1000), 0);
+        assert!(sessions.counts.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+//! Custom setup of session commands in the child process right before they are executed,
+//! like moving them into a cgroup or into the namespaces of a container.
+
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use eyre::{bail, Context, Result};
+use rustix::fs::{Mode, OFlags};
+use tokio::process::Command;
+use users::User;
+
+use crate::config::SetupStepConfig;
+
+/// The part of a [`SessionSetup`] that runs in the child process.
+pub type PreExec = Box<dyn FnMut() -> io::Result<()> + Send + Sync>;
+
+/// A step of setting up the process of a session, configured in `session.setup`.
+///
+/// Other steps can be implemented for this trait and added in [`steps`].
+pub trait SessionSetup: Send + Sync {
+    /// Prepares the step for a session of `user` in the monitor, returning what runs in the child.
+    ///
+    /// The child is forked from the multithreaded monitor, so the returned closure must only do
+    /// async-signal-safe things, mostly plain syscalls: no allocating, no locking and no logging.
+    /// Everything else, like expanding paths or opening files, has to be done here.
+    /// The closure runs as root, after the PTY has been set up, but before the chroot and before
+    /// the privileges are dropped. If it fails, the command is not executed.
+    fn prepare(&self, user: &User) -> Result<PreExec>;
+
+    /// Whether the step moves the command into another mount namespace.
+    ///
+    /// The chroot directory is checked in the mount namespace of the monitor, so such steps
+    /// can't be combined with `session.chroot_directory`.
+    fn enters_mount_namespace(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The steps configured in `session.setup`, in order.
+pub fn steps(config: &[SetupStepConfig]) -> Vec<Arc<dyn SessionSetup>> {
+    config
+        .iter()
+        .map(|step| -> Arc<dyn SessionSetup> {
+            match step {
+                SetupStepConfig::Cgroup { path } => Arc::new(Cgroup { path: path.clone() }),
+                SetupStepConfig::Namespace { path } => Arc::new(Namespace { path: path.clone() }),
+            }
+        })
+        .collect()
+}
+
+/// Runs the steps in the child process of `cmd`.
+pub fn apply(steps: &[Arc<dyn SessionSetup>], cmd: &mut Command, user: &User) -> Result<()> {
+    for step in steps {
+        let pre_exec = step.prepare(user)?;
+        unsafe {
+            cmd.pre_exec(pre_exec);
+        }
+    }
+    Ok(())
+}
+
+/// Moves the command into a cgroup (v2), like one that limits the resources of all sessions of the user.
+struct Cgroup {
+    path: String,
+}
+
+impl SessionSetup for Cgroup {
+    fn prepare(&self, user: &User) -> Result<PreExec> {
+        let path = crate::chroot::expand_path(&self.path, user)
+            .wrap_err("invalid cgroup path")?
+            .join("cgroup.procs");
+        let procs = open(&path, OFlags::WRONLY)?;
+        Ok(Box::new(move || {
+            // Writing 0 moves the writing process.
+            rustix::io::write(&procs, b"0")?;
+            Ok(())
+        }))
+    }
+}
+
+/// Enters a namespace, like `/proc/<pid>/ns/net` of a container.
+struct Namespace {
+    path: PathBuf,
+}
+
+impl SessionSetup for Namespace {
+    fn prepare(&self, _user: &User) -> Result<PreExec> {
+        let namespace = open(&self.path, OFlags::RDONLY)?;
+        Ok(Box::new(move || {
+            rustix::thread::move_into_link_name_space(namespace.as_fd(), None)?;
+            Ok(())
+        }))
+    }
+
+    fn enters_mount_namespace(&self) -> Result<bool> {
+        // NS_GET_NSTYPE from linux/nsfs.h, which is not in libc.
+        const NS_GET_NSTYPE: libc::c_ulong = 0xb703;
+        let namespace = open(&self.path, OFlags::RDONLY)?;
+        let kind = unsafe { libc::ioctl(namespace.as_raw_fd(), NS_GET_NSTYPE as _) };
+        if kind == -1 {
+            return Err(io::Error::last_os_error())
+                .wrap_err_with(|| format!("{} is not a namespace", self.path.display()));
+        }
+        Ok(kind == libc::CLONE_NEWNS)
+    }
+}
+
+/// Fails if any of the steps makes the chroot at `chroot_dir` unsafe, see
+/// [`SessionSetup::enters_mount_namespace`].
+pub fn check_chroot(steps: &[Arc<dyn SessionSetup>], chroot_dir: Option<&Path>) -> Result<()> {
+    let Some(chroot_dir) = chroot_dir else {
+        return Ok(());
+    };
+    for step in steps {
+        if step.enters_mount_namespace()? {
+            bail!(
+                "cannot chroot into {} after entering a mount namespace in session.setup",
+                chroot_dir.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn open(path: &Path, flags: OFlags) -> Result<OwnedFd> {
+    rustix::fs::open(path, flags | OFlags::CLOEXEC, Mode::empty())
+        .wrap_err_with(|| format!("failed to open {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use eyre::{bail, Result};
+    use users::User;
+
+    use super::{PreExec, SessionSetup};
+
+    struct Umask;
+
+    impl SessionSetup for Umask {
+        fn prepare(&self, _user: &User) -> Result<PreExec> {
+            Ok(Box::new(|| {
+                unsafe { libc::umask(0o077) };
+                Ok(())
+            }))
+        }
+    }
+
+    struct Unprepared;
+
+    impl SessionSetup for Unprepared {
+        fn prepare(&self, _user: &User) -> Result<PreExec> {
+            bail!("not available")
+        }
+    }
+
+    struct Failing;
+
+    impl SessionSetup for Failing {
+        fn prepare(&self, _user: &User) -> Result<PreExec> {
+            Ok(Box::new(|| {
+                Err(std::io::Error::from_raw_os_error(libc::EPERM))
+            }))
+        }
+    }
+
+    struct MountNamespace;
+
+    impl SessionSetup for MountNamespace {
+        fn prepare(&self, _user: &User) -> Result<PreExec> {
+            Ok(Box::new(|| Ok(())))
+        }
+
+        fn enters_mount_namespace(&self) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    fn user() -> User {
+        users::get_user_by_uid(rustix::process::getuid().as_raw()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn steps_run_in_child() {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg("umask");
+        super::apply(&[Arc::new(Umask)], &mut cmd, &user()).unwrap();
+        let output = cmd.output().await.unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "0077\n");
+    }
+
+    #[tokio::test]
+    async fn failing_steps_prevent_command() {
+        let mut cmd = tokio::process::Command::new("true");
+        assert!(super::apply(&[Arc::new(Unprepared)], &mut cmd, &user()).is_err());
+
+        let mut cmd = tokio::process::Command::new("true");
+        super::apply(&[Arc::new(Failing)], &mut cmd, &user()).unwrap();
+        assert!(cmd.output().await.is_err());
+    }
+
+    #[test]
+    fn namespace_type() {
+        let namespace = |path: &str| super::Namespace { path: path.into() };
+        assert!(namespace("/proc/self/ns/mnt")
+            .enters_mount_namespace()
+            .unwrap());
+        assert!(!namespace("/proc/self/ns/net")
+            .enters_mount_namespace()
+            .unwrap());
+        assert!(namespace("/proc/self/status")
+            .enters_mount_namespace()
+            .is_err());
+    }
+
+    #[test]
+    fn mount_namespace_with_chroot() {
+        let chroot = Some(Path::new("/srv/chroot"));
+        let steps: [Arc<dyn SessionSetup>; 2] = [Arc::new(Umask), Arc::new(MountNamespace)];
+        super::check_chroot(&steps[..1], chroot).unwrap();
+        super::check_chroot(&steps, None).unwrap();
+        assert!(super::check_chroot(&steps, chroot).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+//! Serving SFTP from the daemon itself, like OpenSSH's `internal-sftp`.
+//!
+//! The monitor spawns the daemon again as the session command of the user, which drops its
+//! privileges and serves SFTP on its stdio. As nothing is executed after dropping privileges,
+//! this needs neither a shell nor a PTY, and works in a chroot that contains no programs at all.
+
+use std::{
+    os::fd::{AsFd, FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use users::{os::unix::UserExt, User};
+
+use crate::{connection::AsyncFdWrapper, setup::SessionSetup, MemFd};
+
+/// The subsystem path and forced command that make the daemon serve SFTP itself.
+pub const INTERNAL_SFTP: &str = "internal-sftp";
+
+const PRIVSEP_SFTP_STATE_FD: RawFd = 3;
+
+/// The state passed to the SFTP process in the STATE_FD
+#[derive(Serialize, Deserialize)]
+struct SerializedSftpState {
+    uid: u32,
+    gid: u32,
+    /// The supplementary groups, only set if the daemon runs as root.
+    groups: Option<Vec<u32>>,
+    chroot_dir: Option<PathBuf>,
+    /// The directory to start in, inside of the chroot.
+    directory: PathBuf,
+    /// Whether to restrict the process with a seccomp filter, see `security.seccomp`.
+    seccomp: bool,
+}
+
+impl SerializedSftpState {
+    fn new(
+        user: &User,
+        chroot_dir: Option<PathBuf>,
+        directory: Option<&str>,
+        seccomp: bool,
+    ) -> Result<Self> {
+        let directory = match directory {
+            Some(directory) => crate::chroot::expand_path(directory, user)
+                .wrap_err("invalid internal-sftp directory")?,
+            None if chroot_dir.is_some() => PathBuf::from("/"),
+            None => user.home_dir().to_owned(),
+        };
+
+        Ok(Self {
+            uid: user.uid(),
+            gid: user.primary_group_id(),
+            groups: crate::rpc::supplementary_groups(user)?,
+            chroot_dir,
+            directory,
+            seccomp,
+        })
+    }
+}
+
+/// The command that serves SFTP to `user` on its stdio, starting in `directory`,
+/// after running the `setup` steps of the session.
+///
+/// The command starts with the privileges of the daemon, it drops them itself before serving,
+/// and restricts itself with a seccomp filter if `seccomp` is set.
+pub fn command(
+    user: &User,
+    chroot_dir: Option<PathBuf>,
+    directory: Option<&str>,
+    setup: &[Arc<dyn SessionSetup>],
+    seccomp: bool,
+) -> Result<Command> {
+    let state = SerializedSftpState::new(user, chroot_dir, directory, seccomp)?;
+    let state = MemFd::new(&state)?;
+
+    let exe = std::env::current_exe().wrap_err("failed to get current executable path")?;
+    let mut cmd = Command::new(exe);
+    cmd.env_clear();
+    cmd.env("CLUELESSH_PRIVSEP_PROCESS", "sftp");
+    // The steps use FDs opened by the monitor, so they have to run before those are closed.
+    crate::setup::apply(setup, &mut cmd, user)?;
+
+    unsafe {
+        cmd.pre_exec(move || {
+            let mut new_state_fd = OwnedFd::from_raw_fd(PRIVSEP_SFTP_STATE_FD);
+            rustix::io::dup2(state.fd.as_fd(), &mut new_state_fd)?;
+
+            // Ensure that all FDs of the monitor are closed except stdio and the state.
+            // libc close_range is not async-signal-safe, so syscall directly.
+            let result = libc::syscall(
+                libc::SYS_close_range,
+                (PRIVSEP_SFTP_STATE_FD as u32) + 1,
+                std::ffi::c_uint::MAX,
+                0,
+            );
+            if result.is_negative() {
+                return Err(std::io::Error::from_raw_os_error(-(result as i32)));
+            }
+
+            // Ensure our new FD stays open, as it will be acquired in the new process.
+            std::mem::forget(new_state_fd);
+            Ok(())
+        });
+    }
+
+    Ok(cmd)
+}
+
+/// The entrypoint of the SFTP process.
+pub fn sftp() -> Result<()> {
+    let state = unsafe { MemFd::<SerializedSftpState>::from_raw_fd(PRIVSEP_SFTP_STATE_FD) }
+        .wrap_err("failed to open memfd")?
+        .read()
+        .wrap_err("failed to read state")?;
+
+    drop_privileges(&state)?;
+
+    let stdin = rustix::stdio::stdin().try_clone_to_owned()?;
+    let stdout = rustix::stdio::stdout().try_clone_to_owned()?;
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(serve(stdin, stdout))
+}
+
+/// Becomes the user in the same order as for other session commands, see `rpc::become_user`.
+fn drop_privileges(state: &SerializedSftpState) -> Result<()> {
+    if let Some(dir) = &state.chroot_dir {
+        rustix::process::chroot(dir).wrap_err("failed to chroot")?;
+        rustix::process::chdir("/").wrap_err("failed to chdir into chroot")?;
+    }
+    if let Some(groups) = &state.groups {
+        let result = unsafe { libc::setgroups(groups.len(), groups.as_ptr()) };
+        if result == -1 {
+            return Err(std::io::Error::last_os_error()).wrap_err("failed to setgroups");
+        }
+    }
+    let result = unsafe { libc::setgid(state.gid) };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error()).wrap_err("failed to setgid");
+    }
+    let result = unsafe { libc::setuid(state.uid) };
+    if result == -1 {
+        return Err(std::io::Error::last_os_error()).wrap_err("failed to setuid");
+    }
+
+    rustix::thread::set_no_new_privs(true)?;
+
+    // Only after becoming the user, so that they can't start in a directory they have no access to.
+    rustix::process::chdir(&state.directory).wrap_err_with(|| {
+        format!(
+            "failed to change to directory {}",
+            state.directory.display()
+        )
+    })?;
+
+    if state.seccomp {
+        crate::sandbox::sftp_seccomp().wrap_err("setting up seccomp")?;
+    }
+
+    Ok(())
+}
+
+async fn serve(input: OwnedFd, output: OwnedFd) -> Result<()> {
+    let input = AsyncFdWrapper::from_fd(input)?;
+    let output = AsyncFdWrapper::from_fd(output)?;
+
+    let mut server = cluelessh_sftp::SftpServer::new(input, output);
+    server.serve().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::fd::OwnedFd,
+        path::{Path, PathBuf},
+        process::Stdio,
+        sync::Arc,
+    };
+
+    use cluelessh_format::{numbers, Reader, Writer};
+    use eyre::Result;
+    use rustix::fs::{Mode, OFlags};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+    use users::{os::unix::UserExt, User};
+
+    use super::{SerializedSftpState, PRIVSEP_SFTP_STATE_FD};
+    use crate::{
+        setup::{PreExec, SessionSetup},
+        MemFd,
+    };
+
+    /// Writes to an FD opened by the monitor, like the cgroup step.
+    struct WriteNull;
+
+    impl SessionSetup for WriteNull {
+        fn prepare(&self, _user: &User) -> Result<PreExec> {
+            let null: OwnedFd = rustix::fs::open("/dev/null", OFlags::WRONLY, Mode::empty())?;
+            Ok(Box::new(move || {
+                rustix::io::write(&null, b"0")?;
+                Ok(())
+            }))
+        }
+    }
+
+    #[test]
+    fn start_directory() {
+        let uid = rustix::process::getuid().as_raw();
+        let user = users::get_user_by_uid(uid).unwrap();
+
+        let state = SerializedSftpState::new(&user, None, None, false).unwrap();
+        assert_eq!(state.directory, user.home_dir());
+
+        let state = SerializedSftpState::new(&user, Some("/srv".into()), None, false).unwrap();
+        assert_eq!(state.directory, Path::new("/"));
+
+        let state = SerializedSftpState::new(&user, None, Some("/srv/sftp/%u"), false).unwrap();
+        let name = user.name().to_str().unwrap();
+        assert_eq!(state.directory, PathBuf::from(format!("/srv/sftp/{name}")));
+
+        let state = SerializedSftpState::new(&user, None, Some("uploads"), false).unwrap();
+        assert_eq!(state.directory, user.home_dir().join("uploads"));
+    }
+
+    #[tokio::test]
+    async fn setup_before_closing_fds() {
+        let uid = rustix::process::getuid().as_raw();
+        let user = users::get_user_by_uid(uid).unwrap();
+
+        let mut cmd = super::command(&user, None, None, &[Arc::new(WriteNull)], false).unwrap();
+        // This executes the test binary, which only lists the tests instead of serving SFTP.
+        cmd.arg("--list").stdout(Stdio::null());
+        let status = cmd.status().await.unwrap();
+        assert!(status.success());
+    }
+
+    /// The SFTP process of [`transfer_with_filter`], which does nothing in a normal test run.
+    /// It serves on a socket in stdin, as the test harness writes to stdout.
+    #[test]
+    fn sftp_process() {
+        if std::env::var("CLUELESSH_PRIVSEP_PROCESS").as_deref() != Ok("sftp") {
+            return;
+        }
+
+        let state = unsafe { MemFd::<SerializedSftpState>::from_raw_fd(PRIVSEP_SFTP_STATE_FD) }
+            .unwrap()
+            .read()
+            .unwrap();
+        super::drop_privileges(&state).unwrap();
+
+        let socket = rustix::stdio::stdin();
+        let result = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(super::serve(
+                socket.try_clone_to_owned().unwrap(),
+                socket.try_clone_to_owned().unwrap(),
+            ));
+        std::process::exit(if result.is_ok() { 0 } else { 1 });
+    }
+
+    /// Just enough of an SFTP client for [`transfer_with_filter`].
+    struct Client {
+        stream: UnixStream,
+        next_id: u32,
+    }
+
+    impl Client {
+        async fn send(&mut self, payload: Vec<u8>) {
+            self.stream
+                .write_all(&(payload.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            self.stream.write_all(&payload).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> (u8, Vec<u8>) {
+            let len = self.stream.read_u32().await.unwrap();
+            let mut payload = vec![0; len as usize];
+            self.stream.read_exact(&mut payload).await.unwrap();
+            (payload[0], payload[1..].to_vec())
+        }
+
+        /// Sends a request, returning the type and the rest of the response after the ID.
+        async fn request(
+            &mut self,
+            packet_type: u8,
+            body: impl FnOnce(&mut Writer),
+        ) -> (u8, Vec<u8>) {
+            let id = self.next_id;
+            self.next_id += 1;
+            let mut w = Writer::new();
+            w.u8(packet_type);
+            w.u32(id);
+            body(&mut w);
+            self.send(w.finish()).await;
+
+            let (response_type, response) = self.receive().await;
+            let mut p = Reader::new(&response);
+            assert_eq!(p.u32().unwrap(), id);
+            (response_type, p.remaining().to_vec())
+        }
+
+        async fn status(&mut self, packet_type: u8, body: impl FnOnce(&mut Writer)) -> u32 {
+            let (response_type, response) = self.request(packet_type, body).await;
+            assert_eq!(response_type, numbers::SSH_FXP_STATUS);
+            Reader::new(&response).u32().unwrap()
+        }
+
+        async fn open(&mut self, path: &str, pflags: u32) -> Vec<u8> {
+            let (response_type, response) = self
+                .request(numbers::SSH_FXP_OPEN, |w| {
+                    w.string(path);
+                    w.u32(pflags);
+                    w.u32(0); // attrs
+                })
+                .await;
+            assert_eq!(response_type, numbers::SSH_FXP_HANDLE);
+            Reader::new(&response).string().unwrap().to_vec()
+        }
+
+        /// The size in the attributes of a STAT or FSTAT response.
+        async fn size(&mut self, packet_type: u8, name: &[u8]) -> u64 {
+            let (response_type, response) = self.request(packet_type, |w| w.string(name)).await;
+            assert_eq!(response_type, numbers::SSH_FXP_ATTRS);
+            let mut p = Reader::new(&response);
+            assert_ne!(p.u32().unwrap() & numbers::SSH_FILEXFER_ATTR_SIZE, 0);
+            p.u64().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    async fn transfer_with_filter() {
+        let uid = rustix::process::getuid().as_raw();
+        let user = users::get_user_by_uid(uid).unwrap();
+        let dir = std::env::temp_dir().join(format!("cluelesshd-sftp-{}", std::process::id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        let mut cmd = super::command(&user, None, dir.to_str(), &[], true).unwrap();
+        let (socket, child_socket) = std::os::unix::net::UnixStream::pair().unwrap();
+        cmd.args(["--exact", "sftp::tests::sftp_process"])
+            .stdin(Stdio::from(OwnedFd::from(child_socket)))
+            .stdout(Stdio::null());
+        let mut child = cmd.spawn().unwrap();
+        // Closes our copy of the socket of the child, so that requests fail if it dies.
+        drop(cmd);
+
+        socket.set_nonblocking(true).unwrap();
+        let mut client = Client {
+            stream: UnixStream::from_std(socket).unwrap(),
+            next_id: 0,
+        };
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_FXP_INIT);
+        w.u32(3); // version
+        client.send(w.finish()).await;
+        assert_eq!(client.receive().await.0, numbers::SSH_FXP_VERSION);
+
+        let content = (0..100_000_u32).map(|i| i as u8).collect::<Vec<_>>();
+        let handle = client
+            .open(
+                "upload",
+                numbers::SSH_FXF_WRITE | numbers::SSH_FXF_CREAT | numbers::SSH_FXF_TRUNC,
+            )
+            .await;
+        for (i, chunk) in content.chunks(32768).enumerate() {
+            let status = client
+                .status(numbers::SSH_FXP_WRITE, |w| {
+                    w.string(&handle);
+                    w.u64(i as u64 * 32768);
+                    w.string(chunk);
+                })
+                .await;
+            assert_eq!(status, numbers::SSH_FX_OK);
+        }
+        let status = client
+            .status(numbers::SSH_FXP_CLOSE, |w| w.string(&handle))
+            .await;
+        assert_eq!(status, numbers::SSH_FX_OK);
+        assert_eq!(std::fs::read(dir.join("upload")).unwrap(), content);
+
+        let size = client.size(numbers::SSH_FXP_STAT, b"upload").await;
+        assert_eq!(size, content.len() as u64);
+        let status = client
+            .status(numbers::SSH_FXP_LSTAT, |w| w.string("missing"))
+            .await;
+        assert_eq!(status, numbers::SSH_FX_NO_SUCH_FILE);
+
+        let handle = client.open("upload", numbers::SSH_FXF_READ).await;
+        assert_eq!(
+            client.size(numbers::SSH_FXP_FSTAT, &handle).await,
+            content.len() as u64
+        );
+        let mut download = Vec::new();
+        loop {
+            let (response_type, response) = client
+                .request(numbers::SSH_FXP_READ, |w| {
+                    w.string(&handle);
+                    w.u64(download.len() as u64);
+                    w.u32(65536);
+                })
+                .await;
+            let mut p = Reader::new(&response);
+            if response_type == numbers::SSH_FXP_STATUS {
+                assert_eq!(p.u32().unwrap(), numbers::SSH_FX_EOF);
+                break;
+            }
+            assert_eq!(response_type, numbers::SSH_FXP_DATA);
+            download.extend_from_slice(p.string().unwrap());
+        }
+        assert_eq!(download, content);
+
+        let status = client
+            .status(numbers::SSH_FXP_MKDIR, |w| {
+                w.string("new");
+                w.u32(0); // attrs
+            })
+            .await;
+        assert_eq!(status, numbers::SSH_FX_OP_UNSUPPORTED);
+
+        drop(client);
+        let status = child.wait().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(status.success());
+    }
+}
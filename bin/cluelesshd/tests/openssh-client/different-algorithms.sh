@@ -2,11 +2,17 @@
 
 # KEX
 ssh -oKexAlgorithms=curve25519-sha256 -p "$PORT" "$HOST" true
+ssh -oKexAlgorithms=curve25519-sha256@libssh.org -p "$PORT" "$HOST" true
 ssh -oKexAlgorithms=ecdh-sha2-nistp256 -p "$PORT" "$HOST" true
+ssh -oKexAlgorithms=diffie-hellman-group14-sha256 -p "$PORT" "$HOST" true
 
 # Encryption
 ssh -oCiphers=chacha20-poly1305@openssh.com -p "$PORT" "$HOST" true
 ssh -oCiphers=aes256-gcm@openssh.com -p "$PORT" "$HOST" true
+ssh -oCiphers=aes128-gcm@openssh.com -p "$PORT" "$HOST" true
 
 # Host Key
 ssh -oHostKeyAlgorithms=ssh-ed25519 -p "$PORT" "$HOST" true
+
+# Compression
+ssh -oCompression=yes -p "$PORT" "$HOST" "head -c 100000 /dev/zero" | wc -c | grep -Fx 100000
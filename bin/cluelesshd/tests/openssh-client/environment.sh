@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+
+passwd_entry=$(getent passwd "$(id -un)")
+home=$(echo "$passwd_entry" | cut -d: -f6)
+shell=$(echo "$passwd_entry" | cut -d: -f7)
+
+ssh -p "$PORT" "$HOST" 'echo "$HOME"' | grep -Fx "$home"
+ssh -p "$PORT" "$HOST" 'echo "$SHELL"' | grep -Fx "$shell"
+ssh -p "$PORT" "$HOST" 'echo "$LOGNAME"' | grep -Fx "$(id -un)"
+ssh -p "$PORT" "$HOST" 'test -n "$PATH"'
@@ -0,0 +1,3 @@
+#!/usr/bin/env bash
+
+printf $'echo "arg0:$0"\rexit\r' | ssh -oRequestTTY=force -p "$PORT" "$HOST" | grep -F 'arg0:-'
@@ -2,10 +2,110 @@ use std::cmp;
 use std::collections::{HashMap, VecDeque};
 use tracing::{debug, info, trace, warn};
 
-use cluelessh_format::numbers;
+use cluelessh_format::{numbers, Writer};
+use cluelessh_keys::public::PublicKey;
+use cluelessh_keys::signature::Signature;
 use cluelessh_transport::packet::Packet;
 use cluelessh_transport::peer_error;
-use cluelessh_transport::Result;
+use cluelessh_transport::{Result, SessionId};
+
+/// A global request from the peer that is passed on to the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GlobalRequest {
+    /// `hostkeys-00@openssh.com`, with which servers announce all of their host keys,
+    /// so that clients can learn new keys before the old ones are rotated out.
+    /// Keys with unsupported algorithms are left out.
+    /// <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL> (section 2.5)
+    HostKeys(Vec<PublicKey>),
+}
+
+/// Handles a SSH_MSG_GLOBAL_REQUEST. Returns the request if the application handles it,
+/// and the failure reply if the peer wants one, as no request is answered with success.
+///
+/// Servers may send global requests like `hostkeys-00@openssh.com` before authentication
+/// has finished, so this is not tied to the [`ChannelsState`].
+pub fn recv_global_request(packet: &Packet) -> Result<(Option<GlobalRequest>, Option<Packet>)> {
+    // <https://datatracker.ietf.org/doc/html/rfc4254#section-4>
+    let mut p = packet.payload_parser();
+    let packet_type = p.u8()?;
+    if packet_type != numbers::SSH_MSG_GLOBAL_REQUEST {
+        return Err(peer_error!(
+            "expected SSH_MSG_GLOBAL_REQUEST, found {}",
+            numbers::packet_type_to_string(packet_type)
+        ));
+    }
+    let request_name = p.utf8_string()?;
+    let want_reply = p.bool()?;
+    debug!(%request_name, %want_reply, "Received global request");
+
+    let request = match request_name {
+        "hostkeys-00@openssh.com" => {
+            let mut keys = Vec::new();
+            while p.has_data() {
+                match PublicKey::from_wire_encoding(p.string()?) {
+                    Ok(key) => keys.push(key),
+                    Err(err) => debug!(%err, "Ignoring unsupported host key"),
+                }
+            }
+            Some(GlobalRequest::HostKeys(keys))
+        }
+        _ => None,
+    };
+
+    Ok((request, want_reply.then(Packet::new_msg_request_failure)))
+}
+
+/// How many global requests of the peer can wait for the application,
+/// far more than the single `hostkeys-00@openssh.com` servers send after each key exchange.
+pub const MAX_QUEUED_GLOBAL_REQUESTS: usize = 16;
+
+/// Queues a request returned by [`recv_global_request`] for the application,
+/// failing if the peer sent more than [`MAX_QUEUED_GLOBAL_REQUESTS`] it hasn't taken yet.
+pub fn queue_global_request(
+    queue: &mut VecDeque<GlobalRequest>,
+    request: GlobalRequest,
+) -> Result<()> {
+    if queue.len() >= MAX_QUEUED_GLOBAL_REQUESTS {
+        return Err(peer_error!(
+            "peer sent more than {MAX_QUEUED_GLOBAL_REQUESTS} global requests that were not handled yet"
+        ));
+    }
+    queue.push_back(request);
+    Ok(())
+}
+
+/// A global request we have sent, see [`ChannelsState::pending_global_requests`].
+enum PendingGlobalRequest {
+    Keepalive,
+    HostKeysProve {
+        session_id: SessionId,
+        keys: Vec<PublicKey>,
+    },
+}
+
+/// Checks the signatures of the reply to a `hostkeys-prove-00@openssh.com` request,
+/// one for each key, and returns the keys if all of them are valid.
+fn verify_host_keys_proof(
+    session_id: SessionId,
+    keys: Vec<PublicKey>,
+    p: &mut cluelessh_format::Reader<'_>,
+) -> Result<Vec<PublicKey>> {
+    for key in &keys {
+        let signature = p.string()?;
+        let mut data = Writer::new();
+        data.string(b"hostkeys-prove-00@openssh.com");
+        data.string(session_id.0);
+        data.string(key.to_wire_encoding());
+
+        let valid = Signature::from_wire_encoding(signature)
+            .is_ok_and(|signature| key.verify_signature(&data.finish(), &signature));
+        if !valid {
+            warn!(key = %key.fingerprint_sha256(), "Server sent an invalid proof for host key");
+            return Ok(Vec::new());
+        }
+    }
+    Ok(keys)
+}
 
 /// A channel number (on our side).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -20,13 +120,35 @@ impl std::fmt::Display for ChannelNumber {
 pub struct ChannelsState {
     packets_to_send: VecDeque<Packet>,
     channel_updates: VecDeque<ChannelUpdate>,
+    global_requests: VecDeque<GlobalRequest>,
 
     channels: HashMap<ChannelNumber, ChannelState>,
     next_channel_id: ChannelNumber,
+    max_forward_channels: usize,
+    /// Global requests we have sent that still need a reply, in the order they were sent.
+    pending_global_requests: VecDeque<PendingGlobalRequest>,
+    /// See [`ChannelsState::next_proven_host_keys`].
+    proven_host_keys: VecDeque<Vec<PublicKey>>,
+    /// See [`ChannelsState::set_window_adjust_threshold`].
+    window_adjust_threshold: Option<u32>,
+    /// See [`ChannelsState::set_initial_window_size`].
+    initial_window_size: u32,
+    /// See [`ChannelsState::set_window_adjust_on_consumption`].
+    window_adjust_on_consumption: bool,
+    /// See [`ChannelsState::set_agent_forwarding`].
+    agent_forwarding: bool,
+    /// See [`ChannelsState::set_debug_log_channel`].
+    debug_log_channel: bool,
 
     is_server: bool,
 }
 
+/// The default limit on simultaneous port forwarding channels for a connection.
+pub const DEFAULT_MAX_FORWARD_CHANNELS: usize = 64;
+
+/// The default window we grant the peer on channels we open, the same as OpenSSH.
+pub const DEFAULT_INITIAL_WINDOW_SIZE: u32 = 2 * 1024 * 1024;
+
 enum ChannelState {
     AwaitingConfirmation {
         /// For validation only.
@@ -38,7 +160,17 @@ enum ChannelState {
     Open(Channel),
 }
 
+impl ChannelState {
+    fn kind(&self) -> &ChannelKind {
+        match self {
+            Self::AwaitingConfirmation { update_message, .. } => update_message,
+            Self::Open(channel) => &channel.kind,
+        }
+    }
+}
+
 struct Channel {
+    kind: ChannelKind,
     /// Whether our side has closed this channel.
     we_closed: bool,
     /// The channel number for the other side.
@@ -53,13 +185,27 @@ struct Channel {
     our_window_size: u32,
     /// For validation only.
     our_max_packet_size: u32,
-    /// By how much we want to increase the window when it gets small.
-    our_window_size_increase_step: u32,
+    /// The window size we grant the peer, which we restore once it has sent enough data.
+    our_max_window_size: u32,
+    /// Received bytes that have been consumed, but not been given back to the peer yet.
+    our_window_consumed: u32,
 
     /// Queued data that we want to send, but have not been able to because of the window limits.
     /// Whenever we get more window space, we will send this data.
     queued_data_default: Vec<u8>,
     queued_data_extended: HashMap<u32, Vec<u8>>,
+    /// Operations that have to wait until the queued data has been sent, so that they don't overtake it.
+    queued_operations: VecDeque<ChannelOperationKind>,
+}
+
+impl Channel {
+    fn has_queued_data(&self) -> bool {
+        !self.queued_data_default.is_empty()
+            || self
+                .queued_data_extended
+                .values()
+                .any(|data| !data.is_empty())
+    }
 }
 
 /// An update from a channel.
@@ -84,6 +230,98 @@ pub enum ChannelUpdateKind {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChannelKind {
     Session,
+    /// `debug-log@cluelessh`, a channel that the server sends its log lines for the connection over.
+    DebugLog,
+    /// A connection from the client to be forwarded to a TCP address by the server.
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-7.2>
+    DirectTcpip {
+        host_to_connect: String,
+        port_to_connect: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    /// A connection to a remotely forwarded TCP port, opened by the server.
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-7.2>
+    ForwardedTcpip {
+        connected_address: String,
+        connected_port: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    /// `direct-streamlocal@openssh.com`, like [`ChannelKind::DirectTcpip`] for Unix sockets.
+    /// <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL>
+    DirectStreamlocal {
+        socket_path: String,
+    },
+    /// `forwarded-streamlocal@openssh.com`, like [`ChannelKind::ForwardedTcpip`] for Unix sockets.
+    ForwardedStreamlocal {
+        socket_path: String,
+    },
+    /// `auth-agent@openssh.com`, a connection to the SSH agent of the client, opened by the server
+    /// after the client has requested agent forwarding with [`ChannelRequest::AuthAgentReq`].
+    /// <https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent>
+    AuthAgent,
+}
+
+impl ChannelKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Session => "session",
+            Self::DebugLog => "debug-log@cluelessh",
+            Self::DirectTcpip { .. } => "direct-tcpip",
+            Self::ForwardedTcpip { .. } => "forwarded-tcpip",
+            Self::DirectStreamlocal { .. } => "direct-streamlocal@openssh.com",
+            Self::ForwardedStreamlocal { .. } => "forwarded-streamlocal@openssh.com",
+            Self::AuthAgent => "auth-agent@openssh.com",
+        }
+    }
+
+    /// Whether this is a port forwarding channel, which count towards the forwarding limit.
+    pub fn is_forwarding(&self) -> bool {
+        match self {
+            Self::Session | Self::DebugLog => false,
+            Self::DirectTcpip { .. }
+            | Self::ForwardedTcpip { .. }
+            | Self::DirectStreamlocal { .. }
+            | Self::ForwardedStreamlocal { .. }
+            | Self::AuthAgent => true,
+        }
+    }
+
+    /// The channel type specific data at the end of SSH_MSG_CHANNEL_OPEN.
+    fn type_specific_data(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Self::Session | Self::DebugLog | Self::AuthAgent => {}
+            Self::DirectTcpip {
+                host_to_connect: address,
+                port_to_connect: port,
+                originator_address,
+                originator_port,
+            }
+            | Self::ForwardedTcpip {
+                connected_address: address,
+                connected_port: port,
+                originator_address,
+                originator_port,
+            } => {
+                w.string(address);
+                w.u32(*port);
+                w.string(originator_address);
+                w.u32(*originator_port);
+            }
+            Self::DirectStreamlocal { socket_path } => {
+                w.string(socket_path);
+                w.string(b""); // reserved
+                w.u32(0); // reserved
+            }
+            Self::ForwardedStreamlocal { socket_path } => {
+                w.string(socket_path);
+                w.string(b""); // reserved
+            }
+        }
+        w.finish()
+    }
 }
 #[derive(Debug)]
 pub enum ChannelRequest {
@@ -116,9 +354,34 @@ pub enum ChannelRequest {
         name: String,
         value: Vec<u8>,
     },
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-6.7>
+    WindowChange {
+        width_chars: u32,
+        height_rows: u32,
+        width_px: u32,
+        height_px: u32,
+    },
+    /// <https://datatracker.ietf.org/doc/html/rfc4254#section-6.9>
+    Signal {
+        /// The signal name without the "SIG" prefix, for example `INT`.
+        name: String,
+    },
+    /// Sends a BREAK condition, for example to a serial console.
+    /// <https://datatracker.ietf.org/doc/html/rfc4335>
+    Break {
+        want_reply: bool,
+        /// The length of the break in milliseconds.
+        length_ms: u32,
+    },
     ExitStatus {
         status: u32,
     },
+    /// `auth-agent-req@openssh.com`, asks the server to forward connections to the SSH agent
+    /// of the client over [`ChannelKind::AuthAgent`] channels.
+    /// <https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent>
+    AuthAgentReq {
+        want_reply: bool,
+    },
 }
 
 impl ChannelNumber {
@@ -151,12 +414,70 @@ impl ChannelsState {
             packets_to_send: VecDeque::new(),
             channels: HashMap::new(),
             channel_updates: VecDeque::new(),
+            global_requests: VecDeque::new(),
             next_channel_id: ChannelNumber(0),
+            max_forward_channels: DEFAULT_MAX_FORWARD_CHANNELS,
+            pending_global_requests: VecDeque::new(),
+            proven_host_keys: VecDeque::new(),
+            window_adjust_threshold: None,
+            initial_window_size: DEFAULT_INITIAL_WINDOW_SIZE,
+            window_adjust_on_consumption: false,
+            agent_forwarding: false,
+            debug_log_channel: false,
 
             is_server,
         }
     }
 
+    /// Limits how many port forwarding channels the peer may have open at the same time.
+    /// Additional ones are refused with SSH_OPEN_RESOURCE_SHORTAGE.
+    pub fn set_max_forward_channels(&mut self, max: usize) {
+        self.max_forward_channels = max;
+    }
+
+    /// Sets how many bytes the peer has to send on a channel before they are acknowledged
+    /// with a single SSH_MSG_CHANNEL_WINDOW_ADJUST, restoring the full window.
+    /// Larger thresholds send fewer adjustments, but the peer may have to wait for them.
+    /// Defaults to half of the window, and is capped to the window size.
+    pub fn set_window_adjust_threshold(&mut self, threshold: u32) {
+        self.window_adjust_threshold = Some(threshold);
+    }
+
+    /// Sets the window we grant the peer on channels we open,
+    /// defaults to [`DEFAULT_INITIAL_WINDOW_SIZE`].
+    pub fn set_initial_window_size(&mut self, size: u32) {
+        self.initial_window_size = size;
+    }
+
+    /// Only gives the window back to the peer once the received data has been passed to
+    /// [`ChannelsState::data_consumed`], instead of as soon as it is received.
+    /// The peer then has to wait when the application is slower than it,
+    /// instead of the data piling up in memory.
+    pub fn set_window_adjust_on_consumption(&mut self, enabled: bool) {
+        self.window_adjust_on_consumption = enabled;
+    }
+
+    /// Accepts `auth-agent@openssh.com` channels from the peer, which are refused with
+    /// SSH_OPEN_ADMINISTRATIVELY_PROHIBITED otherwise.
+    /// Only enable this for servers that are trusted with the agent, as they can use its keys
+    /// for as long as the connection is open.
+    pub fn set_agent_forwarding(&mut self, enabled: bool) {
+        self.agent_forwarding = enabled;
+    }
+
+    /// Accepts `debug-log@cluelessh` channels from the peer, which are refused with
+    /// SSH_OPEN_ADMINISTRATIVELY_PROHIBITED otherwise.
+    pub fn set_debug_log_channel(&mut self, enabled: bool) {
+        self.debug_log_channel = enabled;
+    }
+
+    fn forward_channel_count(&self) -> usize {
+        self.channels
+            .values()
+            .filter(|channel| channel.kind().is_forwarding())
+            .count()
+    }
+
     pub fn recv_packet(&mut self, packet: Packet) -> Result<()> {
         // TODO: what if we mostly ignored window and just always increased it again?
         // there's an excention to ignore it entirely that we could also support...
@@ -164,12 +485,33 @@ impl ChannelsState {
         let packet_type = p.u8()?;
         match packet_type {
             numbers::SSH_MSG_GLOBAL_REQUEST => {
-                let request_name = p.utf8_string()?;
-                let want_reply = p.bool()?;
-                debug!(%request_name, %want_reply, "Received global request");
-
-                self.packets_to_send
-                    .push_back(Packet::new_msg_request_failure());
+                let (request, reply) = recv_global_request(&packet)?;
+                // Only servers send the requests we handle, so a client can't make them pile up.
+                if let Some(request) = request.filter(|_| !self.is_server) {
+                    queue_global_request(&mut self.global_requests, request)?;
+                }
+                if let Some(reply) = reply {
+                    self.packets_to_send.push_back(reply);
+                }
+            }
+            numbers::SSH_MSG_REQUEST_SUCCESS | numbers::SSH_MSG_REQUEST_FAILURE => {
+                // Replies are sent in the order of the requests.
+                let Some(request) = self.pending_global_requests.pop_front() else {
+                    return Err(peer_error!("unexpected global request reply"));
+                };
+                match request {
+                    // The reply itself is all that matters.
+                    PendingGlobalRequest::Keepalive => {}
+                    PendingGlobalRequest::HostKeysProve { session_id, keys } => {
+                        let proven = if packet_type == numbers::SSH_MSG_REQUEST_SUCCESS {
+                            verify_host_keys_proof(session_id, keys, &mut p)?
+                        } else {
+                            debug!("Server refused to prove its host keys");
+                            Vec::new()
+                        };
+                        self.proven_host_keys.push_back(proven);
+                    }
+                }
             }
             numbers::SSH_MSG_CHANNEL_OPEN => {
                 // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.1>
@@ -182,6 +524,57 @@ impl ChannelsState {
 
                 let update_message = match channel_type {
                     "session" => ChannelKind::Session,
+                    "debug-log@cluelessh" => {
+                        if !self.debug_log_channel {
+                            debug!("Refusing debug log channel, it is not allowed");
+                            self.packets_to_send
+                                .push_back(Packet::new_msg_channel_open_failure(
+                                    sender_channel,
+                                    numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                    b"debug log channel is not allowed",
+                                    b"",
+                                ));
+                            return Ok(());
+                        }
+                        ChannelKind::DebugLog
+                    }
+                    "direct-tcpip" => ChannelKind::DirectTcpip {
+                        host_to_connect: p.utf8_string()?.to_owned(),
+                        port_to_connect: p.u32()?,
+                        originator_address: p.utf8_string()?.to_owned(),
+                        originator_port: p.u32()?,
+                    },
+                    "forwarded-tcpip" => ChannelKind::ForwardedTcpip {
+                        connected_address: p.utf8_string()?.to_owned(),
+                        connected_port: p.u32()?,
+                        originator_address: p.utf8_string()?.to_owned(),
+                        originator_port: p.u32()?,
+                    },
+                    "direct-streamlocal@openssh.com" => {
+                        let socket_path = p.utf8_string()?.to_owned();
+                        let _reserved = p.string()?;
+                        let _reserved = p.u32()?;
+                        ChannelKind::DirectStreamlocal { socket_path }
+                    }
+                    "forwarded-streamlocal@openssh.com" => {
+                        let socket_path = p.utf8_string()?.to_owned();
+                        let _reserved = p.string()?;
+                        ChannelKind::ForwardedStreamlocal { socket_path }
+                    }
+                    "auth-agent@openssh.com" => {
+                        if !self.agent_forwarding {
+                            debug!("Refusing agent channel, agent forwarding is not enabled");
+                            self.packets_to_send
+                                .push_back(Packet::new_msg_channel_open_failure(
+                                    sender_channel,
+                                    numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                                    b"agent forwarding is not enabled",
+                                    b"",
+                                ));
+                            return Ok(());
+                        }
+                        ChannelKind::AuthAgent
+                    }
                     _ => {
                         self.packets_to_send
                             .push_back(Packet::new_msg_channel_open_failure(
@@ -194,6 +587,20 @@ impl ChannelsState {
                     }
                 };
 
+                if update_message.is_forwarding()
+                    && self.forward_channel_count() >= self.max_forward_channels
+                {
+                    debug!(%channel_type, max = %self.max_forward_channels, "Refusing forwarding channel, too many are open");
+                    self.packets_to_send
+                        .push_back(Packet::new_msg_channel_open_failure(
+                            sender_channel,
+                            numbers::SSH_OPEN_RESOURCE_SHORTAGE,
+                            b"too many forwarding channels",
+                            b"",
+                        ));
+                    return Ok(());
+                }
+
                 let our_number = self.next_channel_id;
                 self.next_channel_id =
                     ChannelNumber(self.next_channel_id.0.checked_add(1).ok_or_else(|| {
@@ -211,16 +618,19 @@ impl ChannelsState {
                 self.channels.insert(
                     our_number,
                     ChannelState::Open(Channel {
+                        kind: update_message.clone(),
                         we_closed: false,
                         peer_channel: sender_channel,
                         peer_max_packet_size: max_packet_size,
                         peer_window_size: initial_window_size,
                         our_max_packet_size: max_packet_size,
                         our_window_size: initial_window_size,
-                        our_window_size_increase_step: initial_window_size,
+                        our_max_window_size: initial_window_size,
+                        our_window_consumed: 0,
 
                         queued_data_default: Vec::new(),
                         queued_data_extended: HashMap::new(),
+                        queued_operations: VecDeque::new(),
                     }),
                 );
 
@@ -242,6 +652,7 @@ impl ChannelsState {
                 else {
                     return Err(peer_error!("unknown channel: {our_channel}"));
                 };
+                let channel_type = update_message.name();
 
                 let peer_channel = p.u32()?;
                 let peer_window_size = p.u32()?;
@@ -255,20 +666,23 @@ impl ChannelsState {
                 self.channels.insert(
                     our_number,
                     ChannelState::Open(Channel {
+                        kind: update_message.clone(),
                         we_closed: false,
                         peer_channel,
                         peer_max_packet_size,
                         peer_window_size,
                         our_max_packet_size,
                         our_window_size,
-                        our_window_size_increase_step: our_window_size,
+                        our_max_window_size: our_window_size,
+                        our_window_consumed: 0,
 
                         queued_data_default: Vec::new(),
                         queued_data_extended: HashMap::new(),
+                        queued_operations: VecDeque::new(),
                     }),
                 );
 
-                debug!(channel_type = %"session", %our_number, "Successfully opened channel");
+                debug!(%channel_type, %our_number, "Successfully opened channel");
             }
             numbers::SSH_MSG_CHANNEL_OPEN_FAILURE => {
                 let our_channel = p.u32()?;
@@ -341,10 +755,24 @@ impl ChannelsState {
                         }
                     }
                 }
+
+                let channel = self.channel(our_channel)?;
+                if !channel.has_queued_data() {
+                    let operations = std::mem::take(&mut channel.queued_operations);
+                    for kind in operations {
+                        self.do_operation(our_channel.construct_op(kind));
+                    }
+                }
             }
-            numbers::SSH_MSG_CHANNEL_DATA => {
+            numbers::SSH_MSG_CHANNEL_DATA | numbers::SSH_MSG_CHANNEL_EXTENDED_DATA => {
+                // <https://datatracker.ietf.org/doc/html/rfc4254#section-5.2>
                 let our_channel = p.u32()?;
                 let our_channel = self.validate_channel(our_channel)?;
+                let code = if packet_type == numbers::SSH_MSG_CHANNEL_EXTENDED_DATA {
+                    Some(p.u32()?)
+                } else {
+                    None
+                };
                 let data = p.string()?;
 
                 let channel = self.channel(our_channel)?;
@@ -368,19 +796,16 @@ impl ChannelsState {
 
                 trace!(channel = %our_channel, window = %channel.our_window_size, "Remaining window on our side");
 
-                // We probably want to make this user-controllable in the future.
-                if channel.our_window_size < 1000 {
-                    let peer = channel.peer_channel;
-                    let bytes_to_add = channel.our_window_size_increase_step;
-                    channel.our_window_size += bytes_to_add;
-                    self.packets_to_send
-                        .push_back(Packet::new_msg_channel_window_adjust(peer, bytes_to_add))
+                if !self.window_adjust_on_consumption {
+                    self.window_consumed(our_channel, data.len() as u32)?;
                 }
 
+                let data = data.to_owned();
                 self.channel_updates.push_back(ChannelUpdate {
                     number: our_channel,
-                    kind: ChannelUpdateKind::Data {
-                        data: data.to_owned(),
+                    kind: match code {
+                        Some(code) => ChannelUpdateKind::ExtendedData { code, data },
+                        None => ChannelUpdateKind::Data { data },
                     },
                 });
             }
@@ -505,14 +930,66 @@ impl ChannelsState {
                             value: value.to_owned(),
                         }
                     }
+                    "window-change" => {
+                        if !self.is_server {
+                            return Err(peer_error!("server tried to change window size"));
+                        }
+
+                        let width_chars = p.u32()?;
+                        let height_rows = p.u32()?;
+                        let width_px = p.u32()?;
+                        let height_px = p.u32()?;
+
+                        debug!(channel = %our_channel, %width_chars, %height_rows, "Changing window size");
+                        ChannelRequest::WindowChange {
+                            width_chars,
+                            height_rows,
+                            width_px,
+                            height_px,
+                        }
+                    }
                     "signal" => {
                         if !self.is_server {
                             return Err(peer_error!("server tried to send signal"));
                         }
 
-                        debug!(channel = %our_channel, "Received signal");
-                        // Ignore signals, something we can do.
-                        return Ok(());
+                        let name = p.utf8_string()?;
+
+                        debug!(channel = %our_channel, %name, "Received signal");
+                        ChannelRequest::Signal {
+                            name: name.to_owned(),
+                        }
+                    }
+                    "break" => {
+                        if !self.is_server {
+                            return Err(peer_error!("server tried to send break"));
+                        }
+
+                        let length_ms = p.u32()?;
+
+                        debug!(channel = %our_channel, %length_ms, "Received break");
+                        ChannelRequest::Break {
+                            want_reply,
+                            length_ms,
+                        }
+                    }
+                    "exit-status" => {
+                        if self.is_server {
+                            return Err(peer_error!("client tried to send exit status"));
+                        }
+
+                        let status = p.u32()?;
+
+                        debug!(channel = %our_channel, %status, "Received exit status");
+                        ChannelRequest::ExitStatus { status }
+                    }
+                    "auth-agent-req@openssh.com" => {
+                        if !self.is_server {
+                            return Err(peer_error!("server tried to request agent forwarding"));
+                        }
+
+                        info!(channel = %our_channel, "Requesting agent forwarding");
+                        ChannelRequest::AuthAgentReq { want_reply }
                     }
                     _ => {
                         warn!(%request_type, channel = %our_channel, "Unknown channel request");
@@ -555,6 +1032,39 @@ impl ChannelsState {
         Ok(())
     }
 
+    /// Sends a `keepalive@openssh.com` global request, which the peer has to reply to.
+    pub fn send_keepalive(&mut self) {
+        self.packets_to_send
+            .push_back(Packet::new_msg_global_request(
+                b"keepalive@openssh.com",
+                true,
+            ));
+        self.pending_global_requests
+            .push_back(PendingGlobalRequest::Keepalive);
+    }
+
+    /// Asks the server to prove that it has the private keys of host keys it announced with
+    /// [`GlobalRequest::HostKeys`], with a `hostkeys-prove-00@openssh.com` global request.
+    /// The keys that have been proven are returned by [`ChannelsState::next_proven_host_keys`].
+    /// <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL> (section 2.5)
+    pub fn send_hostkeys_prove(&mut self, session_id: SessionId, keys: Vec<PublicKey>) {
+        let mut packet = Packet::new_msg_global_request(b"hostkeys-prove-00@openssh.com", true);
+        let mut w = Writer::new();
+        for key in &keys {
+            w.string(key.to_wire_encoding());
+        }
+        packet.payload.extend(w.finish());
+        self.packets_to_send.push_back(packet);
+        self.pending_global_requests
+            .push_back(PendingGlobalRequest::HostKeysProve { session_id, keys });
+    }
+
+    /// The keys of a [`ChannelsState::send_hostkeys_prove`] once the server has replied.
+    /// If the server refused or a signature is invalid, none of the keys are proven and this is empty.
+    pub fn next_proven_host_keys(&mut self) -> Option<Vec<PublicKey>> {
+        self.proven_host_keys.pop_front()
+    }
+
     pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
         self.packets_to_send.drain(..)
     }
@@ -563,6 +1073,71 @@ impl ChannelsState {
         self.channel_updates.pop_front()
     }
 
+    /// The next global request of the peer that the application should handle, see [`GlobalRequest`].
+    pub fn next_global_request(&mut self) -> Option<GlobalRequest> {
+        self.global_requests.pop_front()
+    }
+
+    /// Tells the connection that the application has processed `len` bytes of data of the channel,
+    /// see [`ChannelsState::set_window_adjust_on_consumption`].
+    pub fn data_consumed(&mut self, number: ChannelNumber, len: u32) {
+        if !self.window_adjust_on_consumption {
+            return;
+        }
+        if self.window_consumed(number, len).is_err() {
+            debug!(%number, "Not adjusting window as channel does not exist, probably because it has been closed");
+        }
+    }
+
+    /// Gives consumed bytes back to the peer with SSH_MSG_CHANNEL_WINDOW_ADJUST,
+    /// once there are enough of them according to the window adjust threshold.
+    fn window_consumed(&mut self, number: ChannelNumber, len: u32) -> Result<()> {
+        let window_adjust_threshold = self.window_adjust_threshold;
+        let channel = self.channel(number)?;
+
+        // Never give back more than the peer has used up.
+        let used = channel.our_max_window_size - channel.our_window_size;
+        channel.our_window_consumed = channel.our_window_consumed.saturating_add(len).min(used);
+
+        let threshold = window_adjust_threshold
+            .unwrap_or(channel.our_max_window_size / 2)
+            .clamp(1, channel.our_max_window_size.max(1));
+        if channel.our_window_consumed >= threshold {
+            let peer = channel.peer_channel;
+            let consumed = std::mem::take(&mut channel.our_window_consumed);
+            channel.our_window_size += consumed;
+            self.packets_to_send
+                .push_back(Packet::new_msg_channel_window_adjust(peer, consumed))
+        }
+        Ok(())
+    }
+
+    /// The number of bytes of data that could not be sent on the channel yet
+    /// because the window of the peer is exhausted. They are sent once the peer adjusts the window.
+    pub fn queued_data_len(&self, number: ChannelNumber) -> usize {
+        match self.channels.get(&number) {
+            Some(ChannelState::Open(channel)) => {
+                let queued_operations = channel
+                    .queued_operations
+                    .iter()
+                    .map(|kind| match kind {
+                        ChannelOperationKind::Data(data)
+                        | ChannelOperationKind::ExtendedData(_, data) => data.len(),
+                        _ => 0,
+                    })
+                    .sum::<usize>();
+                channel.queued_data_default.len()
+                    + channel
+                        .queued_data_extended
+                        .values()
+                        .map(Vec::len)
+                        .sum::<usize>()
+                    + queued_operations
+            }
+            _ => 0,
+        }
+    }
+
     /// Create a new channel
     pub fn create_channel(&mut self, kind: ChannelKind) -> ChannelNumber {
         let our_number = self.next_channel_id;
@@ -573,18 +1148,19 @@ impl ChannelsState {
                 .expect("created too many channels"),
         );
 
-        assert_eq!(kind, ChannelKind::Session, "TODO");
+        let channel_type = kind.name();
 
-        let our_window_size = 2097152; // same as OpenSSH
+        let our_window_size = self.initial_window_size;
         let our_max_packet_size = 32768; // same as OpenSSH
 
-        self.packets_to_send
-            .push_back(Packet::new_msg_channel_open_session(
-                b"session",
-                our_number.0,
-                our_window_size,
-                our_max_packet_size,
-            ));
+        let mut open = Packet::new_msg_channel_open_session(
+            channel_type.as_bytes(),
+            our_number.0,
+            our_window_size,
+            our_max_packet_size,
+        );
+        open.payload.extend(kind.type_specific_data());
+        self.packets_to_send.push_back(open);
 
         self.channels.insert(
             our_number,
@@ -595,7 +1171,7 @@ impl ChannelsState {
             },
         );
 
-        debug!(channel_type = %"session", %our_number, "Opening channel");
+        debug!(%channel_type, %our_number, "Opening channel");
 
         our_number
     }
@@ -616,6 +1192,21 @@ impl ChannelsState {
             return;
         }
 
+        let is_data = matches!(
+            op.kind,
+            ChannelOperationKind::Data(_) | ChannelOperationKind::ExtendedData(..)
+        );
+        if matches!(op.kind, ChannelOperationKind::Close) {
+            channel.queued_data_default.clear();
+            channel.queued_data_extended.clear();
+            channel.queued_operations.clear();
+        } else if !channel.queued_operations.is_empty() || (channel.has_queued_data() && !is_data) {
+            // Data is queued in order already, everything else waits until it has been sent.
+            trace!(number = %op.number, "Queueing operation until the queued data has been sent");
+            channel.queued_operations.push_back(op.kind);
+            return;
+        }
+
         match op.kind {
             ChannelOperationKind::Success => self.send_channel_success(peer),
             ChannelOperationKind::Failure => self.send_channel_failure(peer),
@@ -649,9 +1240,48 @@ impl ChannelsState {
                     ChannelRequest::Shell { want_reply } => {
                         Packet::new_msg_channel_request_shell(peer, b"shell", want_reply)
                     }
-                    ChannelRequest::Exec { .. } => todo!("exec"),
+                    ChannelRequest::Exec {
+                        want_reply,
+                        command,
+                    } => Packet::new_msg_channel_request_exec(peer, b"exec", want_reply, &command),
                     ChannelRequest::Subsystem { .. } => todo!("subsystem"),
-                    ChannelRequest::Env { .. } => todo!("env"),
+                    ChannelRequest::Env {
+                        want_reply,
+                        name,
+                        value,
+                    } => Packet::new_msg_channel_request_env(
+                        peer,
+                        b"env",
+                        want_reply,
+                        name.as_bytes(),
+                        &value,
+                    ),
+                    ChannelRequest::WindowChange {
+                        width_chars,
+                        height_rows,
+                        width_px,
+                        height_px,
+                    } => Packet::new_msg_channel_request_window_change(
+                        peer,
+                        b"window-change",
+                        false,
+                        width_chars,
+                        height_rows,
+                        width_px,
+                        height_px,
+                    ),
+                    ChannelRequest::Signal { name } => Packet::new_msg_channel_request_signal(
+                        peer,
+                        b"signal",
+                        false,
+                        name.as_bytes(),
+                    ),
+                    ChannelRequest::Break {
+                        want_reply,
+                        length_ms,
+                    } => {
+                        Packet::new_msg_channel_request_break(peer, b"break", want_reply, length_ms)
+                    }
                     ChannelRequest::ExitStatus { status } => {
                         Packet::new_msg_channel_request_exit_status(
                             peer,
@@ -660,6 +1290,14 @@ impl ChannelsState {
                             status,
                         )
                     }
+                    ChannelRequest::AuthAgentReq { want_reply } => {
+                        // Like "shell", the request has no data of its own.
+                        Packet::new_msg_channel_request_shell(
+                            peer,
+                            b"auth-agent-req@openssh.com",
+                            want_reply,
+                        )
+                    }
                 };
                 self.packets_to_send.push_back(packet);
             }
@@ -808,7 +1446,11 @@ impl ChannelOperation {
                 ChannelRequest::Exec { .. } => "exec",
                 ChannelRequest::Subsystem { .. } => "subsystem",
                 ChannelRequest::Env { .. } => "env",
+                ChannelRequest::WindowChange { .. } => "window-change",
+                ChannelRequest::Signal { .. } => "signal",
+                ChannelRequest::Break { .. } => "break",
                 ChannelRequest::ExitStatus { .. } => "exit-status",
+                ChannelRequest::AuthAgentReq { .. } => "auth-agent-req@openssh.com",
             },
             ChannelOperationKind::Eof => "eof",
             ChannelOperationKind::Close => "close",
@@ -819,10 +1461,16 @@ impl ChannelOperation {
 
 #[cfg(test)]
 mod tests {
-    use cluelessh_format::numbers;
+    use cluelessh_format::{numbers, Writer};
+    use cluelessh_keys::private::PlaintextPrivateKey;
+    use cluelessh_keys::{KeyGenerationParams, KeyType};
     use cluelessh_transport::packet::Packet;
+    use cluelessh_transport::SessionId;
 
-    use crate::{ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelsState};
+    use crate::{
+        ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelRequest,
+        ChannelsState, GlobalRequest,
+    };
 
     /// If a test fails, add this to the test to get logs.
     #[allow(dead_code)]
@@ -886,6 +1534,520 @@ mod tests {
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
     }
 
+    #[test]
+    fn signal() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_signal(
+                0, b"signal", false, b"INT",
+            ))
+            .unwrap();
+        assert_response_types(state, &[]);
+
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Request(crate::ChannelRequest::Signal { name }) if name == "INT"
+        ));
+    }
+
+    #[test]
+    fn env() {
+        let client = &mut ChannelsState::new(false);
+        let number = client.create_channel(ChannelKind::Session);
+        let open = client.packets_to_send().next().unwrap();
+
+        let server = &mut ChannelsState::new(true);
+        server.recv_packet(open).unwrap();
+        let _open = server.next_channel_update().unwrap();
+        let confirmation = server.packets_to_send().next().unwrap();
+        client.recv_packet(confirmation).unwrap();
+
+        client.do_operation(number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::Env {
+                want_reply: false,
+                name: "LANG".to_owned(),
+                value: b"C.UTF-8".to_vec(),
+            },
+        )));
+        server
+            .recv_packet(client.packets_to_send().next().unwrap())
+            .unwrap();
+        assert_response_types(server, &[]);
+
+        let update = server.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Request(crate::ChannelRequest::Env {
+                want_reply: false,
+                name,
+                value,
+            }) if name == "LANG" && value == b"C.UTF-8"
+        ));
+    }
+
+    #[test]
+    fn break_request() {
+        let client = &mut ChannelsState::new(false);
+        let number = client.create_channel(ChannelKind::Session);
+        let open = client.packets_to_send().next().unwrap();
+
+        let server = &mut ChannelsState::new(true);
+        server.recv_packet(open).unwrap();
+        let _open = server.next_channel_update().unwrap();
+        let confirmation = server.packets_to_send().next().unwrap();
+        client.recv_packet(confirmation).unwrap();
+
+        client.do_operation(number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::Break {
+                want_reply: true,
+                length_ms: 500,
+            },
+        )));
+        server
+            .recv_packet(client.packets_to_send().next().unwrap())
+            .unwrap();
+
+        let update = server.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Request(crate::ChannelRequest::Break {
+                want_reply: true,
+                length_ms: 500,
+            })
+        ));
+        server.do_operation(update.number.construct_op(ChannelOperationKind::Success));
+        assert_response_types(server, &[numbers::SSH_MSG_CHANNEL_SUCCESS]);
+
+        // Only clients send breaks.
+        server.do_operation(update.number.construct_op(ChannelOperationKind::Request(
+            ChannelRequest::Break {
+                want_reply: false,
+                length_ms: 0,
+            },
+        )));
+        client
+            .recv_packet(server.packets_to_send().next().unwrap())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn window_change() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        let _open = state.next_channel_update().unwrap();
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_window_change(
+                0,
+                b"window-change",
+                false,
+                120,
+                40,
+                0,
+                0,
+            ))
+            .unwrap();
+        assert_response_types(state, &[]);
+
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Request(crate::ChannelRequest::WindowChange {
+                width_chars: 120,
+                height_rows: 40,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn client_receives_exit_status() {
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        let _open = state.packets_to_send().next().unwrap();
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 0, 2048, 1024,
+            ))
+            .unwrap();
+        let _open = state.next_channel_update().unwrap();
+
+        state
+            .recv_packet(Packet::new_msg_channel_request_exit_status(
+                number.0,
+                b"exit-status",
+                false,
+                3,
+            ))
+            .unwrap();
+        assert_response_types(state, &[]);
+
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Request(crate::ChannelRequest::ExitStatus { status: 3 })
+        ));
+    }
+
+    #[test]
+    fn client_receives_stderr() {
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::Session);
+        let _open = state.packets_to_send().next().unwrap();
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 0, 2048, 1024,
+            ))
+            .unwrap();
+        let _open = state.next_channel_update().unwrap();
+
+        state
+            .recv_packet(Packet::new_msg_channel_data(number.0, b"out"))
+            .unwrap();
+        state
+            .recv_packet(Packet::new_msg_channel_extended_data(
+                number.0,
+                numbers::SSH_EXTENDED_DATA_STDERR,
+                b"err",
+            ))
+            .unwrap();
+
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Data { data } if data == b"out"
+        ));
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::ExtendedData { code: numbers::SSH_EXTENDED_DATA_STDERR, data } if data == b"err"
+        ));
+    }
+
+    fn host_keys_request(keys: &[Vec<u8>]) -> Packet {
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_MSG_GLOBAL_REQUEST);
+        w.string(b"hostkeys-00@openssh.com");
+        w.bool(false);
+        for key in keys {
+            w.string(key);
+        }
+        Packet {
+            payload: w.finish(),
+        }
+    }
+
+    #[test]
+    fn global_request() {
+        let state = &mut ChannelsState::new(true);
+
+        state.recv_packet(host_keys_request(&[])).unwrap();
+        assert_response_types(state, &[]);
+        // Clients don't send host keys.
+        assert_eq!(state.next_global_request(), None);
+
+        state
+            .recv_packet(Packet::new_msg_global_request(
+                b"keepalive@openssh.com",
+                true,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_REQUEST_FAILURE]);
+    }
+
+    #[test]
+    fn host_keys_global_request() {
+        let state = &mut ChannelsState::new(false);
+        let key = PlaintextPrivateKey::generate(
+            String::new(),
+            KeyGenerationParams {
+                key_type: KeyType::Ed25519,
+            },
+        )
+        .private_key
+        .public_key();
+
+        let mut unsupported = Writer::new();
+        unsupported.string(b"ssh-unknown");
+        unsupported.string(b"key");
+        state
+            .recv_packet(host_keys_request(&[
+                key.to_wire_encoding(),
+                unsupported.finish(),
+            ]))
+            .unwrap();
+        assert_response_types(state, &[]);
+        assert_eq!(
+            state.next_global_request(),
+            Some(GlobalRequest::HostKeys(vec![key]))
+        );
+        assert_eq!(state.next_global_request(), None);
+    }
+
+    #[test]
+    fn host_keys_global_requests_limited() {
+        let state = &mut ChannelsState::new(false);
+
+        for _ in 0..super::MAX_QUEUED_GLOBAL_REQUESTS {
+            state.recv_packet(host_keys_request(&[])).unwrap();
+        }
+        assert!(state.recv_packet(host_keys_request(&[])).is_err());
+
+        // Requests the application has taken don't count.
+        let state = &mut ChannelsState::new(false);
+        for _ in 0..=super::MAX_QUEUED_GLOBAL_REQUESTS {
+            state.recv_packet(host_keys_request(&[])).unwrap();
+            assert!(state.next_global_request().is_some());
+        }
+    }
+
+    /// The reply of a server to `hostkeys-prove-00@openssh.com`, with a signature of each of `signing_keys`.
+    fn hostkeys_prove_reply(
+        session_id: SessionId,
+        signing_keys: &[&PlaintextPrivateKey],
+    ) -> Packet {
+        let mut reply = Writer::new();
+        reply.u8(numbers::SSH_MSG_REQUEST_SUCCESS);
+        for key in signing_keys {
+            let mut data = Writer::new();
+            data.string(b"hostkeys-prove-00@openssh.com");
+            data.string(session_id.0);
+            data.string(key.private_key.public_key().to_wire_encoding());
+            reply.string(key.private_key.sign(&data.finish()).to_wire_encoding());
+        }
+        Packet {
+            payload: reply.finish(),
+        }
+    }
+
+    #[test]
+    fn hostkeys_prove() {
+        let state = &mut ChannelsState::new(false);
+        let session_id = SessionId([1; 32]);
+        let generate = || {
+            PlaintextPrivateKey::generate(
+                String::new(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ed25519,
+                },
+            )
+        };
+        let (new_key, other_key) = (generate(), generate());
+        let new_public = new_key.private_key.public_key();
+
+        state.send_hostkeys_prove(session_id, vec![new_public.clone()]);
+        assert_response_types(state, &[numbers::SSH_MSG_GLOBAL_REQUEST]);
+        assert_eq!(state.next_proven_host_keys(), None);
+        state
+            .recv_packet(hostkeys_prove_reply(session_id, &[&new_key]))
+            .unwrap();
+        assert_eq!(
+            state.next_proven_host_keys(),
+            Some(vec![new_public.clone()])
+        );
+
+        // Signed by a different key.
+        state.send_hostkeys_prove(session_id, vec![new_public.clone()]);
+        state
+            .recv_packet(hostkeys_prove_reply(session_id, &[&other_key]))
+            .unwrap();
+        assert_eq!(state.next_proven_host_keys(), Some(vec![]));
+
+        // Signed for a different session.
+        state.send_hostkeys_prove(session_id, vec![new_public.clone()]);
+        state
+            .recv_packet(hostkeys_prove_reply(SessionId([2; 32]), &[&new_key]))
+            .unwrap();
+        assert_eq!(state.next_proven_host_keys(), Some(vec![]));
+
+        state.send_hostkeys_prove(session_id, vec![new_public]);
+        state
+            .recv_packet(Packet::new_msg_request_failure())
+            .unwrap();
+        assert_eq!(state.next_proven_host_keys(), Some(vec![]));
+        assert_eq!(state.next_proven_host_keys(), None);
+    }
+
+    #[test]
+    fn keepalive() {
+        let state = &mut ChannelsState::new(true);
+
+        state.send_keepalive();
+        assert_response_types(state, &[numbers::SSH_MSG_GLOBAL_REQUEST]);
+
+        state
+            .recv_packet(Packet::new_msg_request_failure())
+            .unwrap();
+        state
+            .recv_packet(Packet::new_msg_request_failure())
+            .unwrap_err();
+    }
+
+    fn direct_tcpip_open(sender_channel: u32) -> Packet {
+        let mut open =
+            Packet::new_msg_channel_open_session(b"direct-tcpip", sender_channel, 2048, 1024);
+        let mut w = cluelessh_format::Writer::new();
+        w.string(b"localhost");
+        w.u32(80);
+        w.string(b"127.0.0.1");
+        w.u32(12345);
+        open.payload.extend(w.finish());
+        open
+    }
+
+    #[test]
+    fn max_forward_channels() {
+        let state = &mut ChannelsState::new(true);
+        state.set_max_forward_channels(2);
+
+        for i in 0..2 {
+            state.recv_packet(direct_tcpip_open(i)).unwrap();
+            assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        }
+        let update = state.next_channel_update().unwrap();
+        let expected = ChannelKind::DirectTcpip {
+            host_to_connect: "localhost".to_owned(),
+            port_to_connect: 80,
+            originator_address: "127.0.0.1".to_owned(),
+            originator_port: 12345,
+        };
+        assert!(matches!(update.kind, crate::ChannelUpdateKind::Open(kind) if kind == expected));
+
+        state.recv_packet(direct_tcpip_open(2)).unwrap();
+        let failure = state.packets_to_send().next().unwrap();
+        let mut p = failure.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_FAILURE);
+        assert_eq!(p.u32().unwrap(), 2);
+        assert_eq!(p.u32().unwrap(), numbers::SSH_OPEN_RESOURCE_SHORTAGE);
+
+        // Sessions are not limited.
+        open_session_channel(state);
+
+        // Closing a forwarding channel makes room for a new one.
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        state.recv_packet(direct_tcpip_open(3)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+    }
+
+    #[test]
+    fn create_forwarded_tcpip_channel() {
+        let state = &mut ChannelsState::new(true);
+        let kind = ChannelKind::ForwardedTcpip {
+            connected_address: "0.0.0.0".to_owned(),
+            connected_port: 8080,
+            originator_address: "192.0.2.1".to_owned(),
+            originator_port: 4000,
+        };
+        state.create_channel(kind.clone());
+
+        let open = state.packets_to_send().next().unwrap();
+        let client = &mut ChannelsState::new(false);
+        client.recv_packet(open).unwrap();
+        assert_response_types(client, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        let update = client.next_channel_update().unwrap();
+        assert!(matches!(update.kind, crate::ChannelUpdateKind::Open(opened) if opened == kind));
+    }
+
+    #[test]
+    fn agent_forwarding() {
+        let server = &mut ChannelsState::new(true);
+        let refused = server.create_channel(ChannelKind::AuthAgent);
+        server.create_channel(ChannelKind::AuthAgent);
+        let mut opens = server.packets_to_send();
+        let (first, second) = (opens.next().unwrap(), opens.next().unwrap());
+
+        let client = &mut ChannelsState::new(false);
+        client.recv_packet(first).unwrap();
+        let failure = client.packets_to_send().next().unwrap();
+        let mut p = failure.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_FAILURE);
+        assert_eq!(p.u32().unwrap(), refused.0);
+        assert_eq!(
+            p.u32().unwrap(),
+            numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED
+        );
+        assert!(client.next_channel_update().is_none());
+
+        client.set_agent_forwarding(true);
+        client.recv_packet(second).unwrap();
+        assert_response_types(client, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+        let update = client.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Open(ChannelKind::AuthAgent)
+        ));
+    }
+
+    #[test]
+    fn debug_log_channel_refused() {
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"debug-log@cluelessh",
+                3,
+                2048,
+                1024,
+            ))
+            .unwrap();
+        let failure = state.packets_to_send().next().unwrap();
+        let mut p = failure.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN_FAILURE);
+        assert_eq!(p.u32().unwrap(), 3);
+        assert_eq!(
+            p.u32().unwrap(),
+            numbers::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED
+        );
+        assert!(state.next_channel_update().is_none());
+    }
+
+    #[test]
+    fn open_debug_log_channel() {
+        let state = &mut ChannelsState::new(true);
+        state.set_debug_log_channel(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"debug-log@cluelessh",
+                0,
+                2048,
+                1024,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Open(ChannelKind::DebugLog)
+        ));
+    }
+
+    #[test]
+    fn create_debug_log_channel() {
+        let state = &mut ChannelsState::new(false);
+        let number = state.create_channel(ChannelKind::DebugLog);
+
+        let open = state.packets_to_send().next().unwrap();
+        let mut p = open.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_OPEN);
+        assert_eq!(p.utf8_string().unwrap(), "debug-log@cluelessh");
+
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 0, 2048, 1024,
+            ))
+            .unwrap();
+        let update = state.next_channel_update().unwrap();
+        assert!(matches!(
+            update.kind,
+            crate::ChannelUpdateKind::Open(ChannelKind::DebugLog)
+        ));
+    }
+
     #[test]
     fn only_single_close_for_double_close_operation() {
         let state = &mut ChannelsState::new(true);
@@ -903,10 +2065,10 @@ mod tests {
 
     #[test]
     fn ignore_operation_after_close() {
-        let mut state = &mut ChannelsState::new(true);
+        let state = &mut ChannelsState::new(true);
         open_session_channel(state);
         state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
-        assert_response_types(&mut state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
         state.do_operation(ChannelOperation {
             number: ChannelNumber(0),
             kind: ChannelOperationKind::Data(vec![0]),
@@ -914,6 +2076,36 @@ mod tests {
         assert_response_types(state, &[]);
     }
 
+    #[test]
+    fn peer_reuses_channel_number() {
+        let state = &mut ChannelsState::new(true);
+        open_session_channel(state);
+        state.recv_packet(Packet::new_msg_channel_close(0)).unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+
+        // The peer opens a channel with its number of the closed one, which gets a new number on our side.
+        open_session_channel(state);
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, b"old"))
+            .unwrap_err();
+        state
+            .recv_packet(Packet::new_msg_channel_data(1, b"new"))
+            .unwrap();
+
+        let updates = std::iter::from_fn(|| state.next_channel_update())
+            .map(|update| update.number)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            [
+                ChannelNumber(0),
+                ChannelNumber(0),
+                ChannelNumber(1),
+                ChannelNumber(1)
+            ]
+        );
+    }
+
     // TODO: test with extended data
     #[test]
     fn respect_peer_windowing() {
@@ -931,6 +2123,7 @@ mod tests {
 
         // 0..10
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+        assert_eq!(state.queued_data_len(ChannelNumber(0)), 190);
 
         state
             .recv_packet(Packet::new_msg_channel_window_adjust(0, 90))
@@ -940,6 +2133,7 @@ mod tests {
             state,
             &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
         );
+        assert_eq!(state.queued_data_len(ChannelNumber(0)), 100);
 
         state
             .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
@@ -949,6 +2143,57 @@ mod tests {
             state,
             &[numbers::SSH_MSG_CHANNEL_DATA, numbers::SSH_MSG_CHANNEL_DATA],
         );
+        assert_eq!(state.queued_data_len(ChannelNumber(0)), 0);
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        assert_response_types(state, &[]);
+    }
+
+    #[test]
+    fn operations_wait_for_queued_data() {
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 20])));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Eof));
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Request(
+            ChannelRequest::ExitStatus { status: 0 },
+        )));
+        assert_response_types(state, &[]);
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        assert_response_types(
+            state,
+            &[
+                numbers::SSH_MSG_CHANNEL_DATA,
+                numbers::SSH_MSG_CHANNEL_EOF,
+                numbers::SSH_MSG_CHANNEL_REQUEST,
+            ],
+        );
+    }
+
+    #[test]
+    fn close_drops_queued_data() {
+        let state = &mut ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(b"session", 0, 10, 50))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Data(vec![0; 20])));
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Eof));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_DATA]);
+        state.do_operation(ChannelNumber(0).construct_op(ChannelOperationKind::Close));
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_CLOSE]);
+        assert_eq!(state.queued_data_len(ChannelNumber(0)), 0);
 
         state
             .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
@@ -971,14 +2216,105 @@ mod tests {
             .unwrap();
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
 
-        // We currently hardcode <1000 for when to send window size adjustments.
+        // By default, the window is adjusted once half of it has been used.
         state
-            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 1000]))
+            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 999]))
             .unwrap();
         assert_response_types(state, &[]);
         state
-            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 1]))
+            .recv_packet(Packet::new_msg_channel_data(0, &[0; 1]))
             .unwrap();
         assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
     }
+
+    #[test]
+    fn window_adjust_on_consumption() {
+        let state = &mut ChannelsState::new(false);
+        state.set_initial_window_size(2000);
+        state.set_window_adjust_on_consumption(true);
+        let number = state.create_channel(ChannelKind::Session);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN]);
+        state
+            .recv_packet(Packet::new_msg_channel_open_confirmation(
+                number.0, 0, 2000, 2000,
+            ))
+            .unwrap();
+
+        // Received data is not enough, it has to be consumed.
+        state
+            .recv_packet(Packet::new_msg_channel_data(number.0, &vec![0; 2000]))
+            .unwrap();
+        assert_response_types(state, &[]);
+        state.data_consumed(number, 999);
+        assert_response_types(state, &[]);
+        state.data_consumed(number, 1);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
+
+        // Only what the peer has used up is given back.
+        state.data_consumed(number, 5000);
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST]);
+        state
+            .recv_packet(Packet::new_msg_channel_data(number.0, &vec![0; 2000]))
+            .unwrap();
+        assert!(state
+            .recv_packet(Packet::new_msg_channel_data(number.0, &[0; 1]))
+            .is_err());
+    }
+
+    #[track_caller]
+    fn assert_window_adjust(state: &mut ChannelsState, bytes_to_add: u32) {
+        let packets = state.packets_to_send().collect::<Vec<_>>();
+        assert_eq!(packets.len(), 1);
+        let mut p = packets[0].payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_CHANNEL_WINDOW_ADJUST);
+        assert_eq!(p.u32().unwrap(), 0);
+        assert_eq!(p.u32().unwrap(), bytes_to_add);
+    }
+
+    #[test]
+    fn window_adjustments_batched() {
+        let state = &mut ChannelsState::new(true);
+        state.set_window_adjust_threshold(1500);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2000, 2000,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        for _ in 0..2 {
+            state
+                .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 700]))
+                .unwrap();
+            assert_response_types(state, &[]);
+        }
+        // Acknowledges all three packets at once.
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 300]))
+            .unwrap();
+        assert_window_adjust(state, 1700);
+
+        // The full window is available again.
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 2000]))
+            .unwrap();
+        assert_window_adjust(state, 2000);
+    }
+
+    #[test]
+    fn window_adjust_threshold_capped_to_window() {
+        let state = &mut ChannelsState::new(true);
+        state.set_window_adjust_threshold(u32::MAX);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 2000, 2000,
+            ))
+            .unwrap();
+        assert_response_types(state, &[numbers::SSH_MSG_CHANNEL_OPEN_CONFIRMATION]);
+
+        state
+            .recv_packet(Packet::new_msg_channel_data(0, &vec![0; 2000]))
+            .unwrap();
+        assert_window_adjust(state, 2000);
+    }
 }
@@ -40,6 +40,11 @@ impl<'a> Reader<'a> {
         Ok(u32::from_be_bytes(arr))
     }
 
+    pub fn u64(&mut self) -> Result<u64> {
+        let arr = self.array()?;
+        Ok(u64::from_be_bytes(arr))
+    }
+
     pub fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
         assert!(N < 100_000);
         if self.0.len() < N {
@@ -59,6 +59,8 @@ consts! {
 
     //  60 to 79   User authentication method specific (numbers can be reused for different authentication methods)
     const SSH_MSG_USERAUTH_PK_OK = 60;
+    const SSH_MSG_USERAUTH_INFO_REQUEST = 60; // Same number
+    const SSH_MSG_USERAUTH_INFO_RESPONSE = 61;
 
     // -----
     // Connection protocol:
@@ -112,6 +114,69 @@ consts! {
 
 pub const SSH_EXTENDED_DATA_STDERR: u32 = 1;
 
+consts! {
+    u8, fn terminal_mode_to_string,
+    // <https://datatracker.ietf.org/doc/html/rfc4254#section-8>
+    const TTY_OP_END = 0;
+    const VINTR = 1;
+    const VQUIT = 2;
+    const VERASE = 3;
+    const VKILL = 4;
+    const VEOF = 5;
+    const VEOL = 6;
+    const VEOL2 = 7;
+    const VSTART = 8;
+    const VSTOP = 9;
+    const VSUSP = 10;
+    const VDSUSP = 11;
+    const VREPRINT = 12;
+    const VWERASE = 13;
+    const VLNEXT = 14;
+    const VFLUSH = 15;
+    const VSWTCH = 16;
+    const VSTATUS = 17;
+    const VDISCARD = 18;
+    const IGNPAR = 30;
+    const PARMRK = 31;
+    const INPCK = 32;
+    const ISTRIP = 33;
+    const INLCR = 34;
+    const IGNCR = 35;
+    const ICRNL = 36;
+    const IUCLC = 37;
+    const IXON = 38;
+    const IXANY = 39;
+    const IXOFF = 40;
+    const IMAXBEL = 41;
+    // <https://datatracker.ietf.org/doc/html/rfc8160>
+    const IUTF8 = 42;
+    const ISIG = 50;
+    const ICANON = 51;
+    const XCASE = 52;
+    const ECHO = 53;
+    const ECHOE = 54;
+    const ECHOK = 55;
+    const ECHONL = 56;
+    const NOFLSH = 57;
+    const TOSTOP = 58;
+    const IEXTEN = 59;
+    const ECHOCTL = 60;
+    const ECHOKE = 61;
+    const PENDIN = 62;
+    const OPOST = 70;
+    const OLCUC = 71;
+    const ONLCR = 72;
+    const OCRNL = 73;
+    const ONOCR = 74;
+    const ONLRET = 75;
+    const CS7 = 90;
+    const CS8 = 91;
+    const PARENB = 92;
+    const PARODD = 93;
+    const TTY_OP_ISPEED = 128;
+    const TTY_OP_OSPEED = 129;
+}
+
 consts! {
     u8, fn sftp_message_type_to_string,
     const SSH_FXP_INIT = 1;
@@ -164,3 +229,13 @@ consts! {
     const SSH_FILEXFER_ATTR_ACMODTIME = 0x00000008;
     const SSH_FILEXFER_ATTR_EXTENDED = 0x80000000;
 }
+
+consts! {
+    u32, fn sftp_open_flag_to_string,
+    const SSH_FXF_READ = 0x00000001;
+    const SSH_FXF_WRITE = 0x00000002;
+    const SSH_FXF_APPEND = 0x00000004;
+    const SSH_FXF_CREAT = 0x00000008;
+    const SSH_FXF_TRUNC = 0x00000010;
+    const SSH_FXF_EXCL = 0x00000020;
+}
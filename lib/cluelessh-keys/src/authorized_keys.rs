@@ -1,7 +1,29 @@
-use crate::public::{PublicKey, PublicKeyWithComment};
+use std::net::IpAddr;
+
+use crate::public::{InvalidLine, PublicKey, PublicKeyWithComment};
 
 pub struct AuthorizedKeys {
-    pub keys: Vec<PublicKeyWithComment>,
+    pub keys: Vec<AuthorizedKey>,
+    /// The lines that were skipped because they are invalid.
+    pub errors: Vec<InvalidLine>,
+}
+
+/// A key of an `authorized_keys` file with the options in front of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthorizedKey {
+    pub options: KeyOptions,
+    pub key: PublicKeyWithComment,
+}
+
+/// The options of a key that restrict what it can be used for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// `command="..."`, a command that is executed instead of whatever the client requests.
+    pub command: Option<String>,
+    /// `no-pty`, PTY allocation is refused.
+    pub no_pty: bool,
+    /// `from="pattern-list"`, the key may only be used from source addresses matching these patterns.
+    pub from: Option<Vec<String>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -9,51 +31,183 @@ pub struct AuthorizedKeys {
 pub struct Error(String);
 
 impl AuthorizedKeys {
-    pub fn parse(authorized_keys: &str) -> Result<Self, Error> {
-        let lines = authorized_keys.lines();
-        let mut keys: Vec<PublicKeyWithComment> = Vec::new();
-
-        for line in lines {
-            let key = line
-                .parse::<PublicKeyWithComment>()
-                .map_err(|err| Error(err.0))?;
-            keys.push(key);
+    /// Parses one key per line, ignoring empty lines and comments starting with `#`.
+    /// Invalid lines, like ones with unsupported key types or options, are skipped and recorded in `errors`.
+    pub fn parse(authorized_keys: &str) -> Self {
+        let mut keys = Vec::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in authorized_keys.lines().enumerate() {
+            match parse_line(line) {
+                Ok(Some(key)) => keys.push(key),
+                Ok(None) => {}
+                Err(err) => errors.push(InvalidLine {
+                    line: i + 1,
+                    message: err.0,
+                }),
+            }
         }
 
-        Ok(Self { keys })
+        Self { keys, errors }
     }
 
-    pub fn contains(&self, provided_key: &PublicKey) -> Option<&PublicKeyWithComment> {
-        self.keys.iter().find(|key| key.key == *provided_key)
+    pub fn contains(&self, provided_key: &PublicKey) -> Option<&AuthorizedKey> {
+        self.keys.iter().find(|key| key.key.key == *provided_key)
     }
 }
 
+fn parse_line(line: &str) -> Result<Option<AuthorizedKey>, Error> {
+    let Some(line) = PublicKey::from_openssh_line(line).map_err(|err| Error(err.0))? else {
+        return Ok(None);
+    };
+    let options = match &line.prefix {
+        Some(options) => KeyOptions::parse(options)?,
+        None => KeyOptions::default(),
+    };
+    Ok(Some(AuthorizedKey {
+        options,
+        key: line.key,
+    }))
+}
+
+impl KeyOptions {
+    /// Parses comma-separated options like `no-pty,command="echo hi"`.
+    fn parse(options: &str) -> Result<Self, Error> {
+        let mut result = Self::default();
+        let mut chars = options.chars().peekable();
+
+        loop {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|&c| c != '=' && c != ',') {
+                name.push(c);
+            }
+            let value = if chars.next_if_eq(&'=').is_some() {
+                if chars.next() != Some('"') {
+                    return Err(Error(format!("value of option {name} must be quoted")));
+                }
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.next_if_eq(&'"').is_some() => value.push('"'),
+                        Some(c) => value.push(c),
+                        None => return Err(Error(format!("unterminated value of option {name}"))),
+                    }
+                }
+                Some(value)
+            } else {
+                None
+            };
+
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("no-pty", None) => result.no_pty = true,
+                ("command", Some(command)) => result.command = Some(command),
+                ("from", Some(from)) => {
+                    result.from = Some(from.split(',').map(ToOwned::to_owned).collect());
+                }
+                _ => return Err(Error(format!("unsupported option: {name}"))),
+            }
+
+            match chars.next() {
+                None => break,
+                Some(',') => {}
+                Some(c) => return Err(Error(format!("unexpected character after option: {c}"))),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Whether the key may be used from `addr`, checking `from` with [`matches_address_list`].
+    pub fn allows_source(&self, addr: IpAddr) -> bool {
+        match &self.from {
+            Some(patterns) => matches_address_list(patterns, addr),
+            None => true,
+        }
+    }
+}
+
+/// Whether `addr` matches a list of patterns like the ones of `from`.
+/// A pattern is an address with `*` and `?` wildcards or a CIDR range like `192.0.2.0/24`,
+/// and patterns starting with `!` reject addresses even if other patterns match.
+pub fn matches_address_list(patterns: &[String], addr: IpAddr) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern.as_str()),
+        };
+        if address_matches(pattern, addr) {
+            if negated {
+                return false;
+            }
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn address_matches(pattern: &str, addr: IpAddr) -> bool {
+    if let Some((network, prefix_len)) = pattern.split_once('/') {
+        let (Ok(network), Ok(prefix_len)) = (network.parse::<IpAddr>(), prefix_len.parse::<u32>())
+        else {
+            return false;
+        };
+        return match (network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) if prefix_len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) if prefix_len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        };
+    }
+    wildcard_matches(pattern, &addr.to_canonical().to_string())
+}
+
+/// Matches `*` (any number of characters) and `?` (a single character) wildcards.
+pub fn wildcard_matches(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((b'*', rest)) => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            Some((b'?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), text.as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::IpAddr;
+
     use crate::public::{PublicKey, PublicKeyWithComment};
 
-    use super::AuthorizedKeys;
+    use super::{AuthorizedKey, AuthorizedKeys, KeyOptions};
 
     #[test]
     fn parse_single() {
         let keys = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
-        let keys = AuthorizedKeys::parse(keys).unwrap();
+        let keys = AuthorizedKeys::parse(keys);
         assert_eq!(
             keys.keys.as_slice(),
-            [PublicKeyWithComment {
-                key: PublicKey::Ed25519 {
-                    public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                        &[
+            [AuthorizedKey {
+                options: KeyOptions::default(),
+                key: PublicKeyWithComment {
+                    key: PublicKey::Ed25519 {
+                        public_key: ed25519_dalek::VerifyingKey::from_bytes(&[
                             109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201,
                             122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129,
                             58, 79,
-                        ]
-                        .try_into()
-                        .unwrap()
-                    )
-                    .unwrap(),
+                        ])
+                        .unwrap(),
+                    },
+                    comment: "nora".into(),
                 },
-                comment: "nora".into(),
             }]
         );
     }
@@ -61,29 +215,21 @@ mod tests {
     #[test]
     fn contains() {
         let keys = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
-        let keys = AuthorizedKeys::parse(keys).unwrap();
+        let keys = AuthorizedKeys::parse(keys);
 
         let provided = PublicKey::Ed25519 {
-            public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                &[
-                    109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201, 122, 234,
-                    102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129, 58, 79,
-                ]
-                .try_into()
-                .unwrap(),
-            )
+            public_key: ed25519_dalek::VerifyingKey::from_bytes(&[
+                109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201, 122, 234,
+                102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129, 58, 79,
+            ])
             .unwrap(),
         };
 
         let flipped = PublicKey::Ed25519 {
-            public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                &[
-                    1, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201, 122, 234,
-                    102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129, 58, 79,
-                ]
-                .try_into()
-                .unwrap(),
-            )
+            public_key: ed25519_dalek::VerifyingKey::from_bytes(&[
+                1, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201, 122, 234, 102,
+                172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129, 58, 79,
+            ])
             .unwrap(),
         };
 
@@ -94,7 +240,7 @@ mod tests {
     #[test]
     fn empty() {
         let keys = "";
-        let keys = AuthorizedKeys::parse(keys).unwrap();
+        let keys = AuthorizedKeys::parse(keys);
         assert_eq!(keys.keys, []);
     }
 
@@ -102,23 +248,22 @@ mod tests {
     fn no_comment() {
         let keys =
             "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP\n";
-        let keys = AuthorizedKeys::parse(keys).unwrap();
+        let keys = AuthorizedKeys::parse(keys);
         assert_eq!(
             keys.keys.as_slice(),
-            [PublicKeyWithComment {
-                key: PublicKey::Ed25519 {
-                    public_key: ed25519_dalek::VerifyingKey::from_bytes(
-                        &[
+            [AuthorizedKey {
+                options: KeyOptions::default(),
+                key: PublicKeyWithComment {
+                    key: PublicKey::Ed25519 {
+                        public_key: ed25519_dalek::VerifyingKey::from_bytes(&[
                             109, 39, 214, 41, 20, 27, 218, 216, 170, 134, 225, 237, 106, 64, 201,
                             122, 234, 102, 172, 80, 161, 13, 179, 52, 154, 197, 62, 61, 118, 129,
                             58, 79,
-                        ]
-                        .try_into()
-                        .unwrap()
-                    )
-                    .unwrap(),
+                        ])
+                        .unwrap(),
+                    },
+                    comment: "".into(),
                 },
-                comment: "".into(),
             }]
         );
     }
@@ -127,22 +272,122 @@ mod tests {
     fn multiple() {
         let keys =
             "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\nssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP peter\n";
-        let keys = AuthorizedKeys::parse(keys).unwrap();
+        let keys = AuthorizedKeys::parse(keys);
         assert_eq!(keys.keys.len(), 2);
     }
 
     #[test]
     fn corrupt() {
         let keys = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG";
-        let keys = AuthorizedKeys::parse(keys);
-        assert!(keys.is_err());
+        assert_skipped(keys);
     }
 
     #[test]
     fn algorithm_mismatch() {
         let keys =
             "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora\n";
+        assert_skipped(keys);
+    }
+
+    /// Asserts that the only line is skipped as invalid.
+    fn assert_skipped(keys: &str) {
         let keys = AuthorizedKeys::parse(keys);
-        assert!(keys.is_err());
+        assert_eq!(keys.keys, []);
+        assert_eq!(keys.errors.len(), 1);
+        assert_eq!(keys.errors[0].line, 1);
+    }
+
+    #[test]
+    fn invalid_lines_skipped() {
+        let keys = AuthorizedKeys::parse(&format!(
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQC7 rsa\n{KEY}\nno-agent-forwarding {KEY}\n"
+        ));
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(
+            keys.errors.iter().map(|err| err.line).collect::<Vec<_>>(),
+            [1, 3]
+        );
+    }
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP nora";
+
+    fn parse_options(options: &str) -> KeyOptions {
+        let keys = AuthorizedKeys::parse(&format!("{options} {KEY}\n"));
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(keys.keys[0].key.comment, "nora");
+        keys.keys[0].options.clone()
+    }
+
+    #[test]
+    fn comments_and_empty_lines() {
+        let keys = AuthorizedKeys::parse(&format!("# my keys\n\n{KEY}\n   \n"));
+        assert_eq!(keys.keys.len(), 1);
+        assert_eq!(keys.errors, []);
+    }
+
+    #[test]
+    fn no_pty() {
+        assert_eq!(
+            parse_options("no-pty"),
+            KeyOptions {
+                no_pty: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn command_with_spaces_and_quotes() {
+        assert_eq!(
+            parse_options(r#"command="echo \"hello world\"",no-pty"#),
+            KeyOptions {
+                command: Some(r#"echo "hello world""#.to_owned()),
+                no_pty: true,
+                from: None,
+            }
+        );
+    }
+
+    #[test]
+    fn from() {
+        let options = parse_options(r#"from="192.0.2.*,!192.0.2.13,2001:db8::/32""#);
+        assert_eq!(
+            options.from,
+            Some(vec![
+                "192.0.2.*".to_owned(),
+                "!192.0.2.13".to_owned(),
+                "2001:db8::/32".to_owned()
+            ])
+        );
+        let allows = |addr: &str| options.allows_source(addr.parse::<IpAddr>().unwrap());
+        assert!(allows("192.0.2.1"));
+        assert!(allows("::ffff:192.0.2.1"));
+        assert!(!allows("192.0.2.13"));
+        assert!(!allows("198.51.100.1"));
+        assert!(allows("2001:db8::1"));
+        assert!(!allows("2001:db9::1"));
+    }
+
+    #[test]
+    fn from_cidr() {
+        let options = parse_options(r#"from="10.0.0.0/8""#);
+        assert!(options.allows_source("10.1.2.3".parse().unwrap()));
+        assert!(!options.allows_source("11.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn no_from_allows_everything() {
+        assert!(KeyOptions::default().allows_source("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn unsupported_option() {
+        assert_skipped(&format!("no-agent-forwarding {KEY}"));
+    }
+
+    #[test]
+    fn unterminated_option() {
+        assert_skipped(&format!(r#"command="echo {KEY}"#));
     }
 }
@@ -0,0 +1,119 @@
+//! `authorized_principals` files, listing the certificate principals that may log in as a user.
+//! See `AuthorizedPrincipalsFile` in sshd_config(5).
+
+use std::path::{Path, PathBuf};
+
+pub struct AuthorizedPrincipals {
+    pub principals: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid authorized_principals: {0}")]
+pub struct Error(String);
+
+impl AuthorizedPrincipals {
+    /// Parses one principal per line, ignoring empty lines and comments starting with `#`.
+    pub fn parse(authorized_principals: &str) -> Result<Self, Error> {
+        let mut principals = Vec::new();
+
+        for line in authorized_principals.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.contains(char::is_whitespace) {
+                return Err(Error(format!("unsupported options on line: {line}")));
+            }
+            principals.push(line.to_owned());
+        }
+
+        Ok(Self { principals })
+    }
+
+    /// The first of the certificate's principals that is authorized, if any.
+    pub fn find_authorized<'a>(&self, cert_principals: &'a [String]) -> Option<&'a str> {
+        cert_principals
+            .iter()
+            .find(|principal| self.principals.contains(principal))
+            .map(String::as_str)
+    }
+
+    /// Expands the `%u` (user name), `%h` (home directory), and `%%` tokens
+    /// in the path of an `authorized_principals` file.
+    /// Relative paths are relative to the home directory.
+    pub fn expand_path(path: &str, user: &str, home: &Path) -> Result<PathBuf, Error> {
+        let mut expanded = String::new();
+        let mut chars = path.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('u') => expanded.push_str(user),
+                Some('h') => expanded.push_str(
+                    home.to_str()
+                        .ok_or_else(|| Error("home directory is not valid UTF-8".to_owned()))?,
+                ),
+                Some('%') => expanded.push('%'),
+                Some(token) => return Err(Error(format!("unknown token %{token} in path"))),
+                None => return Err(Error("trailing % in path".to_owned())),
+            }
+        }
+
+        Ok(home.join(expanded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::AuthorizedPrincipals;
+
+    #[test]
+    fn listed_principal_accepted() {
+        let principals = AuthorizedPrincipals::parse("nora\nadmins\n").unwrap();
+        let cert = ["web-team".to_owned(), "admins".to_owned()];
+        assert_eq!(principals.find_authorized(&cert), Some("admins"));
+    }
+
+    #[test]
+    fn unlisted_principal_rejected() {
+        let principals = AuthorizedPrincipals::parse("nora\nadmins\n").unwrap();
+        let cert = ["peter".to_owned()];
+        assert_eq!(principals.find_authorized(&cert), None);
+        assert_eq!(principals.find_authorized(&[]), None);
+    }
+
+    #[test]
+    fn comments_and_empty_lines() {
+        let principals = AuthorizedPrincipals::parse("# the team\n\n  nora  \n").unwrap();
+        assert_eq!(principals.principals, ["nora"]);
+    }
+
+    #[test]
+    fn options_unsupported() {
+        let principals = AuthorizedPrincipals::parse("command=\"true\" nora\n");
+        assert!(principals.is_err());
+    }
+
+    #[test]
+    fn expand_path() {
+        let home = Path::new("/home/nora");
+        assert_eq!(
+            AuthorizedPrincipals::expand_path("/etc/ssh/principals/%u", "nora", home).unwrap(),
+            PathBuf::from("/etc/ssh/principals/nora")
+        );
+        assert_eq!(
+            AuthorizedPrincipals::expand_path("%h/.ssh/principals", "nora", home).unwrap(),
+            PathBuf::from("/home/nora/.ssh/principals")
+        );
+        assert_eq!(
+            AuthorizedPrincipals::expand_path(".ssh/100%%", "nora", home).unwrap(),
+            PathBuf::from("/home/nora/.ssh/100%")
+        );
+        assert!(AuthorizedPrincipals::expand_path("/etc/%x", "nora", home).is_err());
+        assert!(AuthorizedPrincipals::expand_path("/etc/%", "nora", home).is_err());
+    }
+}
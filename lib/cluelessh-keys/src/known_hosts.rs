@@ -0,0 +1,516 @@
+//! `known_hosts` files, recording the host keys of servers a client has connected to.
+//! See the SSH_KNOWN_HOSTS FILE FORMAT section in sshd(8).
+
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use cluelessh_format::ParseError;
+
+use crate::public::{InvalidLine, PublicKey};
+
+pub struct KnownHosts {
+    lines: Vec<Line>,
+    errors: Vec<InvalidLine>,
+}
+
+enum Line {
+    Entry(KnownHost),
+    /// A key marked with `@revoked`, which must not be accepted for any host.
+    Revoked(KnownHost),
+    /// Comments, hashed host names, other markers, and keys we don't support.
+    /// They are kept as-is so that rewriting the file does not lose them.
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownHost {
+    /// The host name patterns, like `example.com` or `[example.com]:2222`.
+    pub hosts: Vec<String>,
+    pub key: PublicKey,
+}
+
+impl KnownHosts {
+    pub fn parse(known_hosts: &str) -> Self {
+        let mut errors = Vec::new();
+        let lines = known_hosts
+            .lines()
+            .enumerate()
+            .map(|(i, line)| match parse_line(line) {
+                Ok(Some(line)) => line,
+                Ok(None) => Line::Other(line.to_owned()),
+                Err(err) => {
+                    errors.push(InvalidLine {
+                        line: i + 1,
+                        message: err.0,
+                    });
+                    Line::Other(line.to_owned())
+                }
+            })
+            .collect();
+
+        Self { lines, errors }
+    }
+
+    /// The lines that look like entries but could not be parsed, like ones with unsupported key types.
+    /// They are kept like other unsupported lines.
+    pub fn errors(&self) -> &[InvalidLine] {
+        &self.errors
+    }
+
+    /// The name a host is recorded under, which includes the port if it is not 22.
+    pub fn host_name(host: &str, port: u16) -> String {
+        if port == 22 {
+            host.to_owned()
+        } else {
+            format!("[{host}]:{port}")
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &KnownHost> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            Line::Revoked(_) | Line::Other(_) => None,
+        })
+    }
+
+    /// Whether the key is marked as `@revoked`. The host patterns of the marker are ignored,
+    /// so a key revoked for any host is rejected for all of them.
+    pub fn is_revoked(&self, key: &PublicKey) -> bool {
+        self.lines
+            .iter()
+            .any(|line| matches!(line, Line::Revoked(entry) if entry.key == *key))
+    }
+
+    /// Marks a key as `@revoked` for all hosts.
+    pub fn revoke(&mut self, key: PublicKey) {
+        if !self.is_revoked(&key) {
+            self.lines.push(Line::Revoked(KnownHost {
+                hosts: vec!["*".to_owned()],
+                key,
+            }));
+        }
+    }
+
+    /// Whether `key` is known for a host name from [`KnownHosts::host_name`].
+    pub fn status(&self, host_name: &str, key: &PublicKey) -> HostKeyStatus {
+        if self.is_revoked(key) {
+            return HostKeyStatus::Revoked;
+        }
+        let mut known = self.find(host_name).peekable();
+        if known.peek().is_none() {
+            HostKeyStatus::Unknown
+        } else if known.any(|known| known == key) {
+            HostKeyStatus::Known
+        } else {
+            HostKeyStatus::Changed
+        }
+    }
+
+    /// All keys recorded for a host name from [`KnownHosts::host_name`].
+    pub fn find<'a>(&'a self, host_name: &'a str) -> impl Iterator<Item = &'a PublicKey> {
+        self.entries()
+            .filter(move |entry| entry.hosts.iter().any(|host| host == host_name))
+            .map(|entry| &entry.key)
+    }
+
+    /// Records a new key for a host, for example on first connection.
+    pub fn add(&mut self, host_name: &str, key: PublicKey) {
+        self.lines.push(Line::Entry(KnownHost {
+            hosts: vec![host_name.to_owned()],
+            key,
+        }));
+    }
+
+    /// Replaces the host's keys of the same algorithm with a new key, for host key rotation.
+    pub fn replace(&mut self, host_name: &str, key: PublicKey) {
+        self.lines.retain_mut(|line| {
+            let Line::Entry(entry) = line else {
+                return true;
+            };
+            if entry.key.algorithm_name() != key.algorithm_name() {
+                return true;
+            }
+            entry.hosts.retain(|host| host != host_name);
+            !entry.hosts.is_empty()
+        });
+        self.add(host_name, key);
+    }
+
+    /// Sets the keys of a host to exactly `keys`, like OpenSSH's `UpdateHostKeys`:
+    /// keys the host no longer has are removed and new ones are added.
+    /// Returns whether anything changed.
+    pub fn set_host_keys(&mut self, host_name: &str, keys: &[PublicKey]) -> bool {
+        let mut changed = false;
+        self.lines.retain_mut(|line| {
+            let Line::Entry(entry) = line else {
+                return true;
+            };
+            if keys.contains(&entry.key) || !entry.hosts.iter().any(|host| host == host_name) {
+                return true;
+            }
+            entry.hosts.retain(|host| host != host_name);
+            changed = true;
+            !entry.hosts.is_empty()
+        });
+        for key in keys {
+            if !self.find(host_name).any(|known| known == key) {
+                self.add(host_name, key.clone());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Writes the file atomically, so that a crash in the middle of writing
+    /// leaves either the old or the new file, never a truncated one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_atomic(path, |file| file.write_all(self.to_string().as_bytes()))
+    }
+}
+
+impl Display for KnownHosts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Entry(entry) => writeln!(f, "{} {}", entry.hosts.join(","), entry.key)?,
+                Line::Revoked(entry) => {
+                    writeln!(f, "@revoked {} {}", entry.hosts.join(","), entry.key)?
+                }
+                Line::Other(line) => writeln!(f, "{line}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Result<Option<Line>, ParseError> {
+    if let Some(revoked) = line.trim_start().strip_prefix("@revoked ") {
+        return Ok(parse_entry(revoked)?.map(Line::Revoked));
+    }
+    Ok(parse_entry(line)?.map(Line::Entry))
+}
+
+fn parse_entry(line: &str) -> Result<Option<KnownHost>, ParseError> {
+    // Other markers and hashed host names are not supported.
+    if line.trim_start().starts_with(['@', '|']) {
+        return Ok(None);
+    }
+    let Some(line) = PublicKey::from_openssh_line(line)? else {
+        return Ok(None);
+    };
+    let Some(hosts) = line.prefix else {
+        return Err(ParseError("missing host names".to_owned()));
+    };
+
+    Ok(Some(KnownHost {
+        hosts: hosts.split(',').map(ToOwned::to_owned).collect(),
+        key: line.key.key,
+    }))
+}
+
+/// Where the host keys of servers are recorded, to check that a server is the one we connected to before.
+/// [`KnownHostsFile`] uses a `known_hosts` file, other implementations can keep them in a database,
+/// or in memory with [`KnownHosts`].
+pub trait HostKeyStore: Send {
+    /// Whether `key` is known for a host name from [`KnownHosts::host_name`].
+    fn lookup(&mut self, host_name: &str, key: &PublicKey) -> io::Result<HostKeyStatus>;
+    /// Records a new key for a host, for example on first connection.
+    fn add(&mut self, host_name: &str, key: &PublicKey) -> io::Result<()>;
+    /// Marks a key as revoked, so that it is not accepted for any host anymore.
+    fn mark_revoked(&mut self, key: &PublicKey) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The key is recorded for the host.
+    Known,
+    /// No keys are recorded for the host.
+    Unknown,
+    /// Only other keys are recorded for the host, someone may be impersonating it.
+    Changed,
+    /// The key has been revoked.
+    Revoked,
+}
+
+impl HostKeyStore for KnownHosts {
+    fn lookup(&mut self, host_name: &str, key: &PublicKey) -> io::Result<HostKeyStatus> {
+        Ok(self.status(host_name, key))
+    }
+
+    fn add(&mut self, host_name: &str, key: &PublicKey) -> io::Result<()> {
+        KnownHosts::add(self, host_name, key.clone());
+        Ok(())
+    }
+
+    fn mark_revoked(&mut self, key: &PublicKey) -> io::Result<()> {
+        self.revoke(key.clone());
+        Ok(())
+    }
+}
+
+/// A [`HostKeyStore`] backed by a `known_hosts` file, like `~/.ssh/known_hosts`.
+/// The file is read again for every operation, so changes of other processes are picked up,
+/// and a missing file is treated as empty.
+pub struct KnownHostsFile {
+    path: PathBuf,
+}
+
+impl KnownHostsFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn load(&self) -> io::Result<KnownHosts> {
+        match fs::read_to_string(&self.path) {
+            Ok(file) => Ok(KnownHosts::parse(&file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(KnownHosts::parse("")),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl HostKeyStore for KnownHostsFile {
+    fn lookup(&mut self, host_name: &str, key: &PublicKey) -> io::Result<HostKeyStatus> {
+        Ok(self.load()?.status(host_name, key))
+    }
+
+    fn add(&mut self, host_name: &str, key: &PublicKey) -> io::Result<()> {
+        let mut known_hosts = self.load()?;
+        known_hosts.add(host_name, key.clone());
+        known_hosts.save(&self.path)
+    }
+
+    fn mark_revoked(&mut self, key: &PublicKey) -> io::Result<()> {
+        let mut known_hosts = self.load()?;
+        known_hosts.revoke(key.clone());
+        known_hosts.save(&self.path)
+    }
+}
+
+/// Writes to a temporary file in the same directory, syncs it, and renames it over `path`.
+fn write_atomic(path: &Path, write: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        write(&mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+
+    // Make sure the rename itself is persisted.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+
+    use crate::public::PublicKey;
+
+    use super::{HostKeyStatus, HostKeyStore, KnownHosts, KnownHostsFile};
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP";
+    const OTHER_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPb9OqRoJ9Ou0dZbiJ3jUCLWjsD2RvIjZYxOzqLrxA3H";
+
+    fn key(key: &str) -> PublicKey {
+        key.parse::<crate::public::PublicKeyWithComment>()
+            .unwrap()
+            .key
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cluelessh-known-hosts-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn find() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "# comment\nexample.com,192.0.2.1 {KEY}\n[example.com]:2222 {OTHER_KEY}\n"
+        ));
+        assert_eq!(
+            known_hosts.find("example.com").collect::<Vec<_>>(),
+            [&key(KEY)]
+        );
+        assert_eq!(
+            known_hosts.find("192.0.2.1").collect::<Vec<_>>(),
+            [&key(KEY)]
+        );
+        assert_eq!(
+            known_hosts
+                .find(&KnownHosts::host_name("example.com", 2222))
+                .collect::<Vec<_>>(),
+            [&key(OTHER_KEY)]
+        );
+        assert_eq!(known_hosts.find("example.org").count(), 0);
+    }
+
+    #[test]
+    fn unsupported_lines_preserved() {
+        let file = format!(
+            "# comment\n|1|c2FsdA==|aGFzaA== {KEY}\n@revoked * {KEY}\nexample.com ssh-rsa AAAAB3NzaC1yc2E=\nexample.org {KEY}\n"
+        );
+        let mut known_hosts = KnownHosts::parse(&file);
+        assert_eq!(known_hosts.entries().count(), 1);
+        assert_eq!(known_hosts.errors().len(), 1);
+        assert_eq!(known_hosts.errors()[0].line, 4);
+
+        known_hosts.add("example.net", key(OTHER_KEY));
+        assert_eq!(
+            known_hosts.to_string(),
+            format!("{file}example.net {OTHER_KEY}\n")
+        );
+    }
+
+    #[test]
+    fn replace() {
+        let mut known_hosts =
+            KnownHosts::parse(&format!("example.com,192.0.2.1 {KEY}\nexample.org {KEY}\n"));
+        known_hosts.replace("example.com", key(OTHER_KEY));
+        assert_eq!(
+            known_hosts.to_string(),
+            format!("192.0.2.1 {KEY}\nexample.org {KEY}\nexample.com {OTHER_KEY}\n")
+        );
+    }
+
+    #[test]
+    fn set_host_keys() {
+        let file = format!("example.com,192.0.2.1 {KEY}\nexample.org {KEY}\n");
+        let mut known_hosts = KnownHosts::parse(&file);
+        assert!(!known_hosts.set_host_keys("example.com", &[key(KEY)]));
+        assert_eq!(known_hosts.to_string(), file);
+
+        assert!(known_hosts.set_host_keys("example.com", &[key(OTHER_KEY)]));
+        assert_eq!(
+            known_hosts.to_string(),
+            format!("192.0.2.1 {KEY}\nexample.org {KEY}\nexample.com {OTHER_KEY}\n")
+        );
+    }
+
+    #[test]
+    fn status() {
+        let mut known_hosts = KnownHosts::parse(&format!(
+            "example.com {KEY}\nexample.org {OTHER_KEY}\n@revoked * {OTHER_KEY}\n"
+        ));
+        assert_eq!(
+            known_hosts.status("example.com", &key(KEY)),
+            HostKeyStatus::Known
+        );
+        assert_eq!(
+            known_hosts.status("example.com", &key(OTHER_KEY)),
+            HostKeyStatus::Revoked
+        );
+        assert_eq!(
+            known_hosts.status("example.org", &key(KEY)),
+            HostKeyStatus::Changed
+        );
+        assert_eq!(
+            known_hosts.status("example.net", &key(KEY)),
+            HostKeyStatus::Unknown
+        );
+
+        known_hosts.mark_revoked(&key(KEY)).unwrap();
+        assert_eq!(
+            known_hosts.status("example.com", &key(KEY)),
+            HostKeyStatus::Revoked
+        );
+        // Revoking twice doesn't add another marker.
+        known_hosts.mark_revoked(&key(KEY)).unwrap();
+        assert_eq!(
+            known_hosts.to_string(),
+            format!(
+                "example.com {KEY}\nexample.org {OTHER_KEY}\n@revoked * {OTHER_KEY}\n@revoked * {KEY}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = test_dir("store");
+        let path = dir.join("known_hosts");
+        let mut store = KnownHostsFile::new(&path);
+
+        assert_eq!(
+            store.lookup("example.com", &key(KEY)).unwrap(),
+            HostKeyStatus::Unknown
+        );
+        store.add("example.com", &key(KEY)).unwrap();
+        assert_eq!(
+            store.lookup("example.com", &key(KEY)).unwrap(),
+            HostKeyStatus::Known
+        );
+        store.mark_revoked(&key(KEY)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("example.com {KEY}\n@revoked * {KEY}\n")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn save() {
+        let dir = test_dir("save");
+        let path = dir.join("known_hosts");
+        std::fs::write(&path, format!("example.com {KEY}\n")).unwrap();
+
+        let mut known_hosts = KnownHosts::parse(&std::fs::read_to_string(&path).unwrap());
+        known_hosts.add("example.org", key(OTHER_KEY));
+        known_hosts.save(&path).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("example.com {KEY}\nexample.org {OTHER_KEY}\n")
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_write_keeps_original() {
+        let dir = test_dir("interrupted");
+        let path = dir.join("known_hosts");
+        let original = format!("example.com {KEY}\n");
+        std::fs::write(&path, &original).unwrap();
+
+        let result = super::write_atomic(&path, |file| {
+            file.write_all(b"example.org ssh-ed")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(result.is_err());
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), original);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,9 @@
 pub mod authorized_keys;
+pub mod authorized_principals;
+pub mod certificate;
 mod crypto;
 pub mod host_keys;
+pub mod known_hosts;
 pub mod private;
 pub mod public;
 pub mod signature;
@@ -232,6 +232,11 @@ impl EncryptedPrivateKeys {
                         private_key,
                     }
                 }
+                PublicKey::Certificate { .. } => {
+                    return Err(cluelessh_format::ParseError(format!(
+                        "private key files cannot contain certificates"
+                    )));
+                }
             };
 
             let comment = p.utf8_string()?;
@@ -454,7 +459,7 @@ NZ1XxE87G/z54ftU4Nhj9SCIDPNXB5/1xu/6mA==
         for key_bytes in keys {
             let key_bytes = pem::parse(key_bytes).unwrap();
             let key_bytes = key_bytes.contents();
-            let keys = EncryptedPrivateKeys::parse(&key_bytes).unwrap();
+            let keys = EncryptedPrivateKeys::parse(key_bytes).unwrap();
             let decrypted = keys.decrypt(passphrase).unwrap();
 
             let encrypted = decrypted[0]
@@ -11,7 +11,10 @@ use base64::Engine;
 
 use cluelessh_format::{ParseError, Reader, Writer};
 
-use crate::signature::Signature;
+use crate::{
+    certificate::{Certificate, ED25519_CERT_ALGORITHM},
+    signature::Signature,
+};
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum PublicKey {
@@ -21,6 +24,8 @@ pub enum PublicKey {
     EcdsaSha2NistP256 {
         public_key: p256::ecdsa::VerifyingKey,
     },
+    /// A key signed by a certificate authority, see [`crate::certificate`].
+    Certificate { certificate: Box<Certificate> },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -29,20 +34,38 @@ pub struct PublicKeyWithComment {
     pub comment: String,
 }
 
+/// A line of an `authorized_keys`, `known_hosts` or `.pub` file, see [`PublicKey::from_openssh_line`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKeyLine {
+    /// Everything in front of the key, like the options in `authorized_keys`
+    /// or the host names in `known_hosts`.
+    pub prefix: Option<String>,
+    pub key: PublicKeyWithComment,
+}
+
+/// A line of a file with public keys that could not be parsed and was skipped.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct InvalidLine {
+    /// The number of the line, starting at 1.
+    pub line: usize,
+    pub message: String,
+}
+
 impl FromStr for PublicKeyWithComment {
     type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_ascii_whitespace();
-        let alg = parts
-            .next()
-            .ok_or_else(|| ParseError("missing algorithm on line".to_owned()))?;
-        let key_blob = parts
-            .next()
-            .ok_or_else(|| ParseError("missing key on line".to_owned()))?;
+        let (alg, rest) = split_word(s.trim());
+        let (key_blob, comment) = split_word(rest);
+        if alg.is_empty() {
+            return Err(ParseError("missing algorithm on line".to_owned()));
+        }
+        if key_blob.is_empty() {
+            return Err(ParseError("missing key on line".to_owned()));
+        }
         let key_blob = base64::prelude::BASE64_STANDARD
             .decode(key_blob)
             .map_err(|err| ParseError(format!("invalid base64 encoding for key: {err}")))?;
-        let comment = parts.next().unwrap_or_default();
 
         let public_key = PublicKey::from_wire_encoding(&key_blob)
             .map_err(|err| ParseError(format!("unsupported key: {err}")))?;
@@ -62,7 +85,71 @@ impl FromStr for PublicKeyWithComment {
     }
 }
 
+/// Splits off the first word, returning the rest with leading whitespace removed.
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (s, ""),
+    }
+}
+
+/// Whether the word is the algorithm of a key, even one that is not supported.
+fn is_key_type(word: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|prefix| word.starts_with(prefix))
+}
+
+/// Splits the first field from the rest of the line at the first whitespace outside of quotes,
+/// as quoted option values in `authorized_keys` may contain spaces.
+fn split_prefix(line: &str) -> Option<(&str, &str)> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c.is_ascii_whitespace() && !in_quotes => {
+                return Some((&line[..i], line[i..].trim_start()));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
 impl PublicKey {
+    /// Parses a line like `[prefix] ssh-ed25519 AAAA... [comment]`, as used in `authorized_keys`,
+    /// `known_hosts` and `.pub` files. Returns `None` for empty lines and comments starting with `#`.
+    ///
+    /// Like sshd, the line only has a prefix if it does not start with a key type,
+    /// or if the key type is followed by another one, like in `ssh-host.example ssh-ed25519 AAAA...`.
+    /// Lines with unsupported key types are an error, so that callers can skip and report them.
+    pub fn from_openssh_line(line: &str) -> Result<Option<PublicKeyLine>, ParseError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (prefix, key) = match split_prefix(line) {
+            Some((first, rest)) if !is_key_type(first) || is_key_type(split_word(rest).0) => {
+                (Some(first.to_owned()), rest)
+            }
+            _ => (None, line),
+        };
+
+        Ok(Some(PublicKeyLine {
+            prefix,
+            key: key.parse()?,
+        }))
+    }
+
+    /// The key in the canonical format of `authorized_keys` and `.pub` files, like `ssh-ed25519 AAAA...`.
+    pub fn to_openssh_line(&self) -> String {
+        self.to_string()
+    }
+
     /// Parses an SSH public key from its wire encoding as specified in
     /// RFC4253, RFC5656, and RFC8709.
     pub fn from_wire_encoding(bytes: &[u8]) -> cluelessh_format::Result<Self> {
@@ -93,12 +180,22 @@ impl PublicKey {
 
                 Self::EcdsaSha2NistP256 { public_key }
             }
+            ED25519_CERT_ALGORITHM => {
+                let certificate = Certificate::from_wire_encoding(bytes)?;
+                return Ok(Self::Certificate {
+                    certificate: Box::new(certificate),
+                });
+            }
             _ => return Err(ParseError(format!("unsupported key type: {alg}"))),
         };
         Ok(k)
     }
 
     pub fn to_wire_encoding(&self) -> Vec<u8> {
+        if let Self::Certificate { certificate } = self {
+            return certificate.to_wire_encoding();
+        }
+
         let mut p = Writer::new();
         p.string(self.algorithm_name());
         match self {
@@ -113,6 +210,7 @@ impl PublicKey {
                 // But OpenSSH does not appear to support that, so let's NOT use it.
                 p.string(public_key.to_encoded_point(false).as_bytes());
             }
+            Self::Certificate { .. } => unreachable!(),
         }
         p.finish()
     }
@@ -121,6 +219,115 @@ impl PublicKey {
         match self {
             Self::Ed25519 { .. } => "ssh-ed25519",
             Self::EcdsaSha2NistP256 { .. } => "ecdsa-sha2-nistp256",
+            Self::Certificate { certificate } => certificate.algorithm_name(),
+        }
+    }
+
+    /// The `SHA256:` fingerprint of the key, the unpadded base64 of the hash of the wire encoding,
+    /// like `ssh-keygen -l` shows it.
+    pub fn fingerprint_sha256(&self) -> String {
+        format!(
+            "SHA256:{}",
+            base64::prelude::BASE64_STANDARD_NO_PAD.encode(self.sha256_digest())
+        )
+    }
+
+    fn sha256_digest(&self) -> [u8; 32] {
+        use sha2::Digest;
+        sha2::Sha256::digest(self.to_wire_encoding()).into()
+    }
+
+    /// The "drunken bishop" visualization of the SHA256 fingerprint,
+    /// like `ssh-keygen -lv` shows it, without a trailing newline.
+    pub fn randomart(&self) -> String {
+        const WIDTH: usize = 17;
+        const HEIGHT: usize = 9;
+        const SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+        const START: u8 = SYMBOLS.len() as u8 - 2;
+        const END: u8 = SYMBOLS.len() as u8 - 1;
+
+        let mut field = [[0u8; HEIGHT]; WIDTH];
+        let (mut x, mut y) = (WIDTH / 2, HEIGHT / 2);
+        for byte in self.sha256_digest() {
+            // Every two bits of the byte move the bishop diagonally, starting at the low bits.
+            for step in 0..4 {
+                let bits = byte >> (step * 2);
+                x = if bits & 1 == 1 {
+                    (x + 1).min(WIDTH - 1)
+                } else {
+                    x.saturating_sub(1)
+                };
+                y = if bits & 2 == 2 {
+                    (y + 1).min(HEIGHT - 1)
+                } else {
+                    y.saturating_sub(1)
+                };
+                if field[x][y] < START - 1 {
+                    field[x][y] += 1;
+                }
+            }
+        }
+        field[WIDTH / 2][HEIGHT / 2] = START;
+        field[x][y] = END;
+
+        let (key_type, bits) = self.randomart_type();
+        let mut title = format!("[{key_type} {bits}]");
+        if title.len() > WIDTH - 2 {
+            title = format!("[{key_type}]");
+        }
+
+        let border = |label: &str| {
+            let left = (WIDTH - label.len()) / 2;
+            let right = WIDTH - label.len() - left;
+            format!("+{}{label}{}+", "-".repeat(left), "-".repeat(right))
+        };
+
+        let mut art = border(&title);
+        art.push('\n');
+        for y in 0..HEIGHT {
+            art.push('|');
+            art.extend((0..WIDTH).map(|x| SYMBOLS[usize::from(field[x][y])] as char));
+            art.push_str("|\n");
+        }
+        art.push_str(&border("[SHA256]"));
+        art
+    }
+
+    /// The key type and size in bits, like OpenSSH shows them in the randomart header.
+    fn randomart_type(&self) -> (&'static str, u32) {
+        match self {
+            Self::Ed25519 { .. } => ("ED25519", 256),
+            Self::EcdsaSha2NistP256 { .. } => ("ECDSA", 256),
+            Self::Certificate { certificate } => {
+                let (key_type, bits) = certificate.key.randomart_type();
+                let key_type = match key_type {
+                    "ED25519" => "ED25519-CERT",
+                    _ => "ECDSA-CERT",
+                };
+                (key_type, bits)
+            }
+        }
+    }
+
+    /// The legacy `MD5:` fingerprint of the key, colon-separated hex of the hash of the wire encoding,
+    /// like `ssh-keygen -l -E md5` shows it.
+    pub fn fingerprint_md5(&self) -> String {
+        use md5::Digest;
+        let digest = md5::Md5::digest(self.to_wire_encoding());
+        let hex = digest
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>();
+        format!("MD5:{}", hex.join(":"))
+    }
+
+    /// The algorithm of the signatures made with this key.
+    /// This is different from [`Self::algorithm_name`] for certificates,
+    /// which sign with the certified key.
+    pub fn signature_algorithm_name(&self) -> &'static str {
+        match self {
+            Self::Certificate { certificate } => certificate.key.signature_algorithm_name(),
+            _ => self.algorithm_name(),
         }
     }
 
@@ -128,12 +335,20 @@ impl PublicKey {
         match self {
             PublicKey::Ed25519 { public_key } => match signature {
                 Signature::Ed25519 { signature } => {
-                    public_key.verify_strict(data, &signature).is_ok()
+                    public_key.verify_strict(data, signature).is_ok()
+                }
+                _ => false,
+            },
+            PublicKey::EcdsaSha2NistP256 { public_key } => match signature {
+                Signature::EcdsaSha2NistP256 { signature } => {
+                    use p256::ecdsa::signature::Verifier;
+
+                    public_key.verify(data, signature).is_ok()
                 }
                 _ => false,
             },
-            PublicKey::EcdsaSha2NistP256 { .. } => {
-                todo!("ecdsa-sha2-nistp256 signature verification")
+            PublicKey::Certificate { certificate } => {
+                certificate.key.verify_signature(data, signature)
             }
         }
     }
@@ -152,7 +367,7 @@ impl Display for PublicKey {
                 let encoded_pubkey = b64encode(&self.to_wire_encoding());
                 write!(f, "{} {encoded_pubkey}", self.algorithm_name())
             }
-            Self::EcdsaSha2NistP256 { .. } => {
+            Self::EcdsaSha2NistP256 { .. } | Self::Certificate { .. } => {
                 let encoded_pubkey = b64encode(&self.to_wire_encoding());
                 write!(f, "{} {encoded_pubkey}", self.algorithm_name())
             }
@@ -160,6 +375,23 @@ impl Display for PublicKey {
     }
 }
 
+impl PublicKeyLine {
+    /// The line in canonical form, with single spaces between the fields.
+    pub fn to_openssh_line(&self) -> String {
+        let mut line = String::new();
+        if let Some(prefix) = &self.prefix {
+            line.push_str(prefix);
+            line.push(' ');
+        }
+        line.push_str(&self.key.key.to_openssh_line());
+        if !self.key.comment.is_empty() {
+            line.push(' ');
+            line.push_str(&self.key.comment);
+        }
+        line
+    }
+}
+
 fn b64encode(bytes: &[u8]) -> String {
     base64::prelude::BASE64_STANDARD.encode(bytes)
 }
@@ -207,7 +439,7 @@ impl<'de> serde::Deserialize<'de> for PublicKey {
 mod tests {
     use base64::Engine;
 
-    use super::PublicKey;
+    use super::{PublicKey, PublicKeyWithComment};
 
     #[track_caller]
     fn test_roundtrip(keys: &[&str]) {
@@ -221,6 +453,123 @@ mod tests {
         }
     }
 
+    #[test]
+    fn fingerprints() {
+        // Generated with `ssh-keygen -l -f` and `ssh-keygen -l -E md5 -f`.
+        let vectors = [
+            (
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHMg8EjDF7EZQtADecNJgtOgrF7BGZVkC860QPb56UqL",
+                "SHA256:+oqMNafTcYzU/VgnBOfRx/Pow57bcAQYCGLgmsnvu8g",
+                "MD5:68:af:88:f2:91:47:5b:65:bc:9a:67:d3:bb:c4:84:1e",
+            ),
+            (
+                "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBFq9zN+cGbQ0ebtDOX6abbLHoDBFDOGK77/yfKbiFD1g6XUV1TV33n50e2V30VftJaa8x7fGxL5UmzEpFhgAUEY=",
+                "SHA256:hMKNHiHdE7SpBe+GhqD61UsQQmLm1XXrbKqsIx3RHV4",
+                "MD5:c2:80:26:a5:22:5f:d9:da:e4:40:37:71:67:aa:f1:b0",
+            ),
+        ];
+        for (key, sha256, md5) in vectors {
+            let key = key.parse::<PublicKeyWithComment>().unwrap().key;
+            assert_eq!(key.fingerprint_sha256(), sha256);
+            assert_eq!(key.fingerprint_md5(), md5);
+        }
+    }
+
+    #[test]
+    fn openssh_lines() {
+        const KEY: &str =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHMg8EjDF7EZQtADecNJgtOgrF7BGZVkC860QPb56UqL";
+        let line = |line: &str| PublicKey::from_openssh_line(line).unwrap().unwrap();
+
+        let key = line(&format!("  {KEY}   my  laptop "));
+        assert_eq!(key.prefix, None);
+        assert_eq!(key.key.comment, "my  laptop");
+        assert_eq!(key.key.key.to_openssh_line(), KEY);
+        assert_eq!(key.to_openssh_line(), format!("{KEY} my  laptop"));
+
+        let key = line(&format!(r#"command="echo \"hi there\"",no-pty {KEY}"#));
+        assert_eq!(
+            key.prefix.as_deref(),
+            Some(r#"command="echo \"hi there\"",no-pty"#)
+        );
+        assert_eq!(key.key.comment, "");
+
+        // Host names that look like key types.
+        let key = line(&format!("ssh-server.example.com,[ecdsa-host]:2222 {KEY}"));
+        assert_eq!(
+            key.prefix.as_deref(),
+            Some("ssh-server.example.com,[ecdsa-host]:2222")
+        );
+        assert_eq!(
+            key.to_openssh_line(),
+            format!("ssh-server.example.com,[ecdsa-host]:2222 {KEY}")
+        );
+    }
+
+    #[test]
+    fn openssh_lines_invalid() {
+        assert_eq!(PublicKey::from_openssh_line("").unwrap(), None);
+        assert_eq!(PublicKey::from_openssh_line("   ").unwrap(), None);
+        assert_eq!(
+            PublicKey::from_openssh_line("# ssh-ed25519 AAAA").unwrap(),
+            None
+        );
+
+        for line in [
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQC7 rsa",
+            "ssh-ed25519",
+            "ssh-ed25519 not-base64!",
+            "no-pty ssh-ed25519",
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIHMg8EjDF7EZQtADecNJgtOgrF7BGZVkC860QPb56UqL",
+        ] {
+            assert!(
+                PublicKey::from_openssh_line(line).is_err(),
+                "{line} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn randomart() {
+        // Generated with `ssh-keygen -lv -f`.
+        let vectors = [
+            (
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHMg8EjDF7EZQtADecNJgtOgrF7BGZVkC860QPb56UqL",
+                "\
++--[ED25519 256]--+
+|    ..o ...o+. . |
+|   . . .  .o.+..o|
+|    .  . . .o .oo|
+| . +  . . . o o..|
+|  =  . oS  + =  .|
+|   .  o.o . . +. |
+|    +.oo     ..o.|
+| . *.=..      o+ |
+|  E B+...     ...|
++----[SHA256]-----+",
+            ),
+            (
+                "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBFq9zN+cGbQ0ebtDOX6abbLHoDBFDOGK77/yfKbiFD1g6XUV1TV33n50e2V30VftJaa8x7fGxL5UmzEpFhgAUEY=",
+                "\
++---[ECDSA 256]---+
+|.ooo+o+o .       |
+|+o.o.Bo=.E.      |
+|... B X.+.       |
+|.. = X +o        |
+|. . B o S+       |
+|.  o +  o        |
+|. . o o.         |
+| o +....         |
+|  o.oo.          |
++----[SHA256]-----+",
+            ),
+        ];
+        for (key, art) in vectors {
+            let key = key.parse::<PublicKeyWithComment>().unwrap().key;
+            assert_eq!(key.randomart(), art);
+        }
+    }
+
     #[test]
     fn ed25519() {
         test_roundtrip(&[
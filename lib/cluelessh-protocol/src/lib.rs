@@ -1,9 +1,10 @@
 use core::panic;
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::mem;
 
 use auth::AuthOption;
-use cluelessh_connection::ChannelOperation;
+use cluelessh_connection::{ChannelOperation, GlobalRequest};
+use cluelessh_format::numbers;
 use tracing::debug;
 
 // Re-exports
@@ -88,6 +89,12 @@ impl ServerConnection {
         self.transport.next_msg_to_send()
     }
 
+    /// Queues a `SSH_MSG_DISCONNECT` after all pending packets, see [`cluelessh_transport::server::ServerConnection::disconnect`].
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) {
+        self.progress();
+        self.transport.disconnect(reason_code, description);
+    }
+
     pub fn next_channel_update(&mut self) -> Option<cluelessh_connection::ChannelUpdate> {
         match &mut self.state {
             ServerConnectionState::Setup(..) | ServerConnectionState::Auth(_) => None,
@@ -154,6 +161,10 @@ impl ServerConnection {
 pub struct ClientConnection {
     transport: cluelessh_transport::client::ClientConnection,
     state: ClientConnectionState,
+    /// Global requests received during authentication.
+    global_requests: VecDeque<GlobalRequest>,
+    /// The identifier of the session, which stays the same after re-exchanging keys.
+    session_id: Option<cluelessh_transport::SessionId>,
 }
 
 enum ClientConnectionState {
@@ -170,16 +181,28 @@ impl ClientConnection {
         Self {
             transport,
             state: ClientConnectionState::Setup(Some(auth)),
+            global_requests: VecDeque::new(),
+            session_id: None,
         }
     }
 
     pub fn recv_bytes(&mut self, bytes: &[u8]) -> Result<()> {
-        self.transport.recv_bytes(bytes)?;
+        // Packets received before the server disconnected are still handled,
+        // so that channel data sent right before the disconnect is not lost.
+        let transport_result = self.transport.recv_bytes(bytes);
 
         if let ClientConnectionState::Setup(auth) = &mut self.state {
             if let Some(session_id) = self.transport.is_open() {
                 let mut auth = mem::take(auth).unwrap();
                 auth.set_session_id(session_id);
+                if let Some(algs) = self
+                    .transport
+                    .connection_info()
+                    .and_then(|info| info.server_sig_algs())
+                {
+                    auth.set_server_sig_algs(algs.into_iter().map(ToOwned::to_owned).collect());
+                }
+                self.session_id = Some(session_id);
 
                 debug!("Connection has been opened");
                 self.state = ClientConnectionState::Auth(auth);
@@ -190,6 +213,21 @@ impl ClientConnection {
         while let Some(packet) = self.transport.next_plaintext_packet() {
             match &mut self.state {
                 ClientConnectionState::Setup(_) => unreachable!("handled above"),
+                // Servers may send global requests like hostkeys-00@openssh.com right after the key exchange.
+                ClientConnectionState::Auth(_)
+                    if packet.packet_type() == numbers::SSH_MSG_GLOBAL_REQUEST =>
+                {
+                    let (request, reply) = cluelessh_connection::recv_global_request(&packet)?;
+                    if let Some(request) = request {
+                        cluelessh_connection::queue_global_request(
+                            &mut self.global_requests,
+                            request,
+                        )?;
+                    }
+                    if let Some(reply) = reply {
+                        self.transport.send_plaintext_packet(reply);
+                    }
+                }
                 ClientConnectionState::Auth(auth) => {
                     auth.recv_packet(packet)?;
                     for to_send in auth.packets_to_send() {
@@ -210,7 +248,7 @@ impl ClientConnection {
             }
         }
 
-        Ok(())
+        transport_result
     }
 
     pub fn auth(&mut self) -> Option<&mut auth::ClientAuth> {
@@ -231,10 +269,34 @@ impl ClientConnection {
         matches!(self.state, ClientConnectionState::Open(_))
     }
 
+    pub fn server_identification(&self) -> Option<&[u8]> {
+        self.transport.server_identification()
+    }
+
+    pub fn connection_info(&self) -> Option<&cluelessh_transport::client::ConnectionInfo> {
+        self.transport.connection_info()
+    }
+
+    /// The session identifier, once the first key exchange has finished.
+    pub fn session_id(&self) -> Option<cluelessh_transport::SessionId> {
+        self.session_id
+    }
+
+    /// See [`cluelessh_transport::client::ClientConnection::received_disconnect`].
+    pub fn received_disconnect(&self) -> Option<(u32, &str)> {
+        self.transport.received_disconnect()
+    }
+
     pub fn next_msg_to_send(&mut self) -> Option<cluelessh_transport::Msg> {
         self.transport.next_msg_to_send()
     }
 
+    /// Queues a `SSH_MSG_DISCONNECT` after all pending packets, see [`cluelessh_transport::client::ClientConnection::disconnect`].
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) {
+        self.progress();
+        self.transport.disconnect(reason_code, description);
+    }
+
     pub fn next_channel_update(&mut self) -> Option<cluelessh_connection::ChannelUpdate> {
         match &mut self.state {
             ClientConnectionState::Setup(_) => None,
@@ -243,6 +305,18 @@ impl ClientConnection {
         }
     }
 
+    /// The next global request of the server, like its host keys.
+    /// Requests that are not handled by the application have already been answered.
+    pub fn next_global_request(&mut self) -> Option<GlobalRequest> {
+        if let Some(request) = self.global_requests.pop_front() {
+            return Some(request);
+        }
+        match &mut self.state {
+            ClientConnectionState::Open(con) => con.next_global_request(),
+            _ => None,
+        }
+    }
+
     pub fn do_operation(&mut self, op: ChannelOperation) {
         match &mut self.state {
             ClientConnectionState::Setup(_) | ClientConnectionState::Auth(_) => {
@@ -276,7 +350,7 @@ impl ClientConnection {
 pub mod auth {
     use std::collections::{HashSet, VecDeque};
 
-    use cluelessh_format::{numbers, NameList};
+    use cluelessh_format::{numbers, NameList, Writer};
     use cluelessh_keys::{public::PublicKey, signature::Signature};
     use cluelessh_transport::{packet::Packet, peer_error, Result, SessionId};
     use tracing::debug;
@@ -288,15 +362,30 @@ pub mod auth {
         options: HashSet<AuthOption>,
         banner: Option<String>,
         server_requests: VecDeque<ServerRequest>,
+        /// Whether the last [`ServerRequest`] has not been answered yet.
+        awaiting_result: bool,
+        /// Requests received while waiting for the answer to a [`ServerRequest`].
+        /// Clients may send multiple requests at once, but they have to be answered in order.
+        pending_packets: VecDeque<Packet>,
         session_id: SessionId,
+        /// The user and the number of prompts of the last [`ServerAuth::info_request`],
+        /// while waiting for the responses.
+        info_request: Option<(String, usize)>,
     }
 
+    /// How many requests a client may send while the server is still answering an earlier one,
+    /// far more than clients pipeline in practice.
+    const MAX_PENDING_PACKETS: usize = 64;
+
     pub enum ServerRequest {
         VerifyPassword(VerifyPassword),
         /// Check whether a pubkey is usable.
         CheckPubkey(CheckPublicKey),
         /// Verify the signature from a pubkey.
         VerifySignature(VerifySignature),
+        /// A step of keyboard-interactive authentication (RFC 4256),
+        /// answered with [`ServerAuth::info_request`] or [`ServerAuth::verification_result`].
+        KeyboardInteractive(KeyboardInteractive),
     }
 
     #[derive(Debug, Clone)]
@@ -316,14 +405,64 @@ pub mod auth {
         pub user: String,
         pub session_id: SessionId,
         pub public_key: PublicKey,
-        /// The signature. Guaranteed to match the algorithm of `public_key`.
+        /// The signature. Guaranteed to match [`PublicKey::signature_algorithm_name`] of `public_key`.
         pub signature: Signature,
     }
 
-    #[derive(Debug, PartialEq, Eq, Hash)]
+    #[derive(Debug, Clone)]
+    pub struct KeyboardInteractive {
+        pub user: String,
+        pub step: KeyboardInteractiveStep,
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum KeyboardInteractiveStep {
+        /// The client starts the exchange.
+        /// `submethods` are hints like `pam`, which may be ignored.
+        Start { submethods: String },
+        /// The responses to the prompts of the last [`ServerAuth::info_request`], in order.
+        Responses(Vec<String>),
+    }
+
+    /// Prompts sent to the user with `SSH_MSG_USERAUTH_INFO_REQUEST`.
+    #[derive(Debug, Clone, Default)]
+    pub struct InfoRequest {
+        pub name: String,
+        pub instruction: String,
+        pub prompts: Vec<Prompt>,
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Prompt {
+        pub prompt: String,
+        /// Whether the client may show the response while it is typed, false for secrets.
+        pub echo: bool,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub enum AuthOption {
         Password,
         PublicKey,
+        KeyboardInteractive,
+    }
+
+    impl AuthOption {
+        pub fn name(self) -> &'static str {
+            match self {
+                AuthOption::Password => "password",
+                AuthOption::PublicKey => "publickey",
+                AuthOption::KeyboardInteractive => "keyboard-interactive",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "password" => Some(AuthOption::Password),
+                "publickey" => Some(AuthOption::PublicKey),
+                "keyboard-interactive" => Some(AuthOption::KeyboardInteractive),
+                _ => None,
+            }
+        }
     }
 
     impl ServerAuth {
@@ -340,21 +479,63 @@ pub mod auth {
                 session_id,
                 banner,
                 server_requests: VecDeque::new(),
+                awaiting_result: false,
+                pending_packets: VecDeque::new(),
+                info_request: None,
             }
         }
 
         pub fn recv_packet(&mut self, packet: Packet) -> Result<()> {
             assert!(self.is_authenticated.is_none(), "Must not feed more packets to authentication after authentication is been completed, check with .is_authenticated()");
 
+            if self.awaiting_result {
+                if self.pending_packets.len() >= MAX_PENDING_PACKETS {
+                    return Err(peer_error!(
+                        "client sent more than {MAX_PENDING_PACKETS} requests without waiting for the results"
+                    ));
+                }
+                self.pending_packets.push_back(packet);
+                return Ok(());
+            }
+            self.handle_packet(packet)
+        }
+
+        fn handle_packet(&mut self, packet: Packet) -> Result<()> {
             // This is a super simplistic implementation of RFC4252 SSH authentication.
             // We ask for a public key, and always let that one pass.
             // The reason for this is that this makes it a lot easier to test locally.
             // It's not very good, but it's good enough for now.
             let mut p = packet.payload_parser();
 
-            if p.u8()? != numbers::SSH_MSG_USERAUTH_REQUEST {
+            let packet_type = p.u8()?;
+            if packet_type == numbers::SSH_MSG_USERAUTH_INFO_RESPONSE {
+                let Some((user, num_prompts)) = self.info_request.take() else {
+                    return Err(peer_error!(
+                        "client sent SSH_MSG_USERAUTH_INFO_RESPONSE without a request"
+                    ));
+                };
+                let num_responses = p.u32()?;
+                if num_responses as usize != num_prompts {
+                    return Err(peer_error!(
+                        "client sent {num_responses} responses to {num_prompts} prompts"
+                    ));
+                }
+                let mut responses = Vec::new();
+                for _ in 0..num_responses {
+                    responses.push(p.utf8_string()?.to_owned());
+                }
+
+                self.request(ServerRequest::KeyboardInteractive(KeyboardInteractive {
+                    user,
+                    step: KeyboardInteractiveStep::Responses(responses),
+                }));
+                return Ok(());
+            }
+            if packet_type != numbers::SSH_MSG_USERAUTH_REQUEST {
                 return Err(peer_error!("did not send SSH_MSG_SERVICE_REQUEST"));
             }
+            // Any new request abandons a keyboard-interactive exchange in progress.
+            self.info_request = None;
             let username = p.utf8_string()?;
             let service_name = p.utf8_string()?;
             let method_name = p.utf8_string()?;
@@ -387,11 +568,10 @@ pub mod auth {
                     }
                     let password = p.utf8_string()?;
 
-                    self.server_requests
-                        .push_back(ServerRequest::VerifyPassword(VerifyPassword {
-                            user: username.to_owned(),
-                            password: password.to_owned(),
-                        }));
+                    self.request(ServerRequest::VerifyPassword(VerifyPassword {
+                        user: username.to_owned(),
+                        password: password.to_owned(),
+                    }));
                 }
                 "publickey" => {
                     if !self.options.contains(&AuthOption::PublicKey) {
@@ -411,26 +591,40 @@ pub mod auth {
 
                     // Whether the client is just checking whether the public key is allowed.
                     if !has_signature {
-                        self.server_requests.push_back(ServerRequest::CheckPubkey(
-                            CheckPublicKey {
-                                user: username.to_owned(),
-                                public_key,
-                            },
-                        ));
+                        self.request(ServerRequest::CheckPubkey(CheckPublicKey {
+                            user: username.to_owned(),
+                            public_key,
+                        }));
                     } else {
                         let signature = p.string()?;
                         let signature = Signature::from_wire_encoding(signature)?;
-                        if signature.algorithm_name() != public_key.algorithm_name() {
+                        if signature.algorithm_name() != public_key.signature_algorithm_name() {
                             return Err(peer_error!("signature algorithm name mismatch"));
                         }
-                        self.server_requests
-                            .push_back(ServerRequest::VerifySignature(VerifySignature {
-                                user: username.to_owned(),
-                                session_id: self.session_id,
-                                public_key,
-                                signature,
-                            }));
+                        self.request(ServerRequest::VerifySignature(VerifySignature {
+                            user: username.to_owned(),
+                            session_id: self.session_id,
+                            public_key,
+                            signature,
+                        }));
+                    }
+                }
+                "keyboard-interactive" => {
+                    if !self.options.contains(&AuthOption::KeyboardInteractive) {
+                        self.has_failed = true;
+                        self.send_failure();
+                        return Ok(());
                     }
+
+                    let _language_tag = p.utf8_string()?;
+                    let submethods = p.utf8_string()?;
+
+                    self.request(ServerRequest::KeyboardInteractive(KeyboardInteractive {
+                        user: username.to_owned(),
+                        step: KeyboardInteractiveStep::Start {
+                            submethods: submethods.to_owned(),
+                        },
+                    }));
                 }
                 _ if self.has_failed => {
                     return Err(peer_error!(
@@ -449,7 +643,8 @@ pub mod auth {
             Ok(())
         }
 
-        pub fn pubkey_check_result(&mut self, is_ok: bool, key: PublicKey) {
+        /// Answers a [`ServerRequest::CheckPubkey`], and handles the requests received in the meantime.
+        pub fn pubkey_check_result(&mut self, is_ok: bool, key: PublicKey) -> Result<()> {
             if is_ok {
                 self.queue_packet(Packet::new_msg_userauth_pk_ok(
                     key.algorithm_name().as_bytes(),
@@ -459,10 +654,13 @@ pub mod auth {
                 self.send_failure();
                 // It's ok, don't treat this as a fatal failure.
             }
+            self.result_sent()
         }
 
+        /// Answers a [`ServerRequest::VerifyPassword`], [`ServerRequest::VerifySignature`]
+        /// or [`ServerRequest::KeyboardInteractive`], and handles the requests received in the meantime.
         // TODO: improve types with a newtype around an authenticated user
-        pub fn verification_result(&mut self, is_ok: bool, user: String) {
+        pub fn verification_result(&mut self, is_ok: bool, user: String) -> Result<()> {
             if is_ok {
                 self.queue_packet(Packet::new_msg_userauth_success());
                 self.is_authenticated = Some(user);
@@ -470,6 +668,49 @@ pub mod auth {
                 self.send_failure();
                 self.has_failed = true;
             }
+            self.result_sent()
+        }
+
+        /// Answers a [`ServerRequest::KeyboardInteractive`] by sending prompts to the user.
+        /// The responses arrive as another [`ServerRequest::KeyboardInteractive`].
+        pub fn info_request(&mut self, user: String, request: InfoRequest) -> Result<()> {
+            let mut packet = Packet::new_msg_userauth_info_request(
+                request.name.as_bytes(),
+                request.instruction.as_bytes(),
+                b"",
+                request.prompts.len() as u32,
+            );
+            let mut w = Writer::new();
+            for prompt in &request.prompts {
+                w.string(prompt.prompt.as_bytes());
+                w.bool(prompt.echo);
+            }
+            packet.payload.extend(w.finish());
+            self.queue_packet(packet);
+
+            self.info_request = Some((user, request.prompts.len()));
+            self.result_sent()
+        }
+
+        fn request(&mut self, request: ServerRequest) {
+            self.awaiting_result = true;
+            self.server_requests.push_back(request);
+        }
+
+        fn result_sent(&mut self) -> Result<()> {
+            self.awaiting_result = false;
+            while !self.awaiting_result {
+                let Some(packet) = self.pending_packets.pop_front() else {
+                    break;
+                };
+                if self.is_authenticated.is_some() {
+                    return Err(peer_error!(
+                        "client sent more requests before authentication succeeded"
+                    ));
+                }
+                self.handle_packet(packet)?;
+            }
+            Ok(())
         }
 
         pub fn packets_to_send(&mut self) -> impl Iterator<Item = Packet> + '_ {
@@ -494,10 +735,7 @@ pub mod auth {
         fn option_list(&self) -> String {
             self.options
                 .iter()
-                .map(|op| match op {
-                    AuthOption::Password => "password",
-                    AuthOption::PublicKey => "publickey",
-                })
+                .map(|op| op.name())
                 .collect::<Vec<&str>>()
                 .join(",")
         }
@@ -513,11 +751,53 @@ pub mod auth {
         user_requests: VecDeque<ClientUserRequest>,
         is_authenticated: bool,
         session_id: Option<SessionId>,
+        /// Never ask the user for anything, only use non-interactive methods.
+        batch_mode: bool,
+        /// The methods to try, in order.
+        methods: Vec<AuthOption>,
+        /// The methods that were attempted, once per attempt.
+        tried: Vec<AuthOption>,
+        /// Methods that can't be used, see [`ClientAuth::user_request_failed`].
+        skipped: Vec<AuthOption>,
+        /// The methods the server said can continue in the last failure.
+        server_methods: Vec<String>,
+        /// The keys to offer for public key authentication, in order.
+        public_keys: Vec<PublicKey>,
+        /// See [`ClientAuth::set_server_sig_algs`].
+        server_sig_algs: Option<Vec<String>>,
+        /// The index of the next key in `public_keys` to offer.
+        next_public_key: usize,
+        /// The keys we asked the server about, each waiting for `SSH_MSG_USERAUTH_PK_OK` or a failure, in order.
+        queried_public_keys: VecDeque<PublicKey>,
+        /// Keys the server accepted while another key was being signed with, tried if that fails.
+        accepted_public_keys: VecDeque<PublicKey>,
+        /// Whether we are signing with an accepted key, or waiting for the server to verify the signature.
+        signing: bool,
+        /// See [`ClientAuth::set_max_pipelined_queries`].
+        max_pipelined_queries: usize,
+        /// See [`ClientAuth::set_max_auth_tries`].
+        max_auth_tries: usize,
+        /// The attempts the server has rejected, not counting the initial `none` request.
+        failed_attempts: usize,
     }
 
+    /// Like OpenSSH's default `NumberOfPasswordPrompts`.
+    const MAX_PASSWORD_ATTEMPTS: usize = 3;
+
+    /// The default for [`ClientAuth::set_max_pipelined_queries`].
+    pub const DEFAULT_MAX_PIPELINED_QUERIES: usize = 3;
+
+    /// The default for [`ClientAuth::set_max_auth_tries`], like OpenSSH's default `MaxAuthTries`.
+    pub const DEFAULT_MAX_AUTH_TRIES: usize = 6;
+
+    #[allow(clippy::large_enum_variant)]
     pub enum ClientUserRequest {
         Password,
-        PrivateKeySign { session_id: SessionId },
+        /// The server accepts this key, sign the session for it.
+        PrivateKeySign {
+            session_id: SessionId,
+            public_key: PublicKey,
+        },
         Banner(Vec<u8>),
     }
 
@@ -534,9 +814,65 @@ pub mod auth {
                 user_requests: VecDeque::new(),
                 is_authenticated: false,
                 session_id: None,
+                batch_mode: false,
+                methods: vec![AuthOption::PublicKey, AuthOption::Password],
+                tried: Vec::new(),
+                skipped: Vec::new(),
+                server_methods: Vec::new(),
+                public_keys: Vec::new(),
+                server_sig_algs: None,
+                next_public_key: 0,
+                queried_public_keys: VecDeque::new(),
+                accepted_public_keys: VecDeque::new(),
+                signing: false,
+                max_pipelined_queries: DEFAULT_MAX_PIPELINED_QUERIES,
+                max_auth_tries: DEFAULT_MAX_AUTH_TRIES,
+                failed_attempts: 0,
             }
         }
 
+        /// The keys to offer for public key authentication, in order.
+        /// For every key, the server is first asked whether it would accept it,
+        /// and only the accepted key is signed with.
+        pub fn set_public_keys(&mut self, public_keys: Vec<PublicKey>) {
+            self.public_keys = public_keys;
+        }
+
+        /// The signature algorithms the server accepts, from its `server-sig-algs` extension.
+        /// Keys that sign with other algorithms are not offered.
+        /// Without it, all keys are offered.
+        pub fn set_server_sig_algs(&mut self, algorithms: Vec<String>) {
+            self.server_sig_algs = Some(algorithms);
+        }
+
+        /// How many keys the server is asked about at once, without waiting for the answers in between,
+        /// which saves round trips when the server accepts a later key.
+        /// Defaults to [`DEFAULT_MAX_PIPELINED_QUERIES`], 1 asks about one key after the other.
+        pub fn set_max_pipelined_queries(&mut self, max: usize) {
+            self.max_pipelined_queries = max.max(1);
+        }
+
+        /// The number of failed attempts after which the server is assumed to disconnect, like its `MaxAuthTries`.
+        /// Keys are only queried ahead as long as the server would still accept a signature
+        /// for any of them even if all others are rejected.
+        /// Defaults to [`DEFAULT_MAX_AUTH_TRIES`].
+        pub fn set_max_auth_tries(&mut self, max: usize) {
+            self.max_auth_tries = max;
+        }
+
+        /// Like OpenSSH's `PreferredAuthentications`: The methods to try, in order.
+        /// Methods that the server does not offer are skipped.
+        /// Defaults to public key authentication, then password authentication.
+        pub fn set_methods(&mut self, methods: Vec<AuthOption>) {
+            self.methods = methods;
+        }
+
+        /// Like OpenSSH's `BatchMode`: Never request a password from the user,
+        /// and fail authentication if no non-interactive method is available.
+        pub fn set_batch_mode(&mut self, batch_mode: bool) {
+            self.batch_mode = batch_mode;
+        }
+
         pub fn set_session_id(&mut self, session_id: SessionId) {
             assert!(self.session_id.is_none());
             self.session_id = Some(session_id);
@@ -554,6 +890,129 @@ pub mod auth {
             self.user_requests.drain(..)
         }
 
+        /// Gives up on the last user request, for example because signing failed,
+        /// and tries the next key or method.
+        pub fn user_request_failed(&mut self) -> Result<()> {
+            if self.signing {
+                // Try the next key that the server accepts instead.
+                self.signing = false;
+                if let Some(public_key) = self.accepted_public_keys.pop_front() {
+                    self.request_signature(public_key);
+                    return Ok(());
+                }
+                if !self.queried_public_keys.is_empty() {
+                    self.query_public_keys();
+                    return Ok(());
+                }
+            } else if let Some(AuthOption::Password) = self.tried.last() {
+                self.skipped.push(AuthOption::Password);
+            }
+            self.try_next_method()
+        }
+
+        fn can_try(&self, method: AuthOption) -> bool {
+            match method {
+                AuthOption::Password => {
+                    let attempts = self
+                        .tried
+                        .iter()
+                        .filter(|&&tried| tried == AuthOption::Password)
+                        .count();
+                    !self.batch_mode && attempts < MAX_PASSWORD_ATTEMPTS
+                }
+                AuthOption::PublicKey => self.public_keys[self.next_public_key..]
+                    .iter()
+                    .any(|public_key| self.server_accepts(public_key)),
+                // Only supported on the server.
+                AuthOption::KeyboardInteractive => false,
+            }
+        }
+
+        fn try_next_method(&mut self) -> Result<()> {
+            let next = self.methods.iter().copied().find(|&method| {
+                !self.skipped.contains(&method)
+                    && self.can_try(method)
+                    && self.server_methods.iter().any(|name| name == method.name())
+            });
+
+            let Some(method) = next else {
+                let mut tried = Vec::new();
+                for method in &self.tried {
+                    if !tried.contains(&method.name()) {
+                        tried.push(method.name());
+                    }
+                }
+                return Err(peer_error!(
+                    "no more authentication methods to try, tried: [{}], server supports: {}",
+                    tried.join(","),
+                    self.server_methods.join(",")
+                ));
+            };
+
+            debug!(method = method.name(), "Trying authentication method");
+            self.tried.push(method);
+            match method {
+                AuthOption::Password => self.user_requests.push_back(ClientUserRequest::Password),
+                AuthOption::PublicKey => self.query_public_keys(),
+                AuthOption::KeyboardInteractive => {
+                    unreachable!("keyboard-interactive is never tried")
+                }
+            }
+            Ok(())
+        }
+
+        /// Asks the server whether it accepts the next keys, with up to `max_pipelined_queries` queries at once.
+        /// The server answers the queries in order.
+        fn query_public_keys(&mut self) {
+            while self.next_public_key < self.public_keys.len() {
+                let pending = self.queried_public_keys.len();
+                // A key queried ahead is wasted if the server accepts an earlier one,
+                // but the server counts it as a failed attempt anyway.
+                if pending > 0
+                    && (pending >= self.max_pipelined_queries
+                        || self.failed_attempts + pending + 1 > self.max_auth_tries)
+                {
+                    break;
+                }
+
+                // <https://datatracker.ietf.org/doc/html/rfc4252#section-7>
+                let public_key = self.public_keys[self.next_public_key].clone();
+                self.next_public_key += 1;
+                if !self.server_accepts(&public_key) {
+                    debug!(%public_key, "Skipping public key with a signature algorithm the server does not accept");
+                    continue;
+                }
+                debug!(%public_key, "Asking whether the server accepts public key");
+                self.packets_to_send
+                    .push_back(Packet::new_msg_userauth_request_publickey_query(
+                        &self.username,
+                        b"ssh-connection",
+                        b"publickey",
+                        false,
+                        public_key.algorithm_name().as_bytes(),
+                        &public_key.to_wire_encoding(),
+                    ));
+                self.queried_public_keys.push_back(public_key);
+            }
+        }
+
+        fn server_accepts(&self, public_key: &PublicKey) -> bool {
+            self.server_sig_algs.as_ref().is_none_or(|algorithms| {
+                algorithms
+                    .iter()
+                    .any(|name| name == public_key.signature_algorithm_name())
+            })
+        }
+
+        fn request_signature(&mut self, public_key: PublicKey) {
+            self.signing = true;
+            self.user_requests
+                .push_back(ClientUserRequest::PrivateKeySign {
+                    session_id: self.session_id.expect("set_session_id has not been called"),
+                    public_key,
+                });
+        }
+
         pub fn send_password(&mut self, password: &str) {
             let packet = Packet::new_msg_userauth_request_password(
                 &self.username,
@@ -596,24 +1055,54 @@ pub mod auth {
                     let authentications = p.name_list()?;
                     let _partial_success = p.bool()?;
 
-                    if authentications.iter().any(|item| item == "password") {
-                        debug!("Received authentication failure, trying password");
-                        self.user_requests.push_back(ClientUserRequest::Password);
-                    } else if authentications.iter().any(|item| item == "publickey") {
-                        debug!("Received authentication failure, trying publickey");
-                        // <https://datatracker.ietf.org/doc/html/rfc4252#section-7>
-                        // TODO: Ask the server whether there are any keys we can use instead of just yoloing the signature.
-                        self.user_requests
-                            .push_back(ClientUserRequest::PrivateKeySign {
-                                session_id: self
-                                    .session_id
-                                    .expect("set_session_id has not been called"),
-                            });
-                    } else {
+                    self.server_methods = authentications.iter().map(ToOwned::to_owned).collect();
+                    if !self.tried.is_empty() {
+                        self.failed_attempts += 1;
+                    }
+
+                    let was_query = self.queried_public_keys.pop_front().is_some();
+                    if !was_query && self.signing {
+                        // The server rejected the signature.
+                        self.signing = false;
+                        if let Some(public_key) = self.accepted_public_keys.pop_front() {
+                            self.request_signature(public_key);
+                            return Ok(());
+                        }
+                    }
+                    if self.signing {
+                        // A key that was queried ahead was rejected, but we are using an accepted one already.
+                        return Ok(());
+                    }
+                    if !self.queried_public_keys.is_empty() {
+                        // Wait for the answers to the other queries.
+                        if self
+                            .server_methods
+                            .iter()
+                            .any(|name| name == AuthOption::PublicKey.name())
+                        {
+                            self.query_public_keys();
+                        }
+                        return Ok(());
+                    }
+                    self.try_next_method()?;
+                }
+                numbers::SSH_MSG_USERAUTH_PK_OK => {
+                    let Some(public_key) = self.queried_public_keys.pop_front() else {
+                        return Err(peer_error!("unexpected SSH_MSG_USERAUTH_PK_OK"));
+                    };
+                    let _key_alg = p.string()?;
+                    let key_blob = p.string()?;
+                    if key_blob != public_key.to_wire_encoding() {
                         return Err(peer_error!(
-                            "server does not support password authentication"
+                            "SSH_MSG_USERAUTH_PK_OK for a key that was not offered"
                         ));
                     }
+
+                    if self.signing {
+                        self.accepted_public_keys.push_back(public_key);
+                    } else {
+                        self.request_signature(public_key);
+                    }
                 }
                 numbers::SSH_MSG_USERAUTH_SUCCESS => {
                     self.is_authenticated = true;
@@ -629,4 +1118,557 @@ pub mod auth {
             Ok(())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use cluelessh_format::{numbers, NameList, Writer};
+        use cluelessh_keys::private::PlaintextPrivateKey;
+        use cluelessh_keys::public::PublicKey;
+        use cluelessh_keys::{KeyGenerationParams, KeyType};
+        use cluelessh_transport::{packet::Packet, SessionId, SshStatus};
+
+        use super::{
+            AuthOption, ClientAuth, ClientUserRequest, InfoRequest, KeyboardInteractiveStep,
+            Prompt, ServerAuth, ServerRequest, DEFAULT_MAX_PIPELINED_QUERIES,
+        };
+
+        fn public_key() -> PublicKey {
+            PlaintextPrivateKey::generate(
+                String::new(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ed25519,
+                },
+            )
+            .private_key
+            .public_key()
+        }
+
+        fn client_auth(batch_mode: bool, public_keys: Vec<PublicKey>) -> ClientAuth {
+            let mut auth = ClientAuth::new(b"user".to_vec());
+            auth.set_session_id(SessionId([0; 32]));
+            auth.set_batch_mode(batch_mode);
+            auth.set_public_keys(public_keys);
+            let initial = auth.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(initial.len(), 1);
+            assert_eq!(initial[0].packet_type(), numbers::SSH_MSG_USERAUTH_REQUEST);
+            auth
+        }
+
+        fn fail(auth: &mut ClientAuth, methods: &str) -> cluelessh_transport::Result<()> {
+            auth.recv_packet(Packet::new_msg_userauth_failure(
+                NameList::multi(methods),
+                false,
+            ))
+        }
+
+        fn pk_ok(auth: &mut ClientAuth, public_key: &PublicKey) -> cluelessh_transport::Result<()> {
+            auth.recv_packet(Packet::new_msg_userauth_pk_ok(
+                public_key.algorithm_name().as_bytes(),
+                &public_key.to_wire_encoding(),
+            ))
+        }
+
+        /// The key of a query whether the server accepts it.
+        fn queried_key(packet: &Packet) -> PublicKey {
+            let mut p = packet.payload_parser();
+            assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_USERAUTH_REQUEST);
+            assert_eq!(p.utf8_string().unwrap(), "user");
+            assert_eq!(p.utf8_string().unwrap(), "ssh-connection");
+            assert_eq!(p.utf8_string().unwrap(), "publickey");
+            assert!(!p.bool().unwrap());
+            let algorithm = p.utf8_string().unwrap();
+            let public_key = PublicKey::from_wire_encoding(p.string().unwrap()).unwrap();
+            assert_eq!(algorithm, public_key.algorithm_name());
+            public_key
+        }
+
+        /// Asserts that the only packets to send ask the server whether it accepts the keys.
+        fn assert_queried(auth: &mut ClientAuth, public_keys: &[PublicKey]) {
+            let packets = auth.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(
+                packets.iter().map(queried_key).collect::<Vec<_>>(),
+                public_keys
+            );
+            assert_eq!(auth.user_requests().count(), 0);
+        }
+
+        /// Asserts that the only user request is to sign with the key.
+        fn assert_signing(auth: &mut ClientAuth, key: &PublicKey) {
+            let requests = auth.user_requests().collect::<Vec<_>>();
+            let [ClientUserRequest::PrivateKeySign { public_key, .. }] = requests.as_slice() else {
+                panic!("did not request a signature");
+            };
+            assert_eq!(public_key, key);
+        }
+
+        #[test]
+        fn password_prompt() {
+            let mut auth = client_auth(false, vec![]);
+            fail(&mut auth, "password").unwrap();
+            assert!(matches!(
+                auth.user_requests().collect::<Vec<_>>().as_slice(),
+                [ClientUserRequest::Password]
+            ));
+        }
+
+        #[test]
+        fn batch_mode_password_only_server() {
+            let mut auth = client_auth(true, vec![public_key()]);
+            let result = fail(&mut auth, "password");
+            assert!(result.is_err());
+            assert_eq!(auth.user_requests().count(), 0);
+            assert_eq!(auth.packets_to_send().count(), 0);
+            assert!(!auth.is_authenticated());
+        }
+
+        #[test]
+        fn batch_mode_prefers_publickey() {
+            let key = public_key();
+            let mut auth = client_auth(true, vec![key.clone()]);
+            fail(&mut auth, "password,publickey").unwrap();
+            assert_queried(&mut auth, &[key]);
+        }
+
+        #[test]
+        fn methods_in_order() {
+            let key = public_key();
+            let mut auth = client_auth(false, vec![key.clone()]);
+            auth.set_methods(vec![AuthOption::Password, AuthOption::PublicKey]);
+
+            for _ in 0..3 {
+                fail(&mut auth, "publickey,password").unwrap();
+                assert!(matches!(
+                    auth.user_requests().collect::<Vec<_>>().as_slice(),
+                    [ClientUserRequest::Password]
+                ));
+            }
+            fail(&mut auth, "publickey,password").unwrap();
+            assert_queried(&mut auth, &[key]);
+
+            let Err(SshStatus::PeerError(err)) = fail(&mut auth, "publickey,password") else {
+                panic!("authentication did not fail");
+            };
+            assert!(err.contains("tried: [password,publickey]"), "{err}");
+        }
+
+        #[test]
+        fn skips_methods_not_offered() {
+            let mut auth = client_auth(false, vec![public_key()]);
+            fail(&mut auth, "password").unwrap();
+            assert!(matches!(
+                auth.user_requests().collect::<Vec<_>>().as_slice(),
+                [ClientUserRequest::Password]
+            ));
+        }
+
+        #[test]
+        fn skips_keys_with_unsupported_signature_algorithms() {
+            let ed25519 = public_key();
+            let ecdsa = PlaintextPrivateKey::generate(
+                String::new(),
+                KeyGenerationParams {
+                    key_type: KeyType::Ecdsa,
+                },
+            )
+            .private_key
+            .public_key();
+            let mut auth = client_auth(true, vec![ed25519.clone(), ecdsa.clone()]);
+            auth.set_server_sig_algs(vec!["ecdsa-sha2-nistp256".to_owned()]);
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &[ecdsa]);
+
+            let mut auth = client_auth(true, vec![ed25519]);
+            auth.set_server_sig_algs(vec!["ecdsa-sha2-nistp256".to_owned()]);
+            let Err(SshStatus::PeerError(err)) = fail(&mut auth, "publickey") else {
+                panic!("offered a key the server does not accept");
+            };
+            assert!(err.contains("no more authentication methods"), "{err}");
+        }
+
+        #[test]
+        fn skips_publickey_without_keys() {
+            let mut auth = client_auth(false, vec![]);
+            fail(&mut auth, "publickey,password").unwrap();
+            assert_eq!(auth.packets_to_send().count(), 0);
+            assert!(matches!(
+                auth.user_requests().collect::<Vec<_>>().as_slice(),
+                [ClientUserRequest::Password]
+            ));
+        }
+
+        #[test]
+        fn only_accepted_key_is_signed() {
+            let keys = [public_key(), public_key(), public_key()];
+            let mut auth = client_auth(true, keys.to_vec());
+            auth.set_max_pipelined_queries(1);
+
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[..1]);
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[1..2]);
+
+            pk_ok(&mut auth, &keys[1]).unwrap();
+            assert_signing(&mut auth, &keys[1]);
+        }
+
+        #[test]
+        fn pipelined_queries() {
+            let keys = [public_key(), public_key(), public_key(), public_key()];
+            let mut auth = client_auth(true, keys.to_vec());
+
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[..3]);
+
+            // The next key is queried as soon as there is room.
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[3..]);
+
+            pk_ok(&mut auth, &keys[1]).unwrap();
+            assert_signing(&mut auth, &keys[1]);
+
+            // The answers to the other queries don't interrupt the signature.
+            fail(&mut auth, "publickey").unwrap();
+            pk_ok(&mut auth, &keys[3]).unwrap();
+            assert_eq!(auth.packets_to_send().count(), 0);
+            assert_eq!(auth.user_requests().count(), 0);
+
+            // If the server rejects the signature, the other accepted key is used.
+            auth.send_signature("ssh-ed25519", &keys[1].to_wire_encoding(), b"signature");
+            assert_eq!(auth.packets_to_send().count(), 1);
+            fail(&mut auth, "publickey").unwrap();
+            assert_signing(&mut auth, &keys[3]);
+        }
+
+        /// The number of round trips until the client can sign for the last of three keys.
+        fn round_trips_until_signing(max_pipelined_queries: usize) -> usize {
+            let keys = [public_key(), public_key(), public_key()];
+            let mut auth = client_auth(true, keys.to_vec());
+            auth.set_max_pipelined_queries(max_pipelined_queries);
+            fail(&mut auth, "publickey").unwrap();
+
+            let mut round_trips = 0;
+            loop {
+                let packets = auth.packets_to_send().collect::<Vec<_>>();
+                assert!(!packets.is_empty());
+                round_trips += 1;
+                for packet in &packets {
+                    let public_key = queried_key(packet);
+                    if public_key == keys[2] {
+                        pk_ok(&mut auth, &public_key).unwrap();
+                    } else {
+                        fail(&mut auth, "publickey").unwrap();
+                    }
+                }
+                if auth.user_requests().count() > 0 {
+                    return round_trips;
+                }
+            }
+        }
+
+        #[test]
+        fn pipelining_saves_round_trips() {
+            assert_eq!(round_trips_until_signing(1), 3);
+            assert_eq!(round_trips_until_signing(DEFAULT_MAX_PIPELINED_QUERIES), 1);
+        }
+
+        #[test]
+        fn pipelining_respects_max_auth_tries() {
+            let keys = [public_key(), public_key(), public_key()];
+            let mut auth = client_auth(true, keys.to_vec());
+            auth.set_max_auth_tries(2);
+
+            // If the first key is rejected, the server must still accept a signature for the second.
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[..2]);
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &[]);
+
+            // Without pipelining, the last key is still tried, like OpenSSH does.
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys[2..]);
+        }
+
+        #[test]
+        fn pk_ok_for_other_key() {
+            let keys = [public_key(), public_key()];
+            let mut auth = client_auth(true, keys.to_vec());
+            fail(&mut auth, "publickey").unwrap();
+            assert_queried(&mut auth, &keys);
+            pk_ok(&mut auth, &keys[1]).unwrap_err();
+        }
+
+        #[test]
+        fn user_request_failed() {
+            let keys = [public_key(), public_key()];
+            let mut auth = client_auth(false, keys.to_vec());
+            fail(&mut auth, "publickey,password").unwrap();
+            assert_queried(&mut auth, &keys);
+            pk_ok(&mut auth, &keys[0]).unwrap();
+            assert_signing(&mut auth, &keys[0]);
+
+            // Signing failed, use the next key once the server accepts it.
+            auth.user_request_failed().unwrap();
+            assert_queried(&mut auth, &[]);
+            pk_ok(&mut auth, &keys[1]).unwrap();
+            assert_signing(&mut auth, &keys[1]);
+
+            // Then fall back to passwords.
+            auth.user_request_failed().unwrap();
+            assert!(matches!(
+                auth.user_requests().collect::<Vec<_>>().as_slice(),
+                [ClientUserRequest::Password]
+            ));
+        }
+
+        #[test]
+        fn server_answers_pipelined_queries_in_order() {
+            let keys = [public_key(), public_key()];
+            let mut client = client_auth(true, keys.to_vec());
+            fail(&mut client, "publickey").unwrap();
+            let mut server =
+                ServerAuth::new([AuthOption::PublicKey].into(), None, SessionId([0; 32]));
+            for packet in client.packets_to_send() {
+                server.recv_packet(packet).unwrap();
+            }
+
+            // The second query is only handled once the first one has been answered.
+            let requests = server.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::CheckPubkey(check)] = requests.as_slice() else {
+                panic!("did not check the first key");
+            };
+            assert_eq!(check.public_key, keys[0]);
+            server
+                .pubkey_check_result(false, check.public_key.clone())
+                .unwrap();
+
+            let requests = server.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::CheckPubkey(check)] = requests.as_slice() else {
+                panic!("did not check the second key");
+            };
+            assert_eq!(check.public_key, keys[1]);
+            server
+                .pubkey_check_result(true, check.public_key.clone())
+                .unwrap();
+
+            for packet in server.packets_to_send() {
+                client.recv_packet(packet).unwrap();
+            }
+            assert_signing(&mut client, &keys[1]);
+        }
+
+        #[test]
+        fn server_limits_pending_requests() {
+            let mut client = client_auth(true, vec![public_key()]);
+            fail(&mut client, "publickey").unwrap();
+            let query = client.packets_to_send().next().unwrap().payload;
+            let mut server =
+                ServerAuth::new([AuthOption::PublicKey].into(), None, SessionId([0; 32]));
+
+            // The first query is handled, the others wait for its result.
+            for _ in 0..=super::MAX_PENDING_PACKETS {
+                let packet = Packet {
+                    payload: query.clone(),
+                };
+                server.recv_packet(packet).unwrap();
+            }
+            assert!(server.recv_packet(Packet { payload: query }).is_err());
+        }
+
+        fn info_response(responses: &[&str]) -> Packet {
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_USERAUTH_INFO_RESPONSE);
+            w.u32(responses.len() as u32);
+            for response in responses {
+                w.string(response.as_bytes());
+            }
+            Packet {
+                payload: w.finish(),
+            }
+        }
+
+        #[test]
+        fn keyboard_interactive() {
+            let mut server = ServerAuth::new(
+                [AuthOption::KeyboardInteractive].into(),
+                None,
+                SessionId([0; 32]),
+            );
+            let mut w = Writer::new();
+            w.u8(numbers::SSH_MSG_USERAUTH_REQUEST);
+            w.string(b"user");
+            w.string(b"ssh-connection");
+            w.string(b"keyboard-interactive");
+            w.string(b"");
+            w.string(b"pam");
+            server
+                .recv_packet(Packet {
+                    payload: w.finish(),
+                })
+                .unwrap();
+
+            let requests = server.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::KeyboardInteractive(request)] = requests.as_slice() else {
+                panic!("did not start keyboard-interactive");
+            };
+            assert_eq!(request.user, "user");
+            assert!(matches!(
+                &request.step,
+                KeyboardInteractiveStep::Start { submethods } if submethods == "pam"
+            ));
+
+            server
+                .info_request(
+                    "user".to_owned(),
+                    InfoRequest {
+                        name: String::new(),
+                        instruction: "Two-factor authentication".to_owned(),
+                        prompts: vec![Prompt {
+                            prompt: "Verification code: ".to_owned(),
+                            echo: false,
+                        }],
+                    },
+                )
+                .unwrap();
+            let packets = server.packets_to_send().collect::<Vec<_>>();
+            assert_eq!(packets.len(), 1);
+            let mut p = packets[0].payload_parser();
+            assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_USERAUTH_INFO_REQUEST);
+            assert_eq!(p.utf8_string().unwrap(), "");
+            assert_eq!(p.utf8_string().unwrap(), "Two-factor authentication");
+            assert_eq!(p.utf8_string().unwrap(), "");
+            assert_eq!(p.u32().unwrap(), 1);
+            assert_eq!(p.utf8_string().unwrap(), "Verification code: ");
+            assert!(!p.bool().unwrap());
+
+            server.recv_packet(info_response(&["123456"])).unwrap();
+            let requests = server.server_requests().collect::<Vec<_>>();
+            let [ServerRequest::KeyboardInteractive(request)] = requests.as_slice() else {
+                panic!("did not pass on the responses");
+            };
+            assert!(matches!(
+                &request.step,
+                KeyboardInteractiveStep::Responses(responses) if responses == &["123456"]
+            ));
+
+            server
+                .verification_result(true, request.user.clone())
+                .unwrap();
+            assert_eq!(server.authenticated_user(), Some("user"));
+        }
+
+        #[test]
+        fn unrequested_info_response() {
+            let mut server = ServerAuth::new(
+                [AuthOption::KeyboardInteractive].into(),
+                None,
+                SessionId([0; 32]),
+            );
+            assert!(server.recv_packet(info_response(&["123456"])).is_err());
+        }
+
+        #[test]
+        fn no_method_offered() {
+            let mut auth = client_auth(false, vec![public_key()]);
+            auth.set_methods(vec![AuthOption::PublicKey]);
+            let Err(SshStatus::PeerError(err)) = fail(&mut auth, "password") else {
+                panic!("authentication did not fail");
+            };
+            assert!(err.contains("tried: []"), "{err}");
+            assert!(err.contains("server supports: password"), "{err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_format::numbers;
+    use cluelessh_keys::private::PlaintextPrivateKey;
+    use cluelessh_transport::packet::Packet;
+    use cluelessh_transport::server::{ServerConfig, ServerConnection};
+
+    use crate::{auth::ClientAuth, ClientConnection, GlobalRequest, OsRng};
+
+    /// Exchanges messages until both sides are idle, returning the packets the server received.
+    fn pump(
+        client: &mut ClientConnection,
+        server: &mut ServerConnection,
+        host_key: &PlaintextPrivateKey,
+    ) -> Vec<Packet> {
+        let mut received = Vec::new();
+        loop {
+            let mut progress = false;
+            while let Some(msg) = client.next_msg_to_send() {
+                server.recv_bytes(&msg.to_bytes()).unwrap();
+                progress = true;
+            }
+            if let Some(params) = server.is_waiting_on_key_exchange() {
+                let response =
+                    cluelessh_transport::server::do_key_exchange(params, host_key, &mut OsRng)
+                        .unwrap();
+                server.do_key_exchange(response);
+                progress = true;
+            }
+            while let Some(msg) = server.next_msg_to_send() {
+                client.recv_bytes(&msg.to_bytes()).unwrap();
+                progress = true;
+            }
+            received.extend(std::iter::from_fn(|| server.next_plaintext_packet()));
+            if !progress {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn client_global_request_during_auth() {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            cluelessh_keys::KeyGenerationParams {
+                key_type: cluelessh_keys::KeyType::Ed25519,
+            },
+        );
+        let mut server = ServerConnection::new(
+            OsRng,
+            ServerConfig {
+                server_identification: b"SSH-2.0-TestServer\r\n".to_vec(),
+                host_keys: vec![host_key.private_key.public_key()],
+            },
+        );
+        let mut client = ClientConnection::new(
+            cluelessh_transport::client::ClientConnection::new(OsRng),
+            ClientAuth::new(b"user".to_vec()),
+        );
+
+        let received = pump(&mut client, &mut server, &host_key);
+        assert!(server.is_open().is_some());
+        assert_eq!(
+            received.iter().map(Packet::packet_type).collect::<Vec<_>>(),
+            [numbers::SSH_MSG_USERAUTH_REQUEST]
+        );
+
+        // The client is now waiting for the authentication result.
+        server.send_plaintext_packet(Packet::new_msg_global_request(
+            b"hostkeys-00@openssh.com",
+            false,
+        ));
+        server.send_plaintext_packet(Packet::new_msg_global_request(
+            b"keepalive@openssh.com",
+            true,
+        ));
+        let received = pump(&mut client, &mut server, &host_key);
+        assert_eq!(
+            received.iter().map(Packet::packet_type).collect::<Vec<_>>(),
+            [numbers::SSH_MSG_REQUEST_FAILURE]
+        );
+        assert_eq!(
+            client.next_global_request(),
+            Some(GlobalRequest::HostKeys(vec![]))
+        );
+        assert_eq!(client.next_global_request(), None);
+
+        // Authentication continues normally afterwards.
+        server.send_plaintext_packet(Packet::new_msg_userauth_success());
+        pump(&mut client, &mut server, &host_key);
+        assert!(client.is_open());
+    }
 }
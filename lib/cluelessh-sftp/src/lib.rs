@@ -9,14 +9,14 @@ use std::{
     sync::atomic::{AtomicU32, Ordering},
 };
 
-use cluelessh_format::{numbers, Writer};
-use eyre::{bail, ensure, OptionExt, Result};
-use rustix::fs::{Mode, OFlags};
+use cluelessh_format::{numbers, Reader, Writer};
+use eyre::{ensure, eyre, OptionExt, Result};
+use rustix::fs::{Mode, OFlags, Stat};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     sync::mpsc,
 };
-use tracing::trace;
+use tracing::{debug, trace};
 use transport::{Packet, PacketTransport};
 
 pub struct SftpServer {
@@ -42,6 +42,8 @@ enum SftpState {
 }
 
 const BUF_SIZE: usize = 1024;
+/// The most data sent for a read request, which is what OpenSSH's client asks for.
+const MAX_READ_LEN: usize = 32768;
 
 struct Event {
     _data: Vec<u8>,
@@ -74,7 +76,12 @@ impl SftpServer {
         loop {
             tokio::select! {
                 read = self.input.read(&mut buf) => {
-                    self.recv_byte(&buf[..read?]).await?;
+                    let read = read?;
+                    if read == 0 {
+                        // The client closed the channel.
+                        return Ok(());
+                    }
+                    self.recv_byte(&buf[..read]).await?;
                 }
                 _event = self.events_recv.recv() => {
                     todo!()
@@ -117,46 +124,107 @@ impl SftpServer {
             }
 
             let mut p = packet.payload_reader();
+            let req_id = p.u32()?;
 
             match packet_type {
+                numbers::SSH_FXP_OPEN => {
+                    let filename = p.utf8_string()?;
+                    let pflags = p.u32()?;
+                    let attrs = Attrs::decode(&mut p)?;
+                    let mode = Mode::from_raw_mode(attrs.permissions.unwrap_or(0o666) & 0o7777);
+
+                    // TODO: dont block lol
+                    let result = rustix::fs::open(filename, open_flags(pflags), mode);
+                    match result {
+                        Ok(fd) => self.send_handle(req_id, fd).await?,
+                        Err(err) => self.send_io_error(req_id, err.into()).await?,
+                    }
+                }
                 numbers::SSH_FXP_CLOSE => {
-                    let req_id = p.u32()?;
-                    let _ = p.u32()?;
-                    let handle = p.u32()?;
-                    let Some(handle) = self.files.remove(&handle) else {
-                        bail!("invalid handle");
-                    };
-                    drop(handle);
+                    let handle = read_handle(&mut p)?;
+                    if self.files.remove(&handle).is_none() {
+                        self.send_invalid_handle(req_id).await?;
+                        continue;
+                    }
                     self.send_packet(status(req_id, numbers::SSH_FX_OK, ""))
                         .await?;
                 }
+                numbers::SSH_FXP_READ => {
+                    let handle = read_handle(&mut p)?;
+                    let offset = p.u64()?;
+                    let len = p.u32()?;
+                    let Some(file) = self.files.get(&handle) else {
+                        self.send_invalid_handle(req_id).await?;
+                        continue;
+                    };
+
+                    let mut data = vec![0; (len as usize).min(MAX_READ_LEN)];
+                    match rustix::io::pread(file, &mut data, offset) {
+                        Ok(0) => {
+                            self.send_packet(status(req_id, numbers::SSH_FX_EOF, ""))
+                                .await?
+                        }
+                        Ok(read) => {
+                            let mut w = Writer::new();
+                            w.u8(numbers::SSH_FXP_DATA);
+                            w.u32(req_id);
+                            w.string(&data[..read]);
+                            self.send_packet(w.finish()).await?;
+                        }
+                        Err(err) => self.send_io_error(req_id, err.into()).await?,
+                    }
+                }
+                numbers::SSH_FXP_WRITE => {
+                    let handle = read_handle(&mut p)?;
+                    let offset = p.u64()?;
+                    let data = p.string()?;
+                    let Some(file) = self.files.get(&handle) else {
+                        self.send_invalid_handle(req_id).await?;
+                        continue;
+                    };
+
+                    match write_all_at(file, data, offset) {
+                        Ok(()) => {
+                            self.send_packet(status(req_id, numbers::SSH_FX_OK, ""))
+                                .await?
+                        }
+                        Err(err) => self.send_io_error(req_id, err).await?,
+                    }
+                }
+                numbers::SSH_FXP_STAT | numbers::SSH_FXP_LSTAT => {
+                    let path = p.utf8_string()?;
+                    let result = if packet_type == numbers::SSH_FXP_STAT {
+                        rustix::fs::stat(path)
+                    } else {
+                        rustix::fs::lstat(path)
+                    };
+                    self.send_attrs(req_id, result).await?;
+                }
+                numbers::SSH_FXP_FSTAT => {
+                    let handle = read_handle(&mut p)?;
+                    let Some(file) = self.files.get(&handle) else {
+                        self.send_invalid_handle(req_id).await?;
+                        continue;
+                    };
+                    let result = rustix::fs::fstat(file);
+                    self.send_attrs(req_id, result).await?;
+                }
                 numbers::SSH_FXP_OPENDIR => {
-                    let req_id = p.u32()?;
                     let path = p.utf8_string()?;
 
                     // TODO: dont block lol
                     let result =
                         rustix::fs::open(path, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty());
                     match result {
-                        Ok(fd) => {
-                            let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
-                            self.files.insert(handle, fd);
-                            let mut w = Writer::new();
-                            w.u8(numbers::SSH_FXP_HANDLE);
-                            w.u32(req_id);
-                            w.u32(4); // handle length
-                            w.u32(handle);
-                            self.send_packet(w.finish()).await?;
-                        }
+                        Ok(fd) => self.send_handle(req_id, fd).await?,
                         Err(err) => self.send_io_error(req_id, err.into()).await?,
                     }
                 }
                 numbers::SSH_FXP_READDIR => {
-                    let req_id = p.u32()?;
-                    let _ = p.u32()?;
-                    let handle = p.u32()?;
+                    let handle = read_handle(&mut p)?;
                     let Some(handle) = self.files.get(&handle) else {
-                        bail!("invalid handle");
+                        self.send_invalid_handle(req_id).await?;
+                        continue;
                     };
                     let mut entries: Vec<(String, String, Attrs)> = Vec::new();
                     let mut buf = Vec::with_capacity(8192);
@@ -167,6 +235,13 @@ impl SftpServer {
                         entries.push((name.clone(), name, Attrs::default()));
                     }
 
+                    // The whole directory is read at once, so the next request reaches the end.
+                    if entries.is_empty() {
+                        self.send_packet(status(req_id, numbers::SSH_FX_EOF, ""))
+                            .await?;
+                        continue;
+                    }
+
                     let mut w = Writer::new();
                     w.u8(numbers::SSH_FXP_NAME);
                     w.u32(req_id);
@@ -180,7 +255,6 @@ impl SftpServer {
                     self.send_packet(w.finish()).await?;
                 }
                 numbers::SSH_FXP_REALPATH => {
-                    let req_id = p.u32()?;
                     let original_path = p.utf8_string()?;
 
                     let p = Path::new(original_path).canonicalize();
@@ -206,7 +280,10 @@ impl SftpServer {
                     }
                 }
                 _ => {
-                    bail!("unknown packet: {packet_type_string} ({packet_type})")
+                    debug!(%packet_type, %packet_type_string, "Unsupported request");
+                    let message = format!("unsupported request: {packet_type_string}");
+                    self.send_packet(status(req_id, numbers::SSH_FX_OP_UNSUPPORTED, &message))
+                        .await?;
                 }
             }
         }
@@ -214,6 +291,34 @@ impl SftpServer {
         Ok(())
     }
 
+    async fn send_handle(&mut self, req_id: u32, fd: OwnedFd) -> Result<()> {
+        let handle = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.files.insert(handle, fd);
+        let mut w = Writer::new();
+        w.u8(numbers::SSH_FXP_HANDLE);
+        w.u32(req_id);
+        w.string(handle.to_be_bytes());
+        self.send_packet(w.finish()).await
+    }
+
+    async fn send_attrs(&mut self, req_id: u32, stat: rustix::io::Result<Stat>) -> Result<()> {
+        match stat {
+            Ok(stat) => {
+                let mut w = Writer::new();
+                w.u8(numbers::SSH_FXP_ATTRS);
+                w.u32(req_id);
+                Attrs::from_stat(&stat).encode(&mut w);
+                self.send_packet(w.finish()).await
+            }
+            Err(err) => self.send_io_error(req_id, err.into()).await,
+        }
+    }
+
+    async fn send_invalid_handle(&mut self, req_id: u32) -> Result<()> {
+        self.send_packet(status(req_id, numbers::SSH_FX_FAILURE, "invalid handle"))
+            .await
+    }
+
     async fn send_io_error(&mut self, req_id: u32, err: io::Error) -> Result<()> {
         self.send_packet(status(req_id, io_error_to_code(&err), &err.to_string()))
             .await
@@ -231,6 +336,46 @@ impl SftpServer {
     }
 }
 
+fn read_handle(p: &mut Reader<'_>) -> Result<Handle> {
+    let handle = p.string()?;
+    let handle = <[u8; 4]>::try_from(handle).map_err(|_| eyre!("invalid handle length"))?;
+    Ok(Handle::from_be_bytes(handle))
+}
+
+fn open_flags(pflags: u32) -> OFlags {
+    use numbers::*;
+
+    let flag = |pflag, flag| {
+        if pflags & pflag != 0 {
+            flag
+        } else {
+            OFlags::empty()
+        }
+    };
+    let access = match (pflags & SSH_FXF_READ != 0, pflags & SSH_FXF_WRITE != 0) {
+        (true, true) => OFlags::RDWR,
+        (false, true) => OFlags::WRONLY,
+        _ => OFlags::RDONLY,
+    };
+    access
+        | flag(SSH_FXF_APPEND, OFlags::APPEND)
+        | flag(SSH_FXF_CREAT, OFlags::CREATE)
+        | flag(SSH_FXF_TRUNC, OFlags::TRUNC)
+        | flag(SSH_FXF_EXCL, OFlags::EXCL)
+}
+
+fn write_all_at(file: &OwnedFd, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    while !data.is_empty() {
+        let written = rustix::io::pwrite(file, data, offset)?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
 fn io_error_to_code(err: &io::Error) -> u32 {
     match err.kind() {
         io::ErrorKind::NotFound => numbers::SSH_FX_NO_SUCH_FILE,
@@ -248,6 +393,41 @@ struct Attrs {
 }
 
 impl Attrs {
+    fn from_stat(stat: &Stat) -> Self {
+        Self {
+            size: Some(stat.st_size as u64),
+            uid_gid: Some((stat.st_uid, stat.st_gid)),
+            permissions: Some(stat.st_mode),
+            atime_mtime: Some((stat.st_atime as u32, stat.st_mtime as u32)),
+        }
+    }
+
+    fn decode(p: &mut Reader<'_>) -> Result<Self> {
+        use numbers::*;
+
+        let flags = p.u32()?;
+        let mut attrs = Self::default();
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(p.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((p.u32()?, p.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(p.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((p.u32()?, p.u32()?));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..p.u32()? {
+                let _type = p.string()?;
+                let _data = p.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+
     fn encode(&self, w: &mut Writer) {
         use numbers::*;
 
@@ -1,19 +1,87 @@
-use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation};
+use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind};
+use cluelessh_keys::known_hosts::{HostKeyStatus, HostKeyStore};
+use cluelessh_keys::public::PublicKey;
 use cluelessh_transport::SessionId;
-use std::{collections::HashMap, pin::Pin, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use cluelessh_protocol::{ChannelUpdateKind, SshStatus};
-use eyre::{bail, ContextCompat, Result, WrapErr};
+use eyre::{bail, ensure, ContextCompat, Result, WrapErr};
 use futures::future::BoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{debug, info, warn};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn, Instrument};
 
-use crate::{Channel, ChannelState, PendingChannel};
+use crate::stream::{self, ChannelStream, CommandStream};
+use crate::term_modes::TermModes;
+use crate::{
+    Channel, ChannelState, PendingChannel, SessionPty, WindowChanges, WindowSize,
+    DEFAULT_CHANNEL_OPERATIONS_BUFFER, DEFAULT_CHANNEL_UPDATES_BUFFER,
+};
+
+pub use cluelessh_connection::GlobalRequest;
+pub use cluelessh_protocol::auth::AuthOption;
+pub use cluelessh_transport::client::ConnectionInfo;
+
+/// The standard reason codes for [`ClientConnection::disconnect`],
+/// see <https://datatracker.ietf.org/doc/html/rfc4253#section-11.1>.
+pub mod disconnect_reason {
+    use cluelessh_format::numbers;
+
+    pub const HOST_NOT_ALLOWED_TO_CONNECT: u32 =
+        numbers::SSH_DISCONNECT_HOST_NOT_ALLOWED_TO_CONNECT;
+    pub const PROTOCOL_ERROR: u32 = numbers::SSH_DISCONNECT_PROTOCOL_ERROR;
+    pub const KEY_EXCHANGE_FAILED: u32 = numbers::SSH_DISCONNECT_KEY_EXCHANGE_FAILED;
+    pub const RESERVED: u32 = numbers::SSH_DISCONNECT_RESERVED;
+    pub const MAC_ERROR: u32 = numbers::SSH_DISCONNECT_MAC_ERROR;
+    pub const COMPRESSION_ERROR: u32 = numbers::SSH_DISCONNECT_COMPRESSION_ERROR;
+    pub const SERVICE_NOT_AVAILABLE: u32 = numbers::SSH_DISCONNECT_SERVICE_NOT_AVAILABLE;
+    pub const PROTOCOL_VERSION_NOT_SUPPORTED: u32 =
+        numbers::SSH_DISCONNECT_PROTOCOL_VERSION_NOT_SUPPORTED;
+    pub const HOST_KEY_NOT_VERIFIABLE: u32 = numbers::SSH_DISCONNECT_HOST_KEY_NOT_VERIFIABLE;
+    pub const CONNECTION_LOST: u32 = numbers::SSH_DISCONNECT_CONNECTION_LOST;
+    pub const BY_APPLICATION: u32 = numbers::SSH_DISCONNECT_BY_APPLICATION;
+    pub const TOO_MANY_CONNECTIONS: u32 = numbers::SSH_DISCONNECT_TOO_MANY_CONNECTIONS;
+    pub const AUTH_CANCELLED_BY_USER: u32 = numbers::SSH_DISCONNECT_AUTH_CANCELLED_BY_USER;
+    pub const NO_MORE_AUTH_METHODS_AVAILABLE: u32 =
+        numbers::SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE;
+    pub const ILLEGAL_USER_NAME: u32 = numbers::SSH_DISCONNECT_ILLEGAL_USER_NAME;
+}
+
+/// The server has closed the connection with `SSH_MSG_DISCONNECT`.
+/// Returned by [`ClientConnection::progress`], get it with [`eyre::Report::downcast_ref`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectError {
+    /// One of [`disconnect_reason`], like [`disconnect_reason::NO_MORE_AUTH_METHODS_AVAILABLE`].
+    pub code: u32,
+    /// The human-readable message of the server.
+    pub description: String,
+}
+
+impl std::fmt::Display for DisconnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received disconnect from server: {} ({})",
+            self.description,
+            cluelessh_format::numbers::disconnect_reason_to_string(self.code)
+        )
+    }
+}
+
+impl std::error::Error for DisconnectError {}
 
 pub struct ClientConnection<S> {
     stream: Pin<Box<S>>,
-    buf: [u8; 1024],
+    buf: Vec<u8>,
 
     proto: cluelessh_protocol::ClientConnection,
     operations_send: tokio::sync::mpsc::Sender<Operation>,
@@ -22,17 +90,111 @@ pub struct ClientConnection<S> {
     /// Cloned and passed on to channels.
     channel_ops_send: tokio::sync::mpsc::Sender<ChannelOperation>,
     channel_ops_recv: tokio::sync::mpsc::Receiver<ChannelOperation>,
+    /// Cloned and passed on to channels, see [`crate::Channel::next_update`].
+    consumed_send: tokio::sync::mpsc::UnboundedSender<(ChannelNumber, u32)>,
+    consumed_recv: tokio::sync::mpsc::UnboundedReceiver<(ChannelNumber, u32)>,
+    /// See [`ClientConfig::channel_updates_buffer`].
+    channel_updates_buffer: usize,
 
     channels: HashMap<ChannelNumber, ChannelState>,
+    new_channels: VecDeque<Channel>,
 
     auth: ClientAuth,
+    /// See [`ClientConfig::forward_agent`].
+    forward_agent: Option<PathBuf>,
+    /// See [`ClientConfig::max_consecutive_open_failures`].
+    max_consecutive_open_failures: Option<u32>,
+    consecutive_open_failures: u32,
+    /// See [`Self::span`].
+    span: tracing::Span,
+}
+
+#[derive(Default)]
+pub struct ClientConfig {
+    /// Ask the server to compress the connection with zlib.
+    pub compression: bool,
+    /// Host key algorithms to offer first, see [`cluelessh_transport::client::ClientConnection::preferred_host_key_algorithms`].
+    pub preferred_host_key_algorithms: Vec<String>,
+    /// The maximum time the key exchange and authentication in [`ClientConnection::connect`] may take.
+    /// If it is exceeded, the returned error contains a [`tokio::time::error::Elapsed`].
+    pub handshake_timeout: Option<Duration>,
+    /// How many consumed bytes of a channel are acknowledged at once,
+    /// see [`cluelessh_connection::ChannelsState::set_window_adjust_threshold`].
+    pub window_adjust_threshold: Option<u32>,
+    /// The window granted to the server on each channel,
+    /// see [`cluelessh_connection::ChannelsState::set_initial_window_size`].
+    /// The window is given back as the data is consumed from the [`crate::Channel`].
+    pub initial_window_size: Option<u32>,
+    /// How many bytes are read from the stream at once, defaults to [`DEFAULT_READ_BUFFER_SIZE`].
+    /// Larger buffers need fewer iterations of the main loop for bulk transfers.
+    pub read_buffer_size: Option<usize>,
+    /// The socket of the SSH agent to forward, usually `$SSH_AUTH_SOCK`.
+    /// Forwarding also has to be requested on a session with [`Channel::request_agent_forwarding`].
+    /// Without this, the server can't open agent channels. Only forward the agent to trusted servers,
+    /// as they can authenticate with its keys for as long as the connection is open.
+    pub forward_agent: Option<PathBuf>,
+    /// After how many channel opens in a row the server refuses, [`ClientConnection::progress`]
+    /// disconnects and returns an error. A successful open resets the count. `None` never disconnects,
+    /// `Some(0)` is rejected by [`ClientConnection::connect`].
+    /// This stops automation that keeps opening channels from spinning forever against a misbehaving server.
+    pub max_consecutive_open_failures: Option<u32>,
+    /// How many operations of all channels, like data to send, can be queued for the connection,
+    /// defaults to [`DEFAULT_CHANNEL_OPERATIONS_BUFFER`]. Once it is full, [`Channel::send`] waits
+    /// until [`ClientConnection::progress`] has taken on some. Larger queues let many channels,
+    /// like simultaneous port forwards, send at once without waiting on each other.
+    pub channel_operations_buffer: Option<usize>,
+    /// How many updates, like received data, can be queued for each channel,
+    /// defaults to [`DEFAULT_CHANNEL_UPDATES_BUFFER`]. Once the queue of a channel is full,
+    /// [`ClientConnection::progress`] waits for it to be read with [`Channel::next_update`],
+    /// which holds up all other channels too. Larger queues tolerate channels that are read
+    /// in bursts, like pipelined SFTP requests, but hold more received data in memory.
+    pub channel_updates_buffer: Option<usize>,
+    /// How many public keys the server is asked about at once,
+    /// see [`cluelessh_protocol::auth::ClientAuth::set_max_pipelined_queries`].
+    pub max_pipelined_queries: Option<usize>,
+    /// The server's limit of failed authentication attempts, which limits how many keys are queried ahead,
+    /// see [`cluelessh_protocol::auth::ClientAuth::set_max_auth_tries`].
+    pub max_auth_tries: Option<usize>,
+    /// Where the host keys of servers are recorded, usually a [`cluelessh_keys::known_hosts::KnownHostsFile`].
+    /// The host key of the server is checked against it after the key exchange, before authenticating.
+    /// Unknown hosts are recorded, like OpenSSH's `StrictHostKeyChecking accept-new`,
+    /// changed and revoked keys abort the connection. `None` accepts any host key.
+    /// The host is recorded under the name, see [`cluelessh_keys::known_hosts::KnownHosts::host_name`].
+    pub host_key_store: Option<(String, Box<dyn HostKeyStore>)>,
+    /// After how much data or time the client starts a new key exchange, defaults to the limits of OpenSSH.
+    pub rekey_limits: cluelessh_transport::client::RekeyLimits,
+    /// Receives the plaintext of every packet, see [`cluelessh_transport::client::ClientConnection::set_packet_capture`].
+    pub packet_capture: Option<cluelessh_transport::packet::PacketCapture>,
+}
+
+/// An interactive shell in a PTY, started with [`ClientConnection::shell_interactive`].
+pub struct InteractiveShell {
+    /// The input and output of the shell.
+    pub stream: ChannelStream,
+    /// Tells the server when the local terminal has been resized, for example on `SIGWINCH`.
+    pub window_changes: WindowChanges,
+    /// Whether the server allocated the PTY, if it refused the shell runs without one.
+    pub has_pty: bool,
 }
 
+/// The default for [`ClientConfig::read_buffer_size`].
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 32 * 1024;
+
 pub struct ClientAuth {
     pub username: String,
+    /// Never call `prompt_password`, fail authentication instead if no non-interactive method works.
+    pub batch_mode: bool,
+    /// The authentication methods to try in order, see [`cluelessh_protocol::auth::ClientAuth::set_methods`].
+    /// `None` uses the default order.
+    pub methods: Option<Vec<AuthOption>>,
     pub prompt_password: Arc<dyn Fn() -> BoxFuture<'static, Result<String>> + Send + Sync>,
-    pub sign_pubkey:
-        Arc<dyn Fn(SessionId) -> BoxFuture<'static, Result<SignatureResult>> + Send + Sync>,
+    /// The keys to offer, in order. The server is asked whether it accepts a key before `sign_pubkey` is called for it.
+    pub public_keys: Vec<PublicKey>,
+    /// Signs the session for one of `public_keys`, after the server has accepted it.
+    /// If this fails, the next key is tried.
+    pub sign_pubkey: Arc<
+        dyn Fn(SessionId, PublicKey) -> BoxFuture<'static, Result<SignatureResult>> + Send + Sync,
+    >,
 }
 
 enum Operation {
@@ -46,36 +208,206 @@ pub struct SignatureResult {
     pub signature: Vec<u8>,
 }
 
+impl ClientConnection<ChannelStream> {
+    /// Connects through a channel of another connection, usually a `direct-tcpip` channel
+    /// to a host that is only reachable from that server, like `ssh -J`.
+    /// The other connection has to keep making progress while this one is used.
+    pub async fn connect_via(
+        channel: Channel,
+        config: ClientConfig,
+        auth: ClientAuth,
+    ) -> Result<Self> {
+        Self::connect(ChannelStream::new(channel), config, auth).await
+    }
+}
+
+impl ClientConnection<TcpStream> {
+    /// Connects over TCP, from `bind_address` if it is set, see [`stream::connect_tcp`].
+    pub async fn connect_tcp(
+        host: &str,
+        port: u16,
+        bind_address: Option<IpAddr>,
+        config: ClientConfig,
+        auth: ClientAuth,
+    ) -> Result<Self> {
+        let stream = stream::connect_tcp(host, port, bind_address)
+            .await
+            .wrap_err_with(|| format!("failed to connect to {host}:{port}"))?;
+        Self::connect(stream, config, auth).await
+    }
+}
+
+impl ClientConnection<CommandStream> {
+    /// Connects through the stdin and stdout of a command run with `sh -c`,
+    /// like OpenSSH's `ProxyCommand`.
+    pub async fn connect_proxy_command(
+        command: &str,
+        config: ClientConfig,
+        auth: ClientAuth,
+    ) -> Result<Self> {
+        let stream = CommandStream::spawn(command)
+            .wrap_err_with(|| format!("failed to run proxy command {command}"))?;
+        Self::connect(stream, config, auth).await
+    }
+}
+
 impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
-    pub async fn connect(stream: S, auth: ClientAuth) -> Result<Self> {
+    pub async fn connect(stream: S, config: ClientConfig, auth: ClientAuth) -> Result<Self> {
+        let read_buffer_size = config.read_buffer_size.unwrap_or(DEFAULT_READ_BUFFER_SIZE);
+        ensure!(read_buffer_size > 0, "read buffer size must not be 0");
+
+        let channel_operations_buffer = config
+            .channel_operations_buffer
+            .unwrap_or(DEFAULT_CHANNEL_OPERATIONS_BUFFER);
+        ensure!(
+            channel_operations_buffer > 0,
+            "channel operations buffer must not be 0"
+        );
+        let channel_updates_buffer = config
+            .channel_updates_buffer
+            .unwrap_or(DEFAULT_CHANNEL_UPDATES_BUFFER);
+        ensure!(
+            channel_updates_buffer > 0,
+            "channel updates buffer must not be 0"
+        );
+        ensure!(
+            config.max_consecutive_open_failures != Some(0),
+            "max consecutive open failures must not be 0"
+        );
+
         let (operations_send, operations_recv) = tokio::sync::mpsc::channel(15);
-        let (channel_ops_send, channel_ops_recv) = tokio::sync::mpsc::channel(15);
+        let (channel_ops_send, channel_ops_recv) =
+            tokio::sync::mpsc::channel(channel_operations_buffer);
+        let (consumed_send, consumed_recv) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut proto_auth =
+            cluelessh_protocol::auth::ClientAuth::new(auth.username.as_bytes().to_vec());
+        proto_auth.set_batch_mode(auth.batch_mode);
+        if let Some(methods) = &auth.methods {
+            proto_auth.set_methods(methods.clone());
+        }
+        proto_auth.set_public_keys(auth.public_keys.clone());
+        if let Some(max) = config.max_pipelined_queries {
+            proto_auth.set_max_pipelined_queries(max);
+        }
+        if let Some(max) = config.max_auth_tries {
+            proto_auth.set_max_auth_tries(max);
+        }
+
+        let mut transport =
+            cluelessh_transport::client::ClientConnection::new(cluelessh_protocol::OsRng);
+        transport.compression = config.compression;
+        transport.preferred_host_key_algorithms = config.preferred_host_key_algorithms;
+        transport.rekey_limits = config.rekey_limits;
+        transport.set_packet_capture(config.packet_capture);
 
         let mut this = Self {
             stream: Box::pin(stream),
-            buf: [0; 1024],
+            buf: vec![0; read_buffer_size],
             operations_send,
             operations_recv,
             channel_ops_send,
             channel_ops_recv,
+            consumed_send,
+            consumed_recv,
+            channel_updates_buffer,
             channels: HashMap::new(),
-            proto: cluelessh_protocol::ClientConnection::new(
-                cluelessh_transport::client::ClientConnection::new(cluelessh_protocol::OsRng),
-                cluelessh_protocol::auth::ClientAuth::new(auth.username.as_bytes().to_vec()),
-            ),
+            new_channels: VecDeque::new(),
+            proto: cluelessh_protocol::ClientConnection::new(transport, proto_auth),
             auth,
+            forward_agent: config.forward_agent,
+            max_consecutive_open_failures: config.max_consecutive_open_failures,
+            consecutive_open_failures: 0,
+            span: crate::connection_span(),
         };
 
-        while !this.proto.is_open() {
-            this.progress().await?;
+        let mut host_key_store = config.host_key_store;
+        let handshake = async {
+            while !this.proto.is_open() {
+                this.progress().await?;
+                if this.proto.connection_info().is_some() {
+                    if let Some((host_name, store)) = host_key_store.take() {
+                        let span = this.span.clone();
+                        this.verify_host_key(&host_name, store)
+                            .instrument(span)
+                            .await?;
+                    }
+                }
+            }
+            Ok::<_, eyre::Report>(())
+        };
+        match config.handshake_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .wrap_err("timed out during the handshake")??,
+            None => handshake.await?,
+        }
+
+        let channels = this
+            .proto
+            .channels()
+            .expect("connection is open after the handshake");
+        channels.set_window_adjust_on_consumption(true);
+        if let Some(threshold) = config.window_adjust_threshold {
+            channels.set_window_adjust_threshold(threshold);
+        }
+        if let Some(size) = config.initial_window_size {
+            channels.set_initial_window_size(size);
         }
+        channels.set_agent_forwarding(this.forward_agent.is_some());
 
         Ok(this)
     }
 
+    /// Checks the host key of the key exchange against the store, see [`ClientConfig::host_key_store`].
+    async fn verify_host_key(
+        &mut self,
+        host_name: &str,
+        mut store: Box<dyn HostKeyStore>,
+    ) -> Result<()> {
+        let host_key = PublicKey::from_wire_encoding(&self.connection_info().host_key)
+            .wrap_err("invalid host key")?;
+        let fingerprint = host_key.fingerprint_sha256();
+        let status = store
+            .lookup(host_name, &host_key)
+            .wrap_err("looking up host key")?;
+        let problem = match status {
+            HostKeyStatus::Known => {
+                debug!(%host_name, %fingerprint, "Host key is known");
+                return Ok(());
+            }
+            HostKeyStatus::Unknown => {
+                info!(%host_name, %fingerprint, "Recording host key of new host");
+                store
+                    .add(host_name, &host_key)
+                    .wrap_err("recording host key")?;
+                return Ok(());
+            }
+            HostKeyStatus::Changed => {
+                "the host key has changed, someone could be impersonating the server"
+            }
+            HostKeyStatus::Revoked => "the host key has been revoked",
+        };
+        let _ = self
+            .disconnect(disconnect_reason::HOST_KEY_NOT_VERIFIABLE, problem)
+            .await;
+        bail!("host key verification failed for {host_name} ({fingerprint}): {problem}");
+    }
+
+    /// The span the logs of this connection are in, with a `conn` ID.
+    /// Instrument tasks that work on the connection with it to correlate their logs.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
     /// Executes one loop iteration of the main loop.
     // IMPORTANT: no operations on this struct should ever block the main loop, except this one.
     pub async fn progress(&mut self) -> Result<()> {
+        let span = self.span.clone();
+        self.progress_inner().instrument(span).await
+    }
+
+    async fn progress_inner(&mut self) -> Result<()> {
         if let Some(auth) = self.proto.auth() {
             for req in auth.user_requests() {
                 match req {
@@ -87,11 +419,14 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
                             let _ = send.send(Operation::PasswordEntered(password)).await;
                         });
                     }
-                    cluelessh_protocol::auth::ClientUserRequest::PrivateKeySign { session_id } => {
+                    cluelessh_protocol::auth::ClientUserRequest::PrivateKeySign {
+                        session_id,
+                        public_key,
+                    } => {
                         let send = self.operations_send.clone();
                         let sign_pubkey = self.auth.sign_pubkey.clone();
                         tokio::spawn(async move {
-                            let signature_result = sign_pubkey(session_id).await;
+                            let signature_result = sign_pubkey(session_id, public_key).await;
                             let _ = send.send(Operation::Signature(signature_result)).await;
                         });
                     }
@@ -102,65 +437,21 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
             }
         }
 
+        self.dispatch_channel_updates().await?;
         if let Some(channels) = self.proto.channels() {
-            while let Some(update) = channels.next_channel_update() {
-                match &update.kind {
-                    ChannelUpdateKind::Open(_) => {
-                        let channel = self
-                            .channels
-                            .get_mut(&update.number)
-                            .wrap_err("unknown channel")?;
-                        match channel {
-                            ChannelState::Pending { updates_send, .. } => {
-                                let updates_send = updates_send.clone();
-                                let old = self
-                                    .channels
-                                    .insert(update.number, ChannelState::Ready(updates_send));
-                                match old.unwrap() {
-                                    ChannelState::Pending { ready_send, .. } => {
-                                        let _ = ready_send.send(Ok(()));
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                            ChannelState::Ready(_) => {
-                                bail!("attemping to open channel twice: {}", update.number);
-                            }
-                        }
-                    }
-                    ChannelUpdateKind::OpenFailed { message, .. } => {
-                        let channel = self
-                            .channels
-                            .get_mut(&update.number)
-                            .wrap_err("unknown channel")?;
-                        match channel {
-                            ChannelState::Pending { .. } => {
-                                let old = self.channels.remove(&update.number);
-                                match old.unwrap() {
-                                    ChannelState::Pending { ready_send, .. } => {
-                                        let _ = ready_send.send(Err(message.clone()));
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                            ChannelState::Ready(_) => {
-                                bail!("attemping to open channel twice: {}", update.number);
-                            }
-                        }
-                    }
-                    _ => {
-                        let channel = self
-                            .channels
-                            .get_mut(&update.number)
-                            .wrap_err("unknown channel")?;
-                        match channel {
-                            ChannelState::Pending { .. } => bail!("channel not ready yet"),
-                            ChannelState::Ready(updates_send) => {
-                                let _ = updates_send.send(update.kind).await;
-                            }
-                        }
-                    }
-                }
+            crate::update_queued_data(&self.channels, channels);
+        }
+        if let Some(max) = self.max_consecutive_open_failures {
+            if self.consecutive_open_failures >= max {
+                let failures = self.consecutive_open_failures;
+                let _ = self
+                    .disconnect(
+                        disconnect_reason::BY_APPLICATION,
+                        "too many failed channel opens",
+                    )
+                    .await;
+                self.close_channels().await?;
+                bail!("server refused {failures} channel opens in a row");
             }
         }
 
@@ -172,15 +463,26 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
                 let read = read.wrap_err("reading from connection")?;
                 if read == 0 {
                     info!("Did not read any bytes from TCP stream, EOF");
-                    return Ok(());
+                    self.close_channels().await?;
+                    bail!("connection closed by server");
                 }
                 if let Err(err) = self.proto.recv_bytes(&self.buf[..read]) {
+                    // Tell the server why we are closing the connection, if the transport has queued a disconnect.
+                    let _ = self.send_off_data().await;
+                    self.close_channels().await?;
                     match err {
                         SshStatus::PeerError(err) => {
                             bail!("disconnecting client after invalid operation: {err}");
                         }
                         SshStatus::Disconnect => {
-                            bail!("Received disconnect from server");
+                            let Some((code, description)) = self.proto.received_disconnect() else {
+                                bail!("Received disconnect from server");
+                            };
+                            return Err(DisconnectError {
+                                code,
+                                description: description.to_owned(),
+                            }
+                            .into());
                         }
                     }
                 }
@@ -191,6 +493,11 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
                     channels.do_operation(channel_op);
                 }
             }
+            Some((number, len)) = self.consumed_recv.recv() => {
+                if let Some(channels) = self.proto.channels() {
+                    channels.data_consumed(number, len);
+                }
+            }
             op = self.operations_recv.recv() => {
                 match op {
                     Some(Operation::PasswordEntered(password)) => {
@@ -201,9 +508,18 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
                         }
                     }
                     Some(Operation::Signature(result)) => {
-                        let result = result?;
                         if let Some(auth) = self.proto.auth() {
-                            auth.send_signature(result.key_alg_name, &result.public_key, &result.signature);
+                            match result {
+                                Ok(result) => {
+                                    auth.send_signature(result.key_alg_name, &result.public_key, &result.signature);
+                                }
+                                Err(err) => {
+                                    debug!(?err, "Failed to sign, trying the next key or authentication method");
+                                    if let Err(SshStatus::PeerError(err)) = auth.user_request_failed() {
+                                        bail!("authentication failed: {err}");
+                                    }
+                                }
+                            }
                         } else {
                             debug!("Ignoring signature as the state has moved on");
                         }
@@ -217,6 +533,101 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
         Ok(())
     }
 
+    /// Passes the updates of the channels on to their [`Channel`].
+    async fn dispatch_channel_updates(&mut self) -> Result<()> {
+        if let Some(channels) = self.proto.channels() {
+            while let Some(update) = channels.next_channel_update() {
+                match &update.kind {
+                    ChannelUpdateKind::Open(channel_kind) => {
+                        match self.channels.remove(&update.number) {
+                            // We opened.
+                            Some(ChannelState::Pending {
+                                ready_send,
+                                senders,
+                            }) => {
+                                self.channels
+                                    .insert(update.number, ChannelState::Ready(senders));
+                                let _ = ready_send.send(Ok(()));
+                                self.consecutive_open_failures = 0;
+                            }
+                            Some(ChannelState::Ready(_)) => {
+                                bail!("attemping to open channel twice: {}", update.number);
+                            }
+                            // They opened.
+                            None => {
+                                let number = update.number;
+                                let (channel, senders) = crate::new_channel(
+                                    number,
+                                    channel_kind.clone(),
+                                    self.channel_ops_send.clone(),
+                                    self.consumed_send.clone(),
+                                    self.channel_updates_buffer,
+                                );
+                                self.channels.insert(number, ChannelState::Ready(senders));
+
+                                match (channel_kind, &self.forward_agent) {
+                                    (ChannelKind::AuthAgent, Some(socket)) => {
+                                        tokio::spawn(forward_agent(channel, socket.clone()));
+                                    }
+                                    _ => self.new_channels.push_back(channel),
+                                }
+                            }
+                        }
+                    }
+                    ChannelUpdateKind::OpenFailed { message, .. } => {
+                        let channel = self
+                            .channels
+                            .get_mut(&update.number)
+                            .wrap_err("unknown channel")?;
+                        match channel {
+                            ChannelState::Pending { .. } => {
+                                let old = self.channels.remove(&update.number);
+                                match old.unwrap() {
+                                    ChannelState::Pending { ready_send, .. } => {
+                                        let _ = ready_send.send(Err(message.clone()));
+                                    }
+                                    _ => unreachable!(),
+                                }
+                                self.consecutive_open_failures += 1;
+                            }
+                            ChannelState::Ready(_) => {
+                                bail!("attemping to open channel twice: {}", update.number);
+                            }
+                        }
+                    }
+                    _ => {
+                        let channel = self
+                            .channels
+                            .get_mut(&update.number)
+                            .wrap_err("unknown channel")?;
+                        match channel {
+                            ChannelState::Pending { .. } => bail!("channel not ready yet"),
+                            ChannelState::Ready(senders) => {
+                                let closed = matches!(update.kind, ChannelUpdateKind::Closed);
+                                let _ = senders.updates_send.send(update.kind).await;
+                                // The number of a closed channel is never used again.
+                                // Dropping the senders ends the channel after the close.
+                                if closed {
+                                    self.channels.remove(&update.number);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes on the updates the channels received before the connection was closed,
+    /// and then drops them, so that each [`Channel`] reports being closed once it has been drained.
+    async fn close_channels(&mut self) -> Result<()> {
+        let result = self.dispatch_channel_updates().await;
+        self.channels.clear();
+        result
+    }
+
     async fn send_off_data(&mut self) -> Result<()> {
         self.proto.progress();
         while let Some(msg) = self.proto.next_msg_to_send() {
@@ -228,31 +639,221 @@ impl<S: AsyncRead + AsyncWrite> ClientConnection<S> {
         Ok(())
     }
 
+    /// Tells the server why the connection is being closed, sends off all pending data
+    /// and closes the stream. The connection must not be used afterwards.
+    ///
+    /// `reason_code` is one of [`disconnect_reason`], usually [`disconnect_reason::BY_APPLICATION`].
+    pub async fn disconnect(&mut self, reason_code: u32, description: &str) -> Result<()> {
+        self.proto.disconnect(reason_code, description);
+        self.send_off_data().await?;
+        self.stream.shutdown().await.wrap_err("closing connection")
+    }
+
+    /// The channels opened by the server, like `forwarded-tcpip` channels for remote port forwarding.
+    /// Agent channels are connected to [`ClientConfig::forward_agent`] instead of being returned here.
+    pub fn next_new_channel(&mut self) -> Option<Channel> {
+        self.new_channels.pop_front()
+    }
+
     pub fn open_channel(&mut self, kind: ChannelKind) -> PendingChannel {
         let Some(channels) = self.proto.channels() else {
             panic!("connection not ready yet")
         };
-        let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
         let (ready_send, ready_recv) = tokio::sync::oneshot::channel();
 
         let number = channels.create_channel(kind.clone());
+        let (channel, senders) = crate::new_channel(
+            number,
+            kind,
+            self.channel_ops_send.clone(),
+            self.consumed_send.clone(),
+            self.channel_updates_buffer,
+        );
 
         self.channels.insert(
             number,
             ChannelState::Pending {
                 ready_send,
-                updates_send,
+                senders,
             },
         );
 
         PendingChannel {
             ready_recv,
-            channel: Channel {
-                number,
-                updates_recv,
-                ops_send: self.channel_ops_send.clone(),
-                kind,
-            },
+            channel,
         }
     }
+
+    /// Opens a `direct-streamlocal@openssh.com` channel to the Unix socket at `socket_path`
+    /// on the server, like `ssh -L local.sock:/var/run/docker.sock host`.
+    pub fn open_direct_streamlocal(&mut self, socket_path: impl Into<String>) -> PendingChannel {
+        self.open_channel(ChannelKind::DirectStreamlocal {
+            socket_path: socket_path.into(),
+        })
+    }
+
+    /// Opens a session with a shell in a PTY like `ssh host`, with the `TERM` and size of the local terminal.
+    ///
+    /// Like [`PendingChannel::wait_ready`], the returned future needs [`Self::progress`]
+    /// to be called until it has finished. Fails if the server refuses to start the shell.
+    pub fn shell_interactive(
+        &mut self,
+    ) -> impl Future<Output = Result<InteractiveShell>> + Send + 'static {
+        let session = self.open_channel(ChannelKind::Session);
+        async move {
+            let mut channel = session.wait_ready().await.map_err(|err| {
+                eyre::eyre!(
+                    "failed to open session channel: {}",
+                    err.as_deref().unwrap_or("connection closed")
+                )
+            })?;
+
+            let pty = SessionPty {
+                term: std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_owned()),
+                term_modes: TermModes::of_local_terminal().encode(),
+                window_size: &WindowSize::of_local_terminal,
+            };
+            let has_pty = channel.start_session(Some(&pty), None).await?;
+
+            // Output the shell printed before the reply is kept for the stream.
+            let mut output = Vec::new();
+            loop {
+                match channel.next_update().await? {
+                    ChannelUpdateKind::Success => break,
+                    ChannelUpdateKind::Failure => bail!("server refused to start the shell"),
+                    ChannelUpdateKind::Closed => bail!("channel has been closed"),
+                    ChannelUpdateKind::Data { data }
+                    | ChannelUpdateKind::ExtendedData { data, .. } => output.extend(data),
+                    _ => {}
+                }
+            }
+
+            Ok(InteractiveShell {
+                window_changes: channel.window_changes(),
+                stream: ChannelStream::with_received(channel, output),
+                has_pty,
+            })
+        }
+    }
+
+    /// The identification string of the server, like `SSH-2.0-OpenSSH_9.7`,
+    /// for example to work around bugs of specific server versions.
+    pub fn server_identification(&self) -> &[u8] {
+        self.proto
+            .server_identification()
+            .expect("connection has been established in connect")
+    }
+
+    /// The next global request of the server that has been received by [`Self::progress`],
+    /// like [`GlobalRequest::HostKeys`]. Other requests are rejected.
+    pub fn next_global_request(&mut self) -> Option<GlobalRequest> {
+        self.proto.next_global_request()
+    }
+
+    /// Asks the server to prove that it has the private keys of host keys it announced with
+    /// [`GlobalRequest::HostKeys`], for example before recording them in `known_hosts`.
+    /// The result is returned by [`Self::next_proven_host_keys`] after the server has replied.
+    /// Fails if the connection has been closed.
+    pub fn prove_host_keys(&mut self, keys: Vec<PublicKey>) -> Result<()> {
+        let session_id = self
+            .proto
+            .session_id()
+            .expect("connection has been established in connect");
+        let channels = self
+            .proto
+            .channels()
+            .context("connection has been closed")?;
+        channels.send_hostkeys_prove(session_id, keys);
+        Ok(())
+    }
+
+    /// The keys of a [`Self::prove_host_keys`] whose proof has been received by [`Self::progress`].
+    /// If the proof has been refused or is invalid, this is empty.
+    pub fn next_proven_host_keys(&mut self) -> Option<Vec<PublicKey>> {
+        self.proto.channels()?.next_proven_host_keys()
+    }
+
+    /// The algorithms negotiated with the server.
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.proto
+            .connection_info()
+            .expect("connection has been established in connect")
+            .clone()
+    }
+}
+
+/// Connects an `auth-agent@openssh.com` channel of the server to the local agent at `socket`.
+async fn forward_agent(channel: Channel, socket: PathBuf) {
+    let mut agent = match tokio::net::UnixStream::connect(&socket).await {
+        Ok(agent) => agent,
+        Err(err) => {
+            warn!(?err, socket = %socket.display(), "Failed to connect to the SSH agent");
+            let _ = channel.send(ChannelOperationKind::Close).await;
+            return;
+        }
+    };
+
+    let mut stream = ChannelStream::new(channel);
+    if let Err(err) = tokio::io::copy_bidirectional(&mut stream, &mut agent).await {
+        debug!(?err, "Error forwarding the SSH agent");
+    }
+    let _ = stream.into_inner().send(ChannelOperationKind::Close).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{ClientAuth, ClientConfig, ClientConnection};
+
+    fn batch_auth() -> ClientAuth {
+        ClientAuth {
+            username: "user".to_owned(),
+            batch_mode: true,
+            methods: None,
+            prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+            public_keys: vec![],
+            sign_pubkey: Arc::new(|_, _| Box::pin(async { unreachable!() })),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        // A server that accepts the connection, but never says anything.
+        let (stream, _server) = tokio::io::duplex(1024);
+
+        let result = ClientConnection::connect(
+            stream,
+            ClientConfig {
+                handshake_timeout: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+            batch_auth(),
+        )
+        .await;
+
+        let Err(err) = result else {
+            panic!("connected to a server that does not respond");
+        };
+        assert!(err.downcast_ref::<tokio::time::error::Elapsed>().is_some());
+    }
+
+    #[tokio::test]
+    async fn proxy_command_handshake_timeout() {
+        // The command never connects anywhere, which the handshake timeout covers.
+        let result = ClientConnection::connect_proxy_command(
+            "sleep 10",
+            ClientConfig {
+                handshake_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            batch_auth(),
+        )
+        .await;
+
+        let Err(err) = result else {
+            panic!("connected through a command that does not connect");
+        };
+        assert!(err.downcast_ref::<tokio::time::error::Elapsed>().is_some());
+    }
 }
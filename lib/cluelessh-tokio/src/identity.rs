@@ -0,0 +1,167 @@
+//! Keys for public key authentication, collected from multiple sources.
+
+use std::sync::Arc;
+
+use cluelessh_keys::{private::PlaintextPrivateKey, public::PublicKey};
+use cluelessh_transport::SessionId;
+use eyre::{Context, OptionExt, Result};
+use futures::future::BoxFuture;
+use tracing::debug;
+
+use crate::client::SignatureResult;
+
+/// A source of keys that can sign for authentication, like key files or the SSH agent.
+pub trait IdentitySource: Send + Sync {
+    /// A name for the source, for logging.
+    fn name(&self) -> &str;
+
+    /// The public keys of this source, in the order they should be offered.
+    fn public_keys(&self) -> BoxFuture<'_, Result<Vec<PublicKey>>>;
+
+    /// Signs `data` with the private key of `public_key`,
+    /// returning the signature in its wire encoding.
+    fn sign<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>>>;
+}
+
+/// Private keys that are loaded already, for example from key files.
+pub struct PrivateKeys(pub Vec<PlaintextPrivateKey>);
+
+impl IdentitySource for PrivateKeys {
+    fn name(&self) -> &str {
+        "private keys"
+    }
+
+    fn public_keys(&self) -> BoxFuture<'_, Result<Vec<PublicKey>>> {
+        let keys = self
+            .0
+            .iter()
+            .map(|key| key.private_key.public_key())
+            .collect();
+        Box::pin(async { Ok(keys) })
+    }
+
+    fn sign<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let key = self
+                .0
+                .iter()
+                .find(|key| key.private_key.public_key() == *public_key)
+                .ok_or_eyre("no private key for public key")?;
+            Ok(key.private_key.sign(data).to_wire_encoding())
+        })
+    }
+}
+
+/// The keys of the SSH agent at `$SSH_AUTH_SOCK`.
+pub struct Agent;
+
+impl IdentitySource for Agent {
+    fn name(&self) -> &str {
+        "SSH agent"
+    }
+
+    fn public_keys(&self) -> BoxFuture<'_, Result<Vec<PublicKey>>> {
+        Box::pin(async {
+            let mut agent = cluelessh_agent_client::SocketAgentConnection::from_env()
+                .await
+                .wrap_err("failed to connect to SSH agent")?;
+            let identities = agent.list_identities().await?;
+            identities
+                .iter()
+                .map(|identity| {
+                    let pubkey = PublicKey::from_wire_encoding(&identity.key_blob)
+                        .wrap_err("received invalid public key from SSH agent")?;
+                    debug!(comment = ?identity.comment, %pubkey, "Found identity in SSH agent");
+                    Ok(pubkey)
+                })
+                .collect()
+        })
+    }
+
+    fn sign<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut agent = cluelessh_agent_client::SocketAgentConnection::from_env()
+                .await
+                .wrap_err("failed to connect to SSH agent")?;
+            agent
+                .sign(&public_key.to_wire_encoding(), data, 0)
+                .await
+                .wrap_err("signing for authentication")
+        })
+    }
+}
+
+/// The keys of multiple identity sources, to pass to [`crate::client::ClientAuth`].
+pub struct Identities {
+    keys: Vec<(PublicKey, Arc<dyn IdentitySource>)>,
+}
+
+impl Identities {
+    /// Collects the keys of the sources, offering the keys of earlier sources first.
+    /// Sources that fail to list their keys are skipped,
+    /// and keys of multiple sources are only offered for the first one.
+    pub async fn collect(sources: Vec<Arc<dyn IdentitySource>>) -> Self {
+        let mut keys: Vec<(PublicKey, Arc<dyn IdentitySource>)> = Vec::new();
+        for source in sources {
+            match source.public_keys().await {
+                Ok(public_keys) => {
+                    for public_key in public_keys {
+                        if !keys.iter().any(|(key, _)| *key == public_key) {
+                            keys.push((public_key, source.clone()));
+                        }
+                    }
+                }
+                Err(err) => {
+                    debug!(?err, source = %source.name(), "Not using identity source");
+                }
+            }
+        }
+        Self { keys }
+    }
+
+    /// The keys to offer, see [`crate::client::ClientAuth::public_keys`].
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Signs with the source of the key when authenticating as `username`,
+    /// see [`crate::client::ClientAuth::sign_pubkey`].
+    pub fn sign_pubkey(
+        &self,
+        username: String,
+    ) -> Arc<
+        dyn Fn(SessionId, PublicKey) -> BoxFuture<'static, Result<SignatureResult>> + Send + Sync,
+    > {
+        let keys = self.keys.clone();
+        Arc::new(move |session_id, public_key| {
+            let source = keys
+                .iter()
+                .find(|(key, _)| *key == public_key)
+                .map(|(_, source)| source.clone());
+            let username = username.clone();
+            Box::pin(async move {
+                let source = source.ok_or_eyre("no identity source for public key")?;
+                let data =
+                    cluelessh_keys::signature::signature_data(session_id.0, &username, &public_key);
+                let signature = source.sign(&public_key, &data).await?;
+                Ok(SignatureResult {
+                    key_alg_name: public_key.algorithm_name(),
+                    public_key: public_key.to_wire_encoding(),
+                    signature,
+                })
+            })
+        })
+    }
+}
@@ -1,43 +1,409 @@
 pub mod client;
+pub mod identity;
+pub mod reconnect;
 pub mod server;
+pub mod ssh_config;
+pub mod stream;
+pub mod term_modes;
 
-use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use cluelessh_connection::{
+    ChannelKind, ChannelNumber, ChannelOperation, ChannelOperationKind, ChannelRequest,
+    ChannelsState,
+};
+use cluelessh_format::numbers;
 use cluelessh_protocol::ChannelUpdateKind;
-use eyre::{OptionExt, Result};
+use eyre::{bail, OptionExt, Result};
+use tracing::{debug, warn};
+
+/// The default for [`client::ClientConfig::channel_operations_buffer`].
+pub const DEFAULT_CHANNEL_OPERATIONS_BUFFER: usize = 15;
+/// The default for [`client::ClientConfig::channel_updates_buffer`].
+pub const DEFAULT_CHANNEL_UPDATES_BUFFER: usize = 10;
+
+/// The span all logs of a connection are in, with an ID that is unique within the process,
+/// so that the logs of concurrent connections can be told apart.
+fn connection_span() -> tracing::Span {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    tracing::info_span!("ssh", conn = id)
+}
 
 pub struct Channel {
     number: ChannelNumber,
     updates_recv: tokio::sync::mpsc::Receiver<ChannelUpdateKind>,
     ops_send: tokio::sync::mpsc::Sender<ChannelOperation>,
+    /// How much data the connection has queued for this channel, see [`Channel::send`].
+    queued_data: tokio::sync::watch::Receiver<usize>,
+    /// Tells the connection how much received data has been consumed, see [`Channel::next_update`].
+    consumed_send: tokio::sync::mpsc::UnboundedSender<(ChannelNumber, u32)>,
     kind: ChannelKind,
 }
 
 impl Channel {
+    /// Passes the operation on to the connection.
+    ///
+    /// Data is only passed on once the connection has been able to send the data before,
+    /// so while the window of the peer is exhausted this waits until the peer adjusts it,
+    /// instead of queueing an unbounded amount of data in memory.
     pub async fn send(&self, op: ChannelOperationKind) -> Result<()> {
+        if let ChannelOperationKind::Data(_) | ChannelOperationKind::ExtendedData(..) = op {
+            self.window_available().await;
+        }
         self.ops_send
             .send(self.number.construct_op(op))
             .await
             .map_err(Into::into)
     }
 
+    /// Waits until the connection has no data of this channel queued anymore.
+    /// Also returns if the connection is gone, sending will fail then.
+    fn window_available(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut queued_data = self.queued_data.clone();
+        async move {
+            let _ = queued_data.wait_for(|&len| len == 0).await;
+        }
+    }
+
+    /// Waits for the next update of the channel.
+    /// If the connection is closed, the updates received before are still returned before this fails.
+    ///
+    /// Returned data counts as consumed, so the client gives the window back to the server
+    /// and the server can send more.
     pub async fn next_update(&mut self) -> Result<ChannelUpdateKind> {
-        self.updates_recv
+        let update = self
+            .updates_recv
             .recv()
             .await
-            .ok_or_eyre("channel has been closed")
+            .ok_or_eyre("channel has been closed")?;
+        self.consumed(&update);
+        Ok(update)
+    }
+
+    /// Tells the connection that the data of the update has been consumed.
+    fn consumed(&self, update: &ChannelUpdateKind) {
+        if let ChannelUpdateKind::Data { data } | ChannelUpdateKind::ExtendedData { data, .. } =
+            update
+        {
+            let _ = self.consumed_send.send((self.number, data.len() as u32));
+        }
+    }
+
+    /// The number of the channel on our side, as it appears in the protocol logs.
+    /// Numbers are never reused, so it is unique over the whole lifetime of the connection.
+    pub fn number(&self) -> ChannelNumber {
+        self.number
     }
 
     pub fn kind(&self) -> &ChannelKind {
         &self.kind
     }
+
+    /// Asks the server to forward connections to the SSH agent for this session channel,
+    /// before the session is started. Returns whether the server agreed.
+    ///
+    /// The server opens a channel for every connection to the agent, which are only accepted if
+    /// the connection has been configured with [`client::ClientConfig::forward_agent`].
+    pub async fn request_agent_forwarding(&mut self) -> Result<bool> {
+        self.send(ChannelOperationKind::Request(
+            ChannelRequest::AuthAgentReq { want_reply: true },
+        ))
+        .await?;
+
+        loop {
+            match self.next_update().await? {
+                ChannelUpdateKind::Success => return Ok(true),
+                ChannelUpdateKind::Failure => return Ok(false),
+                ChannelUpdateKind::Closed => bail!("channel has been closed"),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sets an environment variable for the session on this channel, before it is started with
+    /// [`Channel::start_session`] or [`Channel::exec`]. Can be called for multiple variables.
+    ///
+    /// Servers usually only accept some variables, like OpenSSH's `AcceptEnv`,
+    /// and silently ignore the others.
+    pub async fn set_env(&self, name: &str, value: &str) -> Result<()> {
+        self.send(ChannelOperationKind::Request(ChannelRequest::Env {
+            want_reply: false,
+            name: name.to_owned(),
+            value: value.as_bytes().to_vec(),
+        }))
+        .await
+    }
+
+    /// Sends a BREAK of `length_ms` milliseconds to the session on this channel,
+    /// for example to a serial console. Servers may use a different length or ignore it.
+    pub async fn send_break(&self, length_ms: u32) -> Result<()> {
+        self.send(ChannelOperationKind::Request(ChannelRequest::Break {
+            want_reply: false,
+            length_ms,
+        }))
+        .await
+    }
+
+    /// Starts the shell, or `command` if set, on this session channel, in a PTY if `pty` is set.
+    /// Returns whether the server allocated the PTY, if it refuses the session is started without one.
+    ///
+    /// The PTY is requested with the size of the terminal before the session is started,
+    /// so that the command starts with the right size. As the terminal may have been resized
+    /// in the meantime, its size is sent again right after starting the session.
+    /// The reply to starting the session is left for the caller to receive.
+    pub async fn start_session(
+        &mut self,
+        pty: Option<&SessionPty<'_>>,
+        command: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut has_pty = false;
+        if let Some(pty) = pty {
+            let size = (pty.window_size)();
+            self.send(ChannelOperationKind::Request(ChannelRequest::PtyReq {
+                want_reply: true,
+                term: pty.term.clone(),
+                width_chars: size.width_chars,
+                height_rows: size.height_rows,
+                width_px: size.width_px,
+                height_px: size.height_px,
+                term_modes: pty.term_modes.clone(),
+            }))
+            .await?;
+
+            has_pty = loop {
+                match self.next_update().await? {
+                    ChannelUpdateKind::Success => break true,
+                    ChannelUpdateKind::Failure => break false,
+                    ChannelUpdateKind::Closed => bail!("channel has been closed"),
+                    _ => {}
+                }
+            };
+            if !has_pty {
+                warn!("Server refused to allocate a PTY");
+            }
+        }
+
+        let request = match command {
+            Some(command) => ChannelRequest::Exec {
+                want_reply: true,
+                command: command.to_vec(),
+            },
+            None => ChannelRequest::Shell { want_reply: true },
+        };
+        self.send(ChannelOperationKind::Request(request)).await?;
+
+        if let (Some(pty), true) = (pty, has_pty) {
+            self.window_change((pty.window_size)()).await?;
+        }
+
+        Ok(has_pty)
+    }
+
+    /// Tells the server that the size of the terminal of the PTY has changed.
+    pub async fn window_change(&self, size: WindowSize) -> Result<()> {
+        self.window_changes().send(size).await
+    }
+
+    /// A handle to send window changes with, which can be used after the channel
+    /// has been turned into a [`stream::ChannelStream`].
+    pub fn window_changes(&self) -> WindowChanges {
+        WindowChanges {
+            number: self.number,
+            ops_send: self.ops_send.clone(),
+        }
+    }
+
+    /// Runs a command on this session channel and collects all of its output.
+    pub async fn exec(self, command: &[u8]) -> Result<CommandOutput> {
+        self.exec_limited(command, usize::MAX).await
+    }
+
+    /// Like [`Channel::exec`], but closes the channel once the command has produced more
+    /// than `max_bytes` of combined stdout and stderr, returning the output up to that point.
+    pub async fn exec_limited(mut self, command: &[u8], max_bytes: usize) -> Result<CommandOutput> {
+        self.send(ChannelOperationKind::Request(ChannelRequest::Exec {
+            want_reply: true,
+            command: command.to_vec(),
+        }))
+        .await?;
+
+        let mut output = CommandOutput {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_status: None,
+            truncated: false,
+        };
+
+        loop {
+            let captured = output.stdout.len() + output.stderr.len();
+            let (buf, data) = match self.next_update().await? {
+                ChannelUpdateKind::Success => continue,
+                ChannelUpdateKind::Failure => bail!("server refused to execute the command"),
+                ChannelUpdateKind::Data { data } => (&mut output.stdout, data),
+                ChannelUpdateKind::ExtendedData { code, data }
+                    if code == numbers::SSH_EXTENDED_DATA_STDERR =>
+                {
+                    (&mut output.stderr, data)
+                }
+                ChannelUpdateKind::Request(ChannelRequest::ExitStatus { status }) => {
+                    output.exit_status = Some(status);
+                    continue;
+                }
+                ChannelUpdateKind::Closed => return Ok(output),
+                _ => continue,
+            };
+            if output.truncated {
+                // The server may still send data until it has received our close.
+                continue;
+            }
+
+            let remaining = max_bytes - captured;
+            if data.len() > remaining {
+                buf.extend_from_slice(&data[..remaining]);
+                output.truncated = true;
+                self.send(ChannelOperationKind::Close).await?;
+            } else {
+                buf.extend_from_slice(&data);
+            }
+        }
+    }
+}
+
+/// The size of a terminal, in characters and pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowSize {
+    pub width_chars: u32,
+    pub height_rows: u32,
+    pub width_px: u32,
+    pub height_px: u32,
+}
+
+impl WindowSize {
+    /// The size of the local terminal on stdin, or 80x24 if stdin is not a terminal.
+    pub fn of_local_terminal() -> Self {
+        match rustix::termios::tcgetwinsize(std::io::stdin()) {
+            Ok(size) => Self {
+                width_chars: size.ws_col.into(),
+                height_rows: size.ws_row.into(),
+                width_px: size.ws_xpixel.into(),
+                height_px: size.ws_ypixel.into(),
+            },
+            Err(err) => {
+                debug!(?err, "Failed to get terminal size");
+                Self {
+                    width_chars: 80,
+                    height_rows: 24,
+                    width_px: 0,
+                    height_px: 0,
+                }
+            }
+        }
+    }
+}
+
+/// Tells the server about window changes of a channel, see [`Channel::window_changes`].
+#[derive(Clone)]
+pub struct WindowChanges {
+    number: ChannelNumber,
+    ops_send: tokio::sync::mpsc::Sender<ChannelOperation>,
+}
+
+impl WindowChanges {
+    /// Like [`Channel::window_change`].
+    pub async fn send(&self, size: WindowSize) -> Result<()> {
+        let request = ChannelRequest::WindowChange {
+            width_chars: size.width_chars,
+            height_rows: size.height_rows,
+            width_px: size.width_px,
+            height_px: size.height_px,
+        };
+        self.ops_send
+            .send(
+                self.number
+                    .construct_op(ChannelOperationKind::Request(request)),
+            )
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// The PTY to request for a session started with [`Channel::start_session`].
+pub struct SessionPty<'a> {
+    /// The `TERM` environment variable.
+    pub term: String,
+    /// The encoded terminal modes, see <https://datatracker.ietf.org/doc/html/rfc4254#section-8>.
+    pub term_modes: Vec<u8>,
+    /// Returns the current size of the local terminal.
+    pub window_size: &'a (dyn Fn() -> WindowSize + Sync),
+}
+
+/// The output of a command executed with [`Channel::exec`].
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The exit status, if the command exited normally and the server sent it.
+    pub exit_status: Option<u32>,
+    /// Whether the output limit was exceeded and the channel has been closed early.
+    pub truncated: bool,
 }
 
 enum ChannelState {
     Pending {
         ready_send: tokio::sync::oneshot::Sender<Result<(), String>>,
-        updates_send: tokio::sync::mpsc::Sender<ChannelUpdateKind>,
+        senders: ChannelSenders,
     },
-    Ready(tokio::sync::mpsc::Sender<ChannelUpdateKind>),
+    Ready(ChannelSenders),
+}
+
+/// The connection side of a [`Channel`].
+struct ChannelSenders {
+    updates_send: tokio::sync::mpsc::Sender<ChannelUpdateKind>,
+    queued_data_send: tokio::sync::watch::Sender<usize>,
+}
+
+fn new_channel(
+    number: ChannelNumber,
+    kind: ChannelKind,
+    ops_send: tokio::sync::mpsc::Sender<ChannelOperation>,
+    consumed_send: tokio::sync::mpsc::UnboundedSender<(ChannelNumber, u32)>,
+    updates_buffer: usize,
+) -> (Channel, ChannelSenders) {
+    let (updates_send, updates_recv) = tokio::sync::mpsc::channel(updates_buffer);
+    let (queued_data_send, queued_data) = tokio::sync::watch::channel(0);
+    let channel = Channel {
+        number,
+        updates_recv,
+        ops_send,
+        queued_data,
+        consumed_send,
+        kind,
+    };
+    let senders = ChannelSenders {
+        updates_send,
+        queued_data_send,
+    };
+    (channel, senders)
+}
+
+/// Tells the channels how much of their data is waiting for the peer to adjust the window,
+/// which wakes up the ones waiting in [`Channel::send`] once it has all been sent.
+fn update_queued_data(channels: &HashMap<ChannelNumber, ChannelState>, state: &ChannelsState) {
+    for (&number, channel) in channels {
+        if let ChannelState::Ready(senders) = channel {
+            let len = state.queued_data_len(number);
+            senders.queued_data_send.send_if_modified(|queued| {
+                let modified = *queued != len;
+                *queued = len;
+                modified
+            });
+        }
+    }
 }
 
 pub struct PendingChannel {
@@ -45,6 +411,11 @@ pub struct PendingChannel {
     channel: Channel,
 }
 impl PendingChannel {
+    /// The number the channel has been assigned on our side, see [`Channel::number`].
+    pub fn number(&self) -> ChannelNumber {
+        self.channel.number
+    }
+
     pub async fn wait_ready(self) -> Result<Channel, Option<String>> {
         match self.ready_recv.await {
             Ok(Ok(())) => Ok(self.channel),
@@ -53,3 +424,297 @@ impl PendingChannel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::{
+        ChannelKind, ChannelNumber, ChannelOperationKind, ChannelRequest, ChannelsState,
+    };
+    use cluelessh_protocol::ChannelUpdateKind;
+    use cluelessh_transport::packet::Packet;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use tokio::sync::{mpsc, watch};
+
+    use super::{new_channel, update_queued_data, Channel, ChannelState, SessionPty, WindowSize};
+
+    fn mock_channel() -> (
+        Channel,
+        mpsc::Sender<ChannelUpdateKind>,
+        mpsc::Receiver<cluelessh_connection::ChannelOperation>,
+    ) {
+        let (updates_send, updates_recv) = mpsc::channel(16);
+        let (ops_send, ops_recv) = mpsc::channel(16);
+        let channel = Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            queued_data: watch::channel(0).1,
+            consumed_send: mpsc::unbounded_channel().0,
+            kind: ChannelKind::Session,
+        };
+        (channel, updates_send, ops_recv)
+    }
+
+    fn size(width_chars: u32, height_rows: u32) -> WindowSize {
+        WindowSize {
+            width_chars,
+            height_rows,
+            width_px: 0,
+            height_px: 0,
+        }
+    }
+
+    /// A local terminal that is resized from 80x24 to 120x40 after the PTY has been requested.
+    fn resized_terminal(calls: &AtomicU32) -> WindowSize {
+        match calls.fetch_add(1, Ordering::Relaxed) {
+            0 => size(80, 24),
+            _ => size(120, 40),
+        }
+    }
+
+    #[tokio::test]
+    async fn start_session_corrects_window_size() {
+        let (mut channel, updates_send, mut ops_recv) = mock_channel();
+
+        // A server that keeps track of the size of the PTY, like `tput cols` and `tput lines` would report it.
+        let server = tokio::spawn(async move {
+            let op = ops_recv.recv().await.unwrap();
+            let ChannelOperationKind::Request(ChannelRequest::PtyReq {
+                want_reply: true,
+                term,
+                width_chars,
+                height_rows,
+                ..
+            }) = op.kind
+            else {
+                panic!("expected pty-req first");
+            };
+            assert_eq!(term, "xterm");
+            let mut pty_size = (width_chars, height_rows);
+            updates_send.send(ChannelUpdateKind::Success).await.unwrap();
+
+            let op = ops_recv.recv().await.unwrap();
+            assert!(matches!(
+                op.kind,
+                ChannelOperationKind::Request(ChannelRequest::Shell { want_reply: true })
+            ));
+            let size_at_start = pty_size;
+
+            let op = ops_recv.recv().await.unwrap();
+            let ChannelOperationKind::Request(ChannelRequest::WindowChange {
+                width_chars,
+                height_rows,
+                ..
+            }) = op.kind
+            else {
+                panic!("expected window-change after shell");
+            };
+            pty_size = (width_chars, height_rows);
+            (size_at_start, pty_size)
+        });
+
+        let calls = AtomicU32::new(0);
+        let window_size = || resized_terminal(&calls);
+        let pty = SessionPty {
+            term: "xterm".to_owned(),
+            term_modes: vec![],
+            window_size: &window_size,
+        };
+        let has_pty = channel.start_session(Some(&pty), None).await.unwrap();
+        assert!(has_pty);
+
+        let (size_at_start, final_size) = server.await.unwrap();
+        assert_eq!(size_at_start, (80, 24));
+        assert_eq!(final_size, (120, 40));
+        assert_eq!(final_size, {
+            let local = window_size();
+            (local.width_chars, local.height_rows)
+        });
+    }
+
+    #[tokio::test]
+    async fn start_session_without_pty() {
+        let (mut channel, updates_send, mut ops_recv) = mock_channel();
+
+        let server = tokio::spawn(async move {
+            let op = ops_recv.recv().await.unwrap();
+            assert!(matches!(
+                op.kind,
+                ChannelOperationKind::Request(ChannelRequest::PtyReq { .. })
+            ));
+            updates_send.send(ChannelUpdateKind::Failure).await.unwrap();
+
+            let op = ops_recv.recv().await.unwrap();
+            assert!(matches!(
+                op.kind,
+                ChannelOperationKind::Request(ChannelRequest::Exec { command, .. }) if command == b"ls"
+            ));
+            // No window-change for a PTY that doesn't exist.
+            assert!(ops_recv.recv().await.is_none());
+        });
+
+        let window_size = || size(80, 24);
+        let pty = SessionPty {
+            term: "xterm".to_owned(),
+            term_modes: vec![],
+            window_size: &window_size,
+        };
+        let has_pty = channel
+            .start_session(Some(&pty), Some(b"ls"))
+            .await
+            .unwrap();
+        assert!(!has_pty);
+        drop(channel);
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_env_before_exec() {
+        let (channel, updates_send, mut ops_recv) = mock_channel();
+
+        let server = tokio::spawn(async move {
+            let mut envs = Vec::new();
+            loop {
+                match ops_recv.recv().await.unwrap().kind {
+                    ChannelOperationKind::Request(ChannelRequest::Env {
+                        want_reply: false,
+                        name,
+                        value,
+                    }) => envs.push((name, value)),
+                    ChannelOperationKind::Request(ChannelRequest::Exec { .. }) => break,
+                    _ => panic!("unexpected operation"),
+                }
+            }
+            updates_send.send(ChannelUpdateKind::Closed).await.unwrap();
+            envs
+        });
+
+        channel.set_env("LANG", "C.UTF-8").await.unwrap();
+        channel.set_env("TZ", "UTC").await.unwrap();
+        channel.exec(b"env").await.unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                ("LANG".to_owned(), b"C.UTF-8".to_vec()),
+                ("TZ".to_owned(), b"UTC".to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn exec_limited_truncates() {
+        let (channel, updates_send, mut ops_recv) = mock_channel();
+
+        // A server running `yes`, which never stops until the channel is closed.
+        let server = tokio::spawn(async move {
+            let op = ops_recv.recv().await.unwrap();
+            assert!(matches!(
+                op.kind,
+                ChannelOperationKind::Request(ChannelRequest::Exec { command, .. }) if command == b"yes"
+            ));
+            updates_send.send(ChannelUpdateKind::Success).await.unwrap();
+            loop {
+                tokio::select! {
+                    op = ops_recv.recv() => {
+                        assert!(matches!(op.unwrap().kind, ChannelOperationKind::Close));
+                        updates_send.send(ChannelUpdateKind::Closed).await.unwrap();
+                        return;
+                    }
+                    result = updates_send.send(ChannelUpdateKind::Data { data: b"y\ny\ny\n".to_vec() }) => {
+                        result.unwrap();
+                    }
+                }
+            }
+        });
+
+        let output = channel.exec_limited(b"yes", 100).await.unwrap();
+        assert!(output.truncated);
+        assert_eq!(output.stdout, b"y\n".repeat(50));
+        assert!(output.stderr.is_empty());
+        assert_eq!(output.exit_status, None);
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_waits_for_window() {
+        let (ops_send, mut ops_recv) = mpsc::channel(16);
+        let mut state = ChannelsState::new(true);
+        state
+            .recv_packet(Packet::new_msg_channel_open_session(
+                b"session", 0, 100, 1024,
+            ))
+            .unwrap();
+        let (channel, senders) = new_channel(
+            ChannelNumber(0),
+            ChannelKind::Session,
+            ops_send,
+            mpsc::unbounded_channel().0,
+            super::DEFAULT_CHANNEL_UPDATES_BUFFER,
+        );
+        let channels = HashMap::from([(ChannelNumber(0), ChannelState::Ready(senders))]);
+
+        // More than the window of the peer, the rest is queued.
+        channel
+            .send(ChannelOperationKind::Data(vec![0; 150]))
+            .await
+            .unwrap();
+        state.do_operation(ops_recv.recv().await.unwrap());
+        update_queued_data(&channels, &state);
+
+        let send = channel.send(ChannelOperationKind::Data(vec![1; 10]));
+        tokio::pin!(send);
+        assert!(tokio::time::timeout(Duration::from_secs(10), &mut send)
+            .await
+            .is_err());
+
+        // Other operations are not held back.
+        channel
+            .send(ChannelOperationKind::Request(ChannelRequest::ExitStatus {
+                status: 0,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            ops_recv.recv().await.unwrap().kind,
+            ChannelOperationKind::Request(ChannelRequest::ExitStatus { status: 0 })
+        ));
+
+        state
+            .recv_packet(Packet::new_msg_channel_window_adjust(0, 100))
+            .unwrap();
+        update_queued_data(&channels, &state);
+        send.await.unwrap();
+        assert!(matches!(
+            ops_recv.recv().await.unwrap().kind,
+            ChannelOperationKind::Data(data) if data == [1; 10]
+        ));
+    }
+
+    #[tokio::test]
+    async fn next_update_reports_consumed_data() {
+        let (mut channel, updates_send, _ops_recv) = mock_channel();
+        let (consumed_send, mut consumed_recv) = mpsc::unbounded_channel();
+        channel.consumed_send = consumed_send;
+
+        updates_send
+            .send(ChannelUpdateKind::Data {
+                data: b"hello".to_vec(),
+            })
+            .await
+            .unwrap();
+        updates_send.send(ChannelUpdateKind::Eof).await.unwrap();
+
+        // Nothing is consumed before the application has received it.
+        assert!(consumed_recv.try_recv().is_err());
+        channel.next_update().await.unwrap();
+        assert_eq!(consumed_recv.try_recv().unwrap(), (ChannelNumber(0), 5));
+        channel.next_update().await.unwrap();
+        assert!(consumed_recv.try_recv().is_err());
+    }
+}
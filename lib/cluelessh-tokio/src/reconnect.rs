@@ -0,0 +1,194 @@
+//! Keeping a client connection up by connecting again whenever it fails,
+//! for long-running programs like tunnels.
+
+use std::{collections::VecDeque, time::Duration};
+
+use cluelessh_connection::ChannelKind;
+use eyre::{bail, Result};
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
+use tracing::{info, warn};
+
+use crate::{client::ClientConnection, PendingChannel};
+
+/// How long to wait between connection attempts.
+///
+/// After the connection went down, the first attempt is made after the initial delay,
+/// which doubles with every attempt that fails, up to the maximum delay.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many attempts in a row have failed, `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the next attempt after `failed_attempts` attempts in a row have failed.
+    fn delay(&self, failed_attempts: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(failed_attempts);
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+/// A change of the connection of a [`ReconnectingClient`].
+pub enum ConnectionEvent {
+    /// A connection has been established.
+    /// The configured channels are being opened on it, in the order they were configured.
+    Up { channels: Vec<PendingChannel> },
+    /// The connection went down or an attempt to connect failed.
+    /// The next attempt is made after `retry_in`.
+    Down {
+        error: eyre::Report,
+        retry_in: Duration,
+    },
+}
+
+type ConnectFn<S> = Box<dyn FnMut() -> BoxFuture<'static, Result<ClientConnection<S>>> + Send>;
+
+/// A [`ClientConnection`] that is established again with `connect` whenever it fails,
+/// after waiting according to a [`Backoff`].
+///
+/// The channels that are needed on every connection, like the session a tunnel runs in,
+/// are opened again every time, see [`ConnectionEvent::Up`].
+/// As with [`ClientConnection`], [`Self::progress`] has to be called in a loop.
+pub struct ReconnectingClient<S> {
+    connect: ConnectFn<S>,
+    backoff: Backoff,
+    channels: Vec<ChannelKind>,
+
+    connection: Option<ClientConnection<S>>,
+    /// The attempt that is in progress, kept across calls to [`Self::progress`]
+    /// so that cancelling it does not start over.
+    connecting: Option<BoxFuture<'static, Result<ClientConnection<S>>>>,
+    /// When to make the next attempt while there is no connection.
+    retry_at: Instant,
+    /// Attempts that failed since the connection was last up.
+    failed_attempts: u32,
+    events: VecDeque<ConnectionEvent>,
+}
+
+impl<S: AsyncRead + AsyncWrite + 'static> ReconnectingClient<S> {
+    /// `connect` is called for every attempt, like
+    /// `|| Box::pin(ClientConnection::connect_tcp(host, port, None, config(), auth()))`.
+    /// The first attempt is made right away on the first call to [`Self::progress`].
+    pub fn new(
+        connect: impl FnMut() -> BoxFuture<'static, Result<ClientConnection<S>>> + Send + 'static,
+        backoff: Backoff,
+        channels: Vec<ChannelKind>,
+    ) -> Self {
+        Self {
+            connect: Box::new(connect),
+            backoff,
+            channels,
+            connection: None,
+            connecting: None,
+            retry_at: Instant::now(),
+            failed_attempts: 0,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Executes one loop iteration of the connection, or of connecting again while it is down.
+    /// Fails only when giving up after [`Backoff::max_attempts`].
+    ///
+    /// This is cancel safe, so it can be used in `select!` with other work.
+    pub async fn progress(&mut self) -> Result<()> {
+        if let Some(connection) = &mut self.connection {
+            if let Err(err) = connection.progress().await {
+                self.connection = None;
+                self.failed_attempts = 0;
+                self.down(err);
+            }
+            return Ok(());
+        }
+
+        let connecting = match &mut self.connecting {
+            Some(connecting) => connecting,
+            None => {
+                tokio::time::sleep_until(self.retry_at).await;
+                self.connecting.insert((self.connect)())
+            }
+        };
+        let result = connecting.await;
+        self.connecting = None;
+
+        match result {
+            Ok(mut connection) => {
+                info!("Connection is up");
+                self.failed_attempts = 0;
+                let channels = self
+                    .channels
+                    .iter()
+                    .map(|kind| connection.open_channel(kind.clone()))
+                    .collect();
+                self.connection = Some(connection);
+                self.events.push_back(ConnectionEvent::Up { channels });
+            }
+            Err(err) => {
+                self.failed_attempts += 1;
+                if let Some(max_attempts) = self.backoff.max_attempts {
+                    if self.failed_attempts >= max_attempts {
+                        bail!("giving up after {max_attempts} failed connection attempts: {err:#}");
+                    }
+                }
+                self.down(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn down(&mut self, error: eyre::Report) {
+        let retry_in = self.backoff.delay(self.failed_attempts);
+        warn!(?retry_in, "Connection is down: {error:#}");
+        self.retry_at = Instant::now() + retry_in;
+        self.events
+            .push_back(ConnectionEvent::Down { error, retry_in });
+    }
+
+    /// The next change of the connection that happened in [`Self::progress`].
+    pub fn next_event(&mut self) -> Option<ConnectionEvent> {
+        self.events.pop_front()
+    }
+
+    /// The current connection, if it is up.
+    pub fn connection(&mut self) -> Option<&mut ClientConnection<S>> {
+        self.connection.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_attempts: None,
+        };
+        let delays = (0..6)
+            .map(|n| backoff.delay(n).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
+}
@@ -1,4 +1,5 @@
 use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperation};
+use cluelessh_format::numbers;
 use cluelessh_keys::public::PublicKey;
 use cluelessh_transport::server::{KeyExchangeParameters, KeyExchangeResponse};
 use futures::future::BoxFuture;
@@ -7,19 +8,25 @@ use std::{
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    time::Instant,
 };
 
 use cluelessh_protocol::{
-    auth::{AuthOption, CheckPublicKey, VerifyPassword, VerifySignature},
+    auth::{
+        AuthOption, CheckPublicKey, InfoRequest, KeyboardInteractive, VerifyPassword,
+        VerifySignature,
+    },
+    transport::SshRng,
     ChannelUpdateKind, SshStatus,
 };
 use eyre::{eyre, ContextCompat, OptionExt, Result, WrapErr};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use tracing::{debug, info, warn, Instrument};
 
 use crate::{Channel, ChannelState, PendingChannel};
 
@@ -41,6 +48,9 @@ pub struct ServerConnection<S> {
     /// Cloned and passed on to channels.
     channel_ops_send: tokio::sync::mpsc::Sender<ChannelOperation>,
     channel_ops_recv: tokio::sync::mpsc::Receiver<ChannelOperation>,
+    /// Cloned and passed on to channels, see [`crate::Channel::next_update`].
+    consumed_send: tokio::sync::mpsc::UnboundedSender<(ChannelNumber, u32)>,
+    consumed_recv: tokio::sync::mpsc::UnboundedReceiver<(ChannelNumber, u32)>,
 
     channels: HashMap<ChannelNumber, ChannelState>,
 
@@ -49,24 +59,116 @@ pub struct ServerConnection<S> {
 
     signature_in_progress: bool,
     auth_verify: ServerAuth,
+
+    client_alive: Option<ClientAlive>,
+    /// When to send the next keepalive request, pushed back whenever the client sends something.
+    client_alive_deadline: Instant,
+    /// Keepalive requests sent since the client last sent something.
+    client_alive_unanswered: u32,
+
+    idle_timeout: Option<Duration>,
+    /// When to disconnect the client for being idle, pushed back whenever there is channel activity.
+    idle_deadline: Instant,
+    /// See [`Self::last_channel_activity`].
+    last_channel_activity: Instant,
+    /// See [`Self::set_debug_log_channel`].
+    debug_log_channel: Option<Box<dyn Fn(&str) -> bool + Send + Sync>>,
+    /// Whether the channels have been set up after authentication.
+    channels_configured: bool,
+
+    disconnect_messages: HashMap<DisconnectReason, String>,
+    /// See [`Self::span`].
+    span: tracing::Span,
+}
+
+/// Checks whether the client is still there by sending it keepalive requests when it has been quiet,
+/// like `ClientAliveInterval` and `ClientAliveCountMax` in OpenSSH.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAlive {
+    /// How long the client may be quiet before a keepalive request is sent.
+    pub interval: Duration,
+    /// How many keepalive requests may go unanswered before the client is considered unresponsive.
+    pub count_max: u32,
+    pub action: ClientAliveAction,
+}
+
+const DISCONNECT_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why the server closes a connection, which determines the reason code and message sent to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// There was no channel activity for the idle timeout, see [`ServerConnection::set_idle_timeout`].
+    Idle,
+    /// The client did not answer keepalive requests, see [`ClientAlive`].
+    Unresponsive,
+    /// The user is already logged in with as many connections as allowed.
+    TooManySessions,
+    /// The client failed to authenticate too often.
+    TooManyAuthFailures,
+}
+
+impl DisconnectReason {
+    /// The `SSH_DISCONNECT_*` reason code.
+    pub fn reason_code(self) -> u32 {
+        match self {
+            Self::Idle => numbers::SSH_DISCONNECT_BY_APPLICATION,
+            Self::Unresponsive => numbers::SSH_DISCONNECT_CONNECTION_LOST,
+            Self::TooManySessions => numbers::SSH_DISCONNECT_TOO_MANY_CONNECTIONS,
+            Self::TooManyAuthFailures => numbers::SSH_DISCONNECT_NO_MORE_AUTH_METHODS_AVAILABLE,
+        }
+    }
+
+    /// The message used unless it was replaced with [`ServerConnection::set_disconnect_message`].
+    pub fn default_message(self) -> &'static str {
+        match self {
+            Self::Idle => "Session idle timeout expired",
+            Self::Unresponsive => "Client did not answer keepalive requests",
+            Self::TooManySessions => "Too many sessions for this user",
+            Self::TooManyAuthFailures => "Too many authentication failures",
+        }
+    }
+}
+
+/// What to do once a client has become unresponsive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAliveAction {
+    Disconnect,
+    /// Only log a warning and keep the connection open, for monitoring.
+    Log,
 }
 
 enum Operation {
     VerifyPassword(String, Result<bool>),
     CheckPubkey(Result<bool>, PublicKey),
     VerifySignature(String, Result<bool>),
+    KeyboardInteractive(String, Result<KeyboardInteractiveResult>),
     KeyExchangeResponseReceived(Result<KeyExchangeResponse>),
 }
 
 pub type AuthFn<A, R> = Arc<dyn Fn(A) -> BoxFuture<'static, R> + Send + Sync>;
 
+/// The answer to a step of keyboard-interactive authentication.
+pub enum KeyboardInteractiveResult {
+    /// Send prompts to the user, whose responses are the next step.
+    InfoRequest(InfoRequest),
+    /// The exchange is over, and the user has been authenticated or not.
+    Verified(bool),
+}
+
 #[derive(Clone)]
 pub struct ServerAuth {
     pub verify_password: Option<AuthFn<VerifyPassword, Result<bool>>>,
     pub verify_signature: Option<AuthFn<VerifySignature, Result<bool>>>,
     pub check_pubkey: Option<AuthFn<CheckPublicKey, Result<bool>>>,
+    /// Called for every step of keyboard-interactive authentication, starting with
+    /// [`cluelessh_protocol::auth::KeyboardInteractiveStep::Start`].
+    pub keyboard_interactive:
+        Option<AuthFn<KeyboardInteractive, Result<KeyboardInteractiveResult>>>,
     pub do_key_exchange: AuthFn<KeyExchangeParameters, Result<KeyExchangeResponse>>,
     pub auth_banner: Option<String>,
+    /// The minimum time a failed authentication attempt takes before the failure is sent, plus up to 25% of jitter.
+    /// This hides how long the verification took, which may reveal whether the user exists, and slows down brute force.
+    pub auth_failure_delay: Option<Duration>,
 }
 fn _assert_send_sync() {
     fn send<T: Send + Sync>() {}
@@ -121,7 +223,9 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         transport_config: cluelessh_transport::server::ServerConfig,
     ) -> Self {
         let (operations_send, operations_recv) = tokio::sync::mpsc::channel(15);
-        let (channel_ops_send, channel_ops_recv) = tokio::sync::mpsc::channel(15);
+        let (channel_ops_send, channel_ops_recv) =
+            tokio::sync::mpsc::channel(crate::DEFAULT_CHANNEL_OPERATIONS_BUFFER);
+        let (consumed_send, consumed_recv) = tokio::sync::mpsc::unbounded_channel();
 
         let mut options = HashSet::new();
         if auth_verify.verify_password.is_some() {
@@ -130,6 +234,9 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         if auth_verify.verify_signature.is_some() {
             options.insert(AuthOption::PublicKey);
         }
+        if auth_verify.keyboard_interactive.is_some() {
+            options.insert(AuthOption::KeyboardInteractive);
+        }
 
         if options.is_empty() {
             panic!("no auth options provided");
@@ -148,6 +255,8 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
             operations_recv,
             channel_ops_send,
             channel_ops_recv,
+            consumed_send,
+            consumed_recv,
             channels: HashMap::new(),
             proto: cluelessh_protocol::ServerConnection::new(
                 cluelessh_transport::server::ServerConnection::new(
@@ -160,16 +269,82 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
             new_channels: VecDeque::new(),
             auth_verify,
             signature_in_progress: false,
+            client_alive: None,
+            client_alive_deadline: Instant::now(),
+            client_alive_unanswered: 0,
+            idle_timeout: None,
+            idle_deadline: Instant::now(),
+            last_channel_activity: Instant::now(),
+            debug_log_channel: None,
+            channels_configured: false,
+            disconnect_messages: HashMap::new(),
+            span: crate::connection_span(),
+        }
+    }
+
+    /// Disconnects the client with [`DisconnectReason::Idle`] once no channel data has been sent
+    /// or received for this long after authentication.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+        self.channel_activity();
+    }
+
+    /// Replaces the default message sent to the client when it is disconnected for this reason.
+    pub fn set_disconnect_message(&mut self, reason: DisconnectReason, message: String) {
+        self.disconnect_messages.insert(reason, message);
+    }
+
+    /// Tells the client why the connection is being closed and sends off all pending data.
+    /// The connection must not be used afterwards.
+    pub async fn disconnect(&mut self, reason: DisconnectReason) -> Result<(), Error> {
+        let message = self
+            .disconnect_messages
+            .get(&reason)
+            .map(String::as_str)
+            .unwrap_or(reason.default_message());
+        self.proto.disconnect(reason.reason_code(), message);
+        // An unresponsive client may not read anymore, so don't wait for it forever.
+        match tokio::time::timeout(DISCONNECT_SEND_TIMEOUT, self.send_off_data()).await {
+            Ok(result) => result?,
+            Err(_) => debug!("Timed out sending disconnect message"),
         }
+        Ok(())
+    }
+
+    /// Enables or disables keepalive requests once the client has authenticated.
+    pub fn set_client_alive(&mut self, client_alive: Option<ClientAlive>) {
+        self.client_alive = client_alive;
+        self.client_alive_unanswered = 0;
+        if let Some(client_alive) = client_alive {
+            self.client_alive_deadline = Instant::now() + client_alive.interval;
+        }
+    }
+
+    /// Accepts `debug-log@cluelessh` channels if `allowed` returns true for the authenticated user,
+    /// see [`cluelessh_connection::ChannelsState::set_debug_log_channel`].
+    /// Channels opened in the same read as the authentication request are refused.
+    pub fn set_debug_log_channel(&mut self, allowed: Box<dyn Fn(&str) -> bool + Send + Sync>) {
+        self.debug_log_channel = Some(allowed);
     }
 
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
 
+    /// The span the logs of this connection are in, with a `conn` ID.
+    /// Instrument tasks that work on the connection with it to correlate their logs.
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
     /// Executes one loop iteration of the main loop.
     // IMPORTANT: no operations on this struct should ever block the main loop, except this one.
     pub async fn progress(&mut self) -> Result<(), Error> {
+        let span = self.span.clone();
+        self.progress_inner().instrument(span).await
+    }
+
+    async fn progress_inner(&mut self) -> Result<(), Error> {
         if let Some(params) = self.proto.is_waiting_on_key_exchange() {
             if !self.signature_in_progress {
                 self.signature_in_progress = true;
@@ -196,8 +371,11 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                             .verify_password
                             .clone()
                             .ok_or_eyre("password auth not supported")?;
+                        let delay = self.auth_verify.auth_failure_delay;
                         tokio::spawn(async move {
+                            let start = Instant::now();
                             let result = verify(password_verify.clone()).await;
+                            delay_auth_failure(start, delay, &result).await;
                             let _ = send
                                 .send(Operation::VerifyPassword(password_verify.user, result))
                                 .await;
@@ -210,8 +388,11 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                             .check_pubkey
                             .clone()
                             .ok_or_eyre("pubkey auth not supported")?;
+                        let delay = self.auth_verify.auth_failure_delay;
                         tokio::spawn(async move {
+                            let start = Instant::now();
                             let result = check(check_pubkey.clone()).await;
+                            delay_auth_failure(start, delay, &result).await;
                             let _ = send
                                 .send(Operation::CheckPubkey(result, check_pubkey.public_key))
                                 .await;
@@ -224,32 +405,78 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                             .verify_signature
                             .clone()
                             .ok_or_eyre("pubkey auth not supported")?;
+                        let delay = self.auth_verify.auth_failure_delay;
                         tokio::spawn(async move {
+                            let start = Instant::now();
                             let result = verify(pubkey_verify.clone()).await;
+                            delay_auth_failure(start, delay, &result).await;
                             let _ = send
                                 .send(Operation::VerifySignature(pubkey_verify.user, result))
                                 .await;
                         });
                     }
+                    cluelessh_protocol::auth::ServerRequest::KeyboardInteractive(step) => {
+                        let send = self.operations_send.clone();
+                        let keyboard_interactive = self
+                            .auth_verify
+                            .keyboard_interactive
+                            .clone()
+                            .ok_or_eyre("keyboard-interactive auth not supported")?;
+                        let delay = self.auth_verify.auth_failure_delay;
+                        tokio::spawn(async move {
+                            let start = Instant::now();
+                            let user = step.user.clone();
+                            let result = keyboard_interactive(step).await;
+                            // Prompts are not a failure, only the final result is delayed.
+                            if !matches!(result, Ok(KeyboardInteractiveResult::InfoRequest(_))) {
+                                let verified =
+                                    matches!(result, Ok(KeyboardInteractiveResult::Verified(true)));
+                                delay_auth_failure(start, delay, &Ok(verified)).await;
+                            }
+                            let _ = send
+                                .send(Operation::KeyboardInteractive(user, result))
+                                .await;
+                        });
+                    }
                 }
             }
         }
 
+        if !self.channels_configured {
+            if let Some(user) = self.proto.authenticated_user().map(ToOwned::to_owned) {
+                let debug_log_channel = self
+                    .debug_log_channel
+                    .as_ref()
+                    .is_some_and(|allowed| allowed(&user));
+                let channels = self
+                    .proto
+                    .channels()
+                    .expect("authenticated connection is open");
+                channels.set_debug_log_channel(debug_log_channel);
+                self.channels_configured = true;
+            }
+        }
+
+        let mut channel_activity = false;
+        let new_channels_before = self.new_channels.len();
         if let Some(channels) = self.proto.channels() {
             while let Some(update) = channels.next_channel_update() {
+                channel_activity = true;
                 match &update.kind {
                     ChannelUpdateKind::Open(channel_kind) => {
                         let channel = self.channels.get_mut(&update.number);
 
                         match channel {
                             // We opened.
-                            Some(ChannelState::Pending { updates_send, .. }) => {
-                                let updates_send = updates_send.clone();
-                                let old = self
-                                    .channels
-                                    .insert(update.number, ChannelState::Ready(updates_send));
+                            Some(ChannelState::Pending { .. }) => {
+                                let old = self.channels.remove(&update.number);
                                 match old.unwrap() {
-                                    ChannelState::Pending { ready_send, .. } => {
+                                    ChannelState::Pending {
+                                        ready_send,
+                                        senders,
+                                    } => {
+                                        self.channels
+                                            .insert(update.number, ChannelState::Ready(senders));
                                         let _ = ready_send.send(Ok(()));
                                     }
                                     _ => unreachable!(),
@@ -263,19 +490,16 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                             }
                             // They opened.
                             None => {
-                                let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
-
                                 let number = update.number;
-
-                                self.channels
-                                    .insert(number, ChannelState::Ready(updates_send));
-
-                                let channel = Channel {
+                                let (channel, senders) = crate::new_channel(
                                     number,
-                                    updates_recv,
-                                    ops_send: self.channel_ops_send.clone(),
-                                    kind: channel_kind.clone(),
-                                };
+                                    channel_kind.clone(),
+                                    self.channel_ops_send.clone(),
+                                    self.consumed_send.clone(),
+                                    crate::DEFAULT_CHANNEL_UPDATES_BUFFER,
+                                );
+
+                                self.channels.insert(number, ChannelState::Ready(senders));
                                 self.new_channels.push_back(channel);
                             }
                         }
@@ -312,8 +536,14 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                             ChannelState::Pending { .. } => {
                                 return Err(Error::ServerError(eyre!("channel not ready yet")))
                             }
-                            ChannelState::Ready(updates_send) => {
-                                let _ = updates_send.send(update.kind).await;
+                            ChannelState::Ready(senders) => {
+                                let closed = matches!(update.kind, ChannelUpdateKind::Closed);
+                                let _ = senders.updates_send.send(update.kind).await;
+                                // The number of a closed channel is never used again.
+                                // Dropping the senders ends the channel after the close.
+                                if closed {
+                                    self.channels.remove(&update.number);
+                                }
                             }
                         }
                     }
@@ -321,9 +551,26 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
             }
         }
 
+        if channel_activity {
+            self.channel_activity();
+        }
+        if let Some(channels) = self.proto.channels() {
+            crate::update_queued_data(&self.channels, channels);
+        }
+
         // Make sure that we send all queued messages before going into the select, waiting for things to happen.
         self.send_off_data().await?;
 
+        // The peer may not send anything else on a channel it opened, like a debug log channel,
+        // so hand it out instead of waiting for the next packet.
+        if self.new_channels.len() > new_channels_before {
+            return Ok(());
+        }
+
+        let is_open = self.proto.channels().is_some();
+        let check_client_alive = self.client_alive.is_some() && is_open;
+        let check_idle = self.idle_timeout.is_some() && is_open;
+
         tokio::select! {
             read = self.stream.read(&mut self.buf) => {
                 let read = read.wrap_err("reading from connection")?;
@@ -334,25 +581,50 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
                 if let Err(err) = self.proto.recv_bytes(&self.buf[..read]) {
                     return Err(Error::SshStatus(err));
                 }
+                self.client_is_alive();
+            }
+            () = tokio::time::sleep_until(self.client_alive_deadline), if check_client_alive => {
+                self.check_client_alive().await?;
+                self.send_off_data().await?;
+            }
+            () = tokio::time::sleep_until(self.idle_deadline), if check_idle => {
+                info!(timeout = ?self.idle_timeout, "Session idle timeout expired, disconnecting");
+                self.disconnect(DisconnectReason::Idle).await?;
+                return Err(Error::SshStatus(SshStatus::Disconnect));
             }
             channel_op = self.channel_ops_recv.recv() => {
                 let channels = self.proto.channels().expect("connection not ready");
                 if let Some(channel_op) = channel_op {
                     channels.do_operation(channel_op);
+                    self.channel_activity();
+                }
+            }
+            Some((number, len)) = self.consumed_recv.recv() => {
+                if let Some(channels) = self.proto.channels() {
+                    channels.data_consumed(number, len);
                 }
             }
             op = self.operations_recv.recv() => {
                 match op {
                     Some(Operation::VerifySignature(user, result)) => if let Some(auth) = self.proto.auth() {
-                        auth.verification_result(result?, user);
+                        auth.verification_result(result?, user).map_err(Error::SshStatus)?;
                     },
                     Some(Operation::CheckPubkey(result, public_key)) => if let Some(auth) = self.proto.auth() {
-                        auth.pubkey_check_result(result?, public_key);
+                        auth.pubkey_check_result(result?, public_key).map_err(Error::SshStatus)?;
                     },
                     Some(Operation::VerifyPassword(user, result)) => if let Some(auth) = self.proto.auth() {
-                        auth.verification_result(result?, user);
+                        auth.verification_result(result?, user).map_err(Error::SshStatus)?;
+                    },
+                    Some(Operation::KeyboardInteractive(user, result)) => if let Some(auth) = self.proto.auth() {
+                        match result? {
+                            KeyboardInteractiveResult::InfoRequest(request) => auth.info_request(user, request),
+                            KeyboardInteractiveResult::Verified(is_ok) => auth.verification_result(is_ok, user),
+                        }
+                        .map_err(Error::SshStatus)?;
                     },
                     Some(Operation::KeyExchangeResponseReceived(signature)) => {
+                        // The client may start a new key exchange later.
+                        self.signature_in_progress = false;
                         let signature = signature?;
                         self.proto.do_key_exchange(signature);
                     }
@@ -365,6 +637,51 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         Ok(())
     }
 
+    fn client_is_alive(&mut self) {
+        let Some(client_alive) = self.client_alive else {
+            return;
+        };
+        if self.client_alive_unanswered > client_alive.count_max {
+            info!("Client is responsive again");
+        }
+        self.client_alive_unanswered = 0;
+        self.client_alive_deadline = Instant::now() + client_alive.interval;
+    }
+
+    fn channel_activity(&mut self) {
+        self.last_channel_activity = Instant::now();
+        if let Some(idle_timeout) = self.idle_timeout {
+            self.idle_deadline = Instant::now() + idle_timeout;
+        }
+    }
+
+    /// Called when the client has been quiet for the keepalive interval.
+    async fn check_client_alive(&mut self) -> Result<(), Error> {
+        let client_alive = self.client_alive.expect("client alive checks are disabled");
+
+        if self.client_alive_unanswered == client_alive.count_max {
+            let unanswered = self.client_alive_unanswered;
+            match client_alive.action {
+                ClientAliveAction::Disconnect => {
+                    warn!(%unanswered, "Client did not answer keepalive requests, disconnecting");
+                    self.disconnect(DisconnectReason::Unresponsive).await?;
+                    return Err(Error::SshStatus(SshStatus::PeerError(format!(
+                        "client did not answer {unanswered} keepalive requests"
+                    ))));
+                }
+                ClientAliveAction::Log => {
+                    warn!(%unanswered, "Client did not answer keepalive requests");
+                }
+            }
+        }
+
+        let channels = self.proto.channels().expect("connection not ready");
+        channels.send_keepalive();
+        self.client_alive_unanswered = self.client_alive_unanswered.saturating_add(1);
+        self.client_alive_deadline = Instant::now() + client_alive.interval;
+        Ok(())
+    }
+
     async fn send_off_data(&mut self) -> Result<()> {
         self.proto.progress();
         while let Some(msg) = self.proto.next_msg_to_send() {
@@ -380,27 +697,28 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         let Some(channels) = self.proto.channels() else {
             panic!("connection not ready yet")
         };
-        let (updates_send, updates_recv) = tokio::sync::mpsc::channel(10);
         let (ready_send, ready_recv) = tokio::sync::oneshot::channel();
 
         let number = channels.create_channel(kind.clone());
+        let (channel, senders) = crate::new_channel(
+            number,
+            kind,
+            self.channel_ops_send.clone(),
+            self.consumed_send.clone(),
+            crate::DEFAULT_CHANNEL_UPDATES_BUFFER,
+        );
 
         self.channels.insert(
             number,
             ChannelState::Pending {
                 ready_send,
-                updates_send,
+                senders,
             },
         );
 
         PendingChannel {
             ready_recv,
-            channel: Channel {
-                number,
-                updates_recv,
-                ops_send: self.channel_ops_send.clone(),
-                kind,
-            },
+            channel,
         }
     }
 
@@ -408,7 +726,1622 @@ impl<S: AsyncRead + AsyncWrite> ServerConnection<S> {
         self.new_channels.pop_front()
     }
 
+    /// When data or a request was last sent or received on any channel, which pushes back the idle timeout.
+    pub fn last_channel_activity(&self) -> Instant {
+        self.last_channel_activity
+    }
+
     pub fn inner(&self) -> &cluelessh_protocol::ServerConnection {
         &self.proto
     }
 }
+
+/// Waits until `delay` and some random jitter have passed since `start` if authentication failed,
+/// so that failures take about the same time regardless of the reason.
+async fn delay_auth_failure(start: Instant, delay: Option<Duration>, result: &Result<bool>) {
+    let Some(delay) = delay else {
+        return;
+    };
+    if let Ok(true) = result {
+        return;
+    }
+
+    let mut random = [0; 4];
+    cluelessh_protocol::OsRng.fill_bytes(&mut random);
+    let jitter = delay.mul_f64(f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX) / 4.0);
+
+    tokio::time::sleep_until(start + delay + jitter).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use cluelessh_connection::{ChannelKind, ChannelOperationKind, ChannelRequest};
+    use cluelessh_keys::known_hosts::{HostKeyStatus, HostKeyStore, KnownHosts};
+    use cluelessh_keys::{private::PlaintextPrivateKey, public::PublicKey};
+    use cluelessh_protocol::auth::{CheckPublicKey, VerifySignature};
+    use cluelessh_protocol::{ChannelUpdateKind, SshStatus};
+    use eyre::Result;
+    use futures::future::BoxFuture;
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, DuplexStream},
+        task::JoinHandle,
+        time::Instant,
+    };
+
+    use super::{
+        delay_auth_failure, ClientAlive, ClientAliveAction, DisconnectReason, Error, ServerAuth,
+    };
+    use crate::client::{
+        disconnect_reason, ClientAuth, ClientConfig, ClientConnection, DisconnectError,
+        SignatureResult,
+    };
+    use crate::identity::{Identities, IdentitySource, PrivateKeys};
+    use crate::stream::ChannelStream;
+    use crate::Channel;
+
+    const DELAY: Duration = Duration::from_secs(2);
+
+    #[track_caller]
+    fn assert_delayed(elapsed: Duration) {
+        assert!(
+            DELAY <= elapsed && elapsed <= DELAY + DELAY / 4,
+            "unexpected delay: {elapsed:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn auth_failure_delayed() {
+        let start = Instant::now();
+        delay_auth_failure(start, Some(DELAY), &Ok(false)).await;
+        assert_delayed(start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn auth_failure_delay_independent_of_verification_time() {
+        let start = Instant::now();
+        // For example looking up an existing user's authorized_keys.
+        tokio::time::advance(Duration::from_millis(500)).await;
+        delay_auth_failure(start, Some(DELAY), &Ok(false)).await;
+        assert_delayed(start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn auth_success_not_delayed() {
+        let start = Instant::now();
+        delay_auth_failure(start, Some(DELAY), &Ok(true)).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    /// Connects a client with password authentication to a server running in a task.
+    async fn connect(
+        configure: impl FnOnce(&mut super::ServerConnection<DuplexStream>),
+    ) -> (
+        JoinHandle<Result<(), Error>>,
+        ClientConnection<DuplexStream>,
+    ) {
+        connect_with(
+            |_| {},
+            configure,
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Connects a client to a server with password authentication running in a task,
+    /// after adjusting the server's authentication.
+    async fn connect_with(
+        server_auth: impl FnOnce(&mut ServerAuth),
+        configure: impl FnOnce(&mut super::ServerConnection<DuplexStream>),
+        client_auth: ClientAuth,
+    ) -> Result<(
+        JoinHandle<Result<(), Error>>,
+        ClientConnection<DuplexStream>,
+    )> {
+        connect_serving(
+            server_auth,
+            configure,
+            client_auth,
+            ClientConfig::default(),
+            |_| {},
+        )
+        .await
+    }
+
+    /// Like [`connect_with`], but passes the channels opened by the client to `serve`.
+    async fn connect_serving(
+        server_auth: impl FnOnce(&mut ServerAuth),
+        configure: impl FnOnce(&mut super::ServerConnection<DuplexStream>),
+        client_auth: ClientAuth,
+        client_config: ClientConfig,
+        mut serve: impl FnMut(Channel) + Send + 'static,
+    ) -> Result<(
+        JoinHandle<Result<(), Error>>,
+        ClientConnection<DuplexStream>,
+    )> {
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let mut server = test_server(server_stream, server_auth);
+        configure(&mut server);
+        let server = tokio::spawn(async move {
+            loop {
+                server.progress().await?;
+                while let Some(channel) = server.next_new_channel() {
+                    serve(channel);
+                }
+            }
+        });
+
+        let client = ClientConnection::connect(client_stream, client_config, client_auth).await?;
+
+        Ok((server, client))
+    }
+
+    /// A server with a new host key that accepts any password, after adjusting its authentication.
+    fn test_server<S: AsyncRead + AsyncWrite>(
+        stream: S,
+        server_auth: impl FnOnce(&mut ServerAuth),
+    ) -> super::ServerConnection<S> {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            cluelessh_keys::KeyGenerationParams {
+                key_type: cluelessh_keys::KeyType::Ed25519,
+            },
+        );
+        let transport_config = cluelessh_transport::server::ServerConfig {
+            server_identification: b"SSH-2.0-TestServer\r\n".to_vec(),
+            host_keys: vec![host_key.private_key.public_key()],
+        };
+        let host_key = Arc::new(host_key);
+        let mut auth = ServerAuth {
+            verify_password: Some(Arc::new(|_| Box::pin(async { Ok(true) }))),
+            verify_signature: None,
+            check_pubkey: None,
+            keyboard_interactive: None,
+            do_key_exchange: Arc::new(move |params| {
+                let host_key = host_key.clone();
+                Box::pin(async move {
+                    cluelessh_transport::server::do_key_exchange(
+                        params,
+                        &host_key,
+                        &mut cluelessh_protocol::OsRng,
+                    )
+                    .map_err(|_| eyre::eyre!("key exchange failed"))
+                })
+            }),
+            auth_banner: None,
+            auth_failure_delay: None,
+        };
+        server_auth(&mut auth);
+
+        super::ServerConnection::new(
+            stream,
+            "127.0.0.1:22".parse().unwrap(),
+            auth,
+            transport_config,
+        )
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl CapturedLogs {
+        fn contains(&self, message: &str) -> bool {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).contains(message)
+        }
+    }
+
+    const CLIENT_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn client_alive_log_only() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // The client is never driven after connecting, so it doesn't answer keepalive requests.
+        let (server, _client) = connect(|server| {
+            server.set_client_alive(Some(ClientAlive {
+                interval: CLIENT_ALIVE_INTERVAL,
+                count_max: 3,
+                action: ClientAliveAction::Log,
+            }))
+        })
+        .await;
+
+        tokio::time::sleep(CLIENT_ALIVE_INTERVAL * 3).await;
+        assert!(!logs.contains("Client did not answer keepalive requests"));
+
+        tokio::time::sleep(CLIENT_ALIVE_INTERVAL * 3).await;
+        assert!(logs.contains("Client did not answer keepalive requests"));
+        assert!(!server.is_finished());
+    }
+
+    #[tokio::test]
+    async fn client_disconnect() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (server, mut client) = connect(|_| {}).await;
+        client
+            .disconnect(disconnect_reason::BY_APPLICATION, "see you later")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(Error::SshStatus(SshStatus::Disconnect))
+        ));
+        assert!(logs.contains("Client disconnecting"));
+        assert!(logs.contains("SSH_DISCONNECT_BY_APPLICATION"));
+        assert!(logs.contains("see you later"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_alive_disconnect() {
+        let start = Instant::now();
+        let (server, _client) = connect(|server| {
+            server.set_client_alive(Some(ClientAlive {
+                interval: CLIENT_ALIVE_INTERVAL,
+                count_max: 3,
+                action: ClientAliveAction::Disconnect,
+            }))
+        })
+        .await;
+
+        let result = server.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::SshStatus(SshStatus::PeerError(_)))
+        ));
+        assert!(start.elapsed() >= CLIENT_ALIVE_INTERVAL * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_disconnect() {
+        let logs = CapturedLogs::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let idle_timeout = Duration::from_secs(60);
+        let start = Instant::now();
+        let (server, mut client) = connect(|server| {
+            server.set_idle_timeout(Some(idle_timeout));
+            server.set_disconnect_message(
+                DisconnectReason::Idle,
+                "Disconnected after 1 minute of inactivity".to_owned(),
+            );
+        })
+        .await;
+
+        let result = server.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(Error::SshStatus(SshStatus::Disconnect))
+        ));
+        assert!(start.elapsed() >= idle_timeout);
+
+        let err = loop {
+            if let Err(err) = client.progress().await {
+                break err;
+            }
+        };
+        assert_eq!(
+            err.downcast_ref::<DisconnectError>(),
+            Some(&DisconnectError {
+                code: disconnect_reason::BY_APPLICATION,
+                description: "Disconnected after 1 minute of inactivity".to_owned(),
+            })
+        );
+        assert!(logs.contains("Server disconnecting"));
+        assert!(logs.contains(&format!(
+            "reason={}",
+            cluelessh_format::numbers::SSH_DISCONNECT_BY_APPLICATION
+        )));
+        assert!(logs.contains("description=Disconnected after 1 minute of inactivity"));
+    }
+
+    #[tokio::test]
+    async fn only_accepted_public_key_signed() {
+        let keys = (0..3)
+            .map(|_| {
+                Arc::new(PlaintextPrivateKey::generate(
+                    String::new(),
+                    cluelessh_keys::KeyGenerationParams {
+                        key_type: cluelessh_keys::KeyType::Ed25519,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        let accepted = keys[1].private_key.public_key();
+        let signed = Arc::new(Mutex::new(Vec::new()));
+
+        let (server, _client) = connect_with(
+            |auth| {
+                auth.verify_password = None;
+                let check_accepted = accepted.clone();
+                auth.check_pubkey = Some(Arc::new(move |check: CheckPublicKey| {
+                    let is_accepted = check.public_key == check_accepted;
+                    Box::pin(async move { Ok(is_accepted) })
+                }));
+                let verify_accepted = accepted.clone();
+                auth.verify_signature = Some(Arc::new(move |verify: VerifySignature| {
+                    let data = cluelessh_keys::signature::signature_data(
+                        verify.session_id.0,
+                        &verify.user,
+                        &verify.public_key,
+                    );
+                    let is_ok = verify.public_key == verify_accepted
+                        && verify.public_key.verify_signature(&data, &verify.signature);
+                    Box::pin(async move { Ok(is_ok) })
+                }));
+            },
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: true,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+                public_keys: keys
+                    .iter()
+                    .map(|key| key.private_key.public_key())
+                    .collect(),
+                sign_pubkey: Arc::new({
+                    let signed = signed.clone();
+                    move |session_id, public_key| {
+                        let key = keys
+                            .iter()
+                            .find(|key| key.private_key.public_key() == public_key)
+                            .unwrap()
+                            .clone();
+                        signed.lock().unwrap().push(public_key.clone());
+                        Box::pin(async move {
+                            let data = cluelessh_keys::signature::signature_data(
+                                session_id.0,
+                                "user",
+                                &public_key,
+                            );
+                            Ok(SignatureResult {
+                                key_alg_name: public_key.algorithm_name(),
+                                public_key: public_key.to_wire_encoding(),
+                                signature: key.private_key.sign(&data).to_wire_encoding(),
+                            })
+                        })
+                    }
+                }),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*signed.lock().unwrap(), [accepted]);
+        server.abort();
+    }
+
+    /// The keys the server is asked about when the first of three keys is accepted.
+    async fn queried_public_keys(client_config: ClientConfig) -> Vec<PublicKey> {
+        let keys = (0..3)
+            .map(|_| {
+                Arc::new(PlaintextPrivateKey::generate(
+                    String::new(),
+                    cluelessh_keys::KeyGenerationParams {
+                        key_type: cluelessh_keys::KeyType::Ed25519,
+                    },
+                ))
+            })
+            .collect::<Vec<_>>();
+        let accepted = keys[0].private_key.public_key();
+        let checked = Arc::new(Mutex::new(Vec::new()));
+
+        let (server, _client) = connect_serving(
+            |auth| {
+                auth.verify_password = None;
+                let checked = checked.clone();
+                let check_accepted = accepted.clone();
+                auth.check_pubkey = Some(Arc::new(move |check: CheckPublicKey| {
+                    checked.lock().unwrap().push(check.public_key.clone());
+                    let is_accepted = check.public_key == check_accepted;
+                    Box::pin(async move { Ok(is_accepted) })
+                }));
+                auth.verify_signature = Some(Arc::new(move |verify: VerifySignature| {
+                    let data = cluelessh_keys::signature::signature_data(
+                        verify.session_id.0,
+                        &verify.user,
+                        &verify.public_key,
+                    );
+                    let is_ok = verify.public_key.verify_signature(&data, &verify.signature);
+                    Box::pin(async move { Ok(is_ok) })
+                }));
+            },
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: true,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+                public_keys: keys
+                    .iter()
+                    .map(|key| key.private_key.public_key())
+                    .collect(),
+                sign_pubkey: Arc::new(move |session_id, public_key| {
+                    let key = keys[0].clone();
+                    Box::pin(async move {
+                        let data = cluelessh_keys::signature::signature_data(
+                            session_id.0,
+                            "user",
+                            &public_key,
+                        );
+                        Ok(SignatureResult {
+                            key_alg_name: public_key.algorithm_name(),
+                            public_key: public_key.to_wire_encoding(),
+                            signature: key.private_key.sign(&data).to_wire_encoding(),
+                        })
+                    })
+                }),
+            },
+            client_config,
+            |_| {},
+        )
+        .await
+        .unwrap();
+        server.abort();
+
+        let checked = checked.lock().unwrap().clone();
+        assert_eq!(checked[0], accepted);
+        checked
+    }
+
+    #[tokio::test]
+    async fn public_key_queries_configured() {
+        // By default, the other keys are queried ahead and wasted.
+        let checked = queried_public_keys(ClientConfig::default()).await;
+        assert_eq!(checked.len(), 3);
+
+        let checked = queried_public_keys(ClientConfig {
+            max_pipelined_queries: Some(1),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(checked.len(), 1);
+
+        // Another query could use up the last attempt the server allows.
+        let checked = queried_public_keys(ClientConfig {
+            max_auth_tries: Some(1),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(checked.len(), 1);
+    }
+
+    /// An SSH agent with a single key.
+    struct FakeAgent(PlaintextPrivateKey);
+
+    impl IdentitySource for FakeAgent {
+        fn name(&self) -> &str {
+            "fake agent"
+        }
+
+        fn public_keys(&self) -> BoxFuture<'_, Result<Vec<PublicKey>>> {
+            let keys = vec![self.0.private_key.public_key()];
+            Box::pin(async { Ok(keys) })
+        }
+
+        fn sign<'a>(
+            &'a self,
+            public_key: &'a PublicKey,
+            data: &'a [u8],
+        ) -> BoxFuture<'a, Result<Vec<u8>>> {
+            assert_eq!(*public_key, self.0.private_key.public_key());
+            let signature = self.0.private_key.sign(data).to_wire_encoding();
+            Box::pin(async { Ok(signature) })
+        }
+    }
+
+    #[tokio::test]
+    async fn identity_file_rejected_agent_key_accepted() {
+        let generate = || {
+            PlaintextPrivateKey::generate(
+                String::new(),
+                cluelessh_keys::KeyGenerationParams {
+                    key_type: cluelessh_keys::KeyType::Ed25519,
+                },
+            )
+        };
+        let file_key = generate();
+        let agent_key = generate();
+        let file_public_key = file_key.private_key.public_key();
+        let agent_public_key = agent_key.private_key.public_key();
+        let checked = Arc::new(Mutex::new(Vec::new()));
+
+        let identities = Identities::collect(vec![
+            Arc::new(PrivateKeys(vec![file_key])),
+            Arc::new(FakeAgent(agent_key)),
+        ])
+        .await;
+        assert_eq!(
+            identities.public_keys(),
+            [file_public_key.clone(), agent_public_key.clone()]
+        );
+
+        let (server, _client) = connect_with(
+            |auth| {
+                auth.verify_password = None;
+                let check_accepted = agent_public_key.clone();
+                let checked = checked.clone();
+                auth.check_pubkey = Some(Arc::new(move |check: CheckPublicKey| {
+                    checked.lock().unwrap().push(check.public_key.clone());
+                    let is_accepted = check.public_key == check_accepted;
+                    Box::pin(async move { Ok(is_accepted) })
+                }));
+                let verify_accepted = agent_public_key.clone();
+                auth.verify_signature = Some(Arc::new(move |verify: VerifySignature| {
+                    let data = cluelessh_keys::signature::signature_data(
+                        verify.session_id.0,
+                        &verify.user,
+                        &verify.public_key,
+                    );
+                    let is_ok = verify.public_key == verify_accepted
+                        && verify.public_key.verify_signature(&data, &verify.signature);
+                    Box::pin(async move { Ok(is_ok) })
+                }));
+            },
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: true,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { unreachable!() })),
+                public_keys: identities.public_keys(),
+                sign_pubkey: identities.sign_pubkey("user".to_owned()),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *checked.lock().unwrap(),
+            [file_public_key, agent_public_key.clone()]
+        );
+        server.abort();
+    }
+
+    /// Downloads `len` bytes from a shell of the server,
+    /// returning how many bytes were received and how many iterations the client main loop took.
+    async fn download(len: usize, client_config: ClientConfig) -> (usize, usize) {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            client_config,
+            move |mut channel| {
+                tokio::spawn(async move {
+                    let update = channel.next_update().await.unwrap();
+                    assert!(matches!(
+                        update,
+                        ChannelUpdateKind::Request(ChannelRequest::Shell { .. })
+                    ));
+                    for chunk in vec![7; len].chunks(32 * 1024) {
+                        channel
+                            .send(ChannelOperationKind::Data(chunk.to_vec()))
+                            .await
+                            .unwrap();
+                    }
+                    channel.send(ChannelOperationKind::Eof).await.unwrap();
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = client.open_channel(ChannelKind::Session);
+        let mut download = tokio::spawn(async move {
+            let mut received = 0;
+            let mut channel = channel.wait_ready().await.unwrap();
+            channel
+                .send(ChannelOperationKind::Request(ChannelRequest::Shell {
+                    want_reply: false,
+                }))
+                .await
+                .unwrap();
+            loop {
+                match channel.next_update().await.unwrap() {
+                    ChannelUpdateKind::Data { data } => {
+                        assert!(data.iter().all(|&b| b == 7));
+                        received += data.len();
+                    }
+                    ChannelUpdateKind::Eof => return received,
+                    _ => {}
+                }
+            }
+        });
+
+        let mut iterations = 0;
+        let received = loop {
+            tokio::select! {
+                result = client.progress() => {
+                    result.unwrap();
+                    iterations += 1;
+                }
+                received = &mut download => break received.unwrap(),
+            }
+        };
+        server.abort();
+        (received, iterations)
+    }
+
+    #[tokio::test]
+    async fn connect_via_channel() {
+        let password_auth = || ClientAuth {
+            username: "user".to_owned(),
+            batch_mode: false,
+            methods: None,
+            prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+            public_keys: vec![],
+            sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+        };
+
+        // The jump host forwards direct-tcpip channels to the target, a server that greets every shell.
+        let (jump_server, mut jump) = connect_serving(
+            |_| {},
+            |_| {},
+            password_auth(),
+            ClientConfig::default(),
+            |channel| {
+                let mut target = test_server(ChannelStream::new(channel), |_| {});
+                tokio::spawn(async move {
+                    loop {
+                        target.progress().await?;
+                        while let Some(mut channel) = target.next_new_channel() {
+                            tokio::spawn(async move {
+                                let update = channel.next_update().await.unwrap();
+                                assert!(matches!(
+                                    update,
+                                    ChannelUpdateKind::Request(ChannelRequest::Shell { .. })
+                                ));
+                                channel
+                                    .send(ChannelOperationKind::Data(b"hello".to_vec()))
+                                    .await
+                                    .unwrap();
+                            });
+                        }
+                    }
+                    #[allow(unreachable_code)]
+                    Ok::<_, Error>(())
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = jump.open_channel(ChannelKind::DirectTcpip {
+            host_to_connect: "target".to_owned(),
+            port_to_connect: 22,
+            originator_address: "127.0.0.1".to_owned(),
+            originator_port: 12345,
+        });
+        let jump_client = tokio::spawn(async move {
+            loop {
+                jump.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+        let channel = channel.wait_ready().await.unwrap();
+
+        let mut target =
+            ClientConnection::connect_via(channel, ClientConfig::default(), password_auth())
+                .await
+                .unwrap();
+        let session = target.open_channel(ChannelKind::Session);
+        let mut greeting = tokio::spawn(async move {
+            let mut session = session.wait_ready().await.unwrap();
+            session
+                .send(ChannelOperationKind::Request(ChannelRequest::Shell {
+                    want_reply: false,
+                }))
+                .await
+                .unwrap();
+            loop {
+                if let ChannelUpdateKind::Data { data } = session.next_update().await.unwrap() {
+                    return data;
+                }
+            }
+        });
+        let greeting = loop {
+            tokio::select! {
+                result = target.progress() => result.unwrap(),
+                greeting = &mut greeting => break greeting.unwrap(),
+            }
+        };
+        assert_eq!(greeting, b"hello");
+
+        jump_client.abort();
+        jump_server.abort();
+    }
+
+    #[tokio::test]
+    async fn direct_streamlocal() {
+        // The server answers on channels to a socket with the path of the socket.
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig::default(),
+            |channel| {
+                let ChannelKind::DirectStreamlocal { socket_path } = channel.kind().clone() else {
+                    panic!("unexpected channel: {:?}", channel.kind());
+                };
+                tokio::spawn(async move {
+                    channel
+                        .send(ChannelOperationKind::Data(socket_path.into_bytes()))
+                        .await
+                        .unwrap();
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = client.open_direct_streamlocal("/var/run/docker.sock");
+        let client = tokio::spawn(async move {
+            loop {
+                client.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+        let mut channel = channel.wait_ready().await.unwrap();
+        channel
+            .send(ChannelOperationKind::Data(b"GET /info".to_vec()))
+            .await
+            .unwrap();
+        let data = loop {
+            if let ChannelUpdateKind::Data { data } = channel.next_update().await.unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(data, b"/var/run/docker.sock");
+
+        client.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn shell_interactive() {
+        use tokio::io::AsyncReadExt;
+
+        // The server accepts the PTY and shell, and reports the window changes as output.
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig::default(),
+            |mut channel| {
+                tokio::spawn(async move {
+                    loop {
+                        match channel.next_update().await.unwrap() {
+                            ChannelUpdateKind::Request(ChannelRequest::PtyReq { .. })
+                            | ChannelUpdateKind::Request(ChannelRequest::Shell { .. }) => {
+                                channel.send(ChannelOperationKind::Success).await.unwrap();
+                            }
+                            ChannelUpdateKind::Request(ChannelRequest::WindowChange {
+                                width_chars,
+                                height_rows,
+                                ..
+                            }) => {
+                                let output = format!("{width_chars}x{height_rows}\n");
+                                channel
+                                    .send(ChannelOperationKind::Data(output.into_bytes()))
+                                    .await
+                                    .unwrap();
+                                if width_chars == 100 {
+                                    channel.send(ChannelOperationKind::Eof).await.unwrap();
+                                    return;
+                                }
+                            }
+                            update => panic!("unexpected update: {update:?}"),
+                        }
+                    }
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let shell = client.shell_interactive();
+        let client = tokio::spawn(async move {
+            loop {
+                client.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+        let mut shell = shell.await.unwrap();
+        assert!(shell.has_pty);
+
+        shell
+            .window_changes
+            .send(crate::WindowSize {
+                width_chars: 100,
+                height_rows: 30,
+                width_px: 0,
+                height_px: 0,
+            })
+            .await
+            .unwrap();
+        let mut output = String::new();
+        shell.stream.read_to_string(&mut output).await.unwrap();
+        // The size of the local terminal is sent right after starting the shell.
+        let local = crate::WindowSize::of_local_terminal();
+        assert_eq!(
+            output,
+            format!("{}x{}\n100x30\n", local.width_chars, local.height_rows)
+        );
+
+        client.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn shell_interactive_refused() {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig::default(),
+            |mut channel| {
+                tokio::spawn(async move {
+                    loop {
+                        let reply = match channel.next_update().await.unwrap() {
+                            ChannelUpdateKind::Request(ChannelRequest::PtyReq { .. }) => {
+                                ChannelOperationKind::Success
+                            }
+                            ChannelUpdateKind::Request(ChannelRequest::Shell { .. }) => {
+                                ChannelOperationKind::Failure
+                            }
+                            _ => continue,
+                        };
+                        channel.send(reply).await.unwrap();
+                    }
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let shell = client.shell_interactive();
+        let client = tokio::spawn(async move {
+            loop {
+                client.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+        let err = shell.await.err().unwrap();
+        assert_eq!(err.to_string(), "server refused to start the shell");
+
+        client.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn forward_agent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // An agent that answers each request with the request itself.
+        let socket = std::env::temp_dir().join(format!(
+            "cluelessh-forward-agent-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let agent = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            conn.read_to_end(&mut request).await.unwrap();
+            conn.write_all(&request).await.unwrap();
+        });
+
+        // The server accepts the agent forwarding request of the session and then opens an agent channel.
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let mut server = test_server(server_stream, |_| {});
+        let (forwarding_send, mut forwarding_recv) = tokio::sync::mpsc::unbounded_channel();
+        let (agent_channel_send, mut agent_channel_recv) = tokio::sync::mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            loop {
+                server.progress().await?;
+                while let Some(mut channel) = server.next_new_channel() {
+                    let forwarding_send = forwarding_send.clone();
+                    tokio::spawn(async move {
+                        let update = channel.next_update().await.unwrap();
+                        assert!(matches!(
+                            update,
+                            ChannelUpdateKind::Request(ChannelRequest::AuthAgentReq {
+                                want_reply: true
+                            })
+                        ));
+                        forwarding_send.send(()).unwrap();
+                        channel.send(ChannelOperationKind::Success).await.unwrap();
+                    });
+                }
+                if forwarding_recv.try_recv().is_ok() {
+                    agent_channel_send
+                        .send(server.open_channel(ChannelKind::AuthAgent))
+                        .unwrap();
+                }
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, Error>(())
+        });
+
+        let mut client = ClientConnection::connect(
+            client_stream,
+            ClientConfig {
+                forward_agent: Some(socket.clone()),
+                ..Default::default()
+            },
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+        )
+        .await
+        .unwrap();
+        let session = client.open_channel(ChannelKind::Session);
+        let client = tokio::spawn(async move {
+            loop {
+                client.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+
+        let mut session = session.wait_ready().await.unwrap();
+        assert!(session.request_agent_forwarding().await.unwrap());
+
+        let agent_channel = agent_channel_recv.recv().await.unwrap();
+        let mut agent_stream = ChannelStream::new(agent_channel.wait_ready().await.unwrap());
+        agent_stream.write_all(b"request").await.unwrap();
+        agent_stream.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        agent_stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"request");
+
+        agent.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+        client.abort();
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn download_larger_than_window() {
+        // More than the initial window of 2 MiB, so the client has to adjust the window for the server to finish.
+        const LEN: usize = 3 * 1024 * 1024;
+
+        let (received, _) = download(
+            LEN,
+            ClientConfig {
+                window_adjust_threshold: Some(64 * 1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(received, LEN);
+    }
+
+    #[tokio::test]
+    async fn download_small_window() {
+        // The window is given back as the data is consumed from the channel.
+        const LEN: usize = 1024 * 1024;
+
+        let (received, _) = download(
+            LEN,
+            ClientConfig {
+                initial_window_size: Some(64 * 1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(received, LEN);
+    }
+
+    #[tokio::test]
+    async fn larger_read_buffer_fewer_iterations() {
+        const LEN: usize = 1024 * 1024;
+
+        let (received, small_iterations) = download(
+            LEN,
+            ClientConfig {
+                read_buffer_size: Some(1024),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(received, LEN);
+        // Every read is at most 1 KiB.
+        assert!(small_iterations > LEN / 1024, "{small_iterations}");
+
+        let (received, default_iterations) = download(LEN, ClientConfig::default()).await;
+        assert_eq!(received, LEN);
+        assert!(
+            default_iterations * 4 < small_iterations,
+            "{default_iterations} iterations with the default buffer, {small_iterations} with 1 KiB"
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_log_channel_allowed_for_user() {
+        for (allowed_user, opened) in [("user", true), ("root", false)] {
+            let (server, mut client) = connect(move |server| {
+                server.set_debug_log_channel(Box::new(move |user| user == allowed_user))
+            })
+            .await;
+            let channel = client.open_channel(ChannelKind::DebugLog);
+            let client = tokio::spawn(async move {
+                loop {
+                    client.progress().await?;
+                }
+                #[allow(unreachable_code)]
+                eyre::Ok(())
+            });
+
+            let result = channel.wait_ready().await;
+            match result {
+                Ok(_) => assert!(opened, "opened debug log channel of {allowed_user}"),
+                Err(err) => {
+                    assert!(!opened, "refused debug log channel of {allowed_user}");
+                    assert_eq!(err.as_deref(), Some("debug log channel is not allowed"));
+                }
+            }
+            client.abort();
+            server.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn client_rekey_limits() {
+        const LEN: usize = 1024 * 1024;
+
+        let kexinits = Arc::new(Mutex::new(0));
+        let capture_kexinits = kexinits.clone();
+        let (received, _) = download(
+            LEN,
+            ClientConfig {
+                rekey_limits: cluelessh_transport::client::RekeyLimits {
+                    bytes: 256 * 1024,
+                    ..Default::default()
+                },
+                packet_capture: Some(Box::new(move |direction, payload| {
+                    if direction == cluelessh_transport::packet::PacketDirection::Outbound
+                        && payload.first() == Some(&cluelessh_format::numbers::SSH_MSG_KEXINIT)
+                    {
+                        *capture_kexinits.lock().unwrap() += 1;
+                    }
+                })),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(received, LEN);
+        // The initial key exchange, and re-exchanges during the transfer, which the default limits don't need.
+        let kexinits = *kexinits.lock().unwrap();
+        assert!(kexinits > 1, "{kexinits} key exchanges");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn channel_drained_after_disconnect() {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |server| server.set_idle_timeout(Some(Duration::from_secs(60))),
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig::default(),
+            |mut channel| {
+                tokio::spawn(async move {
+                    let update = channel.next_update().await.unwrap();
+                    assert!(matches!(
+                        update,
+                        ChannelUpdateKind::Request(ChannelRequest::Shell { .. })
+                    ));
+                    for data in [b"hello", b"world"] {
+                        channel
+                            .send(ChannelOperationKind::Data(data.to_vec()))
+                            .await
+                            .unwrap();
+                    }
+                    channel.send(ChannelOperationKind::Eof).await.unwrap();
+                    // The server disconnects after the idle timeout, while the channel is still open.
+                    let _ = channel.next_update().await;
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = client.open_channel(ChannelKind::Session);
+        let mut ready = tokio::spawn(channel.wait_ready());
+        let mut channel = loop {
+            tokio::select! {
+                result = client.progress() => result.unwrap(),
+                channel = &mut ready => break channel.unwrap().unwrap(),
+            }
+        };
+        channel
+            .send(ChannelOperationKind::Request(ChannelRequest::Shell {
+                want_reply: false,
+            }))
+            .await
+            .unwrap();
+
+        // The channel is not read until the connection has been closed.
+        while client.progress().await.is_ok() {}
+        assert!(server.await.unwrap().is_err());
+
+        let mut received = Vec::new();
+        loop {
+            match channel.next_update().await.unwrap() {
+                ChannelUpdateKind::Data { data } => received.extend(data),
+                ChannelUpdateKind::Eof => break,
+                update => panic!("unexpected update: {update:?}"),
+            }
+        }
+        assert_eq!(received, b"helloworld");
+        // After the buffered updates, the channel is closed together with the connection,
+        // even though the client has not been dropped yet.
+        assert!(channel.next_update().await.is_err());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn channel_numbers() {
+        let (server, mut client) = connect(|_| {}).await;
+
+        let first = client.open_channel(ChannelKind::Session);
+        let second = client.open_channel(ChannelKind::Session);
+        let numbers = (first.number(), second.number());
+        assert_ne!(numbers.0, numbers.1);
+
+        let mut ready = tokio::spawn(async move {
+            let first = first.wait_ready().await.unwrap();
+            let second = second.wait_ready().await.unwrap();
+            (first.number(), second.number())
+        });
+        let ready = loop {
+            tokio::select! {
+                result = client.progress() => result.unwrap(),
+                ready = &mut ready => break ready.unwrap(),
+            }
+        };
+        assert_eq!(ready, numbers);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn closed_channel_removed() {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig::default(),
+            |mut channel| {
+                tokio::spawn(async move {
+                    let update = channel.next_update().await.unwrap();
+                    assert!(matches!(
+                        update,
+                        ChannelUpdateKind::Request(ChannelRequest::Shell { .. })
+                    ));
+                    channel.send(ChannelOperationKind::Close).await.unwrap();
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = client.open_channel(ChannelKind::Session);
+        let mut closed = tokio::spawn(async move {
+            let mut channel = channel.wait_ready().await.unwrap();
+            channel
+                .send(ChannelOperationKind::Request(ChannelRequest::Shell {
+                    want_reply: false,
+                }))
+                .await
+                .unwrap();
+            assert!(matches!(
+                channel.next_update().await.unwrap(),
+                ChannelUpdateKind::Closed
+            ));
+            // The connection doesn't keep the channel around after the close.
+            channel.next_update().await.is_err()
+        });
+        let closed = loop {
+            tokio::select! {
+                result = client.progress() => result.unwrap(),
+                closed = &mut closed => break closed.unwrap(),
+            }
+        };
+        assert!(closed);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn too_many_open_failures() {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig {
+                max_consecutive_open_failures: Some(2),
+                ..Default::default()
+            },
+            |_| {},
+        )
+        .await
+        .unwrap();
+
+        // The server doesn't accept agent channels, so it refuses every open.
+        let first = client.open_channel(ChannelKind::AuthAgent);
+        let second = client.open_channel(ChannelKind::AuthAgent);
+        let err = loop {
+            if let Err(err) = client.progress().await {
+                break err;
+            }
+        };
+        assert_eq!(err.to_string(), "server refused 2 channel opens in a row");
+        assert!(first.wait_ready().await.is_err());
+        assert!(second.wait_ready().await.is_err());
+
+        // The client disconnected.
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn channel_updates_buffer() {
+        let (server, mut client) = connect_serving(
+            |_| {},
+            |_| {},
+            ClientAuth {
+                username: "user".to_owned(),
+                batch_mode: false,
+                methods: None,
+                prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                public_keys: vec![],
+                sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+            },
+            ClientConfig {
+                channel_updates_buffer: Some(64),
+                ..Default::default()
+            },
+            |mut channel| {
+                tokio::spawn(async move {
+                    let update = channel.next_update().await.unwrap();
+                    assert!(matches!(
+                        update,
+                        ChannelUpdateKind::Request(ChannelRequest::Shell { .. })
+                    ));
+                    for i in 0..50_u8 {
+                        channel
+                            .send(ChannelOperationKind::Data(vec![i]))
+                            .await
+                            .unwrap();
+                    }
+                });
+            },
+        )
+        .await
+        .unwrap();
+
+        let channel = client.open_channel(ChannelKind::Session);
+        let mut ready = tokio::spawn(channel.wait_ready());
+        let mut channel = loop {
+            tokio::select! {
+                result = client.progress() => result.unwrap(),
+                channel = &mut ready => break channel.unwrap().unwrap(),
+            }
+        };
+        channel
+            .send(ChannelOperationKind::Request(ChannelRequest::Shell {
+                want_reply: false,
+            }))
+            .await
+            .unwrap();
+
+        let client = tokio::spawn(async move {
+            loop {
+                client.progress().await?;
+            }
+            #[allow(unreachable_code)]
+            eyre::Ok(())
+        });
+        // All updates are queued for the channel without it being read, which would block
+        // the connection with the default buffer.
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while channel.updates_recv.len() < 50 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        for i in 0..50_u8 {
+            assert!(matches!(
+                channel.next_update().await.unwrap(),
+                ChannelUpdateKind::Data { data } if data == [i]
+            ));
+        }
+
+        client.abort();
+        server.abort();
+    }
+
+    /// A store that can still be looked at after the client has taken it.
+    #[derive(Clone)]
+    struct SharedHostKeyStore(Arc<Mutex<KnownHosts>>);
+
+    impl HostKeyStore for SharedHostKeyStore {
+        fn lookup(&mut self, host_name: &str, key: &PublicKey) -> io::Result<HostKeyStatus> {
+            self.0.lock().unwrap().lookup(host_name, key)
+        }
+
+        fn add(&mut self, host_name: &str, key: &PublicKey) -> io::Result<()> {
+            HostKeyStore::add(&mut *self.0.lock().unwrap(), host_name, key)
+        }
+
+        fn mark_revoked(&mut self, key: &PublicKey) -> io::Result<()> {
+            self.0.lock().unwrap().mark_revoked(key)
+        }
+    }
+
+    #[tokio::test]
+    async fn host_key_store() {
+        let store = SharedHostKeyStore(Arc::new(Mutex::new(KnownHosts::parse(""))));
+        let connect = || {
+            connect_serving(
+                |_| {},
+                |_| {},
+                ClientAuth {
+                    username: "user".to_owned(),
+                    batch_mode: false,
+                    methods: None,
+                    prompt_password: Arc::new(|| Box::pin(async { Ok("password".to_owned()) })),
+                    public_keys: vec![],
+                    sign_pubkey: Arc::new(|_, _| Box::pin(async { Err(eyre::eyre!("no keys")) })),
+                },
+                ClientConfig {
+                    host_key_store: Some(("[test]:2222".to_owned(), Box::new(store.clone()))),
+                    ..Default::default()
+                },
+                |_| {},
+            )
+        };
+
+        // The key of a new host is recorded.
+        let (server, client) = connect().await.unwrap();
+        let host_key = PublicKey::from_wire_encoding(&client.connection_info().host_key).unwrap();
+        assert_eq!(
+            store.0.lock().unwrap().status("[test]:2222", &host_key),
+            HostKeyStatus::Known
+        );
+        server.abort();
+
+        // Every test server has a new host key.
+        let Err(err) = connect().await else {
+            panic!("connected to a server with a changed host key");
+        };
+        assert!(err.to_string().contains("host key has changed"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn client_connection_info() {
+        let (server, client) = connect(|_| {}).await;
+
+        assert_eq!(client.server_identification(), b"SSH-2.0-TestServer");
+        let info = client.connection_info();
+        assert_eq!(info.server_identification, "SSH-2.0-TestServer");
+        assert_eq!(info.kex_algorithm, "curve25519-sha256");
+        assert_eq!(info.host_key_algorithm, "ssh-ed25519");
+        assert_eq!(
+            info.encryption_client_to_server,
+            "chacha20-poly1305@openssh.com"
+        );
+        assert_eq!(info.compression_server_to_client, "none");
+
+        server.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect() {
+        use crate::reconnect::{Backoff, ConnectionEvent, ReconnectingClient};
+
+        // The first attempt fails, the others connect to a new server each.
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(Mutex::new(0));
+        let connect = {
+            let servers = servers.clone();
+            let attempts = attempts.clone();
+            move || {
+                let servers = servers.clone();
+                let attempts = attempts.clone();
+                Box::pin(async move {
+                    let attempt = {
+                        let mut attempts = attempts.lock().unwrap();
+                        *attempts += 1;
+                        *attempts
+                    };
+                    if attempt == 1 {
+                        eyre::bail!("server is not up yet");
+                    }
+                    let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+                    let mut server = test_server(server_stream, |_| {});
+                    servers.lock().unwrap().push(tokio::spawn(async move {
+                        loop {
+                            server.progress().await?;
+                        }
+                        #[allow(unreachable_code)]
+                        Ok::<_, Error>(())
+                    }));
+                    ClientConnection::connect(
+                        client_stream,
+                        ClientConfig::default(),
+                        ClientAuth {
+                            username: "user".to_owned(),
+                            batch_mode: false,
+                            methods: None,
+                            prompt_password: Arc::new(|| {
+                                Box::pin(async { Ok("password".to_owned()) })
+                            }),
+                            public_keys: vec![],
+                            sign_pubkey: Arc::new(|_, _| {
+                                Box::pin(async { Err(eyre::eyre!("no keys")) })
+                            }),
+                        },
+                    )
+                    .await
+                }) as BoxFuture<'static, _>
+            }
+        };
+        let backoff = Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: Some(3),
+        };
+        let mut client = ReconnectingClient::new(connect, backoff, vec![ChannelKind::Session]);
+
+        let mut next_event = async || loop {
+            client.progress().await.unwrap();
+            if let Some(event) = client.next_event() {
+                return (event, Instant::now());
+            }
+        };
+
+        let (event, failed_at) = next_event().await;
+        let ConnectionEvent::Down { retry_in, .. } = event else {
+            panic!("first attempt did not fail");
+        };
+        assert_eq!(retry_in, Duration::from_secs(2));
+
+        let (event, up_at) = next_event().await;
+        let ConnectionEvent::Up { channels } = event else {
+            panic!("second attempt did not connect");
+        };
+        assert_eq!(up_at - failed_at, Duration::from_secs(2));
+        assert_eq!(channels.len(), 1);
+
+        servers.lock().unwrap()[0].abort();
+        let (event, down_at) = next_event().await;
+        let ConnectionEvent::Down { retry_in, .. } = event else {
+            panic!("connection did not go down");
+        };
+        assert_eq!(retry_in, Duration::from_secs(1));
+
+        // The channels are opened again on the new connection.
+        let (event, up_at) = next_event().await;
+        let ConnectionEvent::Up { channels } = event else {
+            panic!("did not reconnect");
+        };
+        assert_eq!(up_at - down_at, Duration::from_secs(1));
+        assert_eq!(*attempts.lock().unwrap(), 3);
+
+        let mut session = tokio::spawn(channels.into_iter().next().unwrap().wait_ready());
+        let session = loop {
+            tokio::select! {
+                result = client.progress() => result.unwrap(),
+                session = &mut session => break session.unwrap(),
+            }
+        };
+        assert!(session.is_ok());
+        servers.lock().unwrap()[1].abort();
+    }
+}
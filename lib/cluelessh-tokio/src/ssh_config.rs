@@ -0,0 +1,610 @@
+//! Client configuration in the format of OpenSSH's `~/.ssh/config`.
+//!
+//! Only the options relevant for connecting are supported, other options are ignored.
+//! `Match` blocks with criteria other than `all`, `host` and `originalhost` never apply.
+//! Like OpenSSH, the first obtained value of an option is used, except for `IdentityFile`,
+//! of which all values are collected.
+
+use std::{net::IpAddr, path::Path};
+
+use eyre::{bail, Context, OptionExt, Result};
+use tracing::{debug, warn};
+
+/// A parsed `ssh_config` file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    blocks: Vec<Block>,
+}
+
+/// The options that apply to a host, see [`Config::lookup`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    /// The real host name to connect to.
+    pub host_name: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    /// The identity files in the order they were specified.
+    pub identity_files: Vec<String>,
+    /// The jump hosts to connect through, like `user@host:port,host2`.
+    pub proxy_jump: Option<String>,
+    /// The command whose stdin and stdout are used instead of a TCP connection,
+    /// before [`HostConfig::expand`]. `none` from the config is not included.
+    pub proxy_command: Option<String>,
+    /// The local address to connect from.
+    pub bind_address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Block {
+    condition: Condition,
+    options: Vec<HostOption>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    /// `Host` patterns, matched against the name given by the user.
+    Host(Vec<Pattern>),
+    /// `Match` criteria, which all have to match.
+    Match(Vec<Criterion>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Criterion {
+    All,
+    /// Matched against the `HostName` if one has been obtained already.
+    Host(Vec<Pattern>),
+    OriginalHost(Vec<Pattern>),
+    /// A criterion like `exec` or `user`, which never matches, so its block is skipped.
+    Unsupported(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    negated: bool,
+    glob: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostOption {
+    HostName(String),
+    Port(u16),
+    User(String),
+    IdentityFile(String),
+    ProxyJump(String),
+    ProxyCommand(String),
+    BindAddress(IpAddr),
+}
+
+impl Config {
+    /// Loads `~/.ssh/config`, returning an empty config if it does not exist.
+    pub fn load() -> Result<Self> {
+        let Some(home) = std::env::var_os("HOME") else {
+            return Ok(Self::default());
+        };
+        Self::load_file(&Path::new(&home).join(".ssh/config"))
+    }
+
+    /// Loads a config file, returning an empty config if it does not exist.
+    pub fn load_file(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                Self::parse(&content).wrap_err_with(|| format!("invalid {}", path.display()))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).wrap_err_with(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        // Options before the first `Host` or `Match` apply to all hosts.
+        let mut blocks = vec![Block {
+            condition: Condition::Match(vec![Criterion::All]),
+            options: Vec::new(),
+        }];
+
+        for (i, line) in content.lines().enumerate() {
+            let line_number = i + 1;
+            let args = split_line(line).wrap_err_with(|| format!("line {line_number}"))?;
+            let Some((keyword, args)) = args.split_first() else {
+                continue;
+            };
+
+            let single_arg = || -> Result<String> {
+                match args {
+                    [arg] => Ok(arg.clone()),
+                    _ => bail!("line {line_number}: {keyword} takes exactly one argument"),
+                }
+            };
+
+            let option = match keyword.to_ascii_lowercase().as_str() {
+                "host" => {
+                    if args.is_empty() {
+                        bail!("line {line_number}: Host requires at least one pattern");
+                    }
+                    let patterns = args.iter().map(|arg| Pattern::parse(arg)).collect();
+                    blocks.push(Block {
+                        condition: Condition::Host(patterns),
+                        options: Vec::new(),
+                    });
+                    continue;
+                }
+                "match" => {
+                    let criteria = parse_match(args, line_number)
+                        .wrap_err_with(|| format!("line {line_number}: invalid Match"))?;
+                    blocks.push(Block {
+                        condition: Condition::Match(criteria),
+                        options: Vec::new(),
+                    });
+                    continue;
+                }
+                "hostname" => HostOption::HostName(single_arg()?),
+                "port" => HostOption::Port(
+                    single_arg()?
+                        .parse()
+                        .wrap_err_with(|| format!("line {line_number}: invalid Port"))?,
+                ),
+                "user" => HostOption::User(single_arg()?),
+                "identityfile" => HostOption::IdentityFile(single_arg()?),
+                "proxyjump" => HostOption::ProxyJump(single_arg()?),
+                "bindaddress" => HostOption::BindAddress(
+                    single_arg()?
+                        .parse()
+                        .wrap_err_with(|| format!("line {line_number}: invalid BindAddress"))?,
+                ),
+                // The command is the rest of the line, without removing quotes or comments.
+                "proxycommand" => {
+                    let rest = line.trim_start()[keyword.len()..].trim_start();
+                    let command = rest.strip_prefix('=').unwrap_or(rest).trim();
+                    if command.is_empty() {
+                        bail!("line {line_number}: ProxyCommand requires a command");
+                    }
+                    HostOption::ProxyCommand(command.to_owned())
+                }
+                "include" => {
+                    debug!(line_number, "Ignoring unsupported Include in ssh_config");
+                    continue;
+                }
+                _ => {
+                    debug!(%keyword, line_number, "Ignoring unsupported option in ssh_config");
+                    continue;
+                }
+            };
+            blocks
+                .last_mut()
+                .expect("there is always a block")
+                .options
+                .push(option);
+        }
+
+        Ok(Self { blocks })
+    }
+
+    /// The options for connecting to `host`, the name given by the user.
+    pub fn lookup(&self, host: &str) -> HostConfig {
+        let mut config = HostConfig::default();
+        for block in &self.blocks {
+            if !block.condition.matches(host, &config) {
+                continue;
+            }
+            for option in &block.options {
+                match option {
+                    HostOption::HostName(host_name) => {
+                        config.host_name.get_or_insert_with(|| host_name.clone());
+                    }
+                    HostOption::Port(port) => {
+                        config.port.get_or_insert(*port);
+                    }
+                    HostOption::User(user) => {
+                        config.user.get_or_insert_with(|| user.clone());
+                    }
+                    HostOption::IdentityFile(path) => config.identity_files.push(path.clone()),
+                    HostOption::ProxyJump(proxy_jump) => {
+                        config.proxy_jump.get_or_insert_with(|| proxy_jump.clone());
+                    }
+                    HostOption::ProxyCommand(command) => {
+                        config.proxy_command.get_or_insert_with(|| command.clone());
+                    }
+                    HostOption::BindAddress(address) => {
+                        config.bind_address.get_or_insert(*address);
+                    }
+                }
+            }
+        }
+        config.proxy_command = config
+            .proxy_command
+            .filter(|command| !command.eq_ignore_ascii_case("none"));
+        config
+    }
+}
+
+impl HostConfig {
+    /// Expands a leading `~` to the home directory and the percent tokens of `value`,
+    /// like the ones of `IdentityFile`.
+    ///
+    /// The supported tokens are `%h` (host name), `%n` (the original host name),
+    /// `%p` (port), `%r` (remote user), `%d` (home directory) and `%%`.
+    /// `original_host` is the name given by the user, which is also used if there is no `HostName`.
+    /// The port defaults to 22, `%r` requires the user to be set.
+    ///
+    /// Host and user names are rejected if they contain shell metacharacters, whitespace
+    /// or control characters, or start with a `-`, as they may come from untrusted sources
+    /// like links and the expanded value may be run by a shell, like the `ProxyCommand`.
+    pub fn expand(&self, value: &str, original_host: &str) -> Result<String> {
+        let home = || -> Result<String> {
+            std::env::var("HOME").wrap_err("$HOME is not set or invalid UTF-8")
+        };
+
+        let mut expanded = String::new();
+        let rest = if value == "~" || value.starts_with("~/") {
+            expanded.push_str(&home()?);
+            &value[1..]
+        } else if value.starts_with('~') {
+            bail!("expanding the home directory of other users is not supported: {value}");
+        } else {
+            value
+        };
+
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push('%'),
+                Some('h') => expanded.push_str(checked_name(
+                    self.host_name.as_deref().unwrap_or(original_host),
+                )?),
+                Some('n') => expanded.push_str(checked_name(original_host)?),
+                Some('p') => expanded.push_str(&self.port.unwrap_or(22).to_string()),
+                Some('r') => expanded.push_str(checked_name(
+                    self.user.as_deref().ok_or_eyre("unknown user for %r")?,
+                )?),
+                Some('d') => expanded.push_str(&home()?),
+                Some(token) => bail!("unsupported token %{token} in {value}"),
+                None => bail!("incomplete token at the end of {value}"),
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// Characters with a special meaning for the shell, which are never part of host or user names.
+const SHELL_METACHARACTERS: &str = "'`\"$\\;&<>|(){}[]*?~#!";
+
+/// Checks a host or user name before it is expanded into a value that may be run by a shell.
+fn checked_name(name: &str) -> Result<&str> {
+    let invalid = |c: char| c.is_whitespace() || c.is_control() || SHELL_METACHARACTERS.contains(c);
+    if name.starts_with('-') || name.contains(invalid) {
+        bail!("refusing to expand {name:?}, it contains invalid characters");
+    }
+    Ok(name)
+}
+
+impl Condition {
+    fn matches(&self, host: &str, config: &HostConfig) -> bool {
+        match self {
+            Self::Host(patterns) => Pattern::list_matches(patterns, host),
+            Self::Match(criteria) => criteria.iter().all(|criterion| match criterion {
+                Criterion::All => true,
+                Criterion::Host(patterns) => {
+                    let host_name = config.host_name.as_deref().unwrap_or(host);
+                    Pattern::list_matches(patterns, host_name)
+                }
+                Criterion::OriginalHost(patterns) => Pattern::list_matches(patterns, host),
+                Criterion::Unsupported(_) => false,
+            }),
+        }
+    }
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        match pattern.strip_prefix('!') {
+            Some(glob) => Self {
+                negated: true,
+                glob: glob.to_owned(),
+            },
+            None => Self {
+                negated: false,
+                glob: pattern.to_owned(),
+            },
+        }
+    }
+
+    /// A list matches if any pattern matches and no negated pattern matches.
+    fn list_matches(patterns: &[Self], host: &str) -> bool {
+        let mut matched = false;
+        for pattern in patterns {
+            if glob_matches(&pattern.glob, host) {
+                if pattern.negated {
+                    return false;
+                }
+                matched = true;
+            }
+        }
+        matched
+    }
+}
+
+fn parse_match(args: &[String], line_number: usize) -> Result<Vec<Criterion>> {
+    let mut criteria = Vec::new();
+    let mut args = args.iter();
+    while let Some(criterion) = args.next() {
+        let mut patterns = || -> Result<Vec<Pattern>> {
+            let list = args
+                .next()
+                .ok_or_eyre(format!("{criterion} requires an argument"))?;
+            Ok(list.split(',').map(Pattern::parse).collect())
+        };
+        let criterion = match criterion.to_ascii_lowercase().as_str() {
+            "all" => Criterion::All,
+            "host" => Criterion::Host(patterns()?),
+            "originalhost" => Criterion::OriginalHost(patterns()?),
+            name @ ("canonical" | "final") => Criterion::Unsupported(name.to_owned()),
+            name => {
+                patterns()?;
+                Criterion::Unsupported(name.to_owned())
+            }
+        };
+        if let Criterion::Unsupported(name) = &criterion {
+            warn!(
+                criterion = %name,
+                line_number,
+                "Skipping Match block with unsupported criterion in ssh_config"
+            );
+        }
+        criteria.push(criterion);
+    }
+    if criteria.is_empty() {
+        bail!("missing criteria");
+    }
+    Ok(criteria)
+}
+
+/// Splits a line into keyword and arguments, handling `Keyword=value`, quotes and comments.
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return Ok(Vec::new());
+    }
+
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    let mut is_keyword = true;
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        // The keyword may be separated from its arguments by a single `=`.
+        if !is_keyword && args.len() == 1 && chars.next_if_eq(&'=').is_some() {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        }
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        if first == '#' {
+            break;
+        }
+
+        let mut arg = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => arg.push(c),
+                    None => bail!("unterminated quote"),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| !(c.is_whitespace() || is_keyword && c == '=')) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+        is_keyword = false;
+    }
+    Ok(args)
+}
+
+/// Matches a host against a pattern with `*` and `?` wildcards, ignoring case.
+fn glob_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase().into_bytes();
+    let host = host.to_ascii_lowercase().into_bytes();
+
+    let (mut p, mut h) = (0, 0);
+    // The position after the last `*` and the host position it is currently matched up to.
+    let mut backtrack = None;
+    while h < host.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, h));
+            }
+            Some(&c) if c == b'?' || c == host[h] => {
+                p += 1;
+                h += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_h)) => {
+                    p = star_p;
+                    h = star_h + 1;
+                    backtrack = Some((star_p, star_h + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, Config, HostConfig};
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("*", "example.com"));
+        assert!(glob_matches("*.example.com", "a.EXAMPLE.com"));
+        assert!(!glob_matches("*.example.com", "example.com"));
+        assert!(glob_matches("host?", "host1"));
+        assert!(!glob_matches("host?", "host12"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn lookup() {
+        let config = Config::parse(
+            r#"
+# Global defaults go first.
+User default
+
+Host web !db.internal *.internal
+    HostName=10.0.0.1
+    Port 2222
+    IdentityFile "~/.ssh/id ed25519"
+
+Host *
+    User fallback
+    IdentityFile ~/.ssh/id_rsa
+    ProxyJump bastion # comment
+    BindAddress 192.0.2.1
+    ProxyCommand nc -X connect -x "proxy:8080" %h %p
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.lookup("web"),
+            HostConfig {
+                host_name: Some("10.0.0.1".to_owned()),
+                port: Some(2222),
+                user: Some("default".to_owned()),
+                identity_files: vec!["~/.ssh/id ed25519".to_owned(), "~/.ssh/id_rsa".to_owned()],
+                proxy_jump: Some("bastion".to_owned()),
+                proxy_command: Some(r#"nc -X connect -x "proxy:8080" %h %p"#.to_owned()),
+                bind_address: Some("192.0.2.1".parse().unwrap()),
+            }
+        );
+        assert_eq!(config.lookup("app.internal").port, Some(2222));
+        assert_eq!(config.lookup("db.internal").port, None);
+        assert_eq!(config.lookup("db.internal").identity_files.len(), 1);
+    }
+
+    #[test]
+    fn match_host() {
+        let config = Config::parse(
+            "
+Host alias
+    HostName real.example.com
+Match host *.example.com originalhost alias
+    Port 2222
+Match host other,!nope
+    User other
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.lookup("alias").port, Some(2222));
+        assert_eq!(config.lookup("real.example.com").port, None);
+        assert_eq!(config.lookup("other").user.as_deref(), Some("other"));
+        assert_eq!(config.lookup("nope").user, None);
+    }
+
+    #[test]
+    fn match_unsupported() {
+        let config = Config::parse(
+            "
+Match exec \"test -f /tmp/flag\" host *
+    Port 2222
+Match canonical all
+    Port 2223
+Match all
+    User default
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.lookup("host").port, None);
+        assert_eq!(config.lookup("host").user.as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn proxy_command_none() {
+        let config = Config::parse(
+            "
+Host direct
+    ProxyCommand=none
+Host *
+    ProxyCommand ssh -W %h:%p bastion
+",
+        )
+        .unwrap();
+        assert_eq!(config.lookup("direct").proxy_command, None);
+        assert_eq!(
+            config.lookup("other").proxy_command.as_deref(),
+            Some("ssh -W %h:%p bastion")
+        );
+    }
+
+    #[test]
+    fn expand() {
+        let config = HostConfig {
+            host_name: Some("real.example.com".to_owned()),
+            port: None,
+            user: Some("nora".to_owned()),
+            ..HostConfig::default()
+        };
+        assert_eq!(
+            config.expand("/keys/%r@%h:%p-%n%%", "alias").unwrap(),
+            "/keys/nora@real.example.com:22-alias%"
+        );
+
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            config.expand("~/.ssh/id_%r", "alias").unwrap(),
+            format!("{home}/.ssh/id_nora")
+        );
+        assert_eq!(config.expand("~", "alias").unwrap(), home);
+
+        assert!(config.expand("~other/key", "alias").is_err());
+        assert!(config.expand("%x", "alias").is_err());
+        assert!(config.expand("key%", "alias").is_err());
+        assert!(HostConfig::default().expand("%r", "alias").is_err());
+
+        // Names from the command line must not be able to inject shell commands.
+        let command = "nc %h %p";
+        for host in [
+            "x;touch /tmp/pwned",
+            "$(id)",
+            "a b",
+            "-oProxyCommand=id",
+            "x\nid",
+        ] {
+            assert!(
+                HostConfig::default().expand(command, host).is_err(),
+                "{host}"
+            );
+        }
+        let config = HostConfig {
+            user: Some("`id`".to_owned()),
+            ..HostConfig::default()
+        };
+        assert!(config.expand("%r", "alias").is_err());
+        assert_eq!(
+            HostConfig::default()
+                .expand(command, "fe80::1%eth0")
+                .unwrap(),
+            "nc fe80::1%eth0 22"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Config::parse("Port abc").is_err());
+        assert!(Config::parse("Host").is_err());
+        assert!(Config::parse("Match user").is_err());
+        assert!(Config::parse("User \"unterminated").is_err());
+        assert!(Config::parse("SomethingElse yes\nInclude other").is_ok());
+    }
+}
@@ -0,0 +1,414 @@
+//! [`AsyncRead`] and [`AsyncWrite`] over a [`Channel`], for example for port forwarding or SFTP,
+//! and over the stdin and stdout of a proxy command.
+//! [`connect_tcp`] opens plain TCP connections from a specific source address.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use cluelessh_connection::{ChannelOperation, ChannelOperationKind};
+use cluelessh_protocol::ChannelUpdateKind;
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpSocket, TcpStream},
+    process::{Child, ChildStdin, ChildStdout},
+    sync::mpsc::{error::SendError, OwnedPermit},
+};
+
+use crate::Channel;
+
+/// The most data that is passed to the connection in a single write.
+/// The connection splits it into packets and queues what does not fit into the window of the peer,
+/// further writes wait until it has been sent.
+const MAX_WRITE_LEN: usize = 32 * 1024;
+
+type Reserve = BoxFuture<'static, Result<OwnedPermit<ChannelOperation>, SendError<()>>>;
+
+/// A [`Channel`] as a byte stream.
+///
+/// Reads return the data and extended data (like stderr) of the channel,
+/// and return 0 bytes once the peer has sent EOF or closed the channel.
+/// Other updates, like requests, are ignored.
+/// Writes are sent as data, and shutting down sends EOF.
+pub struct ChannelStream {
+    channel: Channel,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_eof: bool,
+    reserve: Option<Reserve>,
+    shutdown: bool,
+}
+
+impl ChannelStream {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_eof: false,
+            reserve: None,
+            shutdown: false,
+        }
+    }
+
+    /// Like [`ChannelStream::new`], with `data` that has already been received from the channel,
+    /// which is read before anything else.
+    pub(crate) fn with_received(channel: Channel, data: Vec<u8>) -> Self {
+        Self {
+            read_buf: data,
+            ..Self::new(channel)
+        }
+    }
+
+    /// The channel, for example to read the exit status after the stream has reached EOF.
+    /// Data that has been received but not read yet is lost.
+    pub fn into_inner(self) -> Channel {
+        self.channel
+    }
+
+    /// Waits until the operation can be passed on to the connection, and sends it.
+    /// Like [`Channel::send`], data waits until the window of the peer is available.
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        kind: ChannelOperationKind,
+    ) -> Poll<io::Result<()>> {
+        let reserve = self.reserve.get_or_insert_with(|| {
+            let window_available = match kind {
+                ChannelOperationKind::Data(_) => Some(self.channel.window_available()),
+                _ => None,
+            };
+            let ops_send = self.channel.ops_send.clone();
+            Box::pin(async move {
+                if let Some(window_available) = window_available {
+                    window_available.await;
+                }
+                ops_send.reserve_owned().await
+            })
+        });
+        let permit = ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+        match permit {
+            Ok(permit) => {
+                permit.send(self.channel.number.construct_op(kind));
+                Poll::Ready(Ok(()))
+            }
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_pos == this.read_buf.len() && !this.read_eof {
+            let update = ready!(this.channel.updates_recv.poll_recv(cx));
+            if let Some(update) = &update {
+                this.channel.consumed(update);
+            }
+            match update {
+                Some(ChannelUpdateKind::Data { data })
+                | Some(ChannelUpdateKind::ExtendedData { data, .. }) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Some(ChannelUpdateKind::Eof | ChannelUpdateKind::Closed) | None => {
+                    this.read_eof = true;
+                }
+                Some(_) => {}
+            }
+        }
+
+        let available = &this.read_buf[this.read_pos..];
+        let len = available.len().min(buf.remaining());
+        buf.put_slice(&available[..len]);
+        this.read_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_WRITE_LEN);
+        ready!(this.poll_send(cx, ChannelOperationKind::Data(buf[..len].to_vec())))?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes are passed on to the connection right away.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.shutdown {
+            return Poll::Ready(Ok(()));
+        }
+        ready!(this.poll_send(cx, ChannelOperationKind::Eof))?;
+        this.shutdown = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The stdout and stdin of a command that connects to the server, like OpenSSH's `ProxyCommand`.
+///
+/// Reads return the stdout of the command, writes go to its stdin, and shutting down closes stdin.
+/// Its stderr is inherited, and it is killed when the stream is dropped.
+pub struct CommandStream {
+    child: Child,
+    /// `None` after shutdown, as shutting down [`ChildStdin`] does not close it.
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl CommandStream {
+    /// Runs `command` with `sh -c`.
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("exec {command}"))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self {
+            child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+
+    /// The command process, for example to wait for it to exit.
+    pub fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stdin {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(stdin) = &mut this.stdin {
+            ready!(Pin::new(stdin).poll_shutdown(cx))?;
+            this.stdin = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Connects to `host` on `port`, trying the resolved addresses in order until one succeeds.
+///
+/// With a `bind_address`, the connection is made from that local address, like OpenSSH's
+/// `BindAddress`, which selects the interface on hosts with several of them.
+/// The local port is chosen by the OS, and only addresses of the same family are tried.
+pub async fn connect_tcp(
+    host: &str,
+    port: u16,
+    bind_address: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        if bind_address.is_some_and(|bind_address| bind_address.is_ipv4() != addr.is_ipv4()) {
+            continue;
+        }
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(bind_address) = bind_address {
+            socket.bind(SocketAddr::new(bind_address, 0))?;
+        }
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no usable address for {host}"),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_connection::{ChannelKind, ChannelNumber, ChannelOperationKind, ChannelRequest};
+    use cluelessh_protocol::ChannelUpdateKind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::{mpsc, watch};
+
+    use super::{ChannelStream, CommandStream};
+    use crate::Channel;
+
+    #[tokio::test]
+    async fn copy_through_stream() {
+        let (updates_send, updates_recv) = mpsc::channel(16);
+        let (ops_send, mut ops_recv) = mpsc::channel(16);
+        let mut stream = ChannelStream::new(Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            queued_data: watch::channel(0).1,
+            consumed_send: mpsc::unbounded_channel().0,
+            kind: ChannelKind::Session,
+        });
+
+        // A peer that echoes everything back, with a request in between that is not part of the data.
+        let peer = tokio::spawn(async move {
+            let mut received = Vec::new();
+            loop {
+                match ops_recv.recv().await.unwrap().kind {
+                    ChannelOperationKind::Data(data) => {
+                        received.extend_from_slice(&data);
+                        updates_send
+                            .send(ChannelUpdateKind::Request(ChannelRequest::ExitStatus {
+                                status: 0,
+                            }))
+                            .await
+                            .unwrap();
+                        updates_send
+                            .send(ChannelUpdateKind::ExtendedData { code: 1, data })
+                            .await
+                            .unwrap();
+                    }
+                    ChannelOperationKind::Eof => break,
+                    _ => panic!("unexpected operation"),
+                }
+            }
+            updates_send.send(ChannelUpdateKind::Eof).await.unwrap();
+            received
+        });
+
+        let input = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let write = async {
+            tokio::io::copy(&mut input.as_slice(), &mut writer)
+                .await
+                .unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).await.unwrap();
+            output
+        };
+        let ((), output) = tokio::join!(write, read);
+
+        assert_eq!(peer.await.unwrap(), input);
+        assert_eq!(output, input);
+
+        stream = reader.unsplit(writer);
+        // After EOF, reads keep returning 0 bytes.
+        assert_eq!(stream.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn closed_connection() {
+        let (updates_send, updates_recv) = mpsc::channel(16);
+        let (ops_send, ops_recv) = mpsc::channel(16);
+        let mut stream = ChannelStream::new(Channel {
+            number: ChannelNumber(0),
+            updates_recv,
+            ops_send,
+            queued_data: watch::channel(0).1,
+            consumed_send: mpsc::unbounded_channel().0,
+            kind: ChannelKind::Session,
+        });
+
+        updates_send
+            .send(ChannelUpdateKind::Data {
+                data: b"last words".to_vec(),
+            })
+            .await
+            .unwrap();
+        drop(updates_send);
+        drop(ops_recv);
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"last words");
+
+        let err = stream.write_all(b"hello").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn command_stream() {
+        let mut stream = CommandStream::spawn("tr a-z A-Z").unwrap();
+        stream.write_all(b"hello proxy").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"HELLO PROXY");
+        assert!(stream.child().wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn connect_tcp_from_bind_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let bind_address = "127.0.0.1".parse().unwrap();
+        let stream = super::connect_tcp("127.0.0.1", port, Some(bind_address))
+            .await
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(peer.ip(), bind_address);
+
+        let err = super::connect_tcp("127.0.0.1", port, Some("::1".parse().unwrap()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,227 @@
+//! The encoded terminal modes of PTY requests, like [`crate::SessionPty::term_modes`].
+//! <https://datatracker.ietf.org/doc/html/rfc4254#section-8>
+
+use cluelessh_format::{numbers, ParseError, Reader, Writer};
+use rustix::termios::{
+    ControlModes, InputModes, LocalModes, OutputModes, SpecialCodeIndex, Termios,
+};
+use tracing::debug;
+
+/// The index in [`rustix::termios::SpecialCodes`] of a special character opcode like [`numbers::VINTR`].
+fn special_code_index(opcode: u8) -> Option<SpecialCodeIndex> {
+    let index = match opcode {
+        numbers::VINTR => SpecialCodeIndex::VINTR,
+        numbers::VQUIT => SpecialCodeIndex::VQUIT,
+        numbers::VERASE => SpecialCodeIndex::VERASE,
+        numbers::VKILL => SpecialCodeIndex::VKILL,
+        numbers::VEOF => SpecialCodeIndex::VEOF,
+        numbers::VEOL => SpecialCodeIndex::VEOL,
+        numbers::VEOL2 => SpecialCodeIndex::VEOL2,
+        numbers::VSTART => SpecialCodeIndex::VSTART,
+        numbers::VSTOP => SpecialCodeIndex::VSTOP,
+        numbers::VSUSP => SpecialCodeIndex::VSUSP,
+        numbers::VREPRINT => SpecialCodeIndex::VREPRINT,
+        numbers::VWERASE => SpecialCodeIndex::VWERASE,
+        numbers::VLNEXT => SpecialCodeIndex::VLNEXT,
+        numbers::VSWTCH => SpecialCodeIndex::VSWTC,
+        numbers::VDISCARD => SpecialCodeIndex::VDISCARD,
+        _ => return None,
+    };
+    Some(index)
+}
+
+const INPUT_MODES: &[(u8, InputModes)] = &[
+    (numbers::IGNPAR, InputModes::IGNPAR),
+    (numbers::PARMRK, InputModes::PARMRK),
+    (numbers::INPCK, InputModes::INPCK),
+    (numbers::ISTRIP, InputModes::ISTRIP),
+    (numbers::INLCR, InputModes::INLCR),
+    (numbers::IGNCR, InputModes::IGNCR),
+    (numbers::ICRNL, InputModes::ICRNL),
+    (numbers::IUCLC, InputModes::IUCLC),
+    (numbers::IXON, InputModes::IXON),
+    (numbers::IXANY, InputModes::IXANY),
+    (numbers::IXOFF, InputModes::IXOFF),
+    (numbers::IMAXBEL, InputModes::IMAXBEL),
+    (numbers::IUTF8, InputModes::IUTF8),
+];
+
+const LOCAL_MODES: &[(u8, LocalModes)] = &[
+    (numbers::ISIG, LocalModes::ISIG),
+    (numbers::ICANON, LocalModes::ICANON),
+    (numbers::XCASE, LocalModes::XCASE),
+    (numbers::ECHO, LocalModes::ECHO),
+    (numbers::ECHOE, LocalModes::ECHOE),
+    (numbers::ECHOK, LocalModes::ECHOK),
+    (numbers::ECHONL, LocalModes::ECHONL),
+    (numbers::NOFLSH, LocalModes::NOFLSH),
+    (numbers::TOSTOP, LocalModes::TOSTOP),
+    (numbers::IEXTEN, LocalModes::IEXTEN),
+    (numbers::ECHOCTL, LocalModes::ECHOCTL),
+    (numbers::ECHOKE, LocalModes::ECHOKE),
+    (numbers::PENDIN, LocalModes::PENDIN),
+];
+
+const OUTPUT_MODES: &[(u8, OutputModes)] = &[
+    (numbers::OPOST, OutputModes::OPOST),
+    (numbers::OLCUC, OutputModes::OLCUC),
+    (numbers::ONLCR, OutputModes::ONLCR),
+    (numbers::OCRNL, OutputModes::OCRNL),
+    (numbers::ONOCR, OutputModes::ONOCR),
+    (numbers::ONLRET, OutputModes::ONLRET),
+];
+
+const CONTROL_MODES: &[(u8, ControlModes)] = &[
+    (numbers::PARENB, ControlModes::PARENB),
+    (numbers::PARODD, ControlModes::PARODD),
+];
+
+/// The terminal modes of a PTY request, as opcodes like [`numbers::VINTR`] with their argument.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TermModes(pub Vec<(u8, u32)>);
+
+impl TermModes {
+    /// The modes of a local terminal, for example of stdin.
+    pub fn from_termios(termios: &Termios) -> Self {
+        let mut modes = Vec::new();
+        for opcode in numbers::VINTR..=numbers::VDISCARD {
+            if let Some(index) = special_code_index(opcode) {
+                modes.push((opcode, termios.special_codes[index].into()));
+            }
+        }
+        for &(opcode, flag) in INPUT_MODES {
+            modes.push((opcode, termios.input_modes.contains(flag).into()));
+        }
+        for &(opcode, flag) in LOCAL_MODES {
+            modes.push((opcode, termios.local_modes.contains(flag).into()));
+        }
+        for &(opcode, flag) in OUTPUT_MODES {
+            modes.push((opcode, termios.output_modes.contains(flag).into()));
+        }
+        let size = termios.control_modes & ControlModes::CSIZE;
+        modes.push((numbers::CS7, (size == ControlModes::CS7).into()));
+        modes.push((numbers::CS8, (size == ControlModes::CS8).into()));
+        for &(opcode, flag) in CONTROL_MODES {
+            modes.push((opcode, termios.control_modes.contains(flag).into()));
+        }
+        modes.push((numbers::TTY_OP_ISPEED, termios.input_speed()));
+        modes.push((numbers::TTY_OP_OSPEED, termios.output_speed()));
+        Self(modes)
+    }
+
+    /// The modes of the local terminal on stdin, or no modes if stdin is not a terminal.
+    pub fn of_local_terminal() -> Self {
+        match rustix::termios::tcgetattr(std::io::stdin()) {
+            Ok(termios) => Self::from_termios(&termios),
+            Err(err) => {
+                debug!(?err, "Failed to get terminal modes");
+                Self::default()
+            }
+        }
+    }
+
+    /// Sets the modes on a terminal, for example on a newly allocated PTY.
+    /// Modes that are unknown or not supported on this platform are ignored.
+    pub fn apply(&self, termios: &mut Termios) {
+        for &(opcode, value) in &self.0 {
+            let enabled = value != 0;
+            if let Some(index) = special_code_index(opcode) {
+                // Special characters are a single byte, other values can't be represented.
+                if let Ok(value) = value.try_into() {
+                    termios.special_codes[index] = value;
+                }
+            } else if let Some(&(_, flag)) = INPUT_MODES.iter().find(|(op, _)| *op == opcode) {
+                termios.input_modes.set(flag, enabled);
+            } else if let Some(&(_, flag)) = LOCAL_MODES.iter().find(|(op, _)| *op == opcode) {
+                termios.local_modes.set(flag, enabled);
+            } else if let Some(&(_, flag)) = OUTPUT_MODES.iter().find(|(op, _)| *op == opcode) {
+                termios.output_modes.set(flag, enabled);
+            } else if let Some(&(_, flag)) = CONTROL_MODES.iter().find(|(op, _)| *op == opcode) {
+                termios.control_modes.set(flag, enabled);
+            } else if (opcode == numbers::CS7 || opcode == numbers::CS8) && enabled {
+                let size = if opcode == numbers::CS7 {
+                    ControlModes::CS7
+                } else {
+                    ControlModes::CS8
+                };
+                termios.control_modes.remove(ControlModes::CSIZE);
+                termios.control_modes.insert(size);
+            } else if opcode == numbers::TTY_OP_ISPEED {
+                if let Err(err) = termios.set_input_speed(value) {
+                    debug!(?err, %value, "Ignoring unsupported input speed");
+                }
+            } else if opcode == numbers::TTY_OP_OSPEED {
+                if let Err(err) = termios.set_output_speed(value) {
+                    debug!(?err, %value, "Ignoring unsupported output speed");
+                }
+            } else {
+                debug!(%opcode, %value, "Ignoring unknown terminal mode");
+            }
+        }
+    }
+
+    /// The wire encoding, terminated by [`numbers::TTY_OP_END`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for &(opcode, value) in &self.0 {
+            w.u8(opcode);
+            w.u32(value);
+        }
+        w.u8(numbers::TTY_OP_END);
+        w.finish()
+    }
+
+    /// Parses the encoded modes up to [`numbers::TTY_OP_END`], or the first opcode of 160 and above,
+    /// whose argument is not known and which stop parsing.
+    pub fn decode(modes: &[u8]) -> Result<Self, ParseError> {
+        let mut r = Reader::new(modes);
+        let mut decoded = Vec::new();
+        while r.has_data() {
+            let opcode = r.u8()?;
+            if opcode == numbers::TTY_OP_END || opcode >= 160 {
+                break;
+            }
+            decoded.push((opcode, r.u32()?));
+        }
+        Ok(Self(decoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cluelessh_format::numbers;
+
+    use super::TermModes;
+
+    #[test]
+    fn roundtrip() {
+        let modes = TermModes(vec![
+            (numbers::VERASE, 127),
+            (numbers::IUTF8, 1),
+            (numbers::ECHO, 0),
+            (numbers::TTY_OP_OSPEED, 38400),
+        ]);
+        let encoded = modes.encode();
+        assert_eq!(&encoded[..5], [numbers::VERASE, 0, 0, 0, 127]);
+        assert_eq!(encoded.last(), Some(&numbers::TTY_OP_END));
+        assert_eq!(TermModes::decode(&encoded).unwrap(), modes);
+    }
+
+    #[test]
+    fn decode_stops() {
+        // After TTY_OP_END.
+        assert_eq!(
+            TermModes::decode(&[numbers::ECHO, 0, 0, 0, 1, 0, numbers::ECHO, 0, 0, 0, 0]).unwrap(),
+            TermModes(vec![(numbers::ECHO, 1)])
+        );
+        // At an undefined opcode, whose argument can't be skipped.
+        assert_eq!(
+            TermModes::decode(&[numbers::ECHO, 0, 0, 0, 1, 200, 1]).unwrap(),
+            TermModes(vec![(numbers::ECHO, 1)])
+        );
+        // At the end of the data.
+        assert_eq!(TermModes::decode(&[]).unwrap(), TermModes::default());
+
+        TermModes::decode(&[numbers::ECHO, 0, 0]).unwrap_err();
+    }
+}
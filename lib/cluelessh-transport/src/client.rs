@@ -1,13 +1,20 @@
-use std::{collections::VecDeque, mem};
+use std::{
+    collections::VecDeque,
+    mem,
+    time::{Duration, Instant},
+};
 
-use tracing::{debug, info, trace};
+use tracing::{debug, info};
 
 use crate::{
     crypto::{
         self, AlgorithmName, EncodedSshSignature, EncryptionAlgorithm, HostKeyVerifyAlgorithm,
         KeyExchangeSecret, SharedSecret, SupportedAlgorithms,
     },
-    packet::{Packet, PacketTransport, ProtocolIdentParser, RecvBytesResult},
+    packet::{
+        CompressionAlgorithm, Packet, PacketCapture, PacketTransport, ProtocolIdentParser,
+        RecvBytesResult,
+    },
     peer_error, Msg, Result, SessionId, SshRng, SshStatus,
 };
 use cluelessh_format::{numbers, NameList, Reader, Writer};
@@ -18,48 +25,143 @@ pub struct ClientConnection {
     rng: Box<dyn SshRng + Send + Sync>,
 
     plaintext_packets: VecDeque<Packet>,
+    /// Packets that are sent while a key exchange is in progress.
+    /// No other packets may be sent between SSH_MSG_KEXINIT and SSH_MSG_NEWKEYS,
+    /// so they are held back until the new keys are in place.
+    paused_packets: VecDeque<Packet>,
+    /// The algorithms negotiated in the key exchange that is in progress.
+    pending_connection_info: Option<ConnectionInfo>,
+    connection_info: Option<ConnectionInfo>,
+    /// The reason code and description of the `SSH_MSG_DISCONNECT` received from the server.
+    received_disconnect: Option<(u32, String)>,
+    /// Whether both sides support strict key exchange, see [`crate::KEX_STRICT_CLIENT`].
+    strict_kex: bool,
+    /// Whether the server sent other packets before its first KEXINIT,
+    /// which is not allowed with strict key exchange.
+    received_before_kexinit: bool,
 
-    supported_algorithms: SupportedAlgorithms,
+    /// Whether to ask the server for compression.
+    /// Off by default, as it only helps on slow links.
+    pub compression: bool,
+    /// Host key algorithms to offer first, usually the ones of the keys already known for the server.
+    /// This makes the server present a known key instead of one of another algorithm.
+    pub preferred_host_key_algorithms: Vec<String>,
+
+    /// When to start a new key exchange.
+    pub rekey_limits: RekeyLimits,
+    /// Payload bytes sent and received since the last key exchange.
+    bytes_since_kex: u64,
+    last_kex: Instant,
 
     pub abort_for_dos: bool,
 }
 
+/// The algorithms negotiated for the connection, like the output of `ssh -v`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The identification string of the server, like `SSH-2.0-OpenSSH_9.7`.
+    pub server_identification: String,
+    pub kex_algorithm: &'static str,
+    pub host_key_algorithm: &'static str,
+    pub encryption_client_to_server: &'static str,
+    pub encryption_server_to_client: &'static str,
+    /// The negotiated MAC, which is not used by the AEAD ciphers.
+    pub mac_client_to_server: &'static str,
+    /// The negotiated MAC, which is not used by the AEAD ciphers.
+    pub mac_server_to_client: &'static str,
+    pub compression_client_to_server: &'static str,
+    pub compression_server_to_client: &'static str,
+    /// The wire encoding of the host key the server proved its identity with.
+    pub host_key: Vec<u8>,
+    /// The names and values of the extensions the server sent with `SSH_MSG_EXT_INFO`.
+    /// <https://datatracker.ietf.org/doc/html/rfc8308>
+    pub extensions: Vec<(String, Vec<u8>)>,
+}
+
+impl ConnectionInfo {
+    /// The signature algorithms the server accepts for public key authentication,
+    /// if it sent the `server-sig-algs` extension.
+    /// <https://datatracker.ietf.org/doc/html/rfc8308#section-3.1>
+    pub fn server_sig_algs(&self) -> Option<Vec<&str>> {
+        let (_, value) = self
+            .extensions
+            .iter()
+            .find(|(name, _)| name == "server-sig-algs")?;
+        let value = std::str::from_utf8(value).ok()?;
+        Some(value.split(',').filter(|name| !name.is_empty()).collect())
+    }
+}
+
+/// Limits after which the client initiates a key re-exchange,
+/// to limit the amount of data protected by the same keys.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyLimits {
+    /// The amount of payload bytes sent and received.
+    pub bytes: u64,
+    /// The time since the last key exchange.
+    pub time: Duration,
+}
+
+impl Default for RekeyLimits {
+    /// Like OpenSSH, rekey after 1GiB or one hour.
+    fn default() -> Self {
+        Self {
+            bytes: 1 << 30,
+            time: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
 enum ClientState {
     ProtoExchange {
         client_ident: Vec<u8>,
         ident_parser: ProtocolIdentParser,
     },
     KexInit {
+        /// The session of the connection if this is a key re-exchange.
+        session_id: Option<SessionId>,
         client_ident: Vec<u8>,
         server_ident: Vec<u8>,
         client_kexinit: Vec<u8>,
     },
     DhKeyInit {
+        session_id: Option<SessionId>,
         client_ident: Vec<u8>,
         server_ident: Vec<u8>,
         kex_secret: Option<KeyExchangeSecret>,
         server_hostkey_algorithm: HostKeyVerifyAlgorithm,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
         client_kexinit: Vec<u8>,
         server_kexinit: Vec<u8>,
     },
     NewKeys {
+        session_id: Option<SessionId>,
+        client_ident: Vec<u8>,
+        server_ident: Vec<u8>,
         h: [u8; 32],
         k: SharedSecret,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
     },
     ServiceRequest {
         session_id: SessionId,
+        client_ident: Vec<u8>,
+        server_ident: Vec<u8>,
     },
     Open {
         session_id: SessionId,
+        client_ident: Vec<u8>,
+        server_ident: Vec<u8>,
     },
 }
 
 impl ClientConnection {
-    pub fn new(rng: impl SshRng + Send + Sync + 'static) -> Self {
+    pub fn new(rng: impl SshRng + 'static) -> Self {
         let client_ident = b"SSH-2.0-ClueleSSH\r\n".to_vec();
 
         let mut packet_transport = PacketTransport::new();
@@ -67,13 +169,23 @@ impl ClientConnection {
 
         Self {
             state: ClientState::ProtoExchange {
-                ident_parser: ProtocolIdentParser::new(),
+                ident_parser: ProtocolIdentParser::new(true),
                 client_ident,
             },
             packet_transport,
             rng: Box::new(rng),
-            supported_algorithms: SupportedAlgorithms::secure(&[]),
+            compression: false,
+            preferred_host_key_algorithms: Vec::new(),
             plaintext_packets: VecDeque::new(),
+            paused_packets: VecDeque::new(),
+            pending_connection_info: None,
+            connection_info: None,
+            received_disconnect: None,
+            strict_kex: false,
+            received_before_kexinit: false,
+            rekey_limits: RekeyLimits::default(),
+            bytes_since_kex: 0,
+            last_kex: Instant::now(),
             abort_for_dos: false,
         }
     }
@@ -94,12 +206,11 @@ impl ClientConnection {
             client_ident,
         } = &mut self.state
         {
-            ident_parser.recv_bytes(bytes);
-            if let Some(server_ident) = ident_parser.get_peer_ident() {
+            if let Some((server_ident, consumed)) = ident_parser.recv_bytes(bytes)? {
                 let client_ident = mem::take(client_ident);
                 // This moves to the next state.
-                self.send_kexinit(client_ident, server_ident);
-                return Ok(RecvBytesResult::Full);
+                self.send_kexinit(client_ident, server_ident, None);
+                return Ok(RecvBytesResult::Partial { consumed });
             }
             return Ok(RecvBytesResult::Full);
         }
@@ -110,7 +221,18 @@ impl ClientConnection {
             let packet_type = packet.payload.first().unwrap_or(&0xFF);
             let packet_type_string = numbers::packet_type_to_string(*packet_type);
 
-            trace!(%packet_type, %packet_type_string, packet_len = %packet.payload.len(), "Received packet");
+            if matches!(
+                *packet_type,
+                numbers::SSH_MSG_IGNORE | numbers::SSH_MSG_DEBUG
+            ) && self.is_initial_kex()
+            {
+                if self.strict_kex {
+                    return Err(peer_error!(
+                        "strict key exchange violation: received {packet_type_string} during the initial key exchange"
+                    ));
+                }
+                self.received_before_kexinit = true;
+            }
 
             // TODO: deduplicate with server
             // Handle some packets ignoring the state.
@@ -126,6 +248,7 @@ impl ClientConnection {
 
                     info!(%reason, %reason_string, %description, "Server disconnecting");
 
+                    self.received_disconnect = Some((reason, description.to_owned()));
                     return Err(SshStatus::Disconnect);
                 }
                 Some(numbers::SSH_MSG_IGNORE) => {
@@ -151,9 +274,36 @@ impl ClientConnection {
                 _ => {}
             }
 
+            // <https://datatracker.ietf.org/doc/html/rfc4253#section-9>
+            if let ClientState::Open {
+                session_id,
+                client_ident,
+                server_ident,
+            } = &mut self.state
+            {
+                if *packet_type == numbers::SSH_MSG_KEXINIT {
+                    debug!("Server initiated key re-exchange");
+                    let session_id = *session_id;
+                    let client_ident = mem::take(client_ident);
+                    let server_ident = mem::take(server_ident);
+                    // This moves to the KexInit state, where the server's KEXINIT is handled.
+                    self.send_kexinit(client_ident, server_ident, Some(session_id));
+                }
+            }
+
             match &mut self.state {
                 ClientState::ProtoExchange { .. } => unreachable!("handled above"),
+                // We initiated a key re-exchange, but the server may still send other packets
+                // until it has received our KEXINIT and responded with its own.
+                ClientState::KexInit {
+                    session_id: Some(_),
+                    ..
+                } if *packet_type >= numbers::SSH_MSG_USERAUTH_REQUEST => {
+                    self.bytes_since_kex += packet.payload.len() as u64;
+                    self.plaintext_packets.push_back(packet);
+                }
                 ClientState::KexInit {
+                    session_id,
                     client_ident,
                     server_ident,
                     client_kexinit,
@@ -167,11 +317,23 @@ impl ClientConnection {
                         ));
                     }
 
-                    let sup_algs = SupportedAlgorithms::secure(&[]);
+                    let sup_algs = Self::supported_algorithms(
+                        self.compression,
+                        &self.preferred_host_key_algorithms,
+                    );
 
                     let _cookie = kexinit.array::<16>()?;
 
                     let kex_algorithm = kexinit.name_list()?;
+                    if session_id.is_none() && kex_algorithm.contains(crate::KEX_STRICT_SERVER) {
+                        if self.received_before_kexinit {
+                            return Err(peer_error!(
+                                "strict key exchange violation: KEXINIT was not the first packet"
+                            ));
+                        }
+                        debug!("Using strict key exchange");
+                        self.strict_kex = true;
+                    }
                     let kex_algorithm = sup_algs.key_exchange.find(true, kex_algorithm.0)?;
                     debug!(name = %kex_algorithm.name(), "Using KEX algorithm");
 
@@ -194,22 +356,29 @@ impl ClientConnection {
                     debug!(name = %encryption_server_to_client.name(), "Using encryption algorithm S->C");
 
                     let mac_algorithms_client_to_server = kexinit.name_list()?;
-                    let _mac_client_to_server = sup_algs
+                    let mac_client_to_server = sup_algs
                         .mac_to_peer
                         .find(true, mac_algorithms_client_to_server.0)?;
                     let mac_algorithms_server_to_client = kexinit.name_list()?;
-                    let _mac_server_to_client = sup_algs
+                    let mac_server_to_client = sup_algs
                         .mac_from_peer
                         .find(true, mac_algorithms_server_to_client.0)?;
+                    // AEAD ciphers ignore the MAC.
+                    let encryption_client_to_server =
+                        encryption_client_to_server.with_mac(mac_client_to_server);
+                    let encryption_server_to_client =
+                        encryption_server_to_client.with_mac(mac_server_to_client);
 
                     let compression_algorithms_client_to_server = kexinit.name_list()?;
-                    let _compression_client_to_server = sup_algs
+                    let compression_client_to_server = sup_algs
                         .compression_to_peer
                         .find(true, compression_algorithms_client_to_server.0)?;
+                    debug!(name = %compression_client_to_server.name(), "Using compression algorithm C->S");
                     let compression_algorithms_server_to_client = kexinit.name_list()?;
-                    let _compression_server_to_client = sup_algs
+                    let compression_server_to_client = sup_algs
                         .compression_from_peer
                         .find(true, compression_algorithms_server_to_client.0)?;
+                    debug!(name = %compression_server_to_client.name(), "Using compression algorithm S->C");
 
                     let _languages_client_to_server = kexinit.name_list()?;
                     let _languages_server_to_client = kexinit.name_list()?;
@@ -218,29 +387,54 @@ impl ClientConnection {
                         return Err(peer_error!("does not support guessed kex init packages"));
                     }
 
+                    self.pending_connection_info = Some(ConnectionInfo {
+                        server_identification: String::from_utf8_lossy(
+                            server_ident
+                                .strip_suffix(b"\r\n")
+                                .unwrap_or(&server_ident[..]),
+                        )
+                        .into_owned(),
+                        kex_algorithm: kex_algorithm.name(),
+                        host_key_algorithm: server_hostkey_algorithm.name(),
+                        encryption_client_to_server: encryption_client_to_server.name(),
+                        encryption_server_to_client: encryption_server_to_client.name(),
+                        mac_client_to_server: mac_client_to_server.name(),
+                        mac_server_to_client: mac_server_to_client.name(),
+                        compression_client_to_server: compression_client_to_server.name(),
+                        compression_server_to_client: compression_server_to_client.name(),
+                        host_key: Vec::new(),
+                        extensions: Vec::new(),
+                    });
+
                     let kex_secret = (kex_algorithm.generate_secret)(&mut *self.rng);
 
                     self.packet_transport
                         .queue_packet(Packet::new_msg_kex_ecdh_init(&kex_secret.pubkey));
 
                     self.state = ClientState::DhKeyInit {
+                        session_id: *session_id,
                         client_ident: mem::take(client_ident),
                         server_ident: mem::take(server_ident),
                         kex_secret: Some(kex_secret),
                         server_hostkey_algorithm,
                         encryption_client_to_server,
                         encryption_server_to_client,
+                        compression_client_to_server,
+                        compression_server_to_client,
                         client_kexinit: mem::take(client_kexinit),
                         server_kexinit: packet.payload,
                     };
                 }
                 ClientState::DhKeyInit {
+                    session_id,
                     client_ident,
                     server_ident,
                     kex_secret,
                     server_hostkey_algorithm,
                     encryption_client_to_server,
                     encryption_server_to_client,
+                    compression_client_to_server,
+                    compression_server_to_client,
                     client_kexinit,
                     server_kexinit,
                 } => {
@@ -277,11 +471,32 @@ impl ClientConnection {
                         &shared_secret,
                     );
 
-                    (server_hostkey_algorithm.verify)(
+                    // The signature over the exchange hash is what binds the whole transcript to the host key,
+                    // if anything was changed on the way, our hash differs from the one the server signed.
+                    if let Err(err) = (server_hostkey_algorithm.verify)(
                         server_hostkey,
                         &hash,
                         &EncodedSshSignature(signature.to_vec()),
-                    )?;
+                    ) {
+                        let reason = match err {
+                            SshStatus::PeerError(reason) => reason,
+                            SshStatus::Disconnect => "disconnected".to_owned(),
+                        };
+                        self.packet_transport
+                            .queue_packet(Packet::new_msg_disconnect(
+                                numbers::SSH_DISCONNECT_KEY_EXCHANGE_FAILED,
+                                b"host key signature over the exchange hash is invalid",
+                                b"",
+                            ));
+                        return Err(peer_error!(
+                            "host key signature over the exchange hash is invalid, \
+                            the key exchange may have been tampered with: {reason}"
+                        ));
+                    }
+
+                    if let Some(info) = &mut self.pending_connection_info {
+                        info.host_key = server_hostkey.to_vec();
+                    }
 
                     // eprintln!("client_public_key: {:x?}", kex_secret.pubkey);
                     // eprintln!("server_public_key: {:x?}", server_ephermal_key);
@@ -292,17 +507,27 @@ impl ClientConnection {
                         payload: vec![numbers::SSH_MSG_NEWKEYS],
                     });
                     self.state = ClientState::NewKeys {
+                        session_id: *session_id,
+                        client_ident: mem::take(client_ident),
+                        server_ident: mem::take(server_ident),
                         h: hash,
                         k: shared_secret,
                         encryption_client_to_server: *encryption_client_to_server,
                         encryption_server_to_client: *encryption_server_to_client,
+                        compression_client_to_server: *compression_client_to_server,
+                        compression_server_to_client: *compression_server_to_client,
                     };
                 }
                 ClientState::NewKeys {
+                    session_id,
+                    client_ident,
+                    server_ident,
                     h,
                     k,
                     encryption_client_to_server,
                     encryption_server_to_client,
+                    compression_client_to_server,
+                    compression_server_to_client,
                 } => {
                     if packet.payload != [numbers::SSH_MSG_NEWKEYS] {
                         return Err(peer_error!("did not send SSH_MSG_NEWKEYS"));
@@ -313,18 +538,65 @@ impl ClientConnection {
                         k,
                         *encryption_client_to_server,
                         *encryption_server_to_client,
+                        *compression_client_to_server,
+                        *compression_server_to_client,
                         false,
                     );
+                    if self.strict_kex {
+                        self.packet_transport.reset_sequence_numbers();
+                    }
+                    self.bytes_since_kex = 0;
+                    self.last_kex = Instant::now();
+                    // Extensions are only sent after the first key exchange, and stay valid.
+                    let extensions = self
+                        .connection_info
+                        .take()
+                        .map(|info| info.extensions)
+                        .unwrap_or_default();
+                    self.connection_info = self.pending_connection_info.take();
+                    if let Some(info) = &mut self.connection_info {
+                        info.extensions = extensions;
+                    }
 
-                    debug!("Requesting ssh-userauth service");
-                    self.packet_transport
-                        .queue_packet(Packet::new_msg_service_request(b"ssh-userauth"));
+                    let client_ident = mem::take(client_ident);
+                    let server_ident = mem::take(server_ident);
+                    match *session_id {
+                        None => {
+                            debug!("Requesting ssh-userauth service");
+                            self.packet_transport
+                                .queue_packet(Packet::new_msg_service_request(b"ssh-userauth"));
 
-                    self.state = ClientState::ServiceRequest {
-                        session_id: SessionId(*h),
-                    };
+                            self.state = ClientState::ServiceRequest {
+                                session_id: SessionId(*h),
+                                client_ident,
+                                server_ident,
+                            };
+                        }
+                        Some(session_id) => {
+                            debug!("Finished key re-exchange");
+                            self.state = ClientState::Open {
+                                session_id,
+                                client_ident,
+                                server_ident,
+                            };
+                        }
+                    }
+
+                    for packet in mem::take(&mut self.paused_packets) {
+                        self.packet_transport.queue_packet(packet);
+                    }
                 }
-                ClientState::ServiceRequest { session_id } => {
+                ClientState::ServiceRequest {
+                    session_id,
+                    client_ident,
+                    server_ident,
+                } => {
+                    // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.4>
+                    if *packet_type == numbers::SSH_MSG_EXT_INFO {
+                        self.recv_ext_info(&packet)?;
+                        continue;
+                    }
+
                     let mut accept = packet.payload_parser();
                     let packet_type = accept.u8()?;
                     if packet_type != numbers::SSH_MSG_SERVICE_ACCEPT {
@@ -338,16 +610,44 @@ impl ClientConnection {
                     debug!("Connection has been opened successfully");
                     self.state = ClientState::Open {
                         session_id: *session_id,
+                        client_ident: mem::take(client_ident),
+                        server_ident: mem::take(server_ident),
                     };
                 }
+                // The server may send its extensions again right before SSH_MSG_USERAUTH_SUCCESS.
+                ClientState::Open { .. } if *packet_type == numbers::SSH_MSG_EXT_INFO => {
+                    self.recv_ext_info(&packet)?;
+                }
                 ClientState::Open { .. } => {
+                    self.bytes_since_kex += packet.payload.len() as u64;
                     self.plaintext_packets.push_back(packet);
+                    self.rekey_if_needed();
                 }
             }
         }
         Ok(consumed)
     }
 
+    fn recv_ext_info(&mut self, packet: &Packet) -> Result<()> {
+        let mut p = packet.payload_parser();
+        p.u8()?;
+        let count = p.u32()?;
+        debug!(%count, "Received extensions");
+
+        let Some(info) = &mut self.connection_info else {
+            return Err(peer_error!("SSH_MSG_EXT_INFO before the key exchange"));
+        };
+        for _ in 0..count {
+            let name = p.utf8_string()?;
+            let value = p.string()?;
+            debug!(?name, "Received extension");
+            // A later SSH_MSG_EXT_INFO replaces the values of the earlier one.
+            info.extensions.retain(|(existing, _)| existing != name);
+            info.extensions.push((name.to_owned(), value.to_vec()));
+        }
+        Ok(())
+    }
+
     pub fn next_msg_to_send(&mut self) -> Option<Msg> {
         self.packet_transport.next_msg_to_send()
     }
@@ -356,18 +656,148 @@ impl ClientConnection {
         self.plaintext_packets.pop_front()
     }
 
+    /// Tells the server why the connection is being closed.
+    /// No more packets should be sent or received afterwards.
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) {
+        // Disconnect messages are allowed during key exchange, so they are never paused.
+        self.packet_transport
+            .queue_packet(Packet::new_msg_disconnect(
+                reason_code,
+                description.as_bytes(),
+                b"",
+            ));
+    }
+
     pub fn send_plaintext_packet(&mut self, packet: Packet) {
-        self.packet_transport.queue_packet(packet);
+        self.bytes_since_kex += packet.payload.len() as u64;
+        self.rekey_if_needed();
+
+        match self.state {
+            ClientState::KexInit { .. }
+            | ClientState::DhKeyInit { .. }
+            | ClientState::NewKeys { .. } => {
+                self.paused_packets.push_back(packet);
+            }
+            _ => self.packet_transport.queue_packet(packet),
+        }
+    }
+
+    fn is_initial_kex(&self) -> bool {
+        matches!(
+            self.state,
+            ClientState::KexInit {
+                session_id: None,
+                ..
+            } | ClientState::DhKeyInit {
+                session_id: None,
+                ..
+            } | ClientState::NewKeys {
+                session_id: None,
+                ..
+            }
+        )
     }
 
     pub fn is_open(&self) -> Option<SessionId> {
         match self.state {
-            ClientState::Open { session_id } => Some(session_id),
+            ClientState::Open { session_id, .. } => Some(session_id),
             _ => None,
         }
     }
 
-    fn send_kexinit(&mut self, client_ident: Vec<u8>, server_ident: Vec<u8>) {
+    /// The identification string of the server without the CR LF, like `SSH-2.0-OpenSSH_9.7`,
+    /// once it has been received.
+    /// Lines the server sent before it are skipped.
+    pub fn server_identification(&self) -> Option<&[u8]> {
+        match &self.state {
+            ClientState::ProtoExchange { .. } => None,
+            ClientState::KexInit { server_ident, .. }
+            | ClientState::DhKeyInit { server_ident, .. }
+            | ClientState::NewKeys { server_ident, .. }
+            | ClientState::ServiceRequest { server_ident, .. }
+            | ClientState::Open { server_ident, .. } => {
+                Some(server_ident.strip_suffix(b"\r\n").unwrap_or(server_ident))
+            }
+        }
+    }
+
+    /// The algorithms negotiated in the most recent key exchange, once it has finished.
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.connection_info.as_ref()
+    }
+
+    /// Calls `capture` with the payload of every packet that is sent or received, for debugging
+    /// or to compare against the traffic of other implementations. Off by default.
+    ///
+    /// The payloads are the *plaintext* after decryption and before encryption,
+    /// including passwords and channel data, so be careful where they end up.
+    pub fn set_packet_capture(&mut self, capture: Option<PacketCapture>) {
+        self.packet_transport.set_packet_capture(capture);
+    }
+
+    /// The reason code and description of the `SSH_MSG_DISCONNECT` the server has sent,
+    /// after [`Self::recv_bytes`] has returned [`SshStatus::Disconnect`].
+    pub fn received_disconnect(&self) -> Option<(u32, &str)> {
+        self.received_disconnect
+            .as_ref()
+            .map(|(reason, description)| (*reason, description.as_str()))
+    }
+
+    /// Starts a key re-exchange if one of the [`RekeyLimits`] has been reached.
+    fn rekey_if_needed(&mut self) {
+        let ClientState::Open {
+            session_id,
+            client_ident,
+            server_ident,
+        } = &mut self.state
+        else {
+            return;
+        };
+
+        if self.bytes_since_kex < self.rekey_limits.bytes
+            && self.last_kex.elapsed() < self.rekey_limits.time
+        {
+            return;
+        }
+
+        debug!(bytes = %self.bytes_since_kex, "Initiating key re-exchange");
+        let session_id = *session_id;
+        let client_ident = mem::take(client_ident);
+        let server_ident = mem::take(server_ident);
+        self.send_kexinit(client_ident, server_ident, Some(session_id));
+    }
+
+    fn supported_algorithms(
+        compression: bool,
+        preferred_host_key_algorithms: &[String],
+    ) -> SupportedAlgorithms {
+        let mut algs = SupportedAlgorithms::secure(&[]);
+        algs.hostkey_verify.supported.sort_by_key(|alg| {
+            preferred_host_key_algorithms
+                .iter()
+                .position(|preferred| preferred == alg.name())
+                .unwrap_or(usize::MAX)
+        });
+        let compression = if compression {
+            vec![
+                CompressionAlgorithm::ZlibOpenSsh,
+                CompressionAlgorithm::Zlib,
+                CompressionAlgorithm::None,
+            ]
+        } else {
+            vec![CompressionAlgorithm::None]
+        };
+        algs.compression_to_peer.supported = compression.clone();
+        algs.compression_from_peer.supported = compression;
+        algs
+    }
+
+    fn send_kexinit(
+        &mut self,
+        client_ident: Vec<u8>,
+        server_ident: Vec<u8>,
+        session_id: Option<SessionId>,
+    ) {
         let mut cookie = [0; 16];
         self.rng.fill_bytes(&mut cookie);
 
@@ -375,8 +805,15 @@ impl ClientConnection {
         kexinit.u8(numbers::SSH_MSG_KEXINIT);
         kexinit.array(cookie);
 
-        let algs = &self.supported_algorithms;
-        kexinit.name_list(NameList::multi(&algs.key_exchange.to_name_list())); // kex_algorithms
+        let algs =
+            &Self::supported_algorithms(self.compression, &self.preferred_host_key_algorithms);
+        let mut kex_algorithms = algs.key_exchange.to_name_list();
+        if session_id.is_none() {
+            // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
+            kex_algorithms.push_str(",ext-info-c,");
+            kex_algorithms.push_str(crate::KEX_STRICT_CLIENT);
+        }
+        kexinit.name_list(NameList::multi(&kex_algorithms)); // kex_algorithms
         kexinit.name_list(NameList::multi(&algs.hostkey_verify.to_name_list())); // server_host_key_algorithms
         kexinit.name_list(NameList::multi(&algs.encryption_to_peer.to_name_list())); // encryption_algorithms_client_to_server
         kexinit.name_list(NameList::multi(&algs.encryption_from_peer.to_name_list())); // encryption_algorithms_server_to_client
@@ -394,9 +831,688 @@ impl ClientConnection {
             payload: kexinit.clone(),
         });
         self.state = ClientState::KexInit {
+            session_id,
             client_ident,
             server_ident,
             client_kexinit: kexinit,
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use cluelessh_format::{numbers, NameList};
+    use cluelessh_keys::{known_hosts::KnownHosts, private::PlaintextPrivateKey};
+    use sha2::Digest;
+
+    use crate::{
+        crypto::{self, AlgorithmName, KexAlgorithm, SharedSecret, SupportedAlgorithms},
+        packet::{
+            CompressionAlgorithm, KeyExchangeEcDhInitPacket, KeyExchangeInitPacket, Packet,
+            PacketDirection, PacketTransport,
+        },
+        server::{self, KeyExchangeParameters},
+        SshRng,
+    };
+
+    use super::{ClientConnection, ConnectionInfo};
+
+    struct TestRng(u64);
+    impl SshRng for TestRng {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(32) {
+                self.0 += 1;
+                let bytes = sha2::Sha256::digest(self.0.to_be_bytes());
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+    }
+
+    const SERVER_IDENT: &[u8] = b"SSH-2.0-TestServer\r\n";
+
+    /// A minimal server that only does what is necessary to test the client.
+    struct TestServer {
+        transport: PacketTransport,
+        rng: TestRng,
+        host_key: PlaintextPrivateKey,
+        client_ident: Vec<u8>,
+        client_kexinit: Vec<u8>,
+        server_kexinit: Vec<u8>,
+        new_keys: Option<([u8; 32], SharedSecret)>,
+        compression: CompressionAlgorithm,
+        kex_algorithm: KexAlgorithm,
+        /// Whether to advertise strict key exchange and reset sequence numbers after NEWKEYS.
+        strict_kex: bool,
+        /// Sent as the first packet after the next NEWKEYS.
+        ext_info: Option<Packet>,
+        /// All packets received from the client.
+        received: Vec<Packet>,
+    }
+
+    impl TestServer {
+        fn new(client: &mut ClientConnection) -> Self {
+            let client_ident = client.next_msg_to_send().unwrap().to_bytes();
+            let mut transport = PacketTransport::new();
+            transport.queue_send_protocol_info(SERVER_IDENT.to_vec());
+
+            Self {
+                transport,
+                rng: TestRng(1000),
+                host_key: PlaintextPrivateKey::generate(
+                    String::new(),
+                    cluelessh_keys::KeyGenerationParams {
+                        key_type: cluelessh_keys::KeyType::Ed25519,
+                    },
+                ),
+                client_ident,
+                client_kexinit: Vec::new(),
+                server_kexinit: Vec::new(),
+                new_keys: None,
+                compression: CompressionAlgorithm::None,
+                kex_algorithm: crypto::KEX_CURVE_25519_SHA256,
+                strict_kex: false,
+                ext_info: None,
+                received: Vec::new(),
+            }
+        }
+
+        fn send_kexinit(&mut self) {
+            let mut cookie = [0; 16];
+            self.rng.fill_bytes(&mut cookie);
+            let mut kex_algorithms = self.kex_algorithm.name().to_owned();
+            if self.strict_kex {
+                kex_algorithms.push(',');
+                kex_algorithms.push_str(crate::KEX_STRICT_SERVER);
+            }
+            let kexinit = KeyExchangeInitPacket {
+                cookie,
+                kex_algorithms: NameList::multi(&kex_algorithms),
+                server_host_key_algorithms: NameList::one("ssh-ed25519"),
+                encryption_algorithms_client_to_server: NameList::one(
+                    "chacha20-poly1305@openssh.com",
+                ),
+                encryption_algorithms_server_to_client: NameList::one(
+                    "chacha20-poly1305@openssh.com",
+                ),
+                mac_algorithms_client_to_server: NameList::one("hmac-sha2-256"),
+                mac_algorithms_server_to_client: NameList::one("hmac-sha2-256"),
+                compression_algorithms_client_to_server: NameList::one(self.compression.name()),
+                compression_algorithms_server_to_client: NameList::one(self.compression.name()),
+                languages_client_to_server: NameList::none(),
+                languages_server_to_client: NameList::none(),
+                first_kex_packet_follows: false,
+            }
+            .to_bytes();
+            self.server_kexinit = kexinit.clone();
+            self.transport.queue_packet(Packet { payload: kexinit });
+        }
+
+        fn send_to(&mut self, client: &mut ClientConnection) {
+            while let Some(msg) = self.transport.next_msg_to_send() {
+                client.recv_bytes(&msg.to_bytes()).unwrap();
+            }
+        }
+
+        fn recv_from(&mut self, client: &mut ClientConnection) {
+            // Every message is exactly one packet, so we can switch keys between them.
+            while let Some(msg) = client.next_msg_to_send() {
+                let _ = self.transport.recv_bytes(&msg.to_bytes()).unwrap();
+                while let Some(packet) = self.transport.recv_next_packet() {
+                    self.handle_packet(&packet);
+                    self.received.push(packet);
+                }
+            }
+        }
+
+        fn handle_packet(&mut self, packet: &Packet) {
+            match packet.packet_type() {
+                numbers::SSH_MSG_KEXINIT => self.client_kexinit = packet.payload.clone(),
+                numbers::SSH_MSG_KEX_ECDH_INIT => {
+                    let dh = KeyExchangeEcDhInitPacket::parse(&packet.payload).unwrap();
+                    let sup_algs =
+                        SupportedAlgorithms::secure(&[self.host_key.private_key.public_key()]);
+                    let params = KeyExchangeParameters {
+                        client_ident: self.client_ident.clone(),
+                        server_ident: SERVER_IDENT.to_vec(),
+                        client_kexinit: self.client_kexinit.clone(),
+                        server_kexinit: self.server_kexinit.clone(),
+                        eph_client_public_key: dh.qc.to_vec(),
+                        server_host_key_algorithm: sup_algs
+                            .hostkey_sign
+                            .find(false, "ssh-ed25519")
+                            .unwrap(),
+                        kex_algorithm: self.kex_algorithm,
+                    };
+                    let response =
+                        server::do_key_exchange(params, &self.host_key, &mut self.rng).unwrap();
+
+                    self.transport.queue_packet(Packet::new_msg_kex_ecdh_reply(
+                        &self.host_key.private_key.public_key().to_wire_encoding(),
+                        &response.server_ephemeral_public_key,
+                        &response.signature.to_wire_encoding(),
+                    ));
+                    self.transport.queue_packet(Packet {
+                        payload: vec![numbers::SSH_MSG_NEWKEYS],
+                    });
+                    self.new_keys = Some((response.hash.0, response.shared_secret));
+                }
+                numbers::SSH_MSG_NEWKEYS => {
+                    let (h, k) = self.new_keys.take().unwrap();
+                    self.transport.set_key(
+                        h,
+                        &k,
+                        crypto::encrypt::CHACHA20POLY1305,
+                        crypto::encrypt::CHACHA20POLY1305,
+                        self.compression,
+                        self.compression,
+                        true,
+                    );
+                    if self.strict_kex {
+                        self.transport.reset_sequence_numbers();
+                    }
+                    if let Some(ext_info) = self.ext_info.take() {
+                        self.transport.queue_packet(ext_info);
+                    }
+                }
+                numbers::SSH_MSG_SERVICE_REQUEST => {
+                    self.transport
+                        .queue_packet(Packet::new_msg_service_accept(b"ssh-userauth"));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn data_packet(i: u32) -> Packet {
+        Packet::new_msg_channel_data(0, &i.to_be_bytes())
+    }
+
+    /// Does the initial key exchange.
+    fn connect() -> (ClientConnection, TestServer) {
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = TestServer::new(&mut client);
+        handshake(&mut client, &mut server);
+        (client, server)
+    }
+
+    fn handshake(client: &mut ClientConnection, server: &mut TestServer) {
+        server.send_kexinit();
+        server.send_to(client);
+        server.recv_from(client);
+        server.send_to(client);
+        server.recv_from(client);
+        server.send_to(client);
+        assert!(client.is_open().is_some());
+        server.received.clear();
+    }
+
+    /// Asserts that only key exchange packets are between KEXINIT and NEWKEYS.
+    fn assert_rekeyed_once(received: &[Packet]) {
+        let types = received
+            .iter()
+            .map(|packet| packet.packet_type())
+            .collect::<Vec<_>>();
+        let kexinit = types
+            .iter()
+            .position(|&ty| ty == numbers::SSH_MSG_KEXINIT)
+            .unwrap();
+        let newkeys = types
+            .iter()
+            .position(|&ty| ty == numbers::SSH_MSG_NEWKEYS)
+            .unwrap();
+        assert_eq!(
+            types[kexinit..=newkeys],
+            [
+                numbers::SSH_MSG_KEXINIT,
+                numbers::SSH_MSG_KEX_ECDH_INIT,
+                numbers::SSH_MSG_NEWKEYS
+            ]
+        );
+    }
+
+    fn data_payloads(packets: &[Packet]) -> Vec<Vec<u8>> {
+        packets
+            .iter()
+            .filter(|packet| packet.packet_type() == numbers::SSH_MSG_CHANNEL_DATA)
+            .map(|packet| packet.payload.clone())
+            .collect()
+    }
+
+    /// Does the initial key exchange, with a man in the middle changing the transcript
+    /// that the server sees after the server KEXINIT has been sent.
+    fn assert_tampered_handshake_aborts(tamper: impl FnOnce(&mut TestServer)) {
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = TestServer::new(&mut client);
+        server.send_kexinit();
+        tamper(&mut server);
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+
+        let mut result = Ok(());
+        while let Some(msg) = server.transport.next_msg_to_send() {
+            result = result.and(client.recv_bytes(&msg.to_bytes()));
+        }
+        let Err(crate::SshStatus::PeerError(err)) = result else {
+            panic!("client accepted a tampered key exchange");
+        };
+        assert!(err.contains("exchange hash"), "{err}");
+        assert!(client.is_open().is_none());
+
+        // The client tells the server why it aborts.
+        let msg = client.next_msg_to_send().unwrap();
+        let _ = server.transport.recv_bytes(&msg.to_bytes()).unwrap();
+        let disconnect = server.transport.recv_next_packet().unwrap();
+        let mut p = disconnect.payload_parser();
+        assert_eq!(p.u8().unwrap(), numbers::SSH_MSG_DISCONNECT);
+        assert_eq!(
+            p.u32().unwrap(),
+            numbers::SSH_DISCONNECT_KEY_EXCHANGE_FAILED
+        );
+    }
+
+    #[test]
+    fn tampered_client_ident_aborts() {
+        assert_tampered_handshake_aborts(|server| {
+            server.client_ident = b"SSH-2.0-Tampered\r\n".to_vec();
+        });
+    }
+
+    #[test]
+    fn tampered_kexinit_aborts() {
+        assert_tampered_handshake_aborts(|server| {
+            // The cookie of the KEXINIT the client received was changed.
+            server.server_kexinit[1] ^= 1;
+        });
+    }
+
+    #[test]
+    fn client_rekey_after_bytes() {
+        let (mut client, mut server) = connect();
+        client.rekey_limits.bytes = 100;
+
+        // Each data packet has 13 bytes, so the 8th one starts the key exchange.
+        for i in 0..10 {
+            client.send_plaintext_packet(data_packet(i));
+        }
+        // The server sends data before it has seen the KEXINIT.
+        for i in 100..105 {
+            server.transport.queue_packet(data_packet(i));
+        }
+        server.send_to(&mut client);
+
+        server.recv_from(&mut client);
+        server.send_kexinit();
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+        assert!(client.is_open().is_some());
+
+        assert_rekeyed_once(&server.received);
+        let expected = (0..10).map(|i| data_packet(i).payload).collect::<Vec<_>>();
+        assert_eq!(data_payloads(&server.received), expected);
+
+        let client_received =
+            std::iter::from_fn(|| client.next_plaintext_packet()).collect::<Vec<_>>();
+        let expected = (100..105)
+            .map(|i| data_packet(i).payload)
+            .collect::<Vec<_>>();
+        assert_eq!(data_payloads(&client_received), expected);
+
+        // The counter has been reset.
+        client.send_plaintext_packet(data_packet(10));
+        server.recv_from(&mut client);
+        assert_eq!(
+            server.received.last().unwrap().packet_type(),
+            numbers::SSH_MSG_CHANNEL_DATA
+        );
+    }
+
+    #[test]
+    fn ext_info() {
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = TestServer::new(&mut client);
+        // The server sends its extensions as the first packet after NEWKEYS.
+        server.ext_info = Some(Packet::new_msg_ext_info_server_sig_algs(
+            1,
+            b"server-sig-algs",
+            NameList::multi("ssh-ed25519,ecdsa-sha2-nistp256"),
+        ));
+        handshake(&mut client, &mut server);
+
+        let kexinit = KeyExchangeInitPacket::parse(&server.client_kexinit).unwrap();
+        assert!(kexinit.kex_algorithms.contains("ext-info-c"));
+        assert_eq!(
+            client.connection_info().unwrap().server_sig_algs(),
+            Some(vec!["ssh-ed25519", "ecdsa-sha2-nistp256"])
+        );
+
+        // And may send them again before authentication succeeds, which replaces the old value.
+        server
+            .transport
+            .queue_packet(Packet::new_msg_ext_info_server_sig_algs(
+                1,
+                b"server-sig-algs",
+                NameList::one("ssh-ed25519"),
+            ));
+        server.send_to(&mut client);
+        assert!(client.next_plaintext_packet().is_none());
+        assert_eq!(
+            client.connection_info().unwrap().server_sig_algs(),
+            Some(vec!["ssh-ed25519"])
+        );
+
+        // The extensions survive a key re-exchange.
+        client.rekey_limits.bytes = 0;
+        client.send_plaintext_packet(data_packet(0));
+        server.recv_from(&mut client);
+        server.send_kexinit();
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+        assert_eq!(
+            client.connection_info().unwrap().server_sig_algs(),
+            Some(vec!["ssh-ed25519"])
+        );
+    }
+
+    #[test]
+    fn packet_capture() {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let mut client = ClientConnection::new(TestRng(0));
+        let captured2 = captured.clone();
+        client.set_packet_capture(Some(Box::new(move |direction, payload| {
+            captured2
+                .lock()
+                .unwrap()
+                .push((direction, payload.to_vec()));
+        })));
+        let mut server = TestServer::new(&mut client);
+        handshake(&mut client, &mut server);
+
+        client.send_plaintext_packet(data_packet(0));
+        server.transport.queue_packet(data_packet(1));
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+
+        let captured = captured.lock().unwrap();
+        let types = captured
+            .iter()
+            .map(|(direction, payload)| (*direction, payload[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                (PacketDirection::Outbound, numbers::SSH_MSG_KEXINIT),
+                (PacketDirection::Inbound, numbers::SSH_MSG_KEXINIT),
+                (PacketDirection::Outbound, numbers::SSH_MSG_KEX_ECDH_INIT),
+                (PacketDirection::Inbound, numbers::SSH_MSG_KEX_ECDH_REPLY),
+                (PacketDirection::Outbound, numbers::SSH_MSG_NEWKEYS),
+                (PacketDirection::Inbound, numbers::SSH_MSG_NEWKEYS),
+                (PacketDirection::Outbound, numbers::SSH_MSG_SERVICE_REQUEST),
+                (PacketDirection::Inbound, numbers::SSH_MSG_SERVICE_ACCEPT),
+                (PacketDirection::Outbound, numbers::SSH_MSG_CHANNEL_DATA),
+                (PacketDirection::Inbound, numbers::SSH_MSG_CHANNEL_DATA),
+            ]
+        );
+        // The payloads are the plaintext.
+        assert_eq!(captured[8].1, data_packet(0).payload);
+        assert_eq!(captured[9].1, data_packet(1).payload);
+        assert_eq!(captured[1].1, server.server_kexinit);
+    }
+
+    #[test]
+    fn strict_kex() {
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = TestServer::new(&mut client);
+        server.strict_kex = true;
+        handshake(&mut client, &mut server);
+
+        // The client only advertises strict key exchange in the initial KEXINIT.
+        let kexinit = KeyExchangeInitPacket::parse(&server.client_kexinit).unwrap();
+        assert!(kexinit.kex_algorithms.contains(crate::KEX_STRICT_CLIENT));
+
+        // Both sides reset their sequence numbers, otherwise the packets would not decrypt.
+        client.send_plaintext_packet(data_packet(0));
+        server.transport.queue_packet(data_packet(100));
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+        assert_eq!(data_payloads(&server.received), [data_packet(0).payload]);
+        assert_eq!(
+            client.next_plaintext_packet().unwrap().payload,
+            data_packet(100).payload
+        );
+
+        // And again after a key re-exchange.
+        client.rekey_limits.bytes = 0;
+        client.send_plaintext_packet(data_packet(1));
+        server.recv_from(&mut client);
+        server.send_kexinit();
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+        server.recv_from(&mut client);
+        assert!(client.is_open().is_some());
+        let kexinit = KeyExchangeInitPacket::parse(&server.client_kexinit).unwrap();
+        assert!(!kexinit.kex_algorithms.contains(crate::KEX_STRICT_CLIENT));
+
+        client.rekey_limits.bytes = u64::MAX;
+        client.send_plaintext_packet(data_packet(2));
+        server.recv_from(&mut client);
+        assert_eq!(
+            data_payloads(&server.received),
+            [0, 1, 2].map(|i| data_packet(i).payload)
+        );
+    }
+
+    #[test]
+    fn strict_kex_rejects_packets_before_kexinit() {
+        for strict_kex in [false, true] {
+            let mut client = ClientConnection::new(TestRng(0));
+            let mut server = TestServer::new(&mut client);
+            server.strict_kex = strict_kex;
+            // A man in the middle can inject this to shift the sequence numbers.
+            server.transport.queue_packet(Packet::new_msg_ignore(b""));
+            server.send_kexinit();
+
+            let mut result = Ok(());
+            while let Some(msg) = server.transport.next_msg_to_send() {
+                result = result.and(client.recv_bytes(&msg.to_bytes()));
+            }
+            if strict_kex {
+                let Err(crate::SshStatus::PeerError(err)) = result else {
+                    panic!("client accepted a packet before KEXINIT");
+                };
+                assert!(err.contains("strict key exchange"), "{err}");
+            } else {
+                result.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn client_rekey_after_time() {
+        let (mut client, mut server) = connect();
+        client.rekey_limits.time = Duration::ZERO;
+
+        client.send_plaintext_packet(data_packet(0));
+        server.recv_from(&mut client);
+        assert_eq!(
+            server
+                .received
+                .iter()
+                .map(|packet| packet.packet_type())
+                .collect::<Vec<_>>(),
+            [numbers::SSH_MSG_KEXINIT]
+        );
+    }
+
+    #[test]
+    fn server_rekey_during_transfer() {
+        let (mut client, mut server) = connect();
+
+        // Start the transfer.
+        for i in 0..10 {
+            client.send_plaintext_packet(data_packet(i));
+        }
+        server.recv_from(&mut client);
+
+        // The server starts a key re-exchange, but the client keeps sending.
+        server.send_kexinit();
+        server.send_to(&mut client);
+        for i in 10..20 {
+            client.send_plaintext_packet(data_packet(i));
+        }
+        server.recv_from(&mut client);
+        server.send_to(&mut client);
+        for i in 20..30 {
+            client.send_plaintext_packet(data_packet(i));
+        }
+        server.recv_from(&mut client);
+        assert!(client.is_open().is_some());
+        for i in 30..40 {
+            client.send_plaintext_packet(data_packet(i));
+        }
+        server.recv_from(&mut client);
+
+        assert_rekeyed_once(&server.received);
+        let expected = (0..40).map(|i| data_packet(i).payload).collect::<Vec<_>>();
+        assert_eq!(data_payloads(&server.received), expected);
+    }
+
+    #[test]
+    fn delayed_compression() {
+        let mut client = ClientConnection::new(TestRng(0));
+        client.compression = true;
+        let mut server = TestServer::new(&mut client);
+        server.compression = CompressionAlgorithm::ZlibOpenSsh;
+        handshake(&mut client, &mut server);
+
+        let data = Packet::new_msg_channel_data(0, &[0; 10_000]);
+
+        // Nothing is compressed before authentication.
+        client.send_plaintext_packet(Packet::new_msg_channel_data(0, &[0; 10_000]));
+        let msg = client.next_msg_to_send().unwrap().to_bytes();
+        assert!(msg.len() > 10_000);
+        let _ = server.transport.recv_bytes(&msg).unwrap();
+        assert_eq!(server.transport.recv_next_packet().unwrap(), data);
+
+        server
+            .transport
+            .queue_packet(Packet::new_msg_userauth_success());
+        server
+            .transport
+            .queue_packet(Packet::new_msg_channel_data(0, &[0; 10_000]));
+        // Both packets are received at once, the second one already needs to be decompressed.
+        let msgs = std::iter::from_fn(|| server.transport.next_msg_to_send())
+            .flat_map(|msg| msg.to_bytes())
+            .collect::<Vec<_>>();
+        assert!(msgs.len() < 1000);
+        client.recv_bytes(&msgs).unwrap();
+        assert_eq!(
+            client.next_plaintext_packet().unwrap().packet_type(),
+            numbers::SSH_MSG_USERAUTH_SUCCESS
+        );
+        assert_eq!(client.next_plaintext_packet().unwrap(), data);
+
+        client.send_plaintext_packet(Packet::new_msg_channel_data(0, &[0; 10_000]));
+        let msg = client.next_msg_to_send().unwrap().to_bytes();
+        assert!(msg.len() < 1000);
+        let _ = server.transport.recv_bytes(&msg).unwrap();
+        assert_eq!(server.transport.recv_next_packet().unwrap(), data);
+    }
+
+    #[test]
+    fn connection_info() {
+        let (client, server) = connect();
+        assert_eq!(
+            client.connection_info(),
+            Some(&ConnectionInfo {
+                server_identification: "SSH-2.0-TestServer".to_owned(),
+                kex_algorithm: "curve25519-sha256",
+                host_key_algorithm: "ssh-ed25519",
+                encryption_client_to_server: "chacha20-poly1305@openssh.com",
+                encryption_server_to_client: "chacha20-poly1305@openssh.com",
+                mac_client_to_server: "hmac-sha2-256",
+                mac_server_to_client: "hmac-sha2-256",
+                compression_client_to_server: "none",
+                compression_server_to_client: "none",
+                host_key: server.host_key.private_key.public_key().to_wire_encoding(),
+                extensions: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn key_exchange_algorithms() {
+        for kex_algorithm in [
+            crypto::KEX_CURVE_25519_SHA256,
+            crypto::KEX_CURVE_25519_SHA256_LIBSSH,
+            crypto::KEX_ECDH_SHA2_NISTP256,
+            crypto::KEX_DH_GROUP14_SHA256,
+        ] {
+            let mut client = ClientConnection::new(TestRng(0));
+            let mut server = TestServer::new(&mut client);
+            server.kex_algorithm = kex_algorithm;
+            handshake(&mut client, &mut server);
+            assert_eq!(
+                client.connection_info().unwrap().kex_algorithm,
+                kex_algorithm.name()
+            );
+
+            client.send_plaintext_packet(data_packet(1));
+            server.recv_from(&mut client);
+            assert_eq!(data_payloads(&server.received), [data_packet(1).payload]);
+        }
+    }
+
+    #[test]
+    fn prefer_known_host_key_algorithms() {
+        let client_host_key_algorithms = |client: &mut ClientConnection| {
+            let mut server = TestServer::new(client);
+            handshake(client, &mut server);
+            let kexinit = KeyExchangeInitPacket::parse(&server.client_kexinit).unwrap();
+            kexinit.server_host_key_algorithms.0.to_owned()
+        };
+
+        let mut client = ClientConnection::new(TestRng(0));
+        assert_eq!(
+            client_host_key_algorithms(&mut client),
+            "ecdsa-sha2-nistp256,ssh-ed25519"
+        );
+
+        let known_hosts = KnownHosts::parse(
+            "example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG0n1ikUG9rYqobh7WpAyXrqZqxQoQ2zNJrFPj12gTpP\n",
+        );
+        let mut client = ClientConnection::new(TestRng(0));
+        client.preferred_host_key_algorithms = known_hosts
+            .find("example.com")
+            .map(|key| key.algorithm_name().to_owned())
+            .collect();
+        assert_eq!(
+            client_host_key_algorithms(&mut client),
+            "ssh-ed25519,ecdsa-sha2-nistp256"
+        );
+    }
+
+    #[test]
+    fn server_identification_after_other_lines() {
+        let mut client = ClientConnection::new(TestRng(0));
+        assert_eq!(client.server_identification(), None);
+
+        client
+            .recv_bytes(b"Welcome to the server\r\nSSH-2.0-Nonsense\r")
+            .unwrap();
+        assert_eq!(client.server_identification(), None);
+        client.recv_bytes(b"\n").unwrap();
+        assert_eq!(
+            client.server_identification(),
+            Some(&b"SSH-2.0-Nonsense"[..])
+        );
+    }
+}
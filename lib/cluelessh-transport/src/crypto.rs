@@ -1,12 +1,16 @@
 pub mod encrypt;
+pub mod mac;
 
 use cluelessh_keys::{public::PublicKey, signature::Signature};
+use crypto_bigint::modular::runtime_mod::{DynResidue, DynResidueParams};
+use crypto_bigint::{Encoding, U2048};
 use p256::ecdsa::signature::Verifier;
 use secrecy::ExposeSecret;
 use sha2::Digest;
+use subtle::ConstantTimeEq;
 
 use crate::{
-    packet::{EncryptedPacket, MsgKind, Packet, RawPacket},
+    packet::{CompressionAlgorithm, EncryptedPacket, MsgKind, Packet, RawPacket},
     peer_error, Msg, Result, SessionId, SshRng,
 };
 
@@ -54,7 +58,9 @@ pub struct KeyExchangeSecret {
 pub fn kex_algorithm_by_name(name: &str) -> Option<KexAlgorithm> {
     match name {
         "curve25519-sha256" => Some(KEX_CURVE_25519_SHA256),
+        "curve25519-sha256@libssh.org" => Some(KEX_CURVE_25519_SHA256_LIBSSH),
         "ecdh-sha2-nistp256" => Some(KEX_ECDH_SHA2_NISTP256),
+        "diffie-hellman-group14-sha256" => Some(KEX_DH_GROUP14_SHA256),
         _ => None,
     }
 }
@@ -85,6 +91,11 @@ pub const KEX_CURVE_25519_SHA256: KexAlgorithm = KexAlgorithm {
         }
     },
 };
+/// The name curve25519-sha256 was used before it was standardized.
+pub const KEX_CURVE_25519_SHA256_LIBSSH: KexAlgorithm = KexAlgorithm {
+    name: "curve25519-sha256@libssh.org",
+    ..KEX_CURVE_25519_SHA256
+};
 /// <https://datatracker.ietf.org/doc/html/rfc5656>
 pub const KEX_ECDH_SHA2_NISTP256: KexAlgorithm = KexAlgorithm {
     name: "ecdh-sha2-nistp256",
@@ -113,20 +124,150 @@ pub const KEX_ECDH_SHA2_NISTP256: KexAlgorithm = KexAlgorithm {
     },
 };
 
+/// <https://datatracker.ietf.org/doc/html/rfc8268>
+pub const KEX_DH_GROUP14_SHA256: KexAlgorithm = KexAlgorithm {
+    name: "diffie-hellman-group14-sha256",
+    generate_secret: |rng| generate_dh_secret(rng, DH_GROUP14_PRIME),
+};
+
+/// The 2048-bit MODP group with generator 2.
+/// <https://datatracker.ietf.org/doc/html/rfc3526#section-3>
+const DH_GROUP14_PRIME: U2048 = U2048::from_be_hex(concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AACAA68FFFFFFFFFFFFFFFF",
+));
+/// The size of the private exponent.
+/// Like OpenSSH, use twice the bits of the hash, which is more than the security of the group.
+const DH_EXPONENT_BITS: usize = 512;
+
+/// Finite field Diffie-Hellman, where the public values e and f are sent as mpints.
+/// <https://datatracker.ietf.org/doc/html/rfc4253#section-8>
+fn generate_dh_secret(rng: &mut (dyn SshRng + Send + Sync), prime: U2048) -> KeyExchangeSecret {
+    let params = DynResidueParams::new(&prime);
+
+    let mut exponent = [0; U2048::BYTES];
+    rng.fill_bytes(&mut exponent[(U2048::BYTES - DH_EXPONENT_BITS / 8)..]);
+    let exponent = U2048::from_be_slice(&exponent); // x
+
+    let public_key = DynResidue::new(&U2048::from_u8(2), params)
+        .pow_bounded_exp(&exponent, DH_EXPONENT_BITS)
+        .retrieve(); // e
+
+    KeyExchangeSecret {
+        pubkey: encode_mpint(&public_key),
+        exchange: Box::new(move |peer_public_key| {
+            let peer_public_key = decode_mpint(peer_public_key)?; // f
+                                                                  // <https://datatracker.ietf.org/doc/html/rfc4253#section-8>
+            if peer_public_key <= U2048::ONE || peer_public_key >= prime.wrapping_sub(&U2048::ONE) {
+                return Err(peer_error!("invalid Diffie-Hellman public value"));
+            }
+
+            let shared_secret = DynResidue::new(&peer_public_key, params)
+                .pow_bounded_exp(&exponent, DH_EXPONENT_BITS)
+                .retrieve(); // K
+
+            Ok(secrecy::Secret::new(SharedSecretInner(
+                shared_secret.to_be_bytes().to_vec(),
+            )))
+        }),
+    }
+}
+
+/// The contents of an mpint, so that encoding it as a string is the same as encoding the mpint.
+fn encode_mpint(uint: &U2048) -> Vec<u8> {
+    let bytes = uint.to_be_bytes();
+    let (bytes, pad_zero) = cluelessh_format::fixup_mpint(&bytes);
+    let mut mpint = Vec::with_capacity(bytes.len() + 1);
+    if pad_zero {
+        mpint.push(0);
+    }
+    mpint.extend_from_slice(bytes);
+    mpint
+}
+
+fn decode_mpint(mpint: &[u8]) -> Result<U2048> {
+    if mpint.first().is_some_and(|&first| first & 0b10000000 != 0) {
+        return Err(peer_error!("negative mpint"));
+    }
+    let start = mpint.iter().take_while(|&&b| b == 0).count();
+    let mpint = &mpint[start..];
+    if mpint.len() > U2048::BYTES {
+        return Err(peer_error!("mpint is too large: {} bytes", mpint.len()));
+    }
+    let mut bytes = [0; U2048::BYTES];
+    bytes[(U2048::BYTES - mpint.len())..].copy_from_slice(mpint);
+    Ok(U2048::from_be_slice(&bytes))
+}
+
 #[derive(Clone, Copy)]
 pub struct EncryptionAlgorithm {
     name: &'static str,
     iv_size: usize,
     key_size: usize,
-    decrypt_len: fn(state: &mut [u8], bytes: &mut [u8], packet_number: u64),
-    decrypt_packet: fn(state: &mut [u8], bytes: RawPacket, packet_number: u64) -> Result<Packet>,
-    encrypt_packet: fn(state: &mut [u8], packet: Packet, packet_number: u64) -> EncryptedPacket,
+    cipher: Cipher,
 }
 impl AlgorithmName for EncryptionAlgorithm {
     fn name(&self) -> &'static str {
         self.name
     }
 }
+
+#[derive(Clone, Copy)]
+enum Cipher {
+    /// Authenticated encryption, the negotiated MAC is not used.
+    Aead(AeadCipher),
+    /// A cipher that is authenticated with the negotiated MAC.
+    Unauthenticated {
+        block_size: usize,
+        /// Encrypts or decrypts the bytes, advancing the state.
+        apply_keystream: fn(state: &mut [u8], bytes: &mut [u8]),
+        /// Set after negotiation by [`EncryptionAlgorithm::with_mac`].
+        mac: Option<MacAlgorithm>,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct AeadCipher {
+    decrypt_len: fn(state: &mut [u8], bytes: &mut [u8], packet_number: u64),
+    decrypt_packet: fn(state: &mut [u8], bytes: RawPacket, packet_number: u64) -> Result<Packet>,
+    encrypt_packet: fn(state: &mut [u8], packet: Packet, packet_number: u64) -> EncryptedPacket,
+}
+
+impl EncryptionAlgorithm {
+    /// Uses the negotiated MAC for ciphers that need one, AEAD ciphers ignore it.
+    pub fn with_mac(mut self, mac: MacAlgorithm) -> Self {
+        if let Cipher::Unauthenticated { mac: mac_alg, .. } = &mut self.cipher {
+            *mac_alg = Some(mac);
+        }
+        self
+    }
+
+    fn mac(&self) -> Option<MacAlgorithm> {
+        match self.cipher {
+            Cipher::Aead(_) => None,
+            Cipher::Unauthenticated { mac, .. } => {
+                Some(mac.expect("MAC is negotiated before the cipher is used"))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MacAlgorithm {
+    name: &'static str,
+    key_size: usize,
+    mac_len: usize,
+    /// Encrypt-then-MAC: The MAC is computed over the encrypted packet, and the length is not encrypted.
+    /// Otherwise, it is computed over the plaintext packet before it is encrypted.
+    etm: bool,
+    compute: fn(key: &[u8], packet_number: u64, data: &[u8]) -> Vec<u8>,
+}
 pub struct EncodedSshSignature(pub Vec<u8>);
 
 #[derive(Clone)]
@@ -234,7 +375,7 @@ impl<T: AlgorithmName> AlgorithmNegotiation<T> {
         };
 
         for alg_name in client_algs {
-            if server_algs.iter().any(|peer| *peer == alg_name) {
+            if server_algs.contains(&alg_name) {
                 // Algorithm is supported
                 if let Some(alg) = self.supported.iter().position(|alg| alg.name() == alg_name) {
                     return Ok(self.supported.remove(alg));
@@ -261,14 +402,14 @@ pub struct SupportedAlgorithms {
     pub hostkey_verify: AlgorithmNegotiation<HostKeyVerifyAlgorithm>,
     pub encryption_to_peer: AlgorithmNegotiation<EncryptionAlgorithm>,
     pub encryption_from_peer: AlgorithmNegotiation<EncryptionAlgorithm>,
-    pub mac_to_peer: AlgorithmNegotiation<&'static str>,
-    pub mac_from_peer: AlgorithmNegotiation<&'static str>,
-    pub compression_to_peer: AlgorithmNegotiation<&'static str>,
-    pub compression_from_peer: AlgorithmNegotiation<&'static str>,
+    pub mac_to_peer: AlgorithmNegotiation<MacAlgorithm>,
+    pub mac_from_peer: AlgorithmNegotiation<MacAlgorithm>,
+    pub compression_to_peer: AlgorithmNegotiation<CompressionAlgorithm>,
+    pub compression_from_peer: AlgorithmNegotiation<CompressionAlgorithm>,
 }
 
 impl SupportedAlgorithms {
-    /// A secure default using elliptic curves and AEAD.
+    /// A secure default using elliptic curves, preferring AEAD.
     pub fn secure(host_keys: &[PublicKey]) -> Self {
         let supported_host_keys = host_keys
             .iter()
@@ -277,7 +418,12 @@ impl SupportedAlgorithms {
 
         Self {
             key_exchange: AlgorithmNegotiation {
-                supported: vec![KEX_CURVE_25519_SHA256, KEX_ECDH_SHA2_NISTP256],
+                supported: vec![
+                    KEX_CURVE_25519_SHA256,
+                    KEX_CURVE_25519_SHA256_LIBSSH,
+                    KEX_ECDH_SHA2_NISTP256,
+                    KEX_DH_GROUP14_SHA256,
+                ],
             },
             hostkey_sign: AlgorithmNegotiation {
                 supported: supported_host_keys,
@@ -286,22 +432,52 @@ impl SupportedAlgorithms {
                 supported: vec![HOSTKEY_VERIFY_ECDSA_SHA2_NISTP256, HOSTKEY_VERIFY_ED25519],
             },
             encryption_to_peer: AlgorithmNegotiation {
-                supported: vec![encrypt::CHACHA20POLY1305, encrypt::AES256_GCM],
+                supported: vec![
+                    encrypt::CHACHA20POLY1305,
+                    encrypt::AES256_GCM,
+                    encrypt::AES128_GCM,
+                    encrypt::AES256_CTR,
+                    encrypt::AES128_CTR,
+                ],
             },
             encryption_from_peer: AlgorithmNegotiation {
-                supported: vec![encrypt::CHACHA20POLY1305, encrypt::AES256_GCM],
+                supported: vec![
+                    encrypt::CHACHA20POLY1305,
+                    encrypt::AES256_GCM,
+                    encrypt::AES128_GCM,
+                    encrypt::AES256_CTR,
+                    encrypt::AES128_CTR,
+                ],
             },
             mac_to_peer: AlgorithmNegotiation {
-                supported: vec!["hmac-sha2-256", "hmac-sha2-256-etm@openssh.com"],
+                supported: vec![
+                    mac::HMAC_SHA2_256_ETM,
+                    mac::HMAC_SHA2_512_ETM,
+                    mac::HMAC_SHA2_256,
+                    mac::HMAC_SHA2_512,
+                ],
             },
             mac_from_peer: AlgorithmNegotiation {
-                supported: vec!["hmac-sha2-256", "hmac-sha2-256-etm@openssh.com"],
+                supported: vec![
+                    mac::HMAC_SHA2_256_ETM,
+                    mac::HMAC_SHA2_512_ETM,
+                    mac::HMAC_SHA2_256,
+                    mac::HMAC_SHA2_512,
+                ],
             },
             compression_to_peer: AlgorithmNegotiation {
-                supported: vec!["none"],
+                supported: vec![
+                    CompressionAlgorithm::None,
+                    CompressionAlgorithm::ZlibOpenSsh,
+                    CompressionAlgorithm::Zlib,
+                ],
             },
             compression_from_peer: AlgorithmNegotiation {
-                supported: vec!["none"],
+                supported: vec![
+                    CompressionAlgorithm::None,
+                    CompressionAlgorithm::ZlibOpenSsh,
+                    CompressionAlgorithm::Zlib,
+                ],
             },
         }
     }
@@ -316,11 +492,118 @@ pub(crate) struct Session {
 struct Tunnel {
     /// `key || IV`
     state: Vec<u8>,
+    /// The integrity key, empty for AEAD ciphers.
+    mac_key: Vec<u8>,
     algorithm: EncryptionAlgorithm,
 }
 
+impl Tunnel {
+    fn decrypt_len(&mut self, bytes: &mut [u8; 4], packet_number: u64) {
+        match self.algorithm.cipher {
+            Cipher::Aead(aead) => (aead.decrypt_len)(&mut self.state, bytes, packet_number),
+            Cipher::Unauthenticated {
+                apply_keystream, ..
+            } => {
+                if !self.algorithm.mac().unwrap().etm {
+                    // The length is decrypted again with the rest of the packet.
+                    apply_keystream(&mut self.state.clone(), bytes);
+                }
+            }
+        }
+    }
+
+    fn decrypt_packet(&mut self, mut raw: RawPacket, packet_number: u64) -> Result<Packet> {
+        let (block_size, apply_keystream, mac) = match self.algorithm.cipher {
+            Cipher::Aead(aead) => {
+                return (aead.decrypt_packet)(&mut self.state, raw, packet_number)
+            }
+            Cipher::Unauthenticated {
+                block_size,
+                apply_keystream,
+                ..
+            } => (block_size, apply_keystream, self.algorithm.mac().unwrap()),
+        };
+
+        let mac_start = raw.raw.len() - mac.mac_len;
+        let (packet, read_mac) = raw.raw.split_at_mut(mac_start);
+        // The length is not encrypted with encrypt-then-MAC.
+        let encrypted_len = if mac.etm {
+            packet.len() - 4
+        } else {
+            packet.len()
+        };
+        if encrypted_len % block_size != 0 {
+            return Err(peer_error!(
+                "packet length {encrypted_len} is not a multiple of the block size {block_size}"
+            ));
+        }
+
+        // With encrypt-then-MAC, nothing is decrypted before the MAC has been verified.
+        if mac.etm {
+            verify_mac(mac, &self.mac_key, packet_number, packet, read_mac)?;
+            apply_keystream(&mut self.state, &mut packet[4..]);
+        } else {
+            apply_keystream(&mut self.state, packet);
+            verify_mac(mac, &self.mac_key, packet_number, packet, read_mac)?;
+        }
+
+        Packet::from_full(raw.content_mut())
+    }
+
+    fn encrypt_packet(&mut self, packet: Packet, packet_number: u64) -> EncryptedPacket {
+        let (block_size, apply_keystream, mac) = match self.algorithm.cipher {
+            Cipher::Aead(aead) => {
+                return (aead.encrypt_packet)(&mut self.state, packet, packet_number)
+            }
+            Cipher::Unauthenticated {
+                block_size,
+                apply_keystream,
+                ..
+            } => (block_size, apply_keystream, self.algorithm.mac().unwrap()),
+        };
+
+        let mut bytes = packet.to_bytes(!mac.etm, block_size as u8);
+        let tag = if mac.etm {
+            apply_keystream(&mut self.state, &mut bytes[4..]);
+            (mac.compute)(&self.mac_key, packet_number, &bytes)
+        } else {
+            let tag = (mac.compute)(&self.mac_key, packet_number, &bytes);
+            apply_keystream(&mut self.state, &mut bytes);
+            tag
+        };
+        bytes.extend_from_slice(&tag);
+
+        EncryptedPacket::from_encrypted_full_bytes(bytes)
+    }
+
+    fn additional_mac_len(&self) -> usize {
+        match self.algorithm.mac() {
+            Some(mac) => mac.mac_len,
+            // All AEAD ciphers have 16 byte tags.
+            None => poly1305::BLOCK_SIZE,
+        }
+    }
+}
+
+/// Compares the MACs in constant time.
+fn verify_mac(
+    mac: MacAlgorithm,
+    key: &[u8],
+    packet_number: u64,
+    packet: &[u8],
+    read_mac: &[u8],
+) -> Result<()> {
+    let expected = (mac.compute)(key, packet_number, packet);
+    if !bool::from(expected.ct_eq(read_mac)) {
+        return Err(peer_error!("invalid {} MAC", mac.name));
+    }
+    Ok(())
+}
+
 pub(crate) trait Keys: Send + Sync + 'static {
     fn decrypt_len(&mut self, bytes: &mut [u8; 4], packet_number: u64);
+    /// Verifies the MAC or tag of the packet and decrypts it.
+    /// MACs must be compared in constant time, to not leak how much of a forged MAC is correct.
     fn decrypt_packet(&mut self, raw_packet: RawPacket, packet_number: u64) -> Result<Packet>;
 
     fn encrypt_packet_to_msg(&mut self, packet: Packet, packet_number: u64) -> Msg;
@@ -388,6 +671,7 @@ impl Session {
         alg_s2c: EncryptionAlgorithm,
         is_server: bool,
     ) -> Self {
+        let mac_key_size = |alg: EncryptionAlgorithm| alg.mac().map_or(0, |mac| mac.key_size);
         let c2s = Tunnel {
             algorithm: alg_c2s,
             state: {
@@ -396,6 +680,7 @@ impl Session {
                 state.extend_from_slice(&iv);
                 state
             },
+            mac_key: derive_key(k, h, "E", session_id, mac_key_size(alg_c2s)),
         };
         let s2c = Tunnel {
             algorithm: alg_s2c,
@@ -404,6 +689,7 @@ impl Session {
                 state.extend_from_slice(&derive_key(k, h, "B", session_id, alg_s2c.iv_size));
                 state
             },
+            mac_key: derive_key(k, h, "F", session_id, mac_key_size(alg_s2c)),
         };
 
         let (from_peer, to_peer) = if is_server { (c2s, s2c) } else { (s2c, c2s) };
@@ -412,29 +698,26 @@ impl Session {
             session_id,
             from_peer,
             to_peer,
-            // integrity_key_client_to_server: derive("E").into(),
-            // integrity_key_server_to_client: derive("F").into(),
         }
     }
 }
 
 impl Keys for Session {
     fn decrypt_len(&mut self, bytes: &mut [u8; 4], packet_number: u64) {
-        (self.from_peer.algorithm.decrypt_len)(&mut self.from_peer.state, bytes, packet_number);
+        self.from_peer.decrypt_len(bytes, packet_number);
     }
 
     fn decrypt_packet(&mut self, bytes: RawPacket, packet_number: u64) -> Result<Packet> {
-        (self.from_peer.algorithm.decrypt_packet)(&mut self.from_peer.state, bytes, packet_number)
+        self.from_peer.decrypt_packet(bytes, packet_number)
     }
 
     fn encrypt_packet_to_msg(&mut self, packet: Packet, packet_number: u64) -> Msg {
-        let packet =
-            (self.to_peer.algorithm.encrypt_packet)(&mut self.to_peer.state, packet, packet_number);
+        let packet = self.to_peer.encrypt_packet(packet, packet_number);
         Msg(MsgKind::EncryptedPacket(packet))
     }
 
     fn additional_mac_len(&self) -> usize {
-        poly1305::BLOCK_SIZE
+        self.from_peer.additional_mac_len()
     }
 
     fn rekey(
@@ -531,6 +814,7 @@ pub fn key_exchange_hash(
     // For normal DH as in RFC4253, e and f are mpints.
     // But for ECDH as defined in RFC5656, Q_C and Q_S are strings.
     // <https://datatracker.ietf.org/doc/html/rfc5656#section-4>
+    // Our DH public keys are already encoded as the contents of an mpint, so this works for both.
     hash_string(&mut hash, eph_client_public_key); // Q_C
     hash_string(&mut hash, eph_server_public_key); // Q_S
     hash_mpint(&mut hash, shared_secret.expose_secret().0.as_slice()); // K
@@ -541,7 +825,21 @@ pub fn key_exchange_hash(
 
 #[cfg(test)]
 mod tests {
-    use super::AlgorithmNegotiation;
+    use crypto_bigint::U2048;
+
+    use super::{
+        encrypt, mac, AlgorithmName, AlgorithmNegotiation, EncryptionAlgorithm, MacAlgorithm,
+        SupportedAlgorithms, Tunnel,
+    };
+    use crate::packet::{Packet, RawPacket};
+    use crate::SshRng;
+
+    struct TestRng;
+    impl SshRng for TestRng {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(0x42);
+        }
+    }
 
     #[test]
     fn alg_negotation() {
@@ -557,10 +855,7 @@ mod tests {
         };
 
         let chosen = we_are_client_negotiation
-            .find(
-                true,
-                &server_algs.iter().copied().collect::<Vec<&str>>().join(","),
-            )
+            .find(true, &server_algs.to_vec().join(","))
             .unwrap();
         assert_eq!(chosen, "ssh-ed25519");
 
@@ -568,11 +863,186 @@ mod tests {
             supported: server_algs.to_vec(),
         };
         let chosen = we_are_server_negotiation
+            .find(true, &client_algs.to_vec().join(","))
+            .unwrap();
+        assert_eq!(chosen, "ssh-ed25519");
+    }
+
+    #[test]
+    fn kex_negotiation() {
+        // The client's order decides, no matter who we are.
+        let chosen = SupportedAlgorithms::secure(&[])
+            .key_exchange
             .find(
                 true,
-                &client_algs.iter().copied().collect::<Vec<&str>>().join(","),
+                "diffie-hellman-group14-sha256,curve25519-sha256@libssh.org",
             )
             .unwrap();
-        assert_eq!(chosen, "ssh-ed25519");
+        assert_eq!(chosen.name(), "curve25519-sha256@libssh.org");
+
+        let chosen = SupportedAlgorithms::secure(&[])
+            .key_exchange
+            .find(
+                false,
+                "diffie-hellman-group14-sha256,curve25519-sha256@libssh.org",
+            )
+            .unwrap();
+        assert_eq!(chosen.name(), "diffie-hellman-group14-sha256");
+    }
+
+    #[test]
+    fn dh_group14() {
+        let client = (super::KEX_DH_GROUP14_SHA256.generate_secret)(&mut TestRng);
+        let server = (super::KEX_DH_GROUP14_SHA256.generate_secret)(&mut TestRng);
+        // The public key is encoded as a positive mpint.
+        assert!(client.pubkey[0] < 0x80);
+        assert!(client.pubkey.len() <= 257);
+
+        let client_public_key = client.pubkey.clone();
+        let k1 = (client.exchange)(&server.pubkey).unwrap();
+        let k2 = (server.exchange)(&client_public_key).unwrap();
+        assert_eq!(
+            secrecy::ExposeSecret::expose_secret(&k1).0,
+            secrecy::ExposeSecret::expose_secret(&k2).0
+        );
+    }
+
+    #[test]
+    fn dh_group14_rejects_invalid_public_values() {
+        let p_minus_one = super::encode_mpint(&super::DH_GROUP14_PRIME.wrapping_sub(&U2048::ONE));
+        let too_large = [&[0][..], &[0xff; 257]].concat();
+        for public_key in [
+            &[][..],
+            &[1],
+            &[0xff],
+            &p_minus_one,
+            &super::encode_mpint(&super::DH_GROUP14_PRIME),
+            &too_large,
+        ] {
+            let secret = (super::KEX_DH_GROUP14_SHA256.generate_secret)(&mut TestRng);
+            assert!((secret.exchange)(public_key).is_err());
+        }
+    }
+
+    fn tunnel(alg: EncryptionAlgorithm, mac: MacAlgorithm) -> Tunnel {
+        Tunnel {
+            state: (0..(alg.key_size + alg.iv_size) as u8).collect(),
+            mac_key: vec![0x0b; mac.key_size],
+            algorithm: alg.with_mac(mac),
+        }
+    }
+
+    /// Decrypts a packet like the packet parser.
+    fn decrypt(
+        tunnel: &mut Tunnel,
+        encrypted: Vec<u8>,
+        packet_number: u64,
+    ) -> crate::Result<Packet> {
+        let mut len = [0; 4];
+        len.copy_from_slice(&encrypted[..4]);
+        tunnel.decrypt_len(&mut len, packet_number);
+        assert_eq!(
+            u32::from_be_bytes(len) as usize,
+            encrypted.len() - 4 - tunnel.additional_mac_len()
+        );
+        let raw = RawPacket {
+            mac_len: tunnel.additional_mac_len(),
+            raw: encrypted,
+        };
+        tunnel.decrypt_packet(raw, packet_number)
+    }
+
+    const MACS: [MacAlgorithm; 4] = [
+        mac::HMAC_SHA2_256,
+        mac::HMAC_SHA2_512,
+        mac::HMAC_SHA2_256_ETM,
+        mac::HMAC_SHA2_512_ETM,
+    ];
+
+    #[test]
+    fn mac_roundtrip() {
+        for alg in [encrypt::AES128_CTR, encrypt::AES256_CTR] {
+            for mac in MACS {
+                let mut encryptor = tunnel(alg, mac);
+                let mut decryptor = tunnel(alg, mac);
+                // The counter of the cipher carries over between packets.
+                for (packet_number, len) in [(0, 5), (1, 1000), (2, 16)] {
+                    let packet = Packet::new_msg_channel_data(0, &vec![packet_number as u8; len]);
+                    let encrypted = encryptor
+                        .encrypt_packet(
+                            Packet::new_msg_channel_data(0, &vec![packet_number as u8; len]),
+                            packet_number,
+                        )
+                        .into_bytes();
+                    assert_eq!(
+                        decrypt(&mut decryptor, encrypted, packet_number).unwrap(),
+                        packet,
+                        "{} {}",
+                        alg.name(),
+                        mac.name()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mac_rejects_tampering() {
+        for mac in MACS {
+            let encrypted = tunnel(encrypt::AES128_CTR, mac)
+                .encrypt_packet(Packet::new_msg_channel_data(0, b"hello"), 0)
+                .into_bytes();
+
+            let mac_start = encrypted.len() - mac.mac_len;
+            for (i, bit) in [(mac_start, 1), (encrypted.len() - 1, 0x80), (10, 1)] {
+                let mut tampered = encrypted.clone();
+                tampered[i] ^= bit;
+                let raw = RawPacket {
+                    mac_len: mac.mac_len,
+                    raw: tampered,
+                };
+                let mut decryptor = tunnel(encrypt::AES128_CTR, mac);
+                assert!(decryptor.decrypt_packet(raw, 0).is_err(), "{}", mac.name());
+            }
+
+            // The sequence number is part of the MAC.
+            assert!(decrypt(&mut tunnel(encrypt::AES128_CTR, mac), encrypted, 1).is_err());
+        }
+    }
+
+    #[test]
+    fn tag_byte_flips_rejected() {
+        let mut algs = [
+            encrypt::CHACHA20POLY1305,
+            encrypt::AES256_GCM,
+            encrypt::AES128_GCM,
+        ]
+        .map(|alg| (alg, mac::HMAC_SHA2_256))
+        .to_vec();
+        for mac in MACS {
+            algs.push((encrypt::AES256_CTR, mac));
+        }
+
+        for (alg, mac) in algs {
+            let encrypted = tunnel(alg, mac)
+                .encrypt_packet(Packet::new_msg_channel_data(0, b"hello"), 0)
+                .into_bytes();
+            let mac_len = tunnel(alg, mac).additional_mac_len();
+
+            for i in (encrypted.len() - mac_len)..encrypted.len() {
+                let mut tampered = encrypted.clone();
+                tampered[i] ^= 0xff;
+                let raw = RawPacket {
+                    mac_len,
+                    raw: tampered,
+                };
+                assert!(
+                    tunnel(alg, mac).decrypt_packet(raw, 0).is_err(),
+                    "{} {} accepted a flipped byte {i}",
+                    alg.name(),
+                    mac.name()
+                );
+            }
+        }
     }
 }
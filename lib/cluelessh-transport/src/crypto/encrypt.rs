@@ -1,63 +1,91 @@
 use crate::Result;
-use aes_gcm::{aead::AeadMutInPlace, KeyInit};
-use chacha20::cipher::{StreamCipher, StreamCipherSeek};
+use aes_gcm::aead::consts::{U12, U16};
+use aes_gcm::aead::{AeadCore, AeadMutInPlace};
+use aes_gcm::KeyInit;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 use subtle::ConstantTimeEq;
 
 use crate::packet::{EncryptedPacket, Packet, RawPacket};
 
-use super::EncryptionAlgorithm;
+use super::{AeadCipher, Cipher, EncryptionAlgorithm};
 
 pub const CHACHA20POLY1305: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "chacha20-poly1305@openssh.com",
     iv_size: 0,
-    key_size: 64, // 32 for header, 32 for main
-    decrypt_len: |state, bytes, packet_number| {
-        let alg = ChaCha20Poly1305OpenSsh::from_state(state);
-        alg.decrypt_len(bytes, packet_number)
-    },
-    decrypt_packet: |state, bytes, packet_number| {
-        let alg = ChaCha20Poly1305OpenSsh::from_state(state);
-        alg.decrypt_packet(bytes, packet_number)
-    },
-    encrypt_packet: |state, packet, packet_number| {
-        let alg = ChaCha20Poly1305OpenSsh::from_state(state);
-        alg.encrypt_packet(packet, packet_number)
-    },
+    key_size: 64, // 32 for main, 32 for header
+    cipher: Cipher::Aead(AeadCipher {
+        decrypt_len: |state, bytes, packet_number| {
+            let alg = ChaCha20Poly1305OpenSsh::from_state(state);
+            alg.decrypt_len(bytes, packet_number)
+        },
+        decrypt_packet: |state, bytes, packet_number| {
+            let alg = ChaCha20Poly1305OpenSsh::from_state(state);
+            alg.decrypt_packet(bytes, packet_number)
+        },
+        encrypt_packet: |state, packet, packet_number| {
+            let alg = ChaCha20Poly1305OpenSsh::from_state(state);
+            alg.encrypt_packet(packet, packet_number)
+        },
+    }),
 };
 pub const AES256_GCM: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "aes256-gcm@openssh.com",
     iv_size: 12,
     key_size: 32,
-    decrypt_len: |state, bytes, packet_number| {
-        let mut alg = Aes256GcmOpenSsh::from_state(state);
-        alg.decrypt_len(bytes, packet_number)
-    },
-    decrypt_packet: |state, bytes, packet_number| {
-        let mut alg = Aes256GcmOpenSsh::from_state(state);
-        alg.decrypt_packet(bytes, packet_number)
-    },
-    encrypt_packet: |state, packet, packet_number| {
-        let mut alg = Aes256GcmOpenSsh::from_state(state);
-        alg.encrypt_packet(packet, packet_number)
-    },
+    cipher: Cipher::Aead(AeadCipher {
+        decrypt_len: |state, bytes, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes256Gcm>::from_state(state);
+            alg.decrypt_len(bytes, packet_number)
+        },
+        decrypt_packet: |state, bytes, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes256Gcm>::from_state(state);
+            alg.decrypt_packet(bytes, packet_number)
+        },
+        encrypt_packet: |state, packet, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes256Gcm>::from_state(state);
+            alg.encrypt_packet(packet, packet_number)
+        },
+    }),
+};
+pub const AES128_GCM: EncryptionAlgorithm = EncryptionAlgorithm {
+    name: "aes128-gcm@openssh.com",
+    iv_size: 12,
+    key_size: 16,
+    cipher: Cipher::Aead(AeadCipher {
+        decrypt_len: |state, bytes, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes128Gcm>::from_state(state);
+            alg.decrypt_len(bytes, packet_number)
+        },
+        decrypt_packet: |state, bytes, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes128Gcm>::from_state(state);
+            alg.decrypt_packet(bytes, packet_number)
+        },
+        encrypt_packet: |state, packet, packet_number| {
+            let mut alg = AesGcmOpenSsh::<aes_gcm::Aes128Gcm>::from_state(state);
+            alg.encrypt_packet(packet, packet_number)
+        },
+    }),
 };
-/// RFC 4344 AES128 in counter mode.
 /// <https://datatracker.ietf.org/doc/html/rfc4344#section-4>
-pub const ENC_AES128_CTR: EncryptionAlgorithm = EncryptionAlgorithm {
+pub const AES128_CTR: EncryptionAlgorithm = EncryptionAlgorithm {
     name: "aes128-ctr",
-    iv_size: 12,
-    key_size: 32,
-    decrypt_len: |state, bytes, packet_number| {
-        let mut alg = Aes128Ctr::from_state(state);
-        alg.decrypt_len(bytes, packet_number)
+    iv_size: 16,
+    key_size: 16,
+    cipher: Cipher::Unauthenticated {
+        block_size: 16,
+        apply_keystream: aes_ctr::<ctr::Ctr128BE<aes::Aes128>>,
+        mac: None,
     },
-    decrypt_packet: |state, bytes, packet_number| {
-        let mut state = Aes128Ctr::from_state(state);
-        state.decrypt_packet(bytes, packet_number)
-    },
-    encrypt_packet: |state, packet, packet_number| {
-        let mut state = Aes128Ctr::from_state(state);
-        state.encrypt_packet(packet, packet_number)
+};
+/// <https://datatracker.ietf.org/doc/html/rfc4344#section-4>
+pub const AES256_CTR: EncryptionAlgorithm = EncryptionAlgorithm {
+    name: "aes256-ctr",
+    iv_size: 16,
+    key_size: 32,
+    cipher: Cipher::Unauthenticated {
+        block_size: 16,
+        apply_keystream: aes_ctr::<ctr::Ctr128BE<aes::Aes256>>,
+        mac: None,
     },
 };
 
@@ -161,17 +189,22 @@ impl ChaCha20Poly1305OpenSsh {
 
 /// <https://datatracker.ietf.org/doc/html/rfc5647>
 /// <https://github.com/openssh/openssh-portable/blob/1ec0a64c5dc57b8a2053a93b5ef0d02ff8598e5c/PROTOCOL#L188C49-L188C64>
-struct Aes256GcmOpenSsh<'a> {
-    key: aes_gcm::Key<aes_gcm::Aes256Gcm>,
+struct AesGcmOpenSsh<'a, C: KeyInit> {
+    key: aes_gcm::Key<C>,
+    /// The fixed field (4 bytes) followed by the invocation counter (8 bytes).
     nonce: &'a mut [u8; 12],
 }
 
-impl<'a> Aes256GcmOpenSsh<'a> {
+impl<'a, C> AesGcmOpenSsh<'a, C>
+where
+    C: KeyInit + AeadMutInPlace + AeadCore<NonceSize = U12, TagSize = U16>,
+{
     fn from_state(keys: &'a mut [u8]) -> Self {
-        assert_eq!(keys.len(), 44);
+        let key_size = C::key_size();
+        assert_eq!(keys.len(), key_size + 12);
         Self {
-            key: <[u8; 32]>::try_from(&keys[..32]).unwrap().into(),
-            nonce: <&mut [u8; 12]>::try_from(&mut keys[32..]).unwrap(),
+            key: aes_gcm::Key::<C>::clone_from_slice(&keys[..key_size]),
+            nonce: <&mut [u8; 12]>::try_from(&mut keys[key_size..]).unwrap(),
         }
     }
 
@@ -181,7 +214,7 @@ impl<'a> Aes256GcmOpenSsh<'a> {
     }
 
     fn decrypt_packet(&mut self, mut bytes: RawPacket, _packet_number: u64) -> Result<Packet> {
-        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
+        let mut cipher = C::new(&self.key);
 
         let mut len = [0; 4];
         len.copy_from_slice(&bytes.full_packet()[..4]);
@@ -206,12 +239,13 @@ impl<'a> Aes256GcmOpenSsh<'a> {
     }
 
     fn encrypt_packet(&mut self, packet: Packet, _packet_number: u64) -> EncryptedPacket {
+        // AES has a block size of 16 bytes, no matter the key size.
         let mut bytes = packet.to_bytes(
             false,
             <aes_gcm::aes::Aes256 as aes_gcm::aes::cipher::BlockSizeUser>::block_size() as u8,
         );
 
-        let mut cipher = aes_gcm::Aes256Gcm::new(&self.key);
+        let mut cipher = C::new(&self.key);
 
         let (aad, plaintext) = bytes.split_at_mut(4);
 
@@ -224,30 +258,231 @@ impl<'a> Aes256GcmOpenSsh<'a> {
         EncryptedPacket::from_encrypted_full_bytes(bytes)
     }
 
+    /// Only the invocation counter is incremented, the fixed field stays the same.
+    /// <https://datatracker.ietf.org/doc/html/rfc5647#section-7.1>
     fn inc_nonce(&mut self) {
-        let mut carry = 1;
-        for i in (0..self.nonce.len()).rev() {
-            let n = self.nonce[i] as u16 + carry;
-            self.nonce[i] = n as u8;
-            carry = n >> 8;
-        }
+        let counter = u64::from_be_bytes(self.nonce[4..].try_into().unwrap());
+        self.nonce[4..].copy_from_slice(&counter.wrapping_add(1).to_be_bytes());
     }
 }
 
-struct Aes128Ctr {
-    _key: ctr::Ctr128BE<aes::Aes128>,
+/// The state is `key || counter`, the counter is advanced past the blocks of `bytes`.
+/// Packets are a multiple of the block size, so no keystream is skipped.
+fn aes_ctr<C: KeyIvInit + StreamCipher>(state: &mut [u8], bytes: &mut [u8]) {
+    let (key, counter) = state.split_at_mut(state.len() - 16);
+    let mut cipher = C::new_from_slices(key, counter).expect("key and IV have the correct size");
+    cipher.apply_keystream(bytes);
+
+    let blocks = bytes.len().div_ceil(16) as u128;
+    let next = u128::from_be_bytes((&*counter).try_into().unwrap()).wrapping_add(blocks);
+    counter.copy_from_slice(&next.to_be_bytes());
 }
-impl Aes128Ctr {
-    fn from_state(_keys: &mut [u8]) -> Self {
-        todo!()
+
+#[cfg(test)]
+mod tests {
+    use hex_literal::hex;
+
+    use crate::packet::{Packet, RawPacket};
+
+    use super::{
+        AeadCipher, Cipher, EncryptionAlgorithm, AES128_CTR, AES128_GCM, AES256_GCM,
+        CHACHA20POLY1305,
+    };
+
+    fn aead(alg: EncryptionAlgorithm) -> AeadCipher {
+        match alg.cipher {
+            Cipher::Aead(aead) => aead,
+            Cipher::Unauthenticated { .. } => panic!("{} is not AEAD", alg.name),
+        }
+    }
+
+    const KEYS: [u8; 64] = {
+        let mut keys = [0; 64];
+        let mut i = 0;
+        while i < 64 {
+            keys[i] = i as u8;
+            i += 1;
+        }
+        keys
+    };
+
+    fn decrypt_len(encrypted: &[u8], packet_number: u64) -> usize {
+        let mut len = [0; 4];
+        len.copy_from_slice(&encrypted[..4]);
+        (aead(CHACHA20POLY1305).decrypt_len)(&mut KEYS.clone(), &mut len, packet_number);
+        u32::from_be_bytes(len) as usize
+    }
+
+    fn decrypt(encrypted: &[u8], packet_number: u64) -> crate::Result<Packet> {
+        let mut keys = KEYS;
+        let raw = RawPacket {
+            mac_len: 16,
+            raw: encrypted.to_vec(),
+        };
+        (aead(CHACHA20POLY1305).decrypt_packet)(&mut keys, raw, packet_number)
     }
 
-    fn decrypt_len(&mut self, _: &mut [u8], _: u64) {}
+    #[test]
+    fn chacha20_poly1305_roundtrip() {
+        for packet_number in [0, 1, 1000, u32::MAX as u64 + 1] {
+            let packet = Packet::new_msg_channel_data(0, &[packet_number as u8; 1000]);
+            let encrypted = (aead(CHACHA20POLY1305).encrypt_packet)(
+                &mut KEYS.clone(),
+                Packet::new_msg_channel_data(0, &[packet_number as u8; 1000]),
+                packet_number,
+            )
+            .into_bytes();
+            assert_eq!(
+                decrypt_len(&encrypted, packet_number),
+                encrypted.len() - 4 - 16
+            );
+            assert_eq!(decrypt(&encrypted, packet_number).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn chacha20_poly1305_known_answer() {
+        // Computed with an independent ChaCha20 and Poly1305 implementation,
+        // following PROTOCOL.chacha20poly1305.
+        let expected = hex!(
+            "a39afcb2211815434e832a5e6c68d395bbe3bc2c34b230367ee33d83761542abde2bd0fce576aa27734b3de5"
+        );
+        let encrypted = (aead(CHACHA20POLY1305).encrypt_packet)(
+            &mut KEYS.clone(),
+            Packet::new_msg_channel_data(0, b"hello"),
+            7,
+        )
+        .into_bytes();
+        assert_eq!(encrypted, expected);
+        assert_eq!(
+            decrypt(&expected, 7).unwrap(),
+            Packet::new_msg_channel_data(0, b"hello")
+        );
+    }
 
-    fn decrypt_packet(&mut self, _bytes: RawPacket, _packet_number: u64) -> Result<Packet> {
-        todo!()
+    #[test]
+    fn chacha20_poly1305_rejects_tampering() {
+        let encrypted = (aead(CHACHA20POLY1305).encrypt_packet)(
+            &mut KEYS.clone(),
+            Packet::new_msg_channel_data(0, b"hello"),
+            0,
+        )
+        .into_bytes();
+
+        let mut tampered = encrypted.clone();
+        tampered[10] ^= 1;
+        assert!(decrypt(&tampered, 0).is_err());
+        // The packet number is part of the nonce.
+        assert!(decrypt(&encrypted, 1).is_err());
     }
-    fn encrypt_packet(&mut self, _packet: Packet, _packet_number: u64) -> EncryptedPacket {
-        todo!()
+
+    /// The key followed by an IV whose invocation counter is about to wrap around.
+    fn aes_gcm_state(alg: EncryptionAlgorithm) -> Vec<u8> {
+        let mut state = (0..alg.key_size as u8).collect::<Vec<_>>();
+        state.extend_from_slice(&hex!("a0a1a2a3 ffffffffffffffff"));
+        state
+    }
+
+    fn aes_gcm_known_answer(alg: EncryptionAlgorithm, expected: [[u8; 52]; 2]) {
+        let mut encrypt_state = aes_gcm_state(alg);
+        let mut decrypt_state = aes_gcm_state(alg);
+
+        // The second packet checks that the counter wraps without touching the fixed field.
+        for (packet_number, expected) in expected.into_iter().enumerate() {
+            let packet_number = packet_number as u64;
+            let encrypted = (aead(alg).encrypt_packet)(
+                &mut encrypt_state,
+                Packet::new_msg_channel_data(0, b"hello"),
+                packet_number,
+            )
+            .into_bytes();
+            assert_eq!(encrypted, expected);
+
+            let mut len = [0; 4];
+            len.copy_from_slice(&encrypted[..4]);
+            (aead(alg).decrypt_len)(&mut decrypt_state, &mut len, packet_number);
+            assert_eq!(u32::from_be_bytes(len) as usize, encrypted.len() - 4 - 16);
+
+            let raw = RawPacket {
+                mac_len: 16,
+                raw: encrypted,
+            };
+            let decrypted =
+                (aead(alg).decrypt_packet)(&mut decrypt_state, raw, packet_number).unwrap();
+            assert_eq!(decrypted, Packet::new_msg_channel_data(0, b"hello"));
+        }
+        assert_eq!(encrypt_state, decrypt_state);
+        assert_eq!(
+            encrypt_state[alg.key_size..],
+            hex!("a0a1a2a3 0000000000000001")
+        );
+    }
+
+    #[test]
+    fn aes128_gcm_known_answer() {
+        // Computed with an independent AES-GCM implementation.
+        aes_gcm_known_answer(
+            AES128_GCM,
+            [
+                hex!("00000020c16d72475ec626c1b08ddc6e26307525cb11f006fcfb552b81892a8a1acb40d4d221f9b7bc8fb68dfb717f4b2443031d"),
+                hex!("000000209b03ce9d478f346f53c342461da614a06c88d5f71360a3757fb82ca8f46e826fe20f6349d99ab122031f9a465ef7eece"),
+            ],
+        );
+    }
+
+    #[test]
+    fn aes256_gcm_known_answer() {
+        aes_gcm_known_answer(
+            AES256_GCM,
+            [
+                hex!("00000020b5e1c24ffc2f732d1a5b7b394771b57a96f85448aae022fc345c045125e261c29e383176b0bbc54efd16b3efaa956ab9"),
+                hex!("000000200de75c362364b5bcb93ed3563db60adb92901d45f94d95156335c5343b5a4a5c668bb9e4a2a8f8d476d50554f63bcccd"),
+            ],
+        );
+    }
+
+    #[test]
+    fn aes_gcm_rejects_out_of_order() {
+        let mut encrypt_state = aes_gcm_state(AES128_GCM);
+        // Every packet advances the nonce, so packets can only be decrypted in order.
+        let _first = (aead(AES128_GCM).encrypt_packet)(
+            &mut encrypt_state,
+            Packet::new_msg_channel_data(0, b"first"),
+            0,
+        );
+        let second = (aead(AES128_GCM).encrypt_packet)(
+            &mut encrypt_state,
+            Packet::new_msg_channel_data(0, b"second"),
+            1,
+        );
+
+        let raw = RawPacket {
+            mac_len: 16,
+            raw: second.into_bytes(),
+        };
+        assert!((aead(AES128_GCM).decrypt_packet)(&mut aes_gcm_state(AES128_GCM), raw, 1).is_err());
+    }
+
+    #[test]
+    fn aes128_ctr_known_answer() {
+        let Cipher::Unauthenticated {
+            apply_keystream, ..
+        } = AES128_CTR.cipher
+        else {
+            panic!("aes128-ctr is not AEAD");
+        };
+        let mut state = (0..16).collect::<Vec<u8>>();
+        state.extend_from_slice(&hex!("0001020304050607 ffffffffffffffff"));
+
+        // Encrypting in two parts continues the counter, which carries into the upper half.
+        let mut bytes = [0; 48];
+        apply_keystream(&mut state, &mut bytes[..16]);
+        apply_keystream(&mut state, &mut bytes[16..]);
+        // Computed with an independent AES-CTR implementation.
+        assert_eq!(
+            bytes,
+            hex!("0083d9ce48e6539116bef60558323f62ba3c8c14ecefe387d04b2cab35e99885ef049d8c69191b5d0a8729404d01ced5")
+        );
+        assert_eq!(state[16..], hex!("0001020304050608 0000000000000002"));
     }
 }
@@ -11,6 +11,13 @@ use serde::{Deserialize, Serialize};
 
 // TODO: extensions
 
+/// Pseudo-algorithms for strict key exchange, which mitigates the Terrapin attack.
+/// Both sides send them in their first KEXINIT, then no other messages are allowed
+/// during the initial key exchange, and sequence numbers are reset after every SSH_MSG_NEWKEYS.
+/// <https://github.com/openssh/openssh-portable/blob/1ec0a64c5dc57b8a2053a93b5ef0d02ff8598e5c/PROTOCOL#L138>
+pub(crate) const KEX_STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+pub(crate) const KEX_STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
 #[derive(Debug)]
 pub enum SshStatus {
     /// The client has sent a disconnect request, close the connection.
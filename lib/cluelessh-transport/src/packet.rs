@@ -1,3 +1,4 @@
+mod compression;
 mod ctors;
 
 use std::collections::VecDeque;
@@ -10,11 +11,29 @@ use crate::Result;
 use crate::{peer_error, SessionId};
 use cluelessh_format::numbers;
 use cluelessh_format::{NameList, Reader, Writer};
+use compression::Compression;
+pub use compression::CompressionAlgorithm;
+
+/// The largest payload a packet may have, after decompression.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc4253#section-6.1>
+/// All implementations MUST be able to process packets with an
+/// uncompressed payload length of 32768 bytes or less and a total packet
+/// size of 35000 bytes or less (including 'packet_length',
+/// 'padding_length', 'payload', 'random padding', and 'mac').
+///
+/// Peers stay well below this, as channel data is limited by the maximum packet size
+/// of the channel, which is 32768 bytes on our side.
+pub(crate) const MAX_PAYLOAD_LEN: usize = 35000;
+/// The largest `packet_length` a packet may have: the padding length, payload and the most padding.
+/// It is checked before anything is buffered, so peers cannot make us allocate more than this.
+const MAX_PACKET_LEN: usize = 1 + MAX_PAYLOAD_LEN + 255;
 
 /// Frames the byte stream into packets.
 pub(crate) struct PacketTransport {
     // TODO: I think we need independent keys for either direction to handle NEWKEYS nicely.
     keys: Box<dyn Keys>,
+    compression: Compression,
     recv_next_packet: PacketParser,
 
     recv_packets: VecDeque<Packet>,
@@ -22,6 +41,20 @@ pub(crate) struct PacketTransport {
 
     msgs_to_send: VecDeque<Msg>,
     send_next_seq_nr: u64,
+
+    /// See [`crate::client::ClientConnection::set_packet_capture`].
+    packet_capture: Option<PacketCapture>,
+}
+
+/// Receives the plaintext payload of every packet, see [`crate::client::ClientConnection::set_packet_capture`].
+pub type PacketCapture = Box<dyn FnMut(PacketDirection, &[u8]) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    /// Received from the peer, after decryption and decompression.
+    Inbound,
+    /// Sent to the peer, before compression and encryption.
+    Outbound,
 }
 
 #[derive(Debug)]
@@ -65,6 +98,7 @@ impl PacketTransport {
     pub(crate) fn new() -> Self {
         PacketTransport {
             keys: Box::new(Plaintext),
+            compression: Compression::new(),
             recv_next_packet: PacketParser::new(),
 
             recv_packets: VecDeque::new(),
@@ -72,6 +106,8 @@ impl PacketTransport {
 
             msgs_to_send: VecDeque::new(),
             send_next_seq_nr: 0,
+
+            packet_capture: None,
         }
     }
     pub(crate) fn recv_bytes(&mut self, mut bytes: &[u8]) -> Result<RecvBytesResult> {
@@ -103,7 +139,16 @@ impl PacketTransport {
             self.recv_next_packet
                 .recv_bytes(bytes, &mut *self.keys, self.recv_next_seq_nr)?;
         if let Some((consumed, result)) = result {
-            let is_new_keys = result.packet_type() == numbers::SSH_MSG_NEWKEYS;
+            let result = self.compression.decompress(result)?;
+            let packet_type = result.packet_type();
+            let packet_type_string = numbers::packet_type_to_string(packet_type);
+            let seq_nr = self.recv_next_seq_nr;
+            // Only the metadata, as the payload can contain secrets like passwords.
+            trace!(%packet_type, %packet_type_string, packet_len = %result.payload.len(), %seq_nr, "Received packet");
+            if let Some(capture) = &mut self.packet_capture {
+                capture(PacketDirection::Inbound, &result.payload);
+            }
+            let is_new_keys = packet_type == numbers::SSH_MSG_NEWKEYS;
 
             self.recv_packets.push_back(result);
             self.recv_next_seq_nr = self.recv_next_seq_nr.wrapping_add(1);
@@ -118,15 +163,29 @@ impl PacketTransport {
     }
 
     pub(crate) fn queue_packet(&mut self, packet: Packet) {
+        let seq_nr = self.send_next_seq_nr;
         let packet_type = packet.packet_type();
         let packet_type_string = numbers::packet_type_to_string(packet_type);
-        trace!(%packet_type, %packet_type_string, packet_len = %packet.payload.len(), "Sending packet");
-        let seq_nr = self.send_next_seq_nr;
+        trace!(%packet_type, %packet_type_string, packet_len = %packet.payload.len(), %seq_nr, "Sending packet");
+        if let Some(capture) = &mut self.packet_capture {
+            capture(PacketDirection::Outbound, &packet.payload);
+        }
         self.send_next_seq_nr = self.send_next_seq_nr.wrapping_add(1);
+        let packet = self.compression.compress(packet);
         let msg = self.keys.encrypt_packet_to_msg(packet, seq_nr);
         self.queue_send_msg(msg);
     }
 
+    pub(crate) fn set_packet_capture(&mut self, capture: Option<PacketCapture>) {
+        self.packet_capture = capture;
+    }
+
+    /// Starts counting packets from zero again, after SSH_MSG_NEWKEYS in strict key exchange.
+    pub(crate) fn reset_sequence_numbers(&mut self) {
+        self.recv_next_seq_nr = 0;
+        self.send_next_seq_nr = 0;
+    }
+
     pub(crate) fn queue_send_protocol_info(&mut self, identification: Vec<u8>) {
         self.queue_send_msg(Msg(MsgKind::ServerProtocolInfo(identification)));
     }
@@ -150,8 +209,24 @@ impl PacketTransport {
         k: &SharedSecret,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
         is_server: bool,
     ) {
+        if is_server {
+            self.compression.set_algorithms(
+                compression_server_to_client,
+                compression_client_to_server,
+                is_server,
+            );
+        } else {
+            self.compression.set_algorithms(
+                compression_client_to_server,
+                compression_server_to_client,
+                is_server,
+            );
+        }
+
         if let Err(()) = self.keys.rekey(
             h,
             k,
@@ -214,7 +289,7 @@ impl Packet {
         //    return Err(peer_error!("full packet length must be multiple of 8: {}", bytes.len()));
         //}
 
-        if payload.len() < 1 {
+        if payload.is_empty() {
             return Err(peer_error!("empty packet without a type"));
         }
 
@@ -247,9 +322,9 @@ impl Packet {
         new.extend_from_slice(&u32::to_be_bytes(packet_len as u32));
         new.extend_from_slice(&[padding_len]);
         new.extend_from_slice(&self.payload);
-        new.extend(std::iter::repeat(0).take(padding_len as usize));
+        new.extend(std::iter::repeat_n(0, padding_len as usize));
 
-        assert!((let_bytes + 1 + self.payload.len() + (padding_len as usize)) % 8 == 0);
+        assert!((let_bytes + 1 + self.payload.len() + (padding_len as usize)).is_multiple_of(8));
 
         new
     }
@@ -289,7 +364,7 @@ pub(crate) struct KeyExchangeInitPacket<'a> {
 }
 
 impl<'a> KeyExchangeInitPacket<'a> {
-    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeInitPacket<'_>> {
+    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeInitPacket<'a>> {
         let mut c = Reader::new(payload);
 
         let kind = c.u8()?;
@@ -356,7 +431,7 @@ pub(crate) struct KeyExchangeEcDhInitPacket<'a> {
     pub(crate) qc: &'a [u8],
 }
 impl<'a> KeyExchangeEcDhInitPacket<'a> {
-    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeEcDhInitPacket<'_>> {
+    pub(crate) fn parse(payload: &'a [u8]) -> Result<KeyExchangeEcDhInitPacket<'a>> {
         let mut c = Reader::new(payload);
 
         let kind = c.u8()?;
@@ -463,6 +538,11 @@ impl PacketParser {
                 keys.decrypt_len(&mut len_to_decrypt, next_seq_nr);
                 let packet_length = u32::from_be_bytes(len_to_decrypt);
                 let packet_length: usize = packet_length.try_into().unwrap();
+                if packet_length > MAX_PACKET_LEN {
+                    return Err(peer_error!(
+                        "packet too large: {packet_length} > {MAX_PACKET_LEN}"
+                    ));
+                }
 
                 let packet_length = packet_length + keys.additional_mac_len();
 
@@ -476,16 +556,6 @@ impl PacketParser {
             }
         };
 
-        // <https://datatracker.ietf.org/doc/html/rfc4253#section-6.1>
-        // All implementations MUST be able to process packets with an
-        // uncompressed payload length of 32768 bytes or less and a total packet
-        // size of 35000 bytes or less (including 'packet_length',
-        // 'padding_length', 'payload', 'random padding', and 'mac').
-        // Implementations SHOULD support longer packets, where they might be needed.
-        if packet_length > 500_000 {
-            return Err(peer_error!("packet too large (>500_000): {packet_length}"));
-        }
-
         let remaining_len = std::cmp::min(bytes.len(), packet_length - (self.raw_data.len() - 4));
         self.raw_data.extend_from_slice(&bytes[..remaining_len]);
         consumed += remaining_len;
@@ -509,33 +579,78 @@ impl PacketParser {
     }
 }
 
-pub(crate) struct ProtocolIdentParser(Vec<u8>);
+/// Parses the protocol version exchange.
+/// <https://datatracker.ietf.org/doc/html/rfc4253#section-4.2>
+pub(crate) struct ProtocolIdentParser {
+    line: Vec<u8>,
+    /// Servers may send other lines before their identification, clients may not.
+    allow_other_lines: bool,
+    other_lines: usize,
+}
+
+/// The maximum length of a line including CR LF.
+const MAX_IDENT_LINE_LEN: usize = 255;
+/// The maximum amount of lines before the identification, like in OpenSSH.
+const MAX_OTHER_LINES: usize = 1024;
 
 impl ProtocolIdentParser {
-    pub(crate) fn new() -> Self {
-        Self(Vec::new())
-    }
-    pub(crate) fn recv_bytes(&mut self, bytes: &[u8]) {
-        self.0.extend_from_slice(bytes);
+    pub(crate) fn new(allow_other_lines: bool) -> Self {
+        Self {
+            line: Vec::new(),
+            allow_other_lines,
+            other_lines: 0,
+        }
     }
-    pub(crate) fn get_peer_ident(&mut self) -> Option<Vec<u8>> {
-        if self.0.windows(2).any(|win| win == b"\r\n") {
-            // TODO: care that its SSH 2.0 instead of anythin anything else
-            // The peer will not send any more information than this until we respond, so discord the rest of the bytes.
-            let peer_ident = mem::take(&mut self.0);
-            let peer_ident_string = String::from_utf8_lossy(&peer_ident);
-            debug!(identification = %peer_ident_string.trim(), "Peer identifier");
 
-            Some(peer_ident)
-        } else {
-            None
+    /// Returns the identification of the peer, always terminated with CR LF,
+    /// and how many of the bytes have been consumed, once it has been received.
+    pub(crate) fn recv_bytes(&mut self, bytes: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if byte != b'\n' {
+                self.line.push(byte);
+                if self.line.len() >= MAX_IDENT_LINE_LEN {
+                    return Err(peer_error!("identification line is too long"));
+                }
+                continue;
+            }
+
+            // Be lenient and also accept lines that are only terminated with LF.
+            let mut line = mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            if line.starts_with(b"SSH-") {
+                let ident_string = String::from_utf8_lossy(&line);
+                debug!(identification = %ident_string, "Peer identifier");
+                if !(line.starts_with(b"SSH-2.0-") || line.starts_with(b"SSH-1.99-")) {
+                    return Err(peer_error!(
+                        "unsupported protocol version: {ident_string:?}"
+                    ));
+                }
+
+                line.extend_from_slice(b"\r\n");
+                return Ok(Some((line, i + 1)));
+            }
+
+            if !self.allow_other_lines {
+                return Err(peer_error!("peer did not send an identification string"));
+            }
+            self.other_lines += 1;
+            if self.other_lines > MAX_OTHER_LINES {
+                return Err(peer_error!("too many lines before the identification"));
+            }
+            debug!(line = %String::from_utf8_lossy(&line), "Skipping line before identification");
         }
+        Ok(None)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::packet::PacketParser;
+    use crate::crypto::Plaintext;
+    use crate::packet::{PacketParser, ProtocolIdentParser, MAX_PACKET_LEN};
+    use crate::SshStatus;
 
     trait OptionExt {
         fn unwrap_none(self);
@@ -577,4 +692,51 @@ mod tests {
         assert_eq!(consumed, 6);
         assert_eq!(data.rest(), &[1, 2]);
     }
+
+    #[test]
+    fn packet_parser_too_large() {
+        for len in [MAX_PACKET_LEN as u32 + 1, u32::MAX] {
+            let mut p = PacketParser::new();
+            let result = p.recv_bytes_inner(&len.to_be_bytes(), &mut Plaintext, 0);
+            assert!(matches!(result, Err(SshStatus::PeerError(_))));
+            // Rejected from the length alone, before any of the packet has been buffered.
+            assert_eq!(p.raw_data.len(), 4);
+        }
+
+        let mut p = PacketParser::new();
+        p.test_recv_bytes(&(MAX_PACKET_LEN as u32).to_be_bytes())
+            .unwrap_none();
+    }
+
+    #[test]
+    fn ident_parser() {
+        let mut p = ProtocolIdentParser::new(false);
+        assert_eq!(p.recv_bytes(b"SSH-2.0-Open").unwrap(), None);
+        let (ident, consumed) = p.recv_bytes(b"SSH_9.7\r\n\0\0\0").unwrap().unwrap();
+        assert_eq!(ident, b"SSH-2.0-OpenSSH_9.7\r\n");
+        assert_eq!(consumed, 9);
+    }
+
+    #[test]
+    fn ident_parser_other_lines() {
+        let mut p = ProtocolIdentParser::new(true);
+        assert_eq!(p.recv_bytes(b"Welcome!\r\nSSH is fun\n").unwrap(), None);
+        let (ident, _) = p.recv_bytes(b"SSH-2.0-Test\n").unwrap().unwrap();
+        assert_eq!(ident, b"SSH-2.0-Test\r\n");
+
+        let mut p = ProtocolIdentParser::new(false);
+        assert!(p.recv_bytes(b"Welcome!\r\nSSH-2.0-Test\r\n").is_err());
+    }
+
+    #[test]
+    fn ident_parser_invalid() {
+        let mut p = ProtocolIdentParser::new(true);
+        assert!(p.recv_bytes(b"SSH-1.5-Test\r\n").is_err());
+
+        let mut p = ProtocolIdentParser::new(true);
+        assert!(p.recv_bytes(&[b'a'; 300]).is_err());
+
+        let mut p = ProtocolIdentParser::new(true);
+        assert!(p.recv_bytes(&b"\r\n".repeat(2000)).is_err());
+    }
 }
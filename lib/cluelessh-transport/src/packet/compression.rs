@@ -0,0 +1,262 @@
+//! Payload compression.
+//! <https://datatracker.ietf.org/doc/html/rfc4253#section-6.2>
+
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::{DataFormat, MZError, MZFlush, StreamResult};
+
+use crate::crypto::AlgorithmName;
+use crate::packet::{Packet, MAX_PAYLOAD_LEN};
+use crate::{peer_error, Result};
+use cluelessh_format::numbers;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    None,
+    /// Compression right after the first key exchange.
+    Zlib,
+    /// Compression that only starts after the server has sent SSH_MSG_USERAUTH_SUCCESS,
+    /// so unauthenticated peers never reach the decompressor.
+    /// <https://github.com/openssh/openssh-portable/blob/master/PROTOCOL>
+    ZlibOpenSsh,
+}
+
+impl AlgorithmName for CompressionAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zlib => "zlib",
+            Self::ZlibOpenSsh => "zlib@openssh.com",
+        }
+    }
+}
+
+impl CompressionAlgorithm {
+    fn is_active(self, authenticated: bool) -> bool {
+        match self {
+            Self::None => false,
+            Self::Zlib => true,
+            Self::ZlibOpenSsh => authenticated,
+        }
+    }
+}
+
+/// The zlib compression level, the same as OpenSSH uses.
+const COMPRESSION_LEVEL: i32 = 6;
+/// The amount of output space that is added while running a zlib stream.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The compression state of both directions of a connection.
+pub(crate) struct Compression {
+    is_server: bool,
+    /// Whether SSH_MSG_USERAUTH_SUCCESS has been sent (as a server) or received (as a client).
+    authenticated: bool,
+    outgoing: CompressionAlgorithm,
+    incoming: CompressionAlgorithm,
+    compressor: Option<Box<CompressorOxide>>,
+    decompressor: Option<Box<InflateState>>,
+}
+
+impl Compression {
+    pub(crate) fn new() -> Self {
+        Self {
+            is_server: false,
+            authenticated: false,
+            outgoing: CompressionAlgorithm::None,
+            incoming: CompressionAlgorithm::None,
+            compressor: None,
+            decompressor: None,
+        }
+    }
+
+    /// Switches to the algorithms of a new key exchange.
+    /// The compression context is reinitialized after every key exchange.
+    pub(crate) fn set_algorithms(
+        &mut self,
+        outgoing: CompressionAlgorithm,
+        incoming: CompressionAlgorithm,
+        is_server: bool,
+    ) {
+        self.is_server = is_server;
+        self.outgoing = outgoing;
+        self.incoming = incoming;
+        self.compressor = None;
+        self.decompressor = None;
+        self.start();
+    }
+
+    fn start(&mut self) {
+        if self.compressor.is_none() && self.outgoing.is_active(self.authenticated) {
+            let flags = create_comp_flags_from_zip_params(COMPRESSION_LEVEL, 15, 0);
+            self.compressor = Some(Box::new(CompressorOxide::new(flags)));
+        }
+        if self.decompressor.is_none() && self.incoming.is_active(self.authenticated) {
+            self.decompressor = Some(InflateState::new_boxed(DataFormat::Zlib));
+        }
+    }
+
+    fn authentication_succeeded(&mut self) {
+        self.authenticated = true;
+        self.start();
+    }
+
+    pub(crate) fn compress(&mut self, packet: Packet) -> Packet {
+        let is_auth_success = packet.packet_type() == numbers::SSH_MSG_USERAUTH_SUCCESS;
+
+        let packet = match &mut self.compressor {
+            None => packet,
+            Some(compressor) => {
+                let payload = run_stream(&packet.payload, usize::MAX, |input, output| {
+                    miniz_oxide::deflate::stream::deflate(compressor, input, output, MZFlush::Sync)
+                })
+                .expect("failed to compress packet");
+                Packet { payload }
+            }
+        };
+
+        if self.is_server && is_auth_success {
+            self.authentication_succeeded();
+        }
+        packet
+    }
+
+    pub(crate) fn decompress(&mut self, packet: Packet) -> Result<Packet> {
+        let packet = match &mut self.decompressor {
+            None => packet,
+            Some(decompressor) => {
+                let payload = run_stream(&packet.payload, MAX_PAYLOAD_LEN, |input, output| {
+                    miniz_oxide::inflate::stream::inflate(
+                        decompressor,
+                        input,
+                        output,
+                        MZFlush::Sync,
+                    )
+                })
+                .map_err(|err| peer_error!("failed to decompress packet: {err:?}"))?;
+                if payload.is_empty() {
+                    return Err(peer_error!("empty packet without a type"));
+                }
+                Packet { payload }
+            }
+        };
+
+        if !self.is_server && packet.packet_type() == numbers::SSH_MSG_USERAUTH_SUCCESS {
+            self.authentication_succeeded();
+        }
+        Ok(packet)
+    }
+}
+
+/// Passes all of `input` through a zlib stream and flushes it,
+/// as every packet has to be decodable on its own.
+fn run_stream(
+    mut input: &[u8],
+    limit: usize,
+    mut step: impl FnMut(&[u8], &mut [u8]) -> StreamResult,
+) -> Result<Vec<u8>, MZError> {
+    let mut output = Vec::new();
+    loop {
+        let written = output.len();
+        if written >= limit {
+            return Err(MZError::Buf);
+        }
+        output.resize(written + CHUNK_SIZE, 0);
+
+        let result = step(input, &mut output[written..]);
+        output.truncate(written + result.bytes_written);
+        input = &input[result.bytes_consumed..];
+
+        match result.status {
+            Ok(_) => {}
+            // No progress could be made, which is fine once everything has been consumed.
+            Err(MZError::Buf) if input.is_empty() => {}
+            Err(err) => return Err(err),
+        }
+
+        // If there was output space left over, everything has been flushed.
+        if input.is_empty() && result.bytes_written < CHUNK_SIZE {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, CompressionAlgorithm};
+    use crate::packet::Packet;
+    use cluelessh_format::numbers;
+
+    fn pair(algorithm: CompressionAlgorithm) -> (Compression, Compression) {
+        let mut client = Compression::new();
+        client.set_algorithms(algorithm, algorithm, false);
+        let mut server = Compression::new();
+        server.set_algorithms(algorithm, algorithm, true);
+        (client, server)
+    }
+
+    fn data_packet(data: &[u8]) -> Packet {
+        Packet::new_msg_channel_data(0, data)
+    }
+
+    #[test]
+    fn zlib_roundtrip() {
+        let (mut client, mut server) = pair(CompressionAlgorithm::Zlib);
+
+        for data in [&b"hello"[..], &[0; 30_000], b"", b"hello"] {
+            let packet = data_packet(data);
+            let compressed = client.compress(data_packet(data));
+            if data.len() > 100 {
+                assert!(compressed.payload.len() < packet.payload.len());
+            }
+            assert_eq!(server.decompress(compressed).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn zlib_openssh_delayed() {
+        let (mut client, mut server) = pair(CompressionAlgorithm::ZlibOpenSsh);
+
+        let packet = Packet::new_msg_userauth_request_none(b"user", b"ssh-connection", b"none");
+        let sent = client.compress(Packet::new_msg_userauth_request_none(
+            b"user",
+            b"ssh-connection",
+            b"none",
+        ));
+        assert_eq!(sent, packet);
+        assert_eq!(server.decompress(sent).unwrap(), packet);
+
+        let success = server.compress(Packet::new_msg_userauth_success());
+        assert_eq!(success.packet_type(), numbers::SSH_MSG_USERAUTH_SUCCESS);
+        client.decompress(success).unwrap();
+
+        let sent = client.compress(data_packet(&[1; 1000]));
+        assert_ne!(sent.packet_type(), numbers::SSH_MSG_CHANNEL_DATA);
+        assert_eq!(server.decompress(sent).unwrap(), data_packet(&[1; 1000]));
+
+        let sent = server.compress(data_packet(&[2; 1000]));
+        assert_ne!(sent.packet_type(), numbers::SSH_MSG_CHANNEL_DATA);
+        assert_eq!(client.decompress(sent).unwrap(), data_packet(&[2; 1000]));
+    }
+
+    #[test]
+    fn client_cannot_start_delayed_compression() {
+        let (_, mut server) = pair(CompressionAlgorithm::ZlibOpenSsh);
+
+        server
+            .decompress(Packet::new_msg_userauth_success())
+            .unwrap();
+        let packet = data_packet(b"uncompressed");
+        assert_eq!(
+            server.decompress(data_packet(b"uncompressed")).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn decompression_limit() {
+        let (mut client, mut server) = pair(CompressionAlgorithm::Zlib);
+
+        let compressed = client.compress(data_packet(&vec![0; 1024 * 1024]));
+        assert!(server.decompress(compressed).is_err());
+    }
+}
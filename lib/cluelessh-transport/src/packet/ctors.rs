@@ -49,7 +49,11 @@ ctors! {
     // Transport layer protocol:
 
     // 1 to 19 Transport layer generic (e.g., disconnect, ignore, debug, etc.)
+    fn new_msg_disconnect(SSH_MSG_DISCONNECT; reason_code: u32, description: string, language_tag: string);
+    fn new_msg_ignore(SSH_MSG_IGNORE; data: string);
     fn new_msg_service_request(SSH_MSG_SERVICE_REQUEST; service_name: string);
+    fn new_msg_service_accept(SSH_MSG_SERVICE_ACCEPT; service_name: string);
+    fn new_msg_ext_info_server_sig_algs(SSH_MSG_EXT_INFO; nr_extensions_1: u32, name_server_sig_algs: string, algorithms: name_list);
     // 20 to 29 Algorithm negotiation
     // 30 to 49 Key exchange method specific (numbers can be reused for different authentication methods)
     fn new_msg_kex_ecdh_init(SSH_MSG_KEX_ECDH_INIT; client_ephemeral_public_key_qc: string);
@@ -84,6 +88,14 @@ ctors! {
         pubkey: string,
         signature: string,
     );
+    fn new_msg_userauth_request_publickey_query(SSH_MSG_USERAUTH_REQUEST;
+        username: string,
+        service_name: string,
+        method_name_pubkey: string,
+        false_: bool,
+        pubkey_alg_name: string,
+        pubkey: string,
+    );
     fn new_msg_userauth_failure(SSH_MSG_USERAUTH_FAILURE;
         auth_options: name_list,
         partial_success: bool,
@@ -96,11 +108,21 @@ ctors! {
         key_alg: string,
         key_blob: string,
     );
+    fn new_msg_userauth_info_request(SSH_MSG_USERAUTH_INFO_REQUEST;
+        name: string,
+        instruction: string,
+        language_tag: string,
+        num_prompts: u32,
+    );
 
     // -----
     // Connection protocol:
 
     // 80 to 89   Connection protocol generic
+    fn new_msg_global_request(SSH_MSG_GLOBAL_REQUEST;
+        request_name: string,
+        want_reply: bool,
+    );
     fn new_msg_request_failure(SSH_MSG_REQUEST_FAILURE;);
 
     // 90 to 127  Channel related messages
@@ -145,6 +167,40 @@ ctors! {
         kind_shell: string,
         want_reply: bool,
     );
+    fn new_msg_channel_request_exec(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_exec: string,
+        want_reply: bool,
+        command: string,
+    );
+    fn new_msg_channel_request_env(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_env: string,
+        want_reply: bool,
+        name: string,
+        value: string,
+    );
+    fn new_msg_channel_request_window_change(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_window_change: string,
+        false_: bool,
+        term_width_char: u32,
+        term_height_rows: u32,
+        term_width_px: u32,
+        term_height_px: u32,
+    );
+    fn new_msg_channel_request_signal(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_signal: string,
+        false_: bool,
+        signal_name: string,
+    );
+    fn new_msg_channel_request_break(SSH_MSG_CHANNEL_REQUEST;
+        recipient_channel: u32,
+        kind_break: string,
+        want_reply: bool,
+        break_length_ms: u32,
+    );
     fn new_msg_channel_request_exit_status(SSH_MSG_CHANNEL_REQUEST; recipient_channel: u32, kind_exit_status: string, false_: bool, exit_status: u32);
 
     fn new_msg_channel_success(SSH_MSG_CHANNEL_SUCCESS; recipient_channel: u32);
@@ -5,8 +5,8 @@ use crate::crypto::{
     SupportedAlgorithms,
 };
 use crate::packet::{
-    KeyExchangeEcDhInitPacket, KeyExchangeInitPacket, Packet, PacketTransport, ProtocolIdentParser,
-    RecvBytesResult,
+    CompressionAlgorithm, KeyExchangeEcDhInitPacket, KeyExchangeInitPacket, Packet,
+    PacketTransport, ProtocolIdentParser, RecvBytesResult,
 };
 use crate::{peer_error, Msg, SshRng, SshStatus};
 use crate::{Result, SessionId};
@@ -14,7 +14,7 @@ use cluelessh_format::numbers;
 use cluelessh_format::{NameList, Reader, Writer};
 use cluelessh_keys::private::PlaintextPrivateKey;
 use cluelessh_keys::signature::Signature;
-use tracing::{debug, info, trace};
+use tracing::{debug, info};
 
 pub struct ServerConnection {
     state: ServerState,
@@ -24,6 +24,18 @@ pub struct ServerConnection {
     config: ServerConfig,
 
     plaintext_packets: VecDeque<Packet>,
+    /// Packets that are sent while a key re-exchange is in progress,
+    /// which are held back until the new keys are in place.
+    paused_packets: VecDeque<Packet>,
+    /// The algorithm of the most recent key exchange.
+    kex_algorithm: Option<&'static str>,
+    /// Whether both sides support strict key exchange, see [`crate::KEX_STRICT_SERVER`].
+    strict_kex: bool,
+    /// Whether the client sent other packets before its first KEXINIT,
+    /// which is not allowed with strict key exchange.
+    received_before_kexinit: bool,
+    /// Whether the client advertised `ext-info-c` in the initial key exchange.
+    send_ext_info: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -37,9 +49,12 @@ enum ServerState {
         ident_parser: ProtocolIdentParser,
     },
     KeyExchangeInit {
+        /// The session of the connection if this is a key re-exchange.
+        session_id: Option<SessionId>,
         client_identification: Vec<u8>,
     },
     DhKeyInit {
+        session_id: Option<SessionId>,
         client_identification: Vec<u8>,
         client_kexinit: Vec<u8>,
         server_kexinit: Vec<u8>,
@@ -47,8 +62,11 @@ enum ServerState {
         server_host_key_algorithm: HostKeySigningAlgorithm,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
     },
     WaitingForKeyExchange {
+        session_id: Option<SessionId>,
         client_identification: Vec<u8>,
         client_kexinit: Vec<u8>,
         server_kexinit: Vec<u8>,
@@ -56,22 +74,30 @@ enum ServerState {
         server_host_key_algorithm: HostKeySigningAlgorithm,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
         client_ephemeral_public_key: Vec<u8>,
     },
     NewKeys {
+        session_id: Option<SessionId>,
+        client_identification: Vec<u8>,
         /// h
         hash: [u8; 32],
         /// k
         shared_secret: SharedSecret,
         encryption_client_to_server: EncryptionAlgorithm,
         encryption_server_to_client: EncryptionAlgorithm,
+        compression_client_to_server: CompressionAlgorithm,
+        compression_server_to_client: CompressionAlgorithm,
     },
     ServiceRequest {
         session_id: SessionId,
+        client_identification: Vec<u8>,
         may_send_extensions: bool,
     },
     Open {
         session_id: SessionId,
+        client_identification: Vec<u8>,
     },
 }
 
@@ -93,15 +119,20 @@ pub struct KeyExchangeResponse {
 }
 
 impl ServerConnection {
-    pub fn new(rng: impl SshRng + Send + Sync + 'static, config: ServerConfig) -> Self {
+    pub fn new(rng: impl SshRng + 'static, config: ServerConfig) -> Self {
         Self {
             state: ServerState::ProtoExchange {
-                ident_parser: ProtocolIdentParser::new(),
+                ident_parser: ProtocolIdentParser::new(false),
             },
             packet_transport: PacketTransport::new(),
             rng: Box::new(rng),
             config,
             plaintext_packets: VecDeque::new(),
+            paused_packets: VecDeque::new(),
+            kex_algorithm: None,
+            strict_kex: false,
+            received_before_kexinit: false,
+            send_ext_info: false,
         }
     }
 
@@ -117,15 +148,15 @@ impl ServerConnection {
 
     fn recv_bytes_inner(&mut self, bytes: &[u8]) -> Result<RecvBytesResult> {
         if let ServerState::ProtoExchange { ident_parser } = &mut self.state {
-            ident_parser.recv_bytes(bytes);
-            if let Some(client_identification) = ident_parser.get_peer_ident() {
+            if let Some((client_identification, consumed)) = ident_parser.recv_bytes(bytes)? {
                 self.packet_transport
                     .queue_send_protocol_info(self.config.server_identification.clone());
                 self.state = ServerState::KeyExchangeInit {
+                    session_id: None,
                     client_identification,
                 };
+                return Ok(RecvBytesResult::Partial { consumed });
             }
-            // This means that we must be called at least twice, which is fine I think.
             return Ok(RecvBytesResult::Full);
         }
 
@@ -135,7 +166,18 @@ impl ServerConnection {
             let packet_type = packet.packet_type();
             let packet_type_string = numbers::packet_type_to_string(packet_type);
 
-            trace!(%packet_type, %packet_type_string, packet_len = %packet.payload.len(), "Received packet");
+            if matches!(
+                packet_type,
+                numbers::SSH_MSG_IGNORE | numbers::SSH_MSG_DEBUG
+            ) && self.is_initial_kex()
+            {
+                if self.strict_kex {
+                    return Err(peer_error!(
+                        "strict key exchange violation: received {packet_type_string} during the initial key exchange"
+                    ));
+                }
+                self.received_before_kexinit = true;
+            }
 
             // Handle some packets ignoring the state.
             match packet_type {
@@ -175,9 +217,25 @@ impl ServerConnection {
                 _ => {}
             }
 
+            // <https://datatracker.ietf.org/doc/html/rfc4253#section-9>
+            if let ServerState::Open {
+                session_id,
+                client_identification,
+            } = &mut self.state
+            {
+                if packet_type == numbers::SSH_MSG_KEXINIT {
+                    debug!("Client initiated key re-exchange");
+                    self.state = ServerState::KeyExchangeInit {
+                        session_id: Some(*session_id),
+                        client_identification: take(client_identification),
+                    };
+                }
+            }
+
             match &mut self.state {
                 ServerState::ProtoExchange { .. } => unreachable!("handled above"),
                 ServerState::KeyExchangeInit {
+                    session_id,
                     client_identification,
                 } => {
                     let kex = KeyExchangeInitPacket::parse(&packet.payload)?;
@@ -186,20 +244,29 @@ impl ServerConnection {
 
                     let kex_algorithm = sup_algs.key_exchange.find(false, kex.kex_algorithms.0)?;
                     debug!(name = %kex_algorithm.name(), "Using KEX algorithm");
+                    self.kex_algorithm = Some(kex_algorithm.name());
+
+                    if session_id.is_none() && kex.kex_algorithms.contains(crate::KEX_STRICT_CLIENT)
+                    {
+                        if self.received_before_kexinit {
+                            return Err(peer_error!(
+                                "strict key exchange violation: KEXINIT was not the first packet"
+                            ));
+                        }
+                        debug!("Using strict key exchange");
+                        self.strict_kex = true;
+                    }
 
                     // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
-                    // TODO: Send some extensions
-                    // TODO: Because of the terrapin attack, we probably want to implement strict kex for that.
-                    let _client_supports_extensions = kex.kex_algorithms.contains("ext-info-c");
+                    if session_id.is_none() {
+                        self.send_ext_info = kex.kex_algorithms.contains("ext-info-c");
+                    }
 
                     let server_host_key_algorithm = sup_algs
                         .hostkey_sign
                         .find(false, kex.server_host_key_algorithms.0)?;
                     debug!(name = %server_host_key_algorithm.name(), "Using host key algorithm");
 
-                    // TODO: Implement aes128-ctr
-                    let _ = crypto::encrypt::ENC_AES128_CTR;
-
                     let encryption_client_to_server = sup_algs
                         .encryption_from_peer
                         .find(false, kex.encryption_algorithms_client_to_server.0)?;
@@ -216,13 +283,20 @@ impl ServerConnection {
                     let mac_algorithm_server_to_client = sup_algs
                         .mac_to_peer
                         .find(false, kex.mac_algorithms_server_to_client.0)?;
+                    // AEAD ciphers ignore the MAC.
+                    let encryption_client_to_server =
+                        encryption_client_to_server.with_mac(mac_algorithm_client_to_server);
+                    let encryption_server_to_client =
+                        encryption_server_to_client.with_mac(mac_algorithm_server_to_client);
 
-                    let compression_algorithm_client_to_server = sup_algs
+                    let compression_client_to_server = sup_algs
                         .compression_from_peer
                         .find(false, kex.compression_algorithms_client_to_server.0)?;
-                    let compression_algorithm_server_to_client = sup_algs
+                    debug!(name = %compression_client_to_server.name(), "Using compression algorithm C->S");
+                    let compression_server_to_client = sup_algs
                         .compression_to_peer
                         .find(false, kex.compression_algorithms_server_to_client.0)?;
+                    debug!(name = %compression_server_to_client.name(), "Using compression algorithm S->C");
 
                     let _ = kex.languages_client_to_server;
                     let _ = kex.languages_server_to_client;
@@ -236,7 +310,11 @@ impl ServerConnection {
                     let mut cookie = [0; 16];
                     self.rng.fill_bytes(&mut cookie);
                     // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.1>
-                    let kex_algorithms = format!("{},ext-info-s", kex_algorithm.name());
+                    let mut kex_algorithms = format!("{},ext-info-s", kex_algorithm.name());
+                    if session_id.is_none() {
+                        kex_algorithms.push(',');
+                        kex_algorithms.push_str(crate::KEX_STRICT_SERVER);
+                    }
                     let server_kexinit = KeyExchangeInitPacket {
                         cookie,
                         // TODO: we should send *all* our algorithms here...
@@ -249,16 +327,16 @@ impl ServerConnection {
                             encryption_server_to_client.name(),
                         ),
                         mac_algorithms_client_to_server: NameList::one(
-                            mac_algorithm_client_to_server,
+                            mac_algorithm_client_to_server.name(),
                         ),
                         mac_algorithms_server_to_client: NameList::one(
-                            mac_algorithm_server_to_client,
+                            mac_algorithm_server_to_client.name(),
                         ),
                         compression_algorithms_client_to_server: NameList::one(
-                            compression_algorithm_client_to_server,
+                            compression_client_to_server.name(),
                         ),
                         compression_algorithms_server_to_client: NameList::one(
-                            compression_algorithm_server_to_client,
+                            compression_server_to_client.name(),
                         ),
                         languages_client_to_server: NameList::none(),
                         languages_server_to_client: NameList::none(),
@@ -271,6 +349,7 @@ impl ServerConnection {
                         payload: server_kexinit_payload.clone(),
                     });
                     self.state = ServerState::DhKeyInit {
+                        session_id: *session_id,
                         client_identification,
                         client_kexinit: packet.payload,
                         server_kexinit: server_kexinit_payload,
@@ -278,9 +357,12 @@ impl ServerConnection {
                         server_host_key_algorithm,
                         encryption_client_to_server,
                         encryption_server_to_client,
+                        compression_client_to_server,
+                        compression_server_to_client,
                     };
                 }
                 ServerState::DhKeyInit {
+                    session_id,
                     client_identification,
                     client_kexinit,
                     server_kexinit,
@@ -288,12 +370,15 @@ impl ServerConnection {
                     server_host_key_algorithm,
                     encryption_client_to_server,
                     encryption_server_to_client,
+                    compression_client_to_server,
+                    compression_server_to_client,
                 } => {
                     let dh = KeyExchangeEcDhInitPacket::parse(&packet.payload)?;
 
                     let client_ephemeral_public_key = dh.qc;
 
                     self.state = ServerState::WaitingForKeyExchange {
+                        session_id: *session_id,
                         client_identification: client_identification.clone(),
                         client_kexinit: client_kexinit.clone(),
                         server_kexinit: server_kexinit.clone(),
@@ -301,6 +386,8 @@ impl ServerConnection {
                         server_host_key_algorithm: server_host_key_algorithm.clone(),
                         encryption_client_to_server: *encryption_client_to_server,
                         encryption_server_to_client: *encryption_server_to_client,
+                        compression_client_to_server: *compression_client_to_server,
+                        compression_server_to_client: *compression_server_to_client,
                         client_ephemeral_public_key: client_ephemeral_public_key.to_vec(),
                     };
                 }
@@ -308,10 +395,14 @@ impl ServerConnection {
                     return Err(peer_error!("unexpected packet"));
                 }
                 ServerState::NewKeys {
+                    session_id,
+                    client_identification,
                     hash: h,
                     shared_secret: k,
                     encryption_client_to_server,
                     encryption_server_to_client,
+                    compression_client_to_server,
+                    compression_server_to_client,
                 } => {
                     if packet.payload != [numbers::SSH_MSG_NEWKEYS] {
                         return Err(peer_error!("did not send SSH_MSG_NEWKEYS"));
@@ -326,15 +417,50 @@ impl ServerConnection {
                         k,
                         *encryption_client_to_server,
                         *encryption_server_to_client,
+                        *compression_client_to_server,
+                        *compression_server_to_client,
                         true,
                     );
-                    self.state = ServerState::ServiceRequest {
-                        session_id: SessionId(*h),
-                        may_send_extensions: true, // TODO: false if the client didn't advertise them
-                    };
+                    if self.strict_kex {
+                        self.packet_transport.reset_sequence_numbers();
+                    }
+
+                    let client_identification = take(client_identification);
+                    match *session_id {
+                        None => {
+                            // <https://datatracker.ietf.org/doc/html/rfc8308#section-2.4>
+                            if self.send_ext_info {
+                                // Users can authenticate with the same algorithms that we verify host keys with.
+                                let sup_algs = SupportedAlgorithms::secure(&self.config.host_keys);
+                                self.packet_transport.queue_packet(
+                                    Packet::new_msg_ext_info_server_sig_algs(
+                                        1,
+                                        b"server-sig-algs",
+                                        NameList::multi(&sup_algs.hostkey_verify.to_name_list()),
+                                    ),
+                                );
+                            }
+                            self.state = ServerState::ServiceRequest {
+                                session_id: SessionId(*h),
+                                client_identification,
+                                may_send_extensions: true, // TODO: false if the client didn't advertise them
+                            };
+                        }
+                        Some(session_id) => {
+                            debug!("Finished key re-exchange");
+                            self.state = ServerState::Open {
+                                session_id,
+                                client_identification,
+                            };
+                            for packet in take(&mut self.paused_packets) {
+                                self.packet_transport.queue_packet(packet);
+                            }
+                        }
+                    }
                 }
                 ServerState::ServiceRequest {
                     session_id,
+                    client_identification,
                     may_send_extensions,
                 } => match packet_type {
                     numbers::SSH_MSG_SERVICE_REQUEST => {
@@ -357,6 +483,7 @@ impl ServerConnection {
                         });
                         self.state = ServerState::Open {
                             session_id: *session_id,
+                            client_identification: take(client_identification),
                         };
                     }
                     numbers::SSH_MSG_EXT_INFO if *may_send_extensions => {
@@ -375,6 +502,7 @@ impl ServerConnection {
 
                         self.state = ServerState::ServiceRequest {
                             session_id: *session_id,
+                            client_identification: take(client_identification),
                             may_send_extensions: false,
                         };
                     }
@@ -392,13 +520,37 @@ impl ServerConnection {
         Ok(consumed)
     }
 
+    fn is_initial_kex(&self) -> bool {
+        matches!(
+            self.state,
+            ServerState::KeyExchangeInit {
+                session_id: None,
+                ..
+            } | ServerState::DhKeyInit {
+                session_id: None,
+                ..
+            } | ServerState::WaitingForKeyExchange {
+                session_id: None,
+                ..
+            } | ServerState::NewKeys {
+                session_id: None,
+                ..
+            }
+        )
+    }
+
     pub fn is_open(&self) -> Option<SessionId> {
         match self.state {
-            ServerState::Open { session_id } => Some(session_id),
+            ServerState::Open { session_id, .. } => Some(session_id),
             _ => None,
         }
     }
 
+    /// The negotiated key exchange algorithm, once the key exchange has started.
+    pub fn kex_algorithm(&self) -> Option<&'static str> {
+        self.kex_algorithm
+    }
+
     pub fn is_waiting_on_key_exchange(&self) -> Option<KeyExchangeParameters> {
         match &self.state {
             ServerState::WaitingForKeyExchange {
@@ -425,8 +577,12 @@ impl ServerConnection {
     pub fn do_key_exchange(&mut self, response: KeyExchangeResponse) {
         match &self.state {
             ServerState::WaitingForKeyExchange {
+                session_id,
+                client_identification,
                 encryption_client_to_server,
                 encryption_server_to_client,
+                compression_client_to_server,
+                compression_server_to_client,
                 server_host_key_algorithm,
                 ..
             } => {
@@ -438,10 +594,14 @@ impl ServerConnection {
 
                 self.packet_transport.queue_packet(packet);
                 self.state = ServerState::NewKeys {
+                    session_id: *session_id,
+                    client_identification: client_identification.clone(),
                     hash: response.hash.0,
                     shared_secret: response.shared_secret.clone(),
                     encryption_client_to_server: *encryption_client_to_server,
                     encryption_server_to_client: *encryption_server_to_client,
+                    compression_client_to_server: *compression_client_to_server,
+                    compression_server_to_client: *compression_server_to_client,
                 };
             }
             _ => unreachable!("doing signature while not waiting for it"),
@@ -456,8 +616,28 @@ impl ServerConnection {
         self.plaintext_packets.pop_front()
     }
 
+    /// Tells the client why the connection is being closed.
+    /// No more packets should be sent or received afterwards.
+    pub fn disconnect(&mut self, reason_code: u32, description: &str) {
+        // Disconnect messages are allowed during key exchange, so they are never paused.
+        self.packet_transport
+            .queue_packet(Packet::new_msg_disconnect(
+                reason_code,
+                description.as_bytes(),
+                b"",
+            ));
+    }
+
     pub fn send_plaintext_packet(&mut self, packet: Packet) {
-        self.packet_transport.queue_packet(packet);
+        match self.state {
+            ServerState::KeyExchangeInit { .. }
+            | ServerState::DhKeyInit { .. }
+            | ServerState::WaitingForKeyExchange { .. }
+            | ServerState::NewKeys { .. } => {
+                self.paused_packets.push_back(packet);
+            }
+            _ => self.packet_transport.queue_packet(packet),
+        }
     }
 }
 
@@ -492,10 +672,13 @@ pub fn do_key_exchange(
 
 #[cfg(test)]
 mod tests {
+    use cluelessh_keys::private::PlaintextPrivateKey;
     use hex_literal::hex;
+    use sha2::Digest;
 
     use crate::{
-        packet::MsgKind,
+        client::ClientConnection,
+        packet::{MsgKind, Packet},
         server::{ServerConfig, ServerConnection},
         SshRng,
     };
@@ -515,6 +698,152 @@ mod tests {
         }
     }
 
+    struct TestRng(u64);
+    impl SshRng for TestRng {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(32) {
+                self.0 += 1;
+                let bytes = sha2::Sha256::digest(self.0.to_be_bytes());
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+    }
+
+    /// Exchanges messages between the client and the server until both are idle.
+    fn pump(
+        client: &mut ClientConnection,
+        server: &mut ServerConnection,
+        host_key: &PlaintextPrivateKey,
+    ) {
+        loop {
+            let mut progress = false;
+            while let Some(msg) = client.next_msg_to_send() {
+                server.recv_bytes(&msg.to_bytes()).unwrap();
+                progress = true;
+            }
+            if let Some(params) = server.is_waiting_on_key_exchange() {
+                let response =
+                    super::do_key_exchange(params, host_key, &mut TestRng(2000)).unwrap();
+                server.do_key_exchange(response);
+                progress = true;
+            }
+            while let Some(msg) = server.next_msg_to_send() {
+                client.recv_bytes(&msg.to_bytes()).unwrap();
+                progress = true;
+            }
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    fn data_packet(i: u32) -> Packet {
+        Packet::new_msg_channel_data(0, &i.to_be_bytes())
+    }
+
+    #[test]
+    fn client_initiated_rekey() {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            cluelessh_keys::KeyGenerationParams {
+                key_type: cluelessh_keys::KeyType::Ed25519,
+            },
+        );
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = ServerConnection::new(
+            TestRng(1000),
+            ServerConfig {
+                server_identification: b"SSH-2.0-TestServer\r\n".to_vec(),
+                host_keys: vec![host_key.private_key.public_key()],
+            },
+        );
+
+        pump(&mut client, &mut server, &host_key);
+        let session_id = server.is_open().unwrap();
+        assert_eq!(client.is_open().unwrap().0, session_id.0);
+
+        client.rekey_limits.bytes = 100;
+        for i in 0..10 {
+            client.send_plaintext_packet(data_packet(i));
+            server.send_plaintext_packet(data_packet(100 + i));
+        }
+        pump(&mut client, &mut server, &host_key);
+        for i in 10..20 {
+            server.send_plaintext_packet(data_packet(100 + i));
+        }
+        pump(&mut client, &mut server, &host_key);
+
+        // The session ID stays the same.
+        assert_eq!(server.is_open().unwrap().0, session_id.0);
+        assert_eq!(client.is_open().unwrap().0, session_id.0);
+
+        let server_received = std::iter::from_fn(|| server.next_plaintext_packet())
+            .map(|packet| packet.payload)
+            .collect::<Vec<_>>();
+        let expected = (0..10).map(|i| data_packet(i).payload).collect::<Vec<_>>();
+        assert_eq!(server_received, expected);
+
+        let client_received = std::iter::from_fn(|| client.next_plaintext_packet())
+            .map(|packet| packet.payload)
+            .collect::<Vec<_>>();
+        let expected = (100..120)
+            .map(|i| data_packet(i).payload)
+            .collect::<Vec<_>>();
+        assert_eq!(client_received, expected);
+    }
+
+    #[test]
+    fn server_sig_algs() {
+        let host_key = PlaintextPrivateKey::generate(
+            String::new(),
+            cluelessh_keys::KeyGenerationParams {
+                key_type: cluelessh_keys::KeyType::Ed25519,
+            },
+        );
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = ServerConnection::new(
+            TestRng(1000),
+            ServerConfig {
+                server_identification: b"SSH-2.0-TestServer\r\n".to_vec(),
+                host_keys: vec![host_key.private_key.public_key()],
+            },
+        );
+
+        pump(&mut client, &mut server, &host_key);
+        assert!(client.is_open().is_some());
+        assert_eq!(
+            client.connection_info().unwrap().server_sig_algs(),
+            Some(vec!["ecdsa-sha2-nistp256", "ssh-ed25519"])
+        );
+    }
+
+    #[test]
+    fn strict_kex_rejects_packets_before_kexinit() {
+        let mut client = ClientConnection::new(TestRng(0));
+        let mut server = ServerConnection::new(
+            TestRng(1000),
+            ServerConfig {
+                server_identification: b"SSH-2.0-TestServer\r\n".to_vec(),
+                host_keys: Vec::new(),
+            },
+        );
+
+        let client_ident = client.next_msg_to_send().unwrap();
+        server.recv_bytes(&client_ident.to_bytes()).unwrap();
+        let server_ident = server.next_msg_to_send().unwrap();
+        client.recv_bytes(&server_ident.to_bytes()).unwrap();
+        // A man in the middle can inject this to shift the sequence numbers.
+        server
+            .recv_bytes(&Packet::new_msg_ignore(b"").to_bytes(true, Packet::DEFAULT_BLOCK_SIZE))
+            .unwrap();
+
+        let kexinit = client.next_msg_to_send().unwrap();
+        let Err(crate::SshStatus::PeerError(err)) = server.recv_bytes(&kexinit.to_bytes()) else {
+            panic!("server accepted a packet before KEXINIT");
+        };
+        assert!(err.contains("strict key exchange"), "{err}");
+    }
+
     #[test]
     fn protocol_exchange() {
         let mut con = ServerConnection::new(NoRng, ServerConfig::default());
@@ -587,7 +916,7 @@ mod tests {
 
         let mut con = ServerConnection::new(HardcodedRng(rng), ServerConfig::default());
         for part in conversation {
-            con.recv_bytes(&part.client).unwrap();
+            con.recv_bytes(part.client).unwrap();
             eprintln!("client: {:x?}", part.client);
             let bytes = con.next_msg_to_send().unwrap().to_bytes();
             if part.server != bytes {